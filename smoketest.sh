@@ -13,3 +13,19 @@ r verify
 
 r add src/
 r verify
+r verify "$(target/debug/dhstore add -d store README.md)"
+
+r gc
+r verify
+
+r audit
+r orphans list
+r stats
+
+rm -rf store2
+target/debug/dhstore -v -v clone store store2
+target/debug/dhstore -v -v verify -d store2
+target/debug/dhstore -v -v audit -d store2
+target/debug/dhstore -v -v compare store store2
+target/debug/dhstore -v -v merge -d store store2
+rm -rf store2
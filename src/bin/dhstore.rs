@@ -1,14 +1,18 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{self, Write};
 use std::process;
+use std::sync::OnceLock;
 
 use clap::{App, Arg, SubCommand, crate_version};
 use log::{Level, error};
 
 use dhstore;
 use dhstore::errors::Error;
-use dhstore::hash::ID;
+use dhstore::hash::{IdEncoding, ObjectId};
+use dhstore::json;
+use dhstore::{Object, ObjectData, PrintOptions, Property};
 use dhstore::logger::init;
+use dhstore::queries::Cancel;
 
 fn main() {
     let verbose = &Arg::with_name("verbose")
@@ -22,19 +26,120 @@ fn main() {
             .takes_value(true)
             .value_name("PATH")
             .help("Location of the store"),
+        Arg::with_name("key-file")
+            .long("key-file")
+            .takes_value(true)
+            .value_name("PATH")
+            .help("Encrypt blobs with a key derived from this file"),
+        Arg::with_name("cache")
+            .long("cache")
+            .takes_value(true)
+            .value_name("PATH")
+            .requires("cache-size")
+            .help("Keep only recently used blobs in this local directory, \
+                   fetching the others from the store"),
+        Arg::with_name("permissive")
+            .long("permissive")
+            .help("Skip the objects that can't be read instead of failing"),
+        Arg::with_name("policy-hook")
+            .long("policy-hook")
+            .takes_value(true)
+            .value_name("PROGRAM")
+            .help("Ask this program which objects to keep, when verifying \
+                   or collecting garbage"),
+        Arg::with_name("cache-size")
+            .long("cache-size")
+            .takes_value(true)
+            .value_name("BYTES")
+            .requires("cache")
+            .help("Maximum size of the blobs kept in the cache"),
+    ];
+    let add_args = &[
+        Arg::with_name("threads")
+            .short("j")
+            .long("threads")
+            .takes_value(true)
+            .value_name("COUNT")
+            .help("Number of threads hashing chunks (default: number of \
+                   CPUs)"),
+        Arg::with_name("no-metadata")
+            .long("no-metadata")
+            .help("Don't record modification times and permissions, so that \
+                   the same files always give the same ID"),
+        Arg::with_name("follow-symlinks")
+            .long("follow-symlinks")
+            .help("Add what symbolic links point to, instead of the links"),
+        Arg::with_name("exclude")
+            .long("exclude")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("PATTERN")
+            .help("Leave out the files and directories matching this \
+                   gitignore-style pattern"),
+        Arg::with_name("no-ingest-cache")
+            .long("no-ingest-cache")
+            .help("Read all the files, even those whose size and \
+                   modification time didn't change since they were last \
+                   added"),
+        Arg::with_name("reserve")
+            .long("reserve")
+            .help("Check that the files fit in the free space before adding \
+                   them, assuming none of them is already stored"),
     ];
     let matches = App::new("dhstore")
         .about("dhstore command-line client")
         .version(crate_version!())
         .author("Remi Rampin <remirampin@gmail.com>")
         .arg(verbose)
+        .arg(Arg::with_name("json")
+             .long("json")
+             .global(true)
+             .help("Write results as JSON, one document per line"))
+        .arg(Arg::with_name("id-encoding")
+             .long("id-encoding")
+             .global(true)
+             .takes_value(true)
+             .possible_values(&["base64", "base32", "base58"])
+             .help("Encoding of the IDs displayed (default: base64); JSON \
+                    output always uses base64"))
         .subcommand(SubCommand::with_name("init")
                     .about("Creates a new store")
                     .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("salt-chunks")
+                         .long("salt-chunks")
+                         .help("Pick chunk boundaries with a random salt, so \
+                                that blob sizes don't reveal known files"))
+                    .arg(Arg::with_name("disk-index")
+                         .long("disk-index")
+                         .help("Keep the objects in an on-disk index, rather \
+                                than loading all of them in memory")))
+        .subcommand(SubCommand::with_name("convert_index")
+                    .about("Moves the objects of the store to an on-disk \
+                            index")
+                    .arg(verbose)
                     .args(store_args))
+        .subcommand(SubCommand::with_name("clone")
+                    .about("Creates a new store as a replica of another")
+                    .arg(verbose)
+                    .arg(Arg::with_name("SOURCE")
+                         .required(true)
+                         .help("Location of the store to copy"))
+                    .arg(Arg::with_name("DEST")
+                         .required(true)
+                         .help("Location of the new store")))
         .subcommand(SubCommand::with_name("verify")
                     .about("Verifies the store (checks for invalid values)")
                     .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("ID")
+                         .help("Only check the objects and blobs reachable \
+                                from this object")))
+        .subcommand(SubCommand::with_name("self_test")
+                    .about("Checks that blobs can be written, read back and \
+                            deleted, and shows how long it takes")
+                    .arg(verbose)
                     .args(store_args))
         .subcommand(SubCommand::with_name("gc")
                     .about("Verifies the store and deletes garbage \
@@ -45,22 +150,317 @@ fn main() {
                     .about("Add a file or directory")
                     .arg(verbose)
                     .args(store_args)
+                    .args(add_args)
                     .arg(Arg::with_name("INPUT")
                          .required(true)
                          .help("Input file")))
+        .subcommand(SubCommand::with_name("snapshot")
+                    .about("Adds a file or directory, and records it as a \
+                            snapshot under a name")
+                    .arg(verbose)
+                    .args(store_args)
+                    .args(add_args)
+                    .arg(Arg::with_name("tree")
+                         .long("tree")
+                         .help("INPUT is the ID of an object already in the \
+                                store, rather than a path to add"))
+                    .arg(Arg::with_name("NAME")
+                         .required(true)
+                         .help("Name of the snapshots"))
+                    .arg(Arg::with_name("INPUT")
+                         .required(true)
+                         .help("File or directory to add")))
+        .subcommand(SubCommand::with_name("snapshots")
+                    .about("Lists the snapshots taken under a name, oldest \
+                            first")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("NAME")
+                         .required(true)
+                         .help("Name of the snapshots")))
         .subcommand(SubCommand::with_name("show")
                     .about("Pretty-print an object")
                     .arg(verbose)
                     .args(store_args)
                     .arg(Arg::with_name("ID")
                          .required(true)
-                         .help("ID of object to print from"))
+                         .help("ID of object to print from, optionally \
+                                followed by a slash-separated path of keys \
+                                and list indices"))
                     .arg(Arg::with_name("depth")
                          .short("D")
                          .long("depth")
                          .takes_value(true)
                          .value_name("DEPTH")
-                         .help("Maximum recursion depth")))
+                         .help("Maximum recursion depth"))
+                    .arg(Arg::with_name("max-objects")
+                         .long("max-objects")
+                         .takes_value(true)
+                         .value_name("COUNT")
+                         .help("Maximum number of objects to expand"))
+                    .arg(Arg::with_name("repeat")
+                         .long("repeat")
+                         .help("Expand objects every time they appear, \
+                                instead of showing them only once"))
+                    .arg(Arg::with_name("format")
+                         .long("format")
+                         .takes_value(true)
+                         .possible_values(&["pretty", "json", "bencode-hex"])
+                         .help("Output format, json with --json")))
+        .subcommand(SubCommand::with_name("chunks")
+                    .about("Lists the chunks of a file: offset, length and \
+                            blob")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of the file object or list of chunks")))
+        .subcommand(SubCommand::with_name("cat")
+                    .about("Prints the contents of a file")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("output")
+                         .short("o")
+                         .long("output")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Write to this file instead of stdout"))
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of the file object or list of chunks")))
+        .subcommand(SubCommand::with_name("extract")
+                    .about("Recreates a stored file or directory on disk")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("force")
+                         .long("force")
+                         .conflicts_with("keep-existing")
+                         .help("Replace existing files"))
+                    .arg(Arg::with_name("keep-existing")
+                         .long("keep-existing")
+                         .help("Leave existing files alone"))
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of the file or directory, optionally \
+                                followed by a slash-separated path in it"))
+                    .arg(Arg::with_name("DEST")
+                         .required(true)
+                         .help("Path to create")))
+        .subcommand(SubCommand::with_name("ls")
+                    .about("Lists a directory: kind, size, ID and name of \
+                            each entry")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("recursive")
+                         .short("r")
+                         .long("recursive")
+                         .help("Also list the contents of subdirectories"))
+                    .arg(Arg::with_name("PATH")
+                         .required(true)
+                         .help("ID of the directory, optionally followed by \
+                                a slash-separated path in it")))
+        .subcommand(SubCommand::with_name("du")
+                    .about("Shows the size of the blobs used by each entry \
+                            of a directory, unique to it and shared")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("PATH")
+                         .required(true)
+                         .help("ID of the directory, optionally followed by \
+                                a path")))
+        .subcommand(SubCommand::with_name("diff")
+                    .about("Shows the entries that were added, removed or \
+                            modified between two directories")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("FIRST")
+                         .required(true)
+                         .help("ID of the first directory, optionally \
+                                followed by a path"))
+                    .arg(Arg::with_name("SECOND")
+                         .required(true)
+                         .help("ID of the second directory, optionally \
+                                followed by a path")))
+        .subcommand(SubCommand::with_name("file_history")
+                    .about("Shows when the file at a path changed, over the \
+                            values of a permanode")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of the permanode, whose values are \
+                                directories"))
+                    .arg(Arg::with_name("PATH")
+                         .required(true)
+                         .help("Path of the file in the directories")))
+        .subcommand(SubCommand::with_name("uses")
+                    .about("Lists the files containing a blob, and their \
+                            paths")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("BLOB_ID")
+                         .required(true)
+                         .help("ID of the blob")))
+        .subcommand(SubCommand::with_name("refs")
+                    .about("Lists the objects referencing an object or blob")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of the object or blob")))
+        .subcommand(SubCommand::with_name("restic_serve")
+                    .about("Serves a restic repository from the store, over \
+                            restic's REST protocol")
+                    .after_help("Each upload or deletion records a new \
+                                 version of the repository, and the \
+                                 previous versions take space that grows \
+                                 with the square of the number of files. \
+                                 Their claims are deleted, so garbage \
+                                 collection can reclaim them if the policy \
+                                 drops deleted claims; with the default \
+                                 policy, everything is kept.")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("listen")
+                         .long("listen")
+                         .takes_value(true)
+                         .value_name("ADDRESS")
+                         .default_value("127.0.0.1:8000")
+                         .help("Address and port to listen on"))
+                    .arg(Arg::with_name("REPOSITORY")
+                         .help("ID of the repository, created if not \
+                                given")))
+        .subcommand(SubCommand::with_name("sign")
+                    .about("Adds a signature for an object")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("signing-key")
+                         .long("signing-key")
+                         .takes_value(true)
+                         .value_name("PATH")
+                         .required(true)
+                         .help("Sign with a key derived from this file"))
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of the object to sign")))
+        .subcommand(SubCommand::with_name("signatures")
+                    .about("Checks the signatures of an object")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("trust")
+                         .long("trust")
+                         .takes_value(true)
+                         .multiple(true)
+                         .number_of_values(1)
+                         .value_name("KEY")
+                         .help("Fail unless there is a good signature from \
+                                one of these public keys"))
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of the signed object")))
+        .subcommand(SubCommand::with_name("public_key")
+                    .about("Prints the public key of a signing key file")
+                    .arg(verbose)
+                    .arg(Arg::with_name("KEY_FILE")
+                         .required(true)
+                         .help("The signing key file")))
+        .subcommand(SubCommand::with_name("prefetch")
+                    .about("Fetches all the blobs reachable from an object \
+                            into the cache")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of object to fetch from")))
+        .subcommand(SubCommand::with_name("availability")
+                    .about("Shows how many of the blobs reachable from an \
+                            object are stored locally")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of object to check from")))
+        .subcommand(SubCommand::with_name("audit")
+                    .about("Lists the administrative operations recorded in \
+                            the log")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("include-deleted")
+                         .long("include-deleted")
+                         .help("Also list the deleted events")))
+        .subcommand(SubCommand::with_name("delete")
+                    .about("Marks a permanode or claim as deleted, keeping \
+                            its history")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of the permanode or claim")))
+        .subcommand(SubCommand::with_name("orphans")
+                    .about("Finds blobs not referenced by any live object")
+                    .arg(verbose)
+                    .args(store_args)
+                    .subcommand(SubCommand::with_name("list")
+                                .about("Lists the orphan blobs"))
+                    .subcommand(SubCommand::with_name("export")
+                                .about("Copies the orphan blobs to a \
+                                        directory")
+                                .arg(Arg::with_name("DEST")
+                                     .required(true)
+                                     .help("Directory to write blobs to"))))
+        .subcommand(SubCommand::with_name("merge")
+                    .about("Imports all the objects and blobs of another \
+                            store")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("OTHER")
+                         .required(true)
+                         .help("Location of the store to import")))
+        .subcommand(SubCommand::with_name("compare")
+                    .about("Shows the objects and blobs that differ between \
+                            two stores")
+                    .arg(verbose)
+                    .arg(Arg::with_name("list")
+                         .short("l")
+                         .long("list")
+                         .help("List the IDs of the objects and blobs that \
+                                are only in one store"))
+                    .arg(Arg::with_name("FIRST")
+                         .required(true)
+                         .help("Location of the first store"))
+                    .arg(Arg::with_name("SECOND")
+                         .required(true)
+                         .help("Location of the second store")))
+        .subcommand(SubCommand::with_name("stats")
+                    .about("Counts the objects and blobs in the store, and \
+                            how much space they take")
+                    .arg(verbose)
+                    .args(store_args))
+        .subcommand(SubCommand::with_name("recover")
+                    .about("Picks a new root config for a store whose root \
+                            file was lost")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("ROOT")
+                         .help("ID of the new root config; if missing, \
+                                candidates are listed to pick from")))
+        .subcommand(SubCommand::with_name("compact_claims")
+                    .about("Summarizes the values of a permanode, so the \
+                            claims can be deleted by the next gc")
+                    .arg(verbose)
+                    .args(store_args)
+                    .arg(Arg::with_name("ID")
+                         .required(true)
+                         .help("ID of the permanode")))
+        .subcommand(SubCommand::with_name("test_vectors")
+                    .about("Writes the test vectors for other \
+                            implementations of the format")
+                    .arg(verbose)
+                    .arg(Arg::with_name("verify")
+                         .long("verify")
+                         .takes_value(true)
+                         .value_name("FILE")
+                         .help("Check a file of test vectors instead")))
         .subcommand(SubCommand::with_name("blob_add")
                     .about("Low-level; add a blob from a file or stdin")
                     .arg(verbose)
@@ -95,8 +495,19 @@ fn main() {
             process::exit(1);
         }
         (command, Some(matches)) => {
-            if let Err(e) = run_command(command, matches) {
+            let result = run_command(command, matches);
+            if let Err(ref e) = result {
                 error!("{}", e);
+                if matches.is_present("json") {
+                    let mut out = String::from("{\"error\":");
+                    json::write_str(&mut out, &e.to_string());
+                    out.push('}');
+                    println!("{}", out);
+                }
+            }
+            // Reports the warnings that were left out
+            log::logger().flush();
+            if result.is_err() {
                 process::exit(1);
             }
         }
@@ -105,33 +516,179 @@ fn main() {
 
 fn run_command(command: &str, matches: &clap::ArgMatches)
         -> dhstore::errors::Result<()> {
-    let get_store = ||
-            -> dhstore::errors::Result<dhstore::Store<dhstore::FileBlobStorage,
-                                       dhstore::MemoryIndex>> {
-        dhstore::open(matches.value_of_os("store")
-                      .unwrap_or_else(|| ".".as_ref()))
+    // Only for the IDs printed as text, JSON always has base64
+    let ids = matches.value_of("id-encoding")
+        .map_or(IdEncoding::Base64, |e| IdEncoding::parse(e).unwrap());
+    let get_store = || -> dhstore::errors::Result<dhstore::DynStore> {
+        let path = matches.value_of_os("store")
+            .unwrap_or_else(|| ".".as_ref());
+        if !matches.is_present("cache") && !matches.is_present("key-file") &&
+            !matches.is_present("permissive") &&
+            !matches.is_present("policy-hook")
+        {
+            return dhstore::open(path);
+        }
+        let mut builder = dhstore::StoreBuilder::new()
+            .path(path)
+            .permissive(matches.is_present("permissive"));
+        if let Some(hook) = matches.value_of_os("policy-hook") {
+            builder = builder.policy(Box::new(dhstore::PolicyHook::new(hook)));
+        }
+        if let Some(key_file) = matches.value_of_os("key-file") {
+            let key = dhstore::EncryptionKey::from_keyfile(key_file)?;
+            builder = builder.wrap_storage(move |storage| {
+                Ok(Box::new(dhstore::EncryptedBlobStorage::new(storage, key)))
+            });
+        }
+        if let Some(cache) = matches.value_of_os("cache") {
+            let capacity = matches.value_of("cache-size").unwrap().parse()
+                .map_err(|_| Error::InvalidInput("Invalid cache size"))?;
+            fs::create_dir_all(cache)
+                .map_err(|e| ("Can't create cache directory", e))?;
+            builder = builder.cache(
+                Box::new(dhstore::FileBlobStorage::open(cache)), capacity);
+        }
+        builder.open()
     };
     match command {
         "init" => {
             let path = matches.value_of_os("store")
                 .unwrap_or_else(|| ".".as_ref());
-            dhstore::create(path)
+            if matches.is_present("salt-chunks") {
+                dhstore::create_salted(path)?;
+            } else {
+                dhstore::create(path)?;
+            }
+            if matches.is_present("disk-index") {
+                dhstore::convert_to_disk_index(path)?;
+            }
+            Ok(())
+        }
+        "convert_index" => {
+            let path = matches.value_of_os("store")
+                .unwrap_or_else(|| ".".as_ref());
+            let nb_objects = dhstore::convert_to_disk_index(path)?;
+            println!("Moved {} objects to the disk index", nb_objects);
+            Ok(())
+        }
+        "clone" => {
+            dhstore::clone(matches.value_of_os("SOURCE").unwrap(),
+                           matches.value_of_os("DEST").unwrap())
+        }
+        "self_test" => {
+            let mut store = get_store()?;
+            let test = store.self_test()?;
+            println!("Write: {:?}", test.write);
+            println!("Read: {:?}", test.read);
+            println!("Delete: {:?}", test.delete);
+            Ok(())
         }
         "verify" => {
-            get_store()?.verify()
+            match matches.value_of("ID") {
+                Some(id) => {
+                    let store = get_store()?;
+                    let id = store.resolve_object(id)?;
+                    let problems = store.verify_tree(&id)?;
+                    if matches.is_present("json") {
+                        println!("{{\"problems\":{}}}", problems);
+                    }
+                    if problems > 0 {
+                        return Err(Error::CorruptedStore(
+                            "Found problems in the tree"));
+                    }
+                    Ok(())
+                }
+                None => {
+                    let mut store = get_store()?;
+                    store.set_cancel(handle_interrupt());
+                    store.verify()?;
+                    let quarantined = store.quarantined();
+                    if matches.is_present("json") {
+                        let mut out = String::from("{\"quarantined\":[");
+                        for (i, q) in quarantined.iter().enumerate() {
+                            if i > 0 {
+                                out.push(',');
+                            }
+                            json::write_str(&mut out,
+                                            &q.path.to_string_lossy());
+                        }
+                        out.push_str("]}");
+                        println!("{}", out);
+                    }
+                    if !quarantined.is_empty() {
+                        if !matches.is_present("json") {
+                            for q in quarantined {
+                                println!("{}", q.path.display());
+                            }
+                        }
+                        return Err(Error::CorruptedStore(
+                            "Some objects couldn't be read"));
+                    }
+                    Ok(())
+                }
+            }
         }
         "gc" => {
-            get_store()?.collect_garbage()
+            let mut store = get_store()?;
+            store.set_cancel(handle_interrupt());
+            store.collect_garbage()
         }
         "add" => {
-            let id = get_store()?.add(matches.value_of_os("INPUT").unwrap())?;
-            println!("{}", id);
+            let mut store = get_store()?;
+            set_add_options(&mut store, matches)?;
+            let id = store.add(matches.value_of_os("INPUT").unwrap())?;
+            if let Some(mut cache) = store.take_ingest_cache() {
+                cache.save()?;
+            }
+            if matches.is_present("json") {
+                let mut out = String::from("{\"id\":");
+                json::write_str(&mut out, &id.id().str());
+                out.push('}');
+                println!("{}", out);
+            } else {
+                println!("{}", id.encode(ids));
+            }
+            Ok(())
+        }
+        "snapshot" => {
+            let mut store = get_store()?;
+            let name = matches.value_of("NAME").unwrap();
+            let snapshot = if matches.is_present("tree") {
+                let tree = store.resolve_object(
+                    matches.value_of("INPUT").unwrap())?;
+                store.snapshot_tree(&tree, name)?
+            } else {
+                set_add_options(&mut store, matches)?;
+                let snapshot = store.snapshot(
+                    matches.value_of_os("INPUT").unwrap(), name)?;
+                if let Some(mut cache) = store.take_ingest_cache() {
+                    cache.save()?;
+                }
+                snapshot
+            };
+            if matches.is_present("json") {
+                println!("{}", snapshot_json(&snapshot));
+            } else {
+                println!("{}", snapshot.tree.encode(ids));
+            }
+            Ok(())
+        }
+        "snapshots" => {
+            let store = get_store()?;
+            let name = matches.value_of("NAME").unwrap();
+            for snapshot in store.snapshots(name)? {
+                if matches.is_present("json") {
+                    println!("{}", snapshot_json(&snapshot));
+                } else {
+                    println!("{}  {}", format_date(snapshot.date),
+                             snapshot.tree.encode(ids));
+                }
+            }
             Ok(())
         }
         "show" => {
             let store = get_store()?;
-            let id = ID::from_str(matches.value_of("ID").unwrap().as_bytes())
-                .ok_or(Error::InvalidInput("Input is not a valid ID"))?;
+            let value = resolve_path(&store, matches.value_of("ID").unwrap())?;
             let depth = if let Some(arg) = matches.value_of_lossy("depth") {
                 match arg.parse() {
                     Ok(i) => Some(i),
@@ -143,7 +700,529 @@ fn run_command(command: &str, matches: &clap::ArgMatches)
             } else {
                 None
             };
-            store.print_object(&id, depth)
+            let max_objects = match matches.value_of("max-objects") {
+                Some(arg) => Some(arg.parse().map_err(|_| {
+                    Error::InvalidInput("Invalid number for --max-objects")
+                })?),
+                None => None,
+            };
+            let options = PrintOptions {
+                max_depth: depth,
+                max_objects,
+                repeat: matches.is_present("repeat"),
+                ids,
+            };
+            let format = match matches.value_of("format") {
+                Some(format) => dhstore::Format::parse(format).unwrap(),
+                None if matches.is_present("json") => dhstore::Format::Json,
+                None => dhstore::Format::Pretty,
+            };
+            let stdout = io::stdout();
+            let mut out = io::BufWriter::new(stdout.lock());
+            store.render_value(&mut out, &value, format, &options)?;
+            writeln!(out).and_then(|()| out.flush())
+                .map_err(|e| ("Error writing output", e).into())
+        }
+        "chunks" => {
+            let store = get_store()?;
+            let id = store.resolve_object(matches.value_of("ID").unwrap())?;
+            for chunk in store.file_manifest(&id)? {
+                match chunk.blob {
+                    Some(blob) => {
+                        println!("{} {} {}", chunk.offset, chunk.length,
+                                 blob.encode(ids))
+                    }
+                    None => println!("{} {} inline", chunk.offset,
+                                     chunk.length),
+                }
+            }
+            Ok(())
+        }
+        "cat" => {
+            let store = get_store()?;
+            let id = store.resolve_object(matches.value_of("ID").unwrap())?;
+            let mut reader = store.get_file(&id)?;
+            match matches.value_of_os("output") {
+                Some(path) => {
+                    let mut fp = File::create(path)
+                        .map_err(|e| ("Cannot open file for writing", e))?;
+                    io::copy(&mut reader, &mut fp)
+                        .map_err(|e| ("Error writing file", e))?;
+                }
+                None => {
+                    let stdout = io::stdout();
+                    let mut out = stdout.lock();
+                    io::copy(&mut reader, &mut out)
+                        .and_then(|_| out.flush())
+                        .map_err(|e| ("Error writing to stdout", e))?;
+                }
+            }
+            Ok(())
+        }
+        "extract" => {
+            let store = get_store()?;
+            let id = match resolve_path(&store,
+                                        matches.value_of("ID").unwrap())? {
+                Property::Reference(id) => id,
+                _ => return Err(Error::InvalidInput(
+                    "Object is not a file or directory")),
+            };
+            let existing = if matches.is_present("force") {
+                dhstore::Existing::Overwrite
+            } else if matches.is_present("keep-existing") {
+                dhstore::Existing::Keep
+            } else {
+                dhstore::Existing::Fail
+            };
+            let extracted = store.extract_with(
+                &id, matches.value_of_os("DEST").unwrap(), existing)?;
+            println!("Extracted {} files ({} bytes) and {} directories",
+                     extracted.files, extracted.bytes, extracted.directories);
+            if extracted.symlinks > 0 {
+                println!("Created {} symbolic links", extracted.symlinks);
+            }
+            if extracted.skipped > 0 {
+                println!("Kept {} existing entries", extracted.skipped);
+            }
+            Ok(())
+        }
+        "ls" => {
+            let store = get_store()?;
+            let id = match resolve_path(&store,
+                                        matches.value_of("PATH").unwrap())? {
+                Property::Reference(id) => id,
+                _ => return Err(Error::InvalidInput(
+                    "Object is not a directory")),
+            };
+            let listing = if matches.is_present("json") {
+                Listing::Json
+            } else {
+                Listing::Text(ids)
+            };
+            let stdout = io::stdout();
+            let mut out = io::BufWriter::new(stdout.lock());
+            list_dir(&store, &mut out, &id, "",
+                     matches.is_present("recursive"), listing,
+                     &mut Vec::new())?;
+            out.flush().map_err(|e| ("Error writing output", e).into())
+        }
+        "du" => {
+            let store = get_store()?;
+            let id = match resolve_path(&store,
+                                        matches.value_of("PATH").unwrap())? {
+                Property::Reference(id) => id,
+                _ => return Err(Error::InvalidInput(
+                    "Object is not a directory")),
+            };
+            for entry in store.disk_usage(&id)? {
+                if matches.is_present("json") {
+                    let mut line = format!("{{\"unique\":{},\"shared\":{},\
+                                            \"id\":",
+                                           entry.unique, entry.shared);
+                    json::write_str(&mut line, &entry.id.id().str());
+                    line.push_str(",\"name\":");
+                    json::write_str(&mut line, &entry.name);
+                    line.push('}');
+                    println!("{}", line);
+                } else {
+                    println!("{:>12} {:>12} {}", entry.unique, entry.shared,
+                             entry.name);
+                }
+            }
+            Ok(())
+        }
+        "diff" => {
+            let store = get_store()?;
+            let mut dirs = Vec::new();
+            for arg in &["FIRST", "SECOND"] {
+                match resolve_path(&store, matches.value_of(arg).unwrap())? {
+                    Property::Reference(id) => dirs.push(id),
+                    _ => return Err(Error::InvalidInput(
+                        "Object is not a directory")),
+                }
+            }
+            for change in store.diff(&dirs[0], &dirs[1])? {
+                if matches.is_present("json") {
+                    let mut line = String::from("{\"change\":");
+                    json::write_str(&mut line, change.change.as_str());
+                    line.push_str(",\"path\":");
+                    json::write_str(&mut line, &change.path);
+                    if let Some(kind) = change.kind {
+                        line.push_str(",\"kind\":");
+                        json::write_str(&mut line, kind.as_str());
+                    }
+                    for (key, id) in [("old", &change.old),
+                                      ("new", &change.new)]
+                    {
+                        if let Some(id) = id {
+                            line.push_str(&format!(",\"{}\":", key));
+                            json::write_str(&mut line, &id.id().str());
+                        }
+                    }
+                    line.push('}');
+                    println!("{}", line);
+                } else {
+                    let marker = match change.change {
+                        dhstore::ChangeKind::Added => '+',
+                        dhstore::ChangeKind::Removed => '-',
+                        dhstore::ChangeKind::Modified => 'M',
+                    };
+                    let slash = match change.kind {
+                        Some(dhstore::EntryKind::Dir) => "/",
+                        _ => "",
+                    };
+                    println!("{} {}{}", marker, change.path, slash);
+                }
+            }
+            Ok(())
+        }
+        "file_history" => {
+            let store = get_store()?;
+            let id = store.resolve_object(matches.value_of("ID").unwrap())?;
+            let path = matches.value_of("PATH").unwrap();
+            for version in store.file_history(&id, path)? {
+                let key = match version.key {
+                    Property::Integer(i) => format_date(i),
+                    ref p => format!("{:?}", p),
+                };
+                match version.file {
+                    Some(file) => {
+                        let size = version.size.map_or_else(
+                            || "-".to_owned(), |s| s.to_string());
+                        println!("{}  {:>12} {}", key, size,
+                                 file.encode(ids));
+                    }
+                    None => println!("{}  {:>12}", key, "#missing#"),
+                }
+            }
+            Ok(())
+        }
+        "uses" => {
+            let store = get_store()?;
+            let id = store.resolve_blob(matches.value_of("BLOB_ID").unwrap())?;
+            let index = store.blob_index()?;
+            for used in index.uses(&id) {
+                let file = used.file.encode(ids);
+                if used.paths.is_empty() {
+                    println!("{}", file);
+                }
+                for (root, path) in used.paths {
+                    println!("{} {}/{}", file, root.encode(ids), path);
+                }
+            }
+            Ok(())
+        }
+        "refs" => {
+            let store = get_store()?;
+            let id = store.resolve_id(matches.value_of("ID").unwrap())?;
+            for (key, source) in store.referrers(&id)? {
+                match key {
+                    dhstore::Backkey::Key(key) => {
+                        println!("{} {:?}", source.encode(ids), key);
+                    }
+                    dhstore::Backkey::Index(i) => {
+                        println!("{} [{}]", source.encode(ids), i);
+                    }
+                }
+            }
+            Ok(())
+        }
+        "restic_serve" => {
+            let mut store = get_store()?;
+            let listen = matches.value_of("listen").unwrap();
+            let listener = std::net::TcpListener::bind(listen)
+                .map_err(|e| ("Can't listen on address", e))?;
+            let mut server = match matches.value_of("REPOSITORY") {
+                Some(id) => {
+                    let id = store.resolve_object(id)?;
+                    dhstore::ResticServer::open(&mut store, &id)?
+                }
+                None => dhstore::ResticServer::create(&mut store)?,
+            };
+            println!("Serving repository {} on http://{}/",
+                     server.node().encode(ids), listen);
+            server.serve(listener)
+        }
+        "sign" => {
+            let mut store = get_store()?;
+            let key = dhstore::SigningKey::from_keyfile(
+                matches.value_of_os("signing-key").unwrap())?;
+            let id = store.resolve_object(matches.value_of("ID").unwrap())?;
+            println!("{}", store.sign(&id, &key)?.encode(ids));
+            Ok(())
+        }
+        "signatures" => {
+            let store = get_store()?;
+            let id = store.resolve_object(matches.value_of("ID").unwrap())?;
+            let trusted = matches.values_of("trust").into_iter().flatten()
+                .map(|k| dhstore::PublicKey::parse(k).ok_or(
+                    Error::InvalidInput("Invalid public key")))
+                .collect::<Result<Vec<_>, _>>()?;
+            let checks = store.verify_signature(&id)?;
+            for check in &checks {
+                println!("{} {} {}",
+                         if check.valid { "good" } else { "BAD" },
+                         check.key, check.signature.encode(ids));
+            }
+            if !trusted.is_empty() &&
+                !checks.iter().any(|c| c.valid && trusted.contains(&c.key))
+            {
+                return Err(Error::InvalidInput(
+                    "No good signature from a trusted key"));
+            }
+            Ok(())
+        }
+        "public_key" => {
+            let key = dhstore::SigningKey::from_keyfile(
+                matches.value_of_os("KEY_FILE").unwrap())?;
+            println!("{}", key.public_key());
+            Ok(())
+        }
+        "availability" => {
+            let store = get_store()?;
+            let id = store.resolve_object(matches.value_of("ID").unwrap())?;
+            let availability = store.availability(&id)?;
+            println!("{}/{} blobs available locally ({:.1}%)",
+                     availability.present, availability.total,
+                     availability.fraction() * 100.0);
+            if let Some(stats) = store.cache_stats() {
+                println!("Cache: {}/{} bytes, {} hits, {} misses ({:.1}% \
+                          hits), {} evictions",
+                         stats.size, stats.capacity, stats.hits, stats.misses,
+                         stats.hit_rate() * 100.0, stats.evictions);
+            }
+            Ok(())
+        }
+        "prefetch" => {
+            let store = get_store()?;
+            let id = store.resolve_object(matches.value_of("ID").unwrap())?;
+            let count = store.prefetch_tree(&id)?;
+            println!("{} blobs", count);
+            Ok(())
+        }
+        "audit" => {
+            let store = get_store()?;
+            let events = store.list_events_with(
+                matches.is_present("include-deleted"))?;
+            for member in events {
+                let date = match member.key {
+                    Property::Integer(i) => format_date(i),
+                    ref p => format!("{:?}", p),
+                };
+                let event = store.get_object(&member.value)?;
+                let event = match event.as_deref() {
+                    Some(Object { data: ObjectData::Dict(d), .. }) => d,
+                    _ => {
+                        println!("{}  {} #missing#", date,
+                                 member.value.encode(ids));
+                        continue;
+                    }
+                };
+                let kind = match event.get("event") {
+                    Some(Property::String(s)) => s as &str,
+                    _ => "?",
+                };
+                print!("{}  {}", date, kind);
+                if member.deleted {
+                    print!("  #deleted#");
+                }
+                for (key, value) in event {
+                    if key != "event" {
+                        match value {
+                            Property::String(s) => print!("  {}={:?}", key, s),
+                            Property::Integer(i) => print!("  {}={}", key, i),
+                            Property::Reference(id) => {
+                                print!("  {}={}", key, id.encode(ids))
+                            }
+                            Property::Blob(id) => {
+                                print!("  {}={}", key, id.encode(ids))
+                            }
+                            Property::Inline(data) => {
+                                print!("  {}=<{} bytes>", key, data.len())
+                            }
+                        }
+                    }
+                }
+                println!();
+            }
+            Ok(())
+        }
+        "delete" => {
+            let mut store = get_store()?;
+            let id = store.resolve_object(matches.value_of("ID").unwrap())?;
+            println!("{}", store.delete(&id)?.encode(ids));
+            Ok(())
+        }
+        "orphans" => {
+            let store = get_store()?;
+            match matches.subcommand() {
+                ("export", Some(m)) => {
+                    let count = store.export_orphans(
+                        m.value_of_os("DEST").unwrap())?;
+                    println!("Exported {} blobs", count);
+                }
+                _ => {
+                    for id in store.orphan_blobs()? {
+                        println!("{}", id.encode(ids));
+                    }
+                }
+            }
+            Ok(())
+        }
+        "merge" => {
+            let mut store = get_store()?;
+            store.set_cancel(handle_interrupt());
+            let path = matches.value_of_os("OTHER").unwrap();
+            let other = dhstore::open(path)?;
+            let copied = store.merge(&other, &path.to_string_lossy())?;
+            println!("Merged {} objects and {} blobs", copied.objects,
+                     copied.blobs);
+            Ok(())
+        }
+        "compare" => {
+            let first = dhstore::open(matches.value_of_os("FIRST").unwrap())?;
+            let second = dhstore::open(
+                matches.value_of_os("SECOND").unwrap())?;
+            let comparison = first.compare(&second)?;
+            let kinds = [("object", "Objects", &comparison.objects),
+                         ("blob", "Blobs", &comparison.blobs)];
+            if matches.is_present("list") {
+                for &(kind, _, difference) in &kinds {
+                    for id in &difference.only_first {
+                        println!("< {} {}", kind, id.encode(ids));
+                    }
+                    for id in &difference.only_second {
+                        println!("> {} {}", kind, id.encode(ids));
+                    }
+                }
+            }
+            for &(_, name, difference) in &kinds {
+                println!("{}: {} only in first, {} only in second, {} in \
+                          both",
+                         name, difference.only_first.len(),
+                         difference.only_second.len(), difference.both);
+            }
+            Ok(())
+        }
+        "stats" => {
+            let store = get_store()?;
+            let stats = store.stats()?;
+            if matches.is_present("json") {
+                let mut out = format!("{{\"objects\":{},\"object_types\":{{",
+                                      stats.objects);
+                for (i, (kind, count)) in
+                    stats.object_types.iter().enumerate()
+                {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    json::write_str(&mut out, kind);
+                    out.push_str(&format!(":{}", count));
+                }
+                out.push_str(&format!(
+                    "}},\"object_bytes\":{},\"blobs\":{},\
+                     \"blob_bytes\":{},\"file_bytes\":{},\"largest\":[",
+                    stats.object_bytes, stats.blobs, stats.blob_bytes,
+                    stats.file_bytes));
+                for (i, (size, id)) in stats.largest.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    out.push_str(&format!("{{\"size\":{},\"id\":", size));
+                    json::write_str(&mut out, &id.id().str());
+                    out.push('}');
+                }
+                out.push_str("]}");
+                println!("{}", out);
+                return Ok(());
+            }
+            println!("Objects: {} ({} bytes)", stats.objects,
+                     stats.object_bytes);
+            for (kind, count) in &stats.object_types {
+                println!("  {:<12} {}", kind, count);
+            }
+            println!("Blobs: {} ({} bytes)", stats.blobs, stats.blob_bytes);
+            println!("Files: {} bytes, deduplication ratio {:.2}",
+                     stats.file_bytes, stats.dedup_ratio());
+            if !stats.largest.is_empty() {
+                println!("Largest objects:");
+                for (size, id) in &stats.largest {
+                    println!("  {:>12} {}", size, id.encode(ids));
+                }
+            }
+            Ok(())
+        }
+        "recover" => {
+            let path = matches.value_of_os("store")
+                .unwrap_or_else(|| ".".as_ref());
+            let root = if let Some(root) = matches.value_of("ROOT") {
+                ObjectId::decode(root.as_bytes())
+                    .ok_or(Error::InvalidInput("Input is not a valid ID"))?
+            } else {
+                let candidates = dhstore::root_candidates(path)?;
+                if candidates.is_empty() {
+                    return Err(Error::CorruptedStore(
+                        "No candidate root config found"));
+                }
+                for (i, object) in candidates.iter().enumerate() {
+                    let keys = match object.data {
+                        ObjectData::Dict(ref d) => {
+                            d.keys().cloned().collect::<Vec<_>>()
+                        }
+                        ObjectData::List(_) => Vec::new(),
+                    };
+                    println!("[{}] {} {{ {} }}", i + 1,
+                             object.id.encode(ids), keys.join(", "));
+                }
+                print!("Pick the new root config [1-{}]: ", candidates.len());
+                io::stdout().flush()
+                    .map_err(|e| ("Error writing to stdout", e))?;
+                let mut line = String::new();
+                io::stdin().read_line(&mut line)
+                    .map_err(|e| ("Error reading from stdin", e))?;
+                let choice: usize = line.trim().parse()
+                    .map_err(|_| Error::InvalidInput("Invalid choice"))?;
+                if choice < 1 || choice > candidates.len() {
+                    return Err(Error::InvalidInput("Invalid choice"));
+                }
+                candidates[choice - 1].id.clone()
+            };
+            dhstore::set_root(path, &root)?;
+            println!("New root config: {}", root.encode(ids));
+            Ok(())
+        }
+        "compact_claims" => {
+            let mut store = get_store()?;
+            let id = store.resolve_object(matches.value_of("ID").unwrap())?;
+            match store.compact_claims(&id)? {
+                Some(checkpoint) => println!("{}", checkpoint.encode(ids)),
+                None => println!("Permanode has no values"),
+            }
+            Ok(())
+        }
+        "test_vectors" => {
+            match matches.value_of_os("verify") {
+                Some(path) => {
+                    let file = File::open(path)
+                        .map_err(|e| ("Cannot open test vectors", e))?;
+                    let failed = dhstore::testvectors::verify(
+                        io::BufReader::new(file))?;
+                    for name in &failed {
+                        println!("{}", name);
+                    }
+                    if !failed.is_empty() {
+                        return Err(Error::InvalidInput(
+                            "Some test vectors don't match"));
+                    }
+                    Ok(())
+                }
+                None => {
+                    let stdout = io::stdout();
+                    let mut out = io::BufWriter::new(stdout.lock());
+                    dhstore::testvectors::write(&mut out)?;
+                    out.flush().map_err(|e| ("Error writing output", e).into())
+                }
+            }
         }
         "blob_add" => {
             let mut store = get_store()?;
@@ -155,13 +1234,12 @@ fn run_command(command: &str, matches: &clap::ArgMatches)
                     .map_err(|e| ("Cannot open file for reading", e))?;
                 store.add_blob(fp)
             }?;
-            println!("{}", id);
+            println!("{}", id.encode(ids));
             Ok(())
         }
         "blob_get" => {
             let store = get_store()?;
-            let id = ID::from_str(matches.value_of("ID").unwrap().as_bytes())
-                .ok_or(Error::InvalidInput("Input is not a valid ID"))?;
+            let id = store.resolve_blob(matches.value_of("ID").unwrap())?;
             match store.get_blob(&id)? {
                 Some(blob) => {
                     io::stdout().write_all(&blob)
@@ -177,3 +1255,165 @@ fn run_command(command: &str, matches: &clap::ArgMatches)
         _ => panic!("Missing code for command {}", command),
     }
 }
+
+/// Cancelled by the first SIGINT, see `handle_interrupt()`.
+static INTERRUPT: OnceLock<Cancel> = OnceLock::new();
+
+/// Makes SIGINT stop the operation cleanly rather than kill the process.
+///
+/// The operation stops at the next object it checks the handle, a second
+/// SIGINT kills the process.
+#[cfg(unix)]
+fn handle_interrupt() -> Cancel {
+    extern "C" fn on_interrupt(_: libc::c_int) {
+        if let Some(cancel) = INTERRUPT.get() {
+            cancel.cancel();
+        }
+        unsafe {
+            libc::signal(libc::SIGINT, libc::SIG_DFL);
+        }
+    }
+
+    let cancel = INTERRUPT.get_or_init(Cancel::new).clone();
+    let handler = on_interrupt as extern "C" fn(libc::c_int);
+    unsafe {
+        libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+    }
+    cancel
+}
+
+#[cfg(not(unix))]
+fn handle_interrupt() -> Cancel {
+    INTERRUPT.get_or_init(Cancel::new).clone()
+}
+
+/// Sets the options of the `add` and `snapshot` commands on the store.
+///
+/// Unless turned off, this loads the ingest cache, which should be saved
+/// after adding.
+fn set_add_options(store: &mut dhstore::DynStore,
+                   matches: &clap::ArgMatches)
+    -> dhstore::errors::Result<()>
+{
+    if let Some(threads) = matches.value_of("threads") {
+        let threads = threads.parse().map_err(|_| {
+            Error::InvalidInput("Invalid number of threads")
+        })?;
+        store.set_threads(threads);
+    }
+    store.set_metadata(!matches.is_present("no-metadata"));
+    store.set_follow_symlinks(matches.is_present("follow-symlinks"));
+    store.set_reserve(matches.is_present("reserve"));
+    if let Some(patterns) = matches.values_of("exclude") {
+        let mut ignore = dhstore::IgnoreRules::new();
+        for pattern in patterns {
+            ignore.add(pattern)?;
+        }
+        store.set_ignore(ignore);
+    }
+    store.set_cancel(handle_interrupt());
+    let store_path = matches.value_of_os("store")
+        .unwrap_or_else(|| ".".as_ref());
+    if !matches.is_present("no-ingest-cache") {
+        store.set_ingest_cache(dhstore::ingest_cache(store_path)?);
+    }
+    Ok(())
+}
+
+/// Formats a snapshot as a JSON object.
+fn snapshot_json(snapshot: &dhstore::Snapshot) -> String {
+    let mut out = format!("{{\"date\":{},\"tree\":", snapshot.date);
+    json::write_str(&mut out, &snapshot.tree.id().str());
+    out.push_str(",\"claim\":");
+    json::write_str(&mut out, &snapshot.claim.id().str());
+    out.push('}');
+    out
+}
+
+/// Finds the value designated by an ID, possibly followed by a path.
+///
+/// See `Store::resolve_path()`.
+fn resolve_path(store: &dhstore::DynStore, arg: &str)
+    -> dhstore::errors::Result<Property>
+{
+    let (id, path) = arg.split_once('/').unwrap_or((arg, ""));
+    let id = store.resolve_object(id)?;
+    store.resolve_path(&id, path)?
+        .ok_or(Error::InvalidInput("Path not found"))
+}
+
+/// How `list_dir()` writes the entries.
+#[derive(Clone, Copy)]
+enum Listing {
+    /// A JSON object per line
+    Json,
+    /// A line of text, with the ID in this encoding
+    Text(IdEncoding),
+}
+
+/// Prints the entries of a directory, prefixing their name with `prefix`.
+fn list_dir<W: Write>(store: &dhstore::DynStore, out: &mut W, id: &ObjectId,
+                      prefix: &str, recursive: bool, listing: Listing,
+                      parents: &mut Vec<ObjectId>)
+    -> dhstore::errors::Result<()>
+{
+    if parents.contains(id) {
+        return Err(Error::ReferenceCycle(id.clone()));
+    }
+    parents.push(id.clone());
+    for entry in store.list_dir(id)? {
+        match listing {
+            Listing::Json => {
+                let mut line = String::from("{\"kind\":");
+                match entry.kind {
+                    Some(k) => json::write_str(&mut line, k.as_str()),
+                    None => line.push_str("null"),
+                }
+                line.push_str(",\"size\":");
+                match entry.size {
+                    Some(s) => line.push_str(&s.to_string()),
+                    None => line.push_str("null"),
+                }
+                line.push_str(",\"id\":");
+                json::write_str(&mut line, &entry.id.id().str());
+                line.push_str(",\"name\":");
+                json::write_str(&mut line,
+                                &format!("{}{}", prefix, entry.name));
+                line.push('}');
+                writeln!(out, "{}", line)
+            }
+            Listing::Text(ids) => {
+                let kind = entry.kind.map_or("?", |k| k.as_str());
+                let size = entry.size.map_or_else(|| "-".to_owned(),
+                                                  |s| s.to_string());
+                writeln!(out, "{:<7} {:>12} {} {}{}", kind, size,
+                         entry.id.encode(ids), prefix, entry.name)
+            }
+        }.map_err(|e| ("Error writing output", e))?;
+        if recursive && entry.kind == Some(dhstore::EntryKind::Dir) {
+            let prefix = format!("{}{}/", prefix, entry.name);
+            list_dir(store, out, &entry.id, &prefix, true, listing, parents)?;
+        }
+    }
+    parents.pop();
+    Ok(())
+}
+
+/// Formats a timestamp in microseconds since the Unix epoch, as UTC.
+fn format_date(micros: i64) -> String {
+    let secs = micros.div_euclid(1_000_000);
+    let days = secs.div_euclid(86400);
+    let time = secs.rem_euclid(86400);
+    // Civil date from days since 1970-01-01 (proleptic Gregorian calendar)
+    let z = days + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
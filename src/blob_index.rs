@@ -0,0 +1,234 @@
+//! Reverse index from blobs to the files using them.
+//!
+//! Objects only reference their children, so finding which files are hurt by
+//! a bad blob means going over the whole index. `BlobIndex` does that once,
+//! then answers for any number of blobs: the file objects containing the
+//! blob, and their paths from each top-level directory they are in.
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet};
+
+use crate::common::{BlobId, ObjectId, Object, ObjectData, ObjectIterator,
+                    Property};
+use crate::errors;
+use crate::{dir_entries, file_contents};
+
+/// A file containing a blob, see `BlobIndex::uses()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlobUse {
+    /// The file object, or the list of chunks if no file object uses it
+    pub file: ObjectId,
+    /// Paths of the file, each from a directory that isn't in another one
+    pub paths: Vec<(ObjectId, String)>,
+}
+
+/// Maps blobs and objects to the objects referencing them.
+///
+/// Built by `Store::blob_index()`.
+pub struct BlobIndex<'a> {
+    /// The objects referencing anything
+    objects: Vec<Cow<'a, Object>>,
+    /// Positions in `objects` of those referencing each blob or object
+    blobs: HashMap<BlobId, Vec<usize>>,
+    referrers: HashMap<ObjectId, Vec<usize>>,
+}
+
+impl<'a> BlobIndex<'a> {
+    pub fn new(objects: ObjectIterator<'a>) -> errors::Result<BlobIndex<'a>> {
+        let mut index = BlobIndex {
+            objects: Vec::new(),
+            blobs: HashMap::new(),
+            referrers: HashMap::new(),
+        };
+        for object in objects {
+            let object = object?;
+            let position = index.objects.len();
+            let mut referencing = false;
+            let properties: Box<dyn Iterator<Item = &Property>> =
+                match object.data {
+                    ObjectData::Dict(ref dict) => Box::new(dict.values()),
+                    ObjectData::List(ref list) => Box::new(list.iter()),
+                };
+            for property in properties {
+                let entry = match property {
+                    Property::Blob(id) => {
+                        index.blobs.entry(id.clone()).or_default()
+                    }
+                    Property::Reference(id) => {
+                        index.referrers.entry(id.clone()).or_default()
+                    }
+                    _ => continue,
+                };
+                // A list can hold the same blob several times
+                if entry.last() != Some(&position) {
+                    entry.push(position);
+                }
+                referencing = true;
+            }
+            if referencing {
+                index.objects.push(object);
+            }
+        }
+        Ok(index)
+    }
+
+    /// Finds the files containing a blob.
+    ///
+    /// Objects using the blob that are not part of a file, for example
+    /// directly referencing it from their own properties, are returned with
+    /// no paths.
+    pub fn uses(&self, blob: &BlobId) -> Vec<BlobUse> {
+        let mut files = Vec::new();
+        let mut seen = HashSet::new();
+        for &object in self.blobs.get(blob).into_iter().flatten() {
+            self.files_of(&self.objects[object], &mut seen, &mut files);
+        }
+        files.into_iter()
+            .map(|file| BlobUse {
+                file: file.clone(),
+                paths: self.paths(file),
+            })
+            .collect()
+    }
+
+    /// The objects referencing an object.
+    fn parents<'s>(&'s self, id: &ObjectId)
+        -> impl Iterator<Item = &'s Object> + 's
+    {
+        self.referrers.get(id).into_iter().flatten()
+            .map(move |&i| &*self.objects[i])
+    }
+
+    /// Finds the outermost file objects made from this object.
+    fn files_of<'s>(&'s self, object: &'s Object,
+                    seen: &mut HashSet<&'s ObjectId>,
+                    files: &mut Vec<&'s ObjectId>)
+    {
+        if !seen.insert(&object.id) {
+            return;
+        }
+        let mut outer = false;
+        for parent in self.parents(&object.id) {
+            if let ObjectData::Dict(ref dict) = parent.data {
+                match file_contents(dict) {
+                    Some((_, Property::Reference(id))) if *id == object.id => {
+                        outer = true;
+                        self.files_of(parent, seen, files);
+                    }
+                    _ => {}
+                }
+            }
+        }
+        if !outer {
+            files.push(&object.id);
+        }
+    }
+
+    /// Lists the paths of an object through directories.
+    fn paths(&self, id: &ObjectId) -> Vec<(ObjectId, String)> {
+        let mut paths = Vec::new();
+        self.paths_from(id, &mut Vec::new(), &mut vec![id], &mut paths);
+        paths
+    }
+
+    /// Goes up the directories containing `id`, `names` being the path
+    /// below it and `chain` the objects on that path, to avoid loops.
+    fn paths_from<'s>(&'s self, id: &'s ObjectId, names: &mut Vec<&'s str>,
+                      chain: &mut Vec<&'s ObjectId>,
+                      paths: &mut Vec<(ObjectId, String)>)
+    {
+        let mut top = true;
+        for parent in self.parents(id) {
+            let dict = match parent.data {
+                ObjectData::Dict(ref dict) => dict,
+                _ => continue,
+            };
+            if file_contents(dict).is_some() || dir_entries(dict).is_none() ||
+                chain.contains(&&parent.id)
+            {
+                continue;
+            }
+            for (name, value) in dict {
+                if let Property::Reference(ref r) = *value {
+                    if r == id {
+                        top = false;
+                        names.push(name);
+                        chain.push(&parent.id);
+                        self.paths_from(&parent.id, names, chain, paths);
+                        chain.pop();
+                        names.pop();
+                    }
+                }
+            }
+        }
+        if top && !names.is_empty() {
+            let path: Vec<&str> = names.iter().rev().cloned().collect();
+            paths.push((id.clone(), path.join("/")));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::common::{Dict, Object, ObjectData, Property};
+    use crate::serialize::{hash_blob, hash_object};
+    use super::BlobIndex;
+
+    fn dict(entries: &[(&str, Property)]) -> Object {
+        let mut dict = Dict::new();
+        for (key, value) in entries {
+            dict.insert(key.to_string(), value.clone());
+        }
+        hash_object(ObjectData::Dict(dict))
+    }
+
+    #[test]
+    fn test_uses() {
+        let blob = hash_blob(b"shared chunk");
+        let other = hash_blob(b"other chunk");
+        let list = hash_object(ObjectData::List(vec![
+            Property::Integer(0), Property::Blob(blob.clone()),
+            Property::Integer(12), Property::Blob(other.clone()),
+            Property::Integer(24), Property::Blob(blob.clone()),
+        ]));
+        let big = dict(&[("size", Property::Integer(36)),
+                         ("contents", Property::Reference(list.id.clone()))]);
+        let small = dict(&[("size", Property::Integer(12)),
+                           ("contents", Property::Blob(blob.clone()))]);
+        let photos = dict(&[("a.jpg", Property::Reference(big.id.clone())),
+                            ("b.jpg", Property::Reference(small.id.clone()))]);
+        let root = dict(&[("photos", Property::Reference(photos.id.clone())),
+                          ("copy.jpg", Property::Reference(big.id.clone()))]);
+        let objects = [list.clone(), big.clone(), small.clone(),
+                       photos.clone(), root.clone()];
+
+        let index = BlobIndex::new(Box::new(
+            objects.iter().map(|o| Ok(Cow::Borrowed(o))))).unwrap();
+        let mut uses = index.uses(&blob);
+        uses.sort_by_key(|u| u.paths.len());
+        assert_eq!(uses.len(), 2);
+        assert_eq!(uses[0].file, small.id);
+        assert_eq!(uses[0].paths,
+                   vec![(root.id.clone(), "photos/b.jpg".to_owned())]);
+        assert_eq!(uses[1].file, big.id);
+        let mut paths = uses[1].paths.clone();
+        paths.sort_by(|a, b| a.1.cmp(&b.1));
+        assert_eq!(paths, vec![(root.id.clone(), "copy.jpg".to_owned()),
+                               (root.id.clone(), "photos/a.jpg".to_owned())]);
+
+        let uses = index.uses(&other);
+        assert_eq!(uses.len(), 1);
+        assert_eq!(uses[0].file, big.id);
+        assert!(index.uses(&hash_blob(b"unknown")).is_empty());
+
+        // Without the file object, the list itself is reported
+        let index = BlobIndex::new(Box::new(
+            Some(Ok(Cow::Borrowed(&list))).into_iter())).unwrap();
+        let uses = index.uses(&other);
+        assert_eq!(uses.len(), 1);
+        assert_eq!(uses[0].file, list.id);
+        assert!(uses[0].paths.is_empty());
+    }
+}
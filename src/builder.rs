@@ -0,0 +1,303 @@
+//! Builder assembling a `Store` from its components.
+//!
+//! `open()` is a shortcut for the common case of opening a store directory
+//! with the default components. `StoreBuilder` allows replacing any of them,
+//! and wrapping the blob storage in additional layers.
+
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use crate::cached_storage::CachedBlobStorage;
+use crate::chunking::ChunkingConfig;
+use crate::common::{ObjectId, EnumerableBlobStorage, Object, ObjectData,
+                    ObjectIndex, Property};
+use crate::disk_index::DiskIndex;
+use crate::errors::{self, Error};
+use crate::memory_index::{MemoryIndex, Policy};
+use crate::registry::Registry;
+use crate::{DynStore, Store, DEFAULT_BLOBS_URL};
+
+/// Function wrapping a blob storage in another layer.
+pub type StorageWrapper = Box<dyn FnOnce(Box<dyn EnumerableBlobStorage>)
+    -> errors::Result<Box<dyn EnumerableBlobStorage>>>;
+
+/// Builder for a `Store`.
+///
+/// Components that are not provided are built from the store directory given
+/// to `path()`, as `open()` would do. Wrappers are applied to the blob
+/// storage in the order they were added, so the last one is the outermost.
+///
+/// ```no_run
+/// # fn main() -> dhstore::errors::Result<()> {
+/// let store = dhstore::StoreBuilder::new()
+///     .path("/home/user/store")
+///     .open()?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct StoreBuilder<'a> {
+    path: Option<PathBuf>,
+    registry: Option<&'a Registry>,
+    storage: Option<Box<dyn EnumerableBlobStorage>>,
+    wrappers: Vec<StorageWrapper>,
+    cache: Option<(Box<dyn EnumerableBlobStorage>, u64)>,
+    index: Option<Box<dyn ObjectIndex>>,
+    policy: Option<Box<dyn Policy>>,
+    permissive: bool,
+}
+
+impl<'a> StoreBuilder<'a> {
+    /// Creates a builder with no components set.
+    pub fn new() -> StoreBuilder<'a> {
+        Default::default()
+    }
+
+    /// Sets the store directory, used to build the missing components.
+    pub fn path<P: AsRef<Path>>(mut self, path: P) -> StoreBuilder<'a> {
+        self.path = Some(path.as_ref().to_path_buf());
+        self
+    }
+
+    /// Sets the registry used to build the blob storage from the URL in the
+    /// root config.
+    pub fn registry(mut self, registry: &'a Registry) -> StoreBuilder<'a> {
+        self.registry = Some(registry);
+        self
+    }
+
+    /// Uses the given blob storage instead of the one in the root config.
+    pub fn blob_storage(mut self, storage: Box<dyn EnumerableBlobStorage>)
+        -> StoreBuilder<'a>
+    {
+        self.storage = Some(storage);
+        self
+    }
+
+    /// Adds a layer around the blob storage.
+    pub fn wrap_storage<F>(mut self, wrapper: F) -> StoreBuilder<'a>
+        where F: FnOnce(Box<dyn EnumerableBlobStorage>)
+                    -> errors::Result<Box<dyn EnumerableBlobStorage>>
+                 + 'static
+    {
+        self.wrappers.push(Box::new(wrapper));
+        self
+    }
+
+    /// Makes the store a bounded cache of its blob storage.
+    ///
+    /// Blobs are kept in `local` as they are read or written, up to
+    /// `capacity` bytes, evicting the least recently used ones. This is
+    /// applied after the other wrappers.
+    pub fn cache(mut self, local: Box<dyn EnumerableBlobStorage>,
+                 capacity: u64) -> StoreBuilder<'a>
+    {
+        self.cache = Some((local, capacity));
+        self
+    }
+
+    /// Uses the given object index instead of opening the store's.
+    pub fn index(mut self, index: Box<dyn ObjectIndex>) -> StoreBuilder<'a> {
+        self.index = Some(index);
+        self
+    }
+
+    /// Sets the policy of the index opened from the store directory.
+    pub fn policy(mut self, policy: Box<dyn Policy>) -> StoreBuilder<'a> {
+        self.policy = Some(policy);
+        self
+    }
+
+    /// Skips the objects that can't be read, rather than failing.
+    ///
+    /// See `MemoryIndex::open_permissive()`.
+    pub fn permissive(mut self, permissive: bool) -> StoreBuilder<'a> {
+        self.permissive = permissive;
+        self
+    }
+
+    /// Assembles the `Store`.
+    pub fn open(self) -> errors::Result<DynStore> {
+        let StoreBuilder { path, registry, storage, wrappers, cache, index,
+                           policy, permissive } = self;
+        let path = path.as_deref();
+
+        if index.is_some() && policy.is_some() {
+            return Err(Error::InvalidInput(
+                "A policy can only be set on the index opened by the \
+                 builder"));
+        }
+        if index.is_some() && permissive {
+            return Err(Error::InvalidInput(
+                "Permissive mode only applies to the index opened by the \
+                 builder"));
+        }
+
+        // Get the ID of the root config -- the configuration is loaded from
+        // the index itself but we need a trust anchor
+        let root_config = match path {
+            Some(path) => Some(read_root(path)?),
+            None => None,
+        };
+
+        let index = match index {
+            Some(index) => index,
+            None => {
+                let (path, root_config) = match (path, &root_config) {
+                    (Some(p), Some(r)) => (p, r),
+                    _ => return Err(Error::InvalidInput(
+                        "No path to open the object index from")),
+                };
+                if path.join("index").is_dir() {
+                    // The on-disk index only reads the objects it needs
+                    if permissive {
+                        return Err(Error::InvalidInput(
+                            "Permissive mode only applies to a \
+                             MemoryIndex"));
+                    }
+                    let mut index = DiskIndex::open(path.join("index"),
+                                                    root_config.clone())?;
+                    if let Some(policy) = policy {
+                        index.set_policy(policy);
+                    }
+                    Box::new(index) as Box<dyn ObjectIndex>
+                } else {
+                    // Create a memory index, that stores all the objects in
+                    // memory, and has to load all of them everytime from
+                    // simple files
+                    let objects = path.join("objects");
+                    let mut index = if permissive {
+                        MemoryIndex::open_permissive(objects,
+                                                     root_config.clone())?
+                    } else {
+                        MemoryIndex::open(objects, root_config.clone())?
+                    };
+                    if let Some(policy) = policy {
+                        index.set_policy(policy);
+                    }
+                    Box::new(index)
+                }
+            }
+        };
+
+        let mut storage = match storage {
+            Some(storage) => storage,
+            None => {
+                let (path, root_config) = match (path, &root_config) {
+                    (Some(p), Some(r)) => (p, r),
+                    _ => return Err(Error::InvalidInput(
+                        "No path to open the blob storage from")),
+                };
+                let default_registry;
+                let registry = match registry {
+                    Some(r) => r,
+                    None => {
+                        default_registry = Registry::with_builtins();
+                        &default_registry
+                    }
+                };
+                let url = blobs_url(&*index, root_config)?;
+                registry.open(&url, path)?
+            }
+        };
+        for wrapper in wrappers {
+            storage = wrapper(storage)?;
+        }
+        if let Some((local, capacity)) = cache {
+            storage = Box::new(CachedBlobStorage::new(local, storage,
+                                                      capacity)?);
+        }
+
+        let (min_free_space, chunking) = match root_config {
+            Some(ref root_config) => (min_free_space(&*index, root_config)?,
+                                      chunking(&*index, root_config)?),
+            None => (None, ChunkingConfig::default()),
+        };
+        let mut store = Store::new(storage, index);
+        store.set_min_free_space(min_free_space);
+        store.set_chunking(chunking);
+        Ok(store)
+    }
+}
+
+/// Reads the ID of the root config from a store directory.
+fn read_root(path: &Path) -> errors::Result<ObjectId> {
+    let mut fp = File::open(path.join("root"))
+        .map_err(|e| ("Can't open root config file", e))?;
+    let mut buf = Vec::new();
+    fp.read_to_end(&mut buf)
+        .map_err(|e| ("Error reading root config file", e))?;
+    ObjectId::parse(&buf)
+        .ok_or(Error::CorruptedStore("Invalid root config file"))
+}
+
+/// Gets the free space threshold from the root config, if set.
+fn min_free_space(index: &dyn ObjectIndex, root_config: &ObjectId)
+    -> errors::Result<Option<u64>>
+{
+    let config = index.get_object(root_config)?;
+    let config = match config.as_deref() {
+        Some(Object { data: ObjectData::Dict(dict), .. }) => dict,
+        _ => return Ok(None),
+    };
+    match config.get("min_free_space") {
+        Some(&Property::Integer(i)) if i >= 0 => Ok(Some(i as u64)),
+        Some(_) => Err(Error::CorruptedStore(
+            "Invalid min_free_space in root config")),
+        None => Ok(None),
+    }
+}
+
+/// Gets the chunking strategies and salt from the root config.
+fn chunking(index: &dyn ObjectIndex, root_config: &ObjectId)
+    -> errors::Result<ChunkingConfig>
+{
+    let config = index.get_object(root_config)?;
+    let config = match config.as_deref() {
+        Some(Object { data: ObjectData::Dict(dict), .. }) => dict,
+        _ => return Ok(ChunkingConfig::default()),
+    };
+    let mut chunking = match config.get("chunking") {
+        Some(Property::Reference(id)) => match index.get_object(id)?
+            .as_deref()
+        {
+            Some(Object { data: ObjectData::Dict(dict), .. }) => {
+                ChunkingConfig::from_dict(dict)?
+            }
+            _ => return Err(Error::CorruptedStore(
+                "Invalid chunking in root config")),
+        },
+        Some(_) => return Err(Error::CorruptedStore(
+            "Invalid chunking in root config")),
+        None => ChunkingConfig::default(),
+    };
+    match config.get("chunking_salt") {
+        Some(Property::String(salt)) => chunking.set_salt(salt.as_bytes()),
+        Some(_) => return Err(Error::CorruptedStore(
+            "Invalid chunking_salt in root config")),
+        None => {}
+    }
+    Ok(chunking)
+}
+
+/// Gets the URL of the blob storage from the root config.
+///
+/// Stores created before that was configurable store blobs as single files in
+/// "blobs".
+fn blobs_url(index: &dyn ObjectIndex, root_config: &ObjectId)
+    -> errors::Result<String>
+{
+    let config = index.get_object(root_config)?
+        .ok_or(Error::CorruptedStore("Missing root object"))?;
+    let config = match config.data {
+        ObjectData::Dict(ref dict) => dict,
+        _ => return Err(Error::CorruptedStore("Root object is not a dict")),
+    };
+    match config.get("blobs") {
+        Some(Property::String(s)) => Ok(s.clone()),
+        Some(_) => Err(Error::CorruptedStore(
+            "Blob storage URL in root config is not a string")),
+        None => Ok(DEFAULT_BLOBS_URL.into()),
+    }
+}
@@ -0,0 +1,324 @@
+//! Blob storage keeping a bounded local copy of a remote storage.
+//!
+//! This lets a store on a small disk front a large archive: the object index
+//! is complete, but only the most recently used blobs are kept locally, up to
+//! a byte limit. Blobs that are not in the cache are fetched from the remote
+//! storage when requested.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap, HashSet};
+
+use log::{debug, info, warn};
+
+use crate::common::{BlobId, BlobIterator, BlobStorage, CacheStats,
+                    EnumerableBlobStorage, SelfTest};
+use crate::errors;
+use crate::queries::Cancel;
+
+/// Least-recently-used bookkeeping of the blobs in the local storage.
+struct Lru {
+    /// Last use and size of each cached blob
+    entries: HashMap<BlobId, (u64, usize)>,
+    /// Cached blobs ordered by last use
+    by_use: BTreeMap<u64, BlobId>,
+    /// Counter incremented on each use
+    clock: u64,
+    /// Total size of the cached blobs
+    size: u64,
+    hits: u64,
+    misses: u64,
+    evictions: u64,
+}
+
+impl Lru {
+    fn new() -> Lru {
+        Lru {
+            entries: HashMap::new(),
+            by_use: BTreeMap::new(),
+            clock: 0,
+            size: 0,
+            hits: 0,
+            misses: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Marks a blob as just used, adding it if it wasn't there.
+    fn touch(&mut self, id: &BlobId, size: usize) {
+        self.clock += 1;
+        if let Some((last, _)) = self.entries.get(id) {
+            self.by_use.remove(last);
+        } else {
+            self.size += size as u64;
+        }
+        self.entries.insert(id.clone(), (self.clock, size));
+        self.by_use.insert(self.clock, id.clone());
+    }
+
+    fn contains(&self, id: &BlobId) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    fn remove(&mut self, id: &BlobId) {
+        if let Some((last, size)) = self.entries.remove(id) {
+            self.by_use.remove(&last);
+            self.size -= size as u64;
+        }
+    }
+
+    /// Gets the least recently used blob.
+    fn oldest(&self) -> Option<BlobId> {
+        self.by_use.values().next().cloned()
+    }
+}
+
+/// Blob storage caching the blobs of a remote storage in a local one.
+///
+/// The remote storage is authoritative: new blobs are written to it, and it
+/// is the one that gets listed. They are also kept in the local storage,
+/// which is emptied of the least recently used blobs once its content goes
+/// over `capacity` bytes.
+pub struct CachedBlobStorage {
+    local: RefCell<Box<dyn EnumerableBlobStorage>>,
+    remote: Box<dyn EnumerableBlobStorage>,
+    capacity: u64,
+    lru: RefCell<Lru>,
+}
+
+impl CachedBlobStorage {
+    /// Wraps a remote storage, caching up to `capacity` bytes in `local`.
+    ///
+    /// The blobs already in `local` are kept, in no particular order of use.
+    pub fn new(local: Box<dyn EnumerableBlobStorage>,
+               remote: Box<dyn EnumerableBlobStorage>,
+               capacity: u64)
+        -> errors::Result<CachedBlobStorage>
+    {
+        let mut lru = Lru::new();
+        for id in local.list_blobs()? {
+            let id = id?;
+            if let Some(size) = local.size(&id)? {
+                lru.touch(&id, size as usize);
+            }
+        }
+        info!("Cache contains {} blobs, {} bytes",
+              lru.entries.len(), lru.size);
+        let storage = CachedBlobStorage {
+            local: RefCell::new(local),
+            remote,
+            capacity,
+            lru: RefCell::new(lru),
+        };
+        storage.evict()?;
+        Ok(storage)
+    }
+
+    /// Total size of the blobs currently in the local storage.
+    pub fn cached_size(&self) -> u64 {
+        self.lru.borrow().size
+    }
+
+    /// Whether a blob is currently in the local storage.
+    pub fn is_cached(&self, id: &BlobId) -> bool {
+        self.lru.borrow().contains(id)
+    }
+
+    /// Puts a blob in the local storage and makes room for it.
+    fn insert(&self, id: &BlobId, blob: &[u8]) -> errors::Result<()> {
+        if !self.lru.borrow().contains(id) {
+            self.local.borrow_mut().add_known_blob(id, blob)?;
+        }
+        self.lru.borrow_mut().touch(id, blob.len());
+        self.evict()
+    }
+
+    /// Deletes the least recently used blobs until under capacity.
+    fn evict(&self) -> errors::Result<()> {
+        let mut lru = self.lru.borrow_mut();
+        while lru.size > self.capacity {
+            let id = match lru.oldest() {
+                Some(id) => id,
+                None => break,
+            };
+            debug!("Evicting {} from cache", id);
+            self.local.borrow_mut().delete_blob(&id)?;
+            lru.remove(&id);
+            lru.evictions += 1;
+        }
+        Ok(())
+    }
+}
+
+impl BlobStorage for CachedBlobStorage {
+    fn get_blob(&self, id: &BlobId) -> errors::Result<Option<Box<[u8]>>> {
+        if self.lru.borrow().contains(id) {
+            let blob = self.local.borrow().get_blob(id)?;
+            if let Some(blob) = blob {
+                let mut lru = self.lru.borrow_mut();
+                lru.touch(id, blob.len());
+                lru.hits += 1;
+                return Ok(Some(blob));
+            }
+            // Removed from the local storage behind our back
+            self.lru.borrow_mut().remove(id);
+        }
+        self.lru.borrow_mut().misses += 1;
+        let blob = self.remote.get_blob(id)?;
+        if let Some(ref blob) = blob {
+            self.insert(id, blob)?;
+        }
+        Ok(blob)
+    }
+
+    fn contains(&self, id: &BlobId) -> errors::Result<bool> {
+        if self.lru.borrow().contains(id) {
+            return Ok(true);
+        }
+        self.remote.contains(id)
+    }
+
+    fn add_blob(&mut self, blob: &[u8]) -> errors::Result<BlobId> {
+        let id = self.remote.add_blob(blob)?;
+        self.insert(&id, blob)?;
+        Ok(id)
+    }
+
+    fn add_known_blob(&mut self, id: &BlobId, blob: &[u8])
+        -> errors::Result<()>
+    {
+        self.remote.add_known_blob(id, blob)?;
+        self.insert(id, blob)
+    }
+
+    fn add_known_blobs(&mut self, blobs: &[(BlobId, &[u8])])
+        -> errors::Result<()>
+    {
+        self.remote.add_known_blobs(blobs)?;
+        for (id, blob) in blobs {
+            self.insert(id, blob)?;
+        }
+        Ok(())
+    }
+
+    fn delete_blob(&mut self, id: &BlobId) -> errors::Result<()> {
+        self.remote.delete_blob(id)?;
+        if self.lru.borrow().contains(id) {
+            self.local.borrow_mut().delete_blob(id)?;
+            self.lru.borrow_mut().remove(id);
+        }
+        Ok(())
+    }
+
+    fn verify(&mut self, cancel: &Cancel) -> errors::Result<()> {
+        self.local.borrow_mut().verify(cancel)?;
+        self.remote.verify(cancel)
+    }
+
+    /// Tests both storages, returning the times of the remote one.
+    fn self_test(&mut self) -> errors::Result<SelfTest> {
+        self.local.borrow_mut().self_test()?;
+        self.remote.self_test()
+    }
+
+    fn prefetch(&self, ids: &[BlobId]) -> errors::Result<()> {
+        let mut fetched = 0;
+        for id in ids {
+            if self.lru.borrow().contains(id) {
+                // Move it up so it doesn't get evicted by the rest
+                let size = self.lru.borrow().entries[id].1;
+                self.lru.borrow_mut().touch(id, size);
+            } else if let Some(blob) = self.remote.get_blob(id)? {
+                self.insert(id, &blob)?;
+                fetched += 1;
+            }
+        }
+        info!("Prefetched {} blobs, {} were already cached",
+              fetched, ids.len() - fetched);
+        if ids.iter().any(|id| !self.lru.borrow().contains(id)) {
+            warn!("Not all the blobs fit in the cache");
+        }
+        Ok(())
+    }
+
+    fn is_local(&self, id: &BlobId) -> errors::Result<bool> {
+        Ok(self.is_cached(id))
+    }
+
+    fn size(&self, id: &BlobId) -> errors::Result<Option<u64>> {
+        if let Some(&(_, size)) = self.lru.borrow().entries.get(id) {
+            return Ok(Some(size as u64));
+        }
+        self.remote.size(id)
+    }
+
+    fn free_space(&self) -> errors::Result<Option<u64>> {
+        // Blobs are written to both, the remote is where they accumulate
+        self.remote.free_space()
+    }
+
+    /// Counts reads with `get_blob()`; prefetching is not counted.
+    fn cache_stats(&self) -> Option<CacheStats> {
+        let lru = self.lru.borrow();
+        Some(CacheStats {
+            hits: lru.hits,
+            misses: lru.misses,
+            evictions: lru.evictions,
+            size: lru.size,
+            capacity: self.capacity,
+        })
+    }
+}
+
+impl EnumerableBlobStorage for CachedBlobStorage {
+    fn list_blobs(&self) -> errors::Result<BlobIterator> {
+        self.remote.list_blobs()
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> errors::Result<Option<BlobId>> {
+        self.remote.resolve_prefix(prefix)
+    }
+
+    fn collect_garbage(&mut self, alive: HashSet<BlobId>, cancel: &Cancel)
+        -> errors::Result<()>
+    {
+        let dead: Vec<BlobId> = self.lru.borrow().entries.keys()
+            .filter(|id| !alive.contains(id))
+            .cloned()
+            .collect();
+        for id in dead {
+            cancel.check()?;
+            self.local.borrow_mut().delete_blob(&id)?;
+            self.lru.borrow_mut().remove(&id);
+        }
+        self.remote.collect_garbage(alive, cancel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::Lru;
+    use crate::hash::{BlobId, Hasher};
+
+    #[test]
+    fn test_lru_order() {
+        let ids: Vec<_> = (0..3u8).map(|i| {
+            let mut hasher = Hasher::new();
+            hasher.write_all(&[i]).unwrap();
+            BlobId::from_id(hasher.result())
+        }).collect();
+        let mut lru = Lru::new();
+        lru.touch(&ids[0], 10);
+        lru.touch(&ids[1], 20);
+        lru.touch(&ids[2], 30);
+        assert_eq!(lru.size, 60);
+        assert_eq!(lru.oldest(), Some(ids[0].clone()));
+        lru.touch(&ids[0], 10);
+        assert_eq!(lru.size, 60);
+        assert_eq!(lru.oldest(), Some(ids[1].clone()));
+        lru.remove(&ids[1]);
+        assert_eq!(lru.size, 40);
+        assert_eq!(lru.oldest(), Some(ids[2].clone()));
+    }
+}
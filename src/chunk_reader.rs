@@ -0,0 +1,195 @@
+//! Adapter reading the chunks of a stream one at a time.
+//!
+//! The chunker hands out the data of a chunk in pieces, borrowed from its own
+//! buffer. `ChunkReader` exposes the current chunk as a `Read`, so it can be
+//! streamed to `BlobStorage::add_blob_from_reader()` without collecting it
+//! first. When the chunks are wanted in memory anyway, `into_iter_owned()`
+//! turns it into an `Iterator` of buffers.
+
+use std::io::{self, Read};
+
+use cdchunking::{ChunkInput, ChunkStream, ChunkerImpl};
+
+/// Reads the chunks of a `ChunkStream` as separate streams.
+///
+/// Call `next_chunk()` to move to the next chunk, then read its data until
+/// the end.
+pub struct ChunkReader<R: Read, I: ChunkerImpl> {
+    stream: ChunkStream<R, I>,
+    /// Data of the current chunk that didn't fit in the caller's buffer
+    pending: Vec<u8>,
+    pos: usize,
+    /// Whether the chunker reached the end of the current chunk
+    chunk_done: bool,
+    /// Size of the current chunk read so far
+    chunk_len: usize,
+}
+
+impl<R: Read, I: ChunkerImpl> ChunkReader<R, I> {
+    pub fn new(stream: ChunkStream<R, I>) -> ChunkReader<R, I> {
+        ChunkReader {
+            stream,
+            pending: Vec::new(),
+            pos: 0,
+            chunk_done: true,
+            chunk_len: 0,
+        }
+    }
+
+    /// Moves to the next chunk, returns `false` at the end of the stream.
+    ///
+    /// The current chunk has to be read entirely first.
+    pub fn next_chunk(&mut self) -> io::Result<bool> {
+        assert!(self.chunk_done && self.pos == self.pending.len());
+        self.chunk_len = 0;
+        match self.stream.read() {
+            None => Ok(false),
+            Some(Err(e)) => Err(e),
+            Some(Ok(ChunkInput::End)) => {
+                // Empty chunk, which the chunker doesn't emit
+                Ok(true)
+            }
+            Some(Ok(ChunkInput::Data(d))) => {
+                self.pending.clear();
+                self.pending.extend_from_slice(d);
+                self.pos = 0;
+                self.chunk_done = false;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Size of the data read so far from the current chunk.
+    pub fn chunk_len(&self) -> usize {
+        self.chunk_len
+    }
+
+    /// Iterates on the remaining chunks, each read into its own buffer.
+    pub fn into_iter_owned(self) -> OwnedChunks<R, I> {
+        OwnedChunks { reader: self, done: false }
+    }
+}
+
+/// Iterator on whole chunks, see `ChunkReader::into_iter_owned()`.
+///
+/// The items don't borrow from the chunker, so they can go through iterator
+/// combinators or be sent to other threads, for example to hash them in
+/// parallel.
+pub struct OwnedChunks<R: Read, I: ChunkerImpl> {
+    reader: ChunkReader<R, I>,
+    /// Set at the end of the stream or after an error
+    done: bool,
+}
+
+impl<R: Read, I: ChunkerImpl> Iterator for OwnedChunks<R, I> {
+    type Item = io::Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<io::Result<Vec<u8>>> {
+        if self.done {
+            return None;
+        }
+        let mut chunk = Vec::new();
+        let result = match self.reader.next_chunk() {
+            Ok(true) => self.reader.read_to_end(&mut chunk).map(|_| chunk),
+            Ok(false) => {
+                self.done = true;
+                return None;
+            }
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            // The reader may be in the middle of a chunk
+            self.done = true;
+        }
+        Some(result)
+    }
+}
+
+impl<R: Read, I: ChunkerImpl> Read for ChunkReader<R, I> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.pos == self.pending.len() {
+            if self.chunk_done {
+                return Ok(0);
+            }
+            match self.stream.read() {
+                None | Some(Ok(ChunkInput::End)) => {
+                    self.chunk_done = true;
+                    return Ok(0);
+                }
+                Some(Err(e)) => return Err(e),
+                Some(Ok(ChunkInput::Data(d))) => {
+                    // Give the caller what fits, keep the rest
+                    let len = buf.len().min(d.len());
+                    buf[..len].copy_from_slice(&d[..len]);
+                    self.pending.clear();
+                    self.pending.extend_from_slice(&d[len..]);
+                    self.pos = 0;
+                    self.chunk_len += len;
+                    return Ok(len);
+                }
+            }
+        }
+        let len = buf.len().min(self.pending.len() - self.pos);
+        buf[..len].copy_from_slice(&self.pending[self.pos..self.pos + len]);
+        self.pos += len;
+        self.chunk_len += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Read};
+
+    use cdchunking::{Chunker, ZPAQ};
+
+    use super::ChunkReader;
+
+    #[test]
+    fn test_chunks_match_chunker() {
+        let data: Vec<u8> = (0..200_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let expected: Vec<Vec<u8>> = Chunker::new(ZPAQ::new(10))
+            .whole_chunks(&data[..])
+            .map(|c| c.unwrap())
+            .collect();
+        assert!(expected.len() > 10);
+
+        let stream = Chunker::new(ZPAQ::new(10)).stream(&data[..]);
+        let mut reader = ChunkReader::new(stream);
+        let mut chunks = Vec::new();
+        while reader.next_chunk().unwrap() {
+            let mut chunk = Vec::new();
+            // Small reads, to go through the pending buffer
+            let mut buf = [0u8; 100];
+            loop {
+                let len = reader.read(&mut buf).unwrap();
+                if len == 0 {
+                    break;
+                }
+                chunk.extend_from_slice(&buf[..len]);
+            }
+            assert_eq!(reader.chunk_len(), chunk.len());
+            chunks.push(chunk);
+        }
+        assert_eq!(chunks, expected);
+    }
+
+    #[test]
+    fn test_owned_chunks() {
+        let data: Vec<u8> = (0..50_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 11) as u8)
+            .collect();
+        let expected: Vec<Vec<u8>> = Chunker::new(ZPAQ::new(8))
+            .whole_chunks(&data[..])
+            .map(|c| c.unwrap())
+            .collect();
+
+        let stream = Chunker::new(ZPAQ::new(8)).stream(&data[..]);
+        let chunks = ChunkReader::new(stream).into_iter_owned()
+            .collect::<io::Result<Vec<Vec<u8>>>>()
+            .unwrap();
+        assert_eq!(chunks, expected);
+    }
+}
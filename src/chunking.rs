@@ -0,0 +1,377 @@
+//! Strategies for cutting files into chunks.
+//!
+//! By default, files are cut with content-defined chunking, which lets
+//! different versions of a file share most of their blobs. This isn't always
+//! the best choice: already-compressed media rarely deduplicates, so cutting
+//! it at fixed offsets is faster, and larger chunks mean fewer blobs for big
+//! files.
+//!
+//! The strategy can be chosen per file extension, from the "chunking" key of
+//! the root config. It references a dict mapping extensions (with the dot,
+//! e.g. ".mp4") and "default" to strategy strings:
+//!
+//! * `cdc:BITS` or `cdc:BITS:MAX`: content-defined chunking with an average
+//!   chunk size of 2^BITS bytes and a hard maximum of MAX bytes (default 8
+//!   times the average)
+//! * `fixed:SIZE`: chunks of exactly SIZE bytes
+//!
+//! Because content-defined boundaries only depend on the data, someone who
+//! can see the size of the blobs can tell whether a store has a known file.
+//! Setting "chunking_salt" in the root config to a secret string makes the
+//! boundaries of content-defined chunking depend on it: the bytes go through
+//! a permutation derived from the salt before being fed to the rolling hash.
+//! Stores with different salts don't deduplicate the same files together.
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use cdchunking::{Chunker, ChunkerImpl, ZPAQ};
+
+use crate::chunk_reader::ChunkReader;
+use crate::common::{Dict, Property};
+use crate::errors::{self, Error};
+use crate::hash::Hasher;
+
+/// Largest chunk size allowed, since a chunk is a single blob.
+const MAX_CHUNK_SIZE: usize = 64 * 1024 * 1024;
+
+/// How to cut a file into chunks.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Chunking {
+    /// Content-defined chunking, with an average size of `1 << bits` bytes.
+    ContentDefined { bits: usize, max_size: usize },
+    /// Chunks of a fixed size.
+    Fixed(usize),
+}
+
+impl Default for Chunking {
+    /// 8 KiB average chunks, with a hard maximum of 64 KiB.
+    fn default() -> Chunking {
+        Chunking::ContentDefined { bits: 13, max_size: 64 * 1024 }
+    }
+}
+
+impl Chunking {
+    /// Parses a strategy string, such as `cdc:13` or `fixed:1048576`.
+    pub fn parse(spec: &str) -> Option<Chunking> {
+        let mut parts = spec.split(':');
+        let chunking = match parts.next()? {
+            "cdc" => {
+                let bits: usize = parts.next()?.parse().ok()?;
+                if !(6..=24).contains(&bits) {
+                    return None;
+                }
+                let max_size = match parts.next() {
+                    Some(m) => m.parse().ok()?,
+                    None => 8 << bits,
+                };
+                if max_size < 1 << bits || max_size > MAX_CHUNK_SIZE {
+                    return None;
+                }
+                Chunking::ContentDefined { bits, max_size }
+            }
+            "fixed" => {
+                let size: usize = parts.next()?.parse().ok()?;
+                if size == 0 || size > MAX_CHUNK_SIZE {
+                    return None;
+                }
+                Chunking::Fixed(size)
+            }
+            _ => return None,
+        };
+        if parts.next().is_some() {
+            return None;
+        }
+        Some(chunking)
+    }
+
+    /// Cuts a stream into chunks, read into separate buffers.
+    ///
+    /// These are the boundaries of a file added to a store without a
+    /// chunking salt. Unlike `for_each_chunk()`, this gives an `Iterator`,
+    /// whose chunks can be sent to other threads.
+    pub fn chunks<'a, R: Read + 'a>(&self, reader: R)
+        -> Box<dyn Iterator<Item = io::Result<Vec<u8>>> + 'a>
+    {
+        match *self {
+            Chunking::ContentDefined { bits, max_size } => {
+                let chunker = Chunker::new(ZPAQ::new(bits)).max_size(max_size);
+                Box::new(ChunkReader::new(chunker.stream(reader))
+                         .into_iter_owned())
+            }
+            Chunking::Fixed(size) => {
+                let chunker = Chunker::new(FixedSize { size, pos: 0 });
+                Box::new(ChunkReader::new(chunker.stream(reader))
+                         .into_iter_owned())
+            }
+        }
+    }
+
+    /// Cuts a stream into chunks, passing each one to a function as a
+    /// `Read`.
+    ///
+    /// The function has to read each chunk to the end, and gets the offset
+    /// of the chunk in the stream. Returns the total size. `salt` is the
+    /// permutation from `ChunkingConfig::salt()`, it doesn't change fixed
+    /// boundaries.
+    pub(crate) fn for_each_chunk<R, F>(&self, salt: Option<&[u8; 256]>,
+                                       reader: R, f: F)
+        -> errors::Result<usize>
+        where R: Read,
+              F: FnMut(usize, &mut dyn Read) -> errors::Result<()>
+    {
+        match *self {
+            Chunking::ContentDefined { bits, max_size } => match salt {
+                Some(table) => {
+                    let zpaq = SaltedZpaq { zpaq: ZPAQ::new(bits), table };
+                    let chunker = Chunker::new(zpaq).max_size(max_size);
+                    read_chunks(ChunkReader::new(chunker.stream(reader)), f)
+                }
+                None => {
+                    let chunker = Chunker::new(ZPAQ::new(bits))
+                        .max_size(max_size);
+                    read_chunks(ChunkReader::new(chunker.stream(reader)), f)
+                }
+            },
+            Chunking::Fixed(size) => {
+                let chunker = Chunker::new(FixedSize { size, pos: 0 });
+                read_chunks(ChunkReader::new(chunker.stream(reader)), f)
+            }
+        }
+    }
+}
+
+/// Passes each chunk to the function, returns the total size.
+fn read_chunks<R, I, F>(mut reader: ChunkReader<R, I>, mut f: F)
+    -> errors::Result<usize>
+    where R: Read,
+          I: ChunkerImpl,
+          F: FnMut(usize, &mut dyn Read) -> errors::Result<()>
+{
+    let mut size = 0;
+    while reader.next_chunk().map_err(|e| ("Error reading from blob", e))? {
+        f(size, &mut reader)?;
+        size += reader.chunk_len();
+    }
+    Ok(size)
+}
+
+/// Chunker cutting at fixed offsets.
+struct FixedSize {
+    size: usize,
+    pos: usize,
+}
+
+impl ChunkerImpl for FixedSize {
+    fn find_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        let left = self.size - self.pos;
+        if data.len() >= left {
+            Some(left - 1)
+        } else {
+            self.pos += data.len();
+            None
+        }
+    }
+
+    fn reset(&mut self) {
+        self.pos = 0;
+    }
+}
+
+/// ZPAQ rolling hash over bytes substituted through a secret permutation.
+///
+/// The permutation keeps equal bytes equal, so the chunk sizes are
+/// distributed the same way, only their boundaries move.
+struct SaltedZpaq<'a> {
+    zpaq: ZPAQ,
+    table: &'a [u8; 256],
+}
+
+impl ChunkerImpl for SaltedZpaq<'_> {
+    fn find_boundary(&mut self, data: &[u8]) -> Option<usize> {
+        data.iter().position(|&b| self.zpaq.update(self.table[b as usize]))
+    }
+
+    fn reset(&mut self) {
+        self.zpaq.reset();
+    }
+}
+
+/// Derives a permutation of byte values from a salt.
+///
+/// This is a Fisher-Yates shuffle, taking its randomness from hashing the
+/// salt with a counter.
+fn permutation(salt: &[u8]) -> [u8; 256] {
+    let mut table = [0u8; 256];
+    for (i, entry) in table.iter_mut().enumerate() {
+        *entry = i as u8;
+    }
+    let mut random = Vec::new();
+    let mut counter = 0u32;
+    for i in (1..256).rev() {
+        if random.len() < 4 {
+            let mut hasher = Hasher::new();
+            hasher.write_all(b"chunking salt\n").unwrap();
+            hasher.write_all(&counter.to_be_bytes()).unwrap();
+            hasher.write_all(salt).unwrap();
+            random.extend_from_slice(&hasher.result().bytes);
+            counter += 1;
+        }
+        let value = u32::from_be_bytes([random[0], random[1], random[2],
+                                        random[3]]);
+        random.drain(..4);
+        let j = value as usize % (i + 1);
+        table.swap(i, j);
+    }
+    table
+}
+
+/// Chunking strategies of a store, by file extension.
+#[derive(Clone, Debug, Default)]
+pub struct ChunkingConfig {
+    default: Chunking,
+    by_extension: HashMap<String, Chunking>,
+    salt: Option<Box<[u8; 256]>>,
+}
+
+impl ChunkingConfig {
+    /// Reads the configuration from a dict, see the module documentation.
+    pub fn from_dict(dict: &Dict) -> errors::Result<ChunkingConfig> {
+        let mut config = ChunkingConfig::default();
+        for (key, value) in dict {
+            let chunking = match value {
+                Property::String(s) => Chunking::parse(s),
+                _ => None,
+            };
+            let chunking = chunking.ok_or(Error::CorruptedStore(
+                "Invalid chunking strategy in config"))?;
+            if key == "default" {
+                config.default = chunking;
+            } else if let Some(ext) = key.strip_prefix('.') {
+                config.by_extension.insert(ext.to_lowercase(), chunking);
+            } else {
+                return Err(Error::CorruptedStore(
+                    "Invalid key in chunking config"));
+            }
+        }
+        Ok(config)
+    }
+
+    /// Sets the strategy used for files with no specific override.
+    pub fn set_default(&mut self, chunking: Chunking) {
+        self.default = chunking;
+    }
+
+    /// Sets the strategy for an extension, given without the dot.
+    pub fn set_extension(&mut self, extension: &str, chunking: Chunking) {
+        self.by_extension.insert(extension.to_lowercase(), chunking);
+    }
+
+    /// Makes content-defined boundaries depend on a secret salt.
+    ///
+    /// See the module documentation.
+    pub fn set_salt(&mut self, salt: &[u8]) {
+        self.salt = Some(Box::new(permutation(salt)));
+    }
+
+    /// The permutation derived from the salt, if one was set.
+    pub(crate) fn salt(&self) -> Option<&[u8; 256]> {
+        self.salt.as_deref()
+    }
+
+    /// Gets the strategy to use for a file.
+    pub fn for_path(&self, path: &Path) -> &Chunking {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .and_then(|e| self.by_extension.get(&e.to_lowercase()))
+            .unwrap_or(&self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::common::{Dict, Property};
+    use super::{Chunking, ChunkingConfig, permutation};
+
+    fn chunk_sizes(chunking: &Chunking, salt: Option<&[u8; 256]>,
+                   data: &[u8]) -> Vec<usize> {
+        let mut sizes = Vec::new();
+        let total = chunking.for_each_chunk(salt, data, |_, reader| {
+            let mut chunk = Vec::new();
+            reader.read_to_end(&mut chunk).unwrap();
+            sizes.push(chunk.len());
+            Ok(())
+        }).unwrap();
+        assert_eq!(total, data.len());
+        sizes
+    }
+
+    #[test]
+    fn test_chunking() {
+        assert_eq!(Chunking::parse("cdc:13"), Some(Chunking::default()));
+        assert_eq!(Chunking::parse("cdc:10:2048"),
+                   Some(Chunking::ContentDefined { bits: 10,
+                                                   max_size: 2048 }));
+        assert_eq!(Chunking::parse("fixed:100"), Some(Chunking::Fixed(100)));
+        assert_eq!(Chunking::parse("fixed:0"), None);
+        assert_eq!(Chunking::parse("cdc:13:100"), None);
+        assert_eq!(Chunking::parse("fixed:1:2"), None);
+        assert_eq!(Chunking::parse("zip"), None);
+
+        let data: Vec<u8> = (0..10_050u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let sizes = chunk_sizes(&Chunking::Fixed(1000), None, &data);
+        assert_eq!(sizes.len(), 11);
+        assert!(sizes[..10].iter().all(|&s| s == 1000));
+        assert_eq!(sizes[10], 50);
+        let sizes = chunk_sizes(&Chunking::parse("cdc:8:512").unwrap(),
+                                None, &data);
+        assert!(sizes.len() > 10);
+        assert!(sizes.iter().all(|&s| s <= 512));
+        let chunks: Vec<Vec<u8>> = Chunking::parse("cdc:8:512").unwrap()
+            .chunks(&data[..])
+            .map(|c| c.unwrap())
+            .collect();
+        assert_eq!(chunks.iter().map(|c| c.len()).collect::<Vec<_>>(), sizes);
+        assert_eq!(chunks.concat(), data);
+
+        let mut dict = Dict::new();
+        dict.insert("default".into(), Property::String("cdc:16".into()));
+        dict.insert(".MP4".into(), Property::String("fixed:1048576".into()));
+        let config = ChunkingConfig::from_dict(&dict).unwrap();
+        assert_eq!(config.for_path(Path::new("a/video.mp4")),
+                   &Chunking::Fixed(1 << 20));
+        assert_eq!(config.for_path(Path::new("notes.txt")),
+                   &Chunking::ContentDefined { bits: 16,
+                                               max_size: 8 << 16 });
+        dict.insert("mp4".into(), Property::String("fixed:1".into()));
+        assert!(ChunkingConfig::from_dict(&dict).is_err());
+    }
+
+    #[test]
+    fn test_salt() {
+        let table = permutation(b"secret");
+        let mut sorted = table;
+        sorted.sort();
+        assert!(sorted.iter().enumerate().all(|(i, &b)| i == b as usize));
+        assert_eq!(table, permutation(b"secret"));
+        assert_ne!(table, permutation(b"other"));
+
+        let data: Vec<u8> = (0..20_000u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect();
+        let chunking = Chunking::parse("cdc:8:1024").unwrap();
+        let plain = chunk_sizes(&chunking, None, &data);
+        let salted = chunk_sizes(&chunking, Some(&table), &data);
+        assert_ne!(plain, salted);
+        assert!(salted.len() > 10);
+        assert!(salted.iter().all(|&s| s <= 1024));
+        assert_eq!(salted, chunk_sizes(&chunking, Some(&table), &data));
+        let sizes = chunk_sizes(&Chunking::Fixed(1000), Some(&table), &data);
+        assert!(sizes.iter().all(|&s| s == 1000));
+    }
+}
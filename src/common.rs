@@ -3,12 +3,20 @@
 //! This module contains the basic structs `Object`, `Property`, and the
 //! `BlobStorage` and `ObjectIndex` traits.
 
+use std::borrow::Cow;
 use std::cmp::{Ord, Ordering};
 use std::collections::{BTreeMap, HashSet};
+use std::io::{self, Read};
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::time::{Duration, Instant};
 
-use crate::errors;
-pub use crate::hash::{HASH_SIZE, HASH_STR_SIZE, ID};
+use rand::Rng;
+
+use crate::errors::{self, Error};
+use crate::queries::Cancel;
+use crate::signing::signed_object;
+pub use crate::hash::{BlobId, HASH_SIZE, HASH_STR_SIZE, ID, ObjectId};
 
 /// Values that appear in an object's metadata.
 ///
@@ -17,8 +25,108 @@ pub use crate::hash::{HASH_SIZE, HASH_STR_SIZE, ID};
 pub enum Property {
     String(String),
     Integer(i64),
-    Reference(ID),
-    Blob(ID),
+    Reference(ObjectId),
+    Blob(BlobId),
+    /// Small binary data, stored in the object rather than as a blob.
+    Inline(Vec<u8>),
+}
+
+impl Property {
+    /// Compares two properties of the same type.
+    ///
+    /// Unlike the `Ord` implementation, which defines an arbitrary order
+    /// across types so properties can be used as keys, this only compares
+    /// values with a meaning: integers numerically, strings lexicographically
+    /// (by code point, independent of locale), and references, blobs or
+    /// inline data for equality only. Dates are stored as integer timestamps
+    /// and so compare as numbers.
+    ///
+    /// Returns `None` if the values are not comparable.
+    pub fn compare(&self, other: &Property) -> Option<Ordering> {
+        match (self, other) {
+            (Property::String(s1), Property::String(s2)) => Some(s1.cmp(s2)),
+            (Property::Integer(i1), Property::Integer(i2)) => {
+                Some(i1.cmp(i2))
+            }
+            (Property::Reference(r1), Property::Reference(r2)) => {
+                if r1 == r2 { Some(Ordering::Equal) } else { None }
+            }
+            (Property::Blob(r1), Property::Blob(r2)) => {
+                if r1 == r2 { Some(Ordering::Equal) } else { None }
+            }
+            (Property::Inline(d1), Property::Inline(d2)) => {
+                if d1 == d2 { Some(Ordering::Equal) } else { None }
+            }
+            _ => None,
+        }
+    }
+
+    /// The hash this points to, if this is a reference or a blob.
+    pub fn target(&self) -> Option<&ID> {
+        match self {
+            Property::Reference(id) => Some(id.id()),
+            Property::Blob(id) => Some(id.id()),
+            _ => None,
+        }
+    }
+
+    /// Checks whether this is a string starting with `prefix`.
+    pub fn has_prefix(&self, prefix: &str) -> bool {
+        match self {
+            Property::String(s) => s.starts_with(prefix),
+            _ => false,
+        }
+    }
+
+    /// Checks whether this is a string matching a `LIKE` pattern.
+    ///
+    /// In the pattern, `%` matches any sequence of characters (including none)
+    /// and `_` matches exactly one character.
+    pub fn is_like(&self, pattern: &str) -> bool {
+        match self {
+            Property::String(s) => like(s, pattern),
+            _ => false,
+        }
+    }
+
+    /// Checks whether this is an integer between `min` and `max` (inclusive).
+    pub fn in_range(&self, min: Option<i64>, max: Option<i64>) -> bool {
+        match *self {
+            Property::Integer(i) => {
+                min.is_none_or(|m| m <= i) && max.is_none_or(|m| i <= m)
+            }
+            _ => false,
+        }
+    }
+}
+
+/// Matches a string against a `LIKE` pattern.
+fn like(string: &str, pattern: &str) -> bool {
+    let string: Vec<char> = string.chars().collect();
+    let pattern: Vec<char> = pattern.chars().collect();
+    let (mut s, mut p) = (0, 0);
+    // Position after the last '%' in the pattern, and the position in the
+    // string it is currently assumed to match up to
+    let mut backtrack = None;
+    while s < string.len() {
+        if p < pattern.len() && pattern[p] == '%' {
+            p += 1;
+            backtrack = Some((p, s));
+        } else if p < pattern.len() &&
+            (pattern[p] == '_' || pattern[p] == string[s])
+        {
+            p += 1;
+            s += 1;
+        } else if let Some((bp, bs)) = backtrack {
+            // Have the last '%' match one more character
+            p = bp;
+            s = bs + 1;
+            backtrack = Some((bp, bs + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '%')
 }
 
 impl PartialOrd for Property {
@@ -46,10 +154,14 @@ impl Ord for Property {
             (&Blob(_), &String(_)) => Ordering::Greater,
             (&Reference(_), &Integer(_)) |
             (&Blob(_), &Integer(_)) => Ordering::Greater,
-            (&Reference(ref r1), &Reference(ref r2)) |
-            (&Reference(ref r1), &Blob(ref r2)) |
-            (&Blob(ref r1), &Reference(ref r2)) |
-            (&Blob(ref r1), &Blob(ref r2)) => r1.cmp(r2),
+            (Reference(r1), Reference(r2)) => r1.cmp(r2),
+            (Reference(r1), Blob(r2)) => r1.id().cmp(r2.id()),
+            (Blob(r1), Reference(r2)) => r1.id().cmp(r2.id()),
+            (Blob(r1), Blob(r2)) => r1.cmp(r2),
+
+            (Inline(d1), Inline(d2)) => d1.cmp(d2),
+            (Inline(_), _) => Ordering::Greater,
+            (_, Inline(_)) => Ordering::Less,
         }
     }
 }
@@ -60,18 +172,34 @@ pub type List = Vec<Property>;
 /// The types of object known to the index.
 ///
 /// Object is simply this structure with an `ID` tacked on.
+#[derive(Clone)]
 pub enum ObjectData {
     Dict(Dict),
     List(List),
 }
 
 /// A schema object, i.e. either a dictionary or a list of properties.
+#[derive(Clone)]
 pub struct Object {
-    pub id: ID,
+    pub id: ObjectId,
     pub data: ObjectData,
 }
 
+impl Object {
+    /// The `dhstore_kind` of a dict, telling what special object it is.
+    pub fn kind(&self) -> Option<&str> {
+        match self.data {
+            ObjectData::Dict(ref dict) => match dict.get("dhstore_kind") {
+                Some(Property::String(kind)) => Some(kind),
+                _ => None,
+            },
+            ObjectData::List(_) => None,
+        }
+    }
+}
+
 /// Sorting field (required for permanodes).
+#[derive(Clone)]
 pub enum Sort {
     Ascending(String),
     Descending(String),
@@ -83,6 +211,14 @@ impl Sort {
             Sort::Ascending(ref s) | Sort::Descending(ref s) => s,
         }
     }
+
+    /// Compares two sort keys, `Less` meaning that `a` is listed first.
+    pub fn compare(&self, a: &Property, b: &Property) -> Ordering {
+        match *self {
+            Sort::Ascending(_) => a.cmp(b),
+            Sort::Descending(_) => b.cmp(a),
+        }
+    }
 }
 
 impl<'a> From<&'a Sort> for String {
@@ -116,34 +252,313 @@ impl FromStr for Sort {
     }
 }
 
+/// A value claimed for a permanode.
+pub struct Member {
+    /// The value of the permanode's sort field in the claim.
+    pub key: Property,
+    /// The claim object, or the checkpoint that summarizes it.
+    pub claim: ObjectId,
+    /// The object the claim associates to the permanode.
+    pub value: ObjectId,
+    /// Whether a tombstone marks the claim or the permanode as deleted.
+    pub deleted: bool,
+}
+
+/// Part of the values of a permanode, returned by
+/// `ObjectIndex::list_permanode()`.
+pub struct MemberPage {
+    pub members: Vec<Member>,
+    /// Cursor to pass as `after` to get the next page, if there is one.
+    pub next: Option<Property>,
+}
+
 /// Trait for the blob storage backends, that handle the specifics of storing
 /// blobs. A blob is an unnamed sequence of bytes, which constitute parts of
 /// some file's contents.
 pub trait BlobStorage {
     /// Gets a blob from its ID.
-    fn get_blob(&self, id: &ID) -> errors::Result<Option<Box<[u8]>>>;
+    fn get_blob(&self, id: &BlobId) -> errors::Result<Option<Box<[u8]>>>;
+    /// Gets a reader on the contents of a blob.
+    ///
+    /// The default reads the whole blob in memory; backends should override
+    /// it to read the blob as it is consumed.
+    fn get_blob_reader(&self, id: &BlobId)
+        -> errors::Result<Option<Box<dyn Read + '_>>>
+    {
+        Ok(self.get_blob(id)?
+            .map(|blob| Box::new(io::Cursor::new(blob)) as Box<dyn Read>))
+    }
     /// Hashes a blob then adds it to the store.
-    fn add_blob(&mut self, blob: &[u8]) -> errors::Result<ID>;
+    fn add_blob(&mut self, blob: &[u8]) -> errors::Result<BlobId>;
     /// Adds a blob whose hash is already known.
-    fn add_known_blob(&mut self, id: &ID, blob: &[u8]) -> errors::Result<()>;
+    fn add_known_blob(&mut self, id: &BlobId, blob: &[u8])
+        -> errors::Result<()>;
+    /// Whether a blob is in the storage.
+    ///
+    /// The default reads the blob; backends should override it if they can
+    /// tell without doing that.
+    fn contains(&self, id: &BlobId) -> errors::Result<bool> {
+        Ok(self.get_blob(id)?.is_some())
+    }
+    /// Size of a blob in bytes, or `None` if it's not in the storage.
+    ///
+    /// Like `contains()`, the default reads the blob.
+    fn size(&self, id: &BlobId) -> errors::Result<Option<u64>> {
+        Ok(self.get_blob(id)?.map(|blob| blob.len() as u64))
+    }
+    /// Hashes a blob while reading it, then adds it to the store.
+    ///
+    /// The default reads the whole blob in memory; backends should override
+    /// it to write the blob as it is read.
+    fn add_blob_from_reader(&mut self, reader: &mut dyn Read)
+        -> errors::Result<BlobId>
+    {
+        let mut blob = Vec::new();
+        reader.read_to_end(&mut blob)
+            .map_err(|e| ("Error reading blob", e))?;
+        self.add_blob(&blob)
+    }
+    /// Adds several blobs, returning their IDs in the same order.
+    ///
+    /// The default adds them one at a time; backends can override it to
+    /// amortize work over the batch.
+    fn add_blobs(&mut self, blobs: &[&[u8]]) -> errors::Result<Vec<BlobId>> {
+        blobs.iter().map(|blob| self.add_blob(blob)).collect()
+    }
+    /// Adds several blobs whose hashes are already known.
+    ///
+    /// The default adds them one at a time with `add_known_blob()`;
+    /// backends can override it like `add_blobs()`.
+    fn add_known_blobs(&mut self, blobs: &[(BlobId, &[u8])])
+        -> errors::Result<()>
+    {
+        for (id, blob) in blobs {
+            self.add_known_blob(id, blob)?;
+        }
+        Ok(())
+    }
+    /// Gets several blobs, in the same order as `ids`.
+    ///
+    /// The default gets them one at a time.
+    fn get_blobs(&self, ids: &[BlobId])
+        -> errors::Result<Vec<Option<Box<[u8]>>>>
+    {
+        ids.iter().map(|id| self.get_blob(id)).collect()
+    }
     /// Deletes a blob from its hash.
-    fn delete_blob(&mut self, id: &ID) -> errors::Result<()>;
+    fn delete_blob(&mut self, id: &BlobId) -> errors::Result<()>;
     /// Checks the blob storage for errors.
-    fn verify(&mut self) -> errors::Result<()>;
+    ///
+    /// Fails with `Error::Cancelled` if `cancel` is cancelled, which is
+    /// checked between blobs.
+    fn verify(&mut self, cancel: &Cancel) -> errors::Result<()>;
+    /// Hints that these blobs are going to be needed soon.
+    ///
+    /// Backends that cache blobs from a slower storage can use this to fetch
+    /// them ahead of time. The default does nothing.
+    fn prefetch(&self, _ids: &[BlobId]) -> errors::Result<()> {
+        Ok(())
+    }
+    /// Free space available to write blobs, in bytes.
+    ///
+    /// Returns `None` if the backend can't tell, which is the default.
+    fn free_space(&self) -> errors::Result<Option<u64>> {
+        Ok(None)
+    }
+    /// Whether a blob can be read without going to a remote storage.
+    ///
+    /// The default reads the blob, which is right for local backends.
+    fn is_local(&self, id: &BlobId) -> errors::Result<bool> {
+        Ok(self.get_blob(id)?.is_some())
+    }
+    /// Statistics of the cache in front of this storage, if there is one.
+    fn cache_stats(&self) -> Option<CacheStats> {
+        None
+    }
+    /// Writes, reads back and deletes a random blob, timing each step.
+    ///
+    /// This catches a storage that accepts writes but loses or corrupts
+    /// them, or is misconfigured, before real data is entrusted to it. The
+    /// blob is random so it can't be one the storage already holds.
+    fn self_test(&mut self) -> errors::Result<SelfTest> {
+        let mut canary = b"dhstore self-test\n".to_vec();
+        let mut random = [0u8; HASH_SIZE];
+        rand::thread_rng().fill_bytes(&mut random);
+        canary.extend_from_slice(&random);
+
+        let start = Instant::now();
+        let id = self.add_blob(&canary)?;
+        let write = start.elapsed();
+
+        let start = Instant::now();
+        let read_back = self.get_blob(&id)?;
+        let read = start.elapsed();
+        match read_back {
+            Some(ref blob) if **blob == *canary => {}
+            Some(_) => {
+                self.delete_blob(&id)?;
+                return Err(Error::CorruptedStore(
+                    "Self-test blob was read back wrong"));
+            }
+            None => return Err(Error::MissingBlob(id)),
+        }
+
+        let start = Instant::now();
+        self.delete_blob(&id)?;
+        let delete = start.elapsed();
+        if self.contains(&id)? {
+            return Err(Error::CorruptedStore(
+                "Self-test blob is still there after deleting it"));
+        }
+        Ok(SelfTest { write, read, delete })
+    }
+}
+
+impl<B: BlobStorage + ?Sized> BlobStorage for Box<B> {
+    fn get_blob(&self, id: &BlobId) -> errors::Result<Option<Box<[u8]>>> {
+        (**self).get_blob(id)
+    }
+
+    fn get_blob_reader(&self, id: &BlobId)
+        -> errors::Result<Option<Box<dyn Read + '_>>>
+    {
+        (**self).get_blob_reader(id)
+    }
+
+    fn add_blob(&mut self, blob: &[u8]) -> errors::Result<BlobId> {
+        (**self).add_blob(blob)
+    }
+
+    fn add_known_blob(&mut self, id: &BlobId, blob: &[u8])
+        -> errors::Result<()>
+    {
+        (**self).add_known_blob(id, blob)
+    }
+
+    fn contains(&self, id: &BlobId) -> errors::Result<bool> {
+        (**self).contains(id)
+    }
+
+    fn size(&self, id: &BlobId) -> errors::Result<Option<u64>> {
+        (**self).size(id)
+    }
+
+    fn add_blob_from_reader(&mut self, reader: &mut dyn Read)
+        -> errors::Result<BlobId>
+    {
+        (**self).add_blob_from_reader(reader)
+    }
+
+    fn add_blobs(&mut self, blobs: &[&[u8]]) -> errors::Result<Vec<BlobId>> {
+        (**self).add_blobs(blobs)
+    }
+
+    fn add_known_blobs(&mut self, blobs: &[(BlobId, &[u8])])
+        -> errors::Result<()>
+    {
+        (**self).add_known_blobs(blobs)
+    }
+
+    fn get_blobs(&self, ids: &[BlobId])
+        -> errors::Result<Vec<Option<Box<[u8]>>>>
+    {
+        (**self).get_blobs(ids)
+    }
+
+    fn delete_blob(&mut self, id: &BlobId) -> errors::Result<()> {
+        (**self).delete_blob(id)
+    }
+
+    fn verify(&mut self, cancel: &Cancel) -> errors::Result<()> {
+        (**self).verify(cancel)
+    }
+
+    fn prefetch(&self, ids: &[BlobId]) -> errors::Result<()> {
+        (**self).prefetch(ids)
+    }
+
+    fn is_local(&self, id: &BlobId) -> errors::Result<bool> {
+        (**self).is_local(id)
+    }
+
+    fn free_space(&self) -> errors::Result<Option<u64>> {
+        (**self).free_space()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        (**self).cache_stats()
+    }
+
+    fn self_test(&mut self) -> errors::Result<SelfTest> {
+        (**self).self_test()
+    }
+}
+
+/// Time taken by each step of `BlobStorage::self_test()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SelfTest {
+    pub write: Duration,
+    pub read: Duration,
+    pub delete: Duration,
+}
+
+/// Usage of a cache since it was opened, see `BlobStorage::cache_stats()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    /// Reads served from the cache
+    pub hits: u64,
+    /// Reads that had to go past the cache
+    pub misses: u64,
+    /// Entries dropped to stay under capacity
+    pub evictions: u64,
+    /// Current size of the cache, in bytes
+    pub size: u64,
+    /// Maximum size of the cache, in bytes
+    pub capacity: u64,
 }
 
+impl CacheStats {
+    /// Fraction of the reads served from the cache, between 0 and 1.
+    ///
+    /// This is 0 if nothing was read yet.
+    pub fn hit_rate(&self) -> f64 {
+        let reads = self.hits + self.misses;
+        if reads == 0 {
+            0.0
+        } else {
+            self.hits as f64 / reads as f64
+        }
+    }
+}
+
+/// Iterator over blob IDs, returned by `EnumerableBlobStorage::list_blobs()`.
+///
+/// This is boxed so that the trait can be used as a trait object, and
+/// backends can be picked at runtime.
+pub type BlobIterator = Box<dyn Iterator<Item = errors::Result<BlobId>>>;
+
 /// Additional trait for a `BlobStorage` that knows how to enumerate all the
 /// blobs it has.
 pub trait EnumerableBlobStorage: BlobStorage {
-    type Iter: Iterator<Item = errors::Result<ID>>;
-
     /// Returns an iterator over the blobs in this store.
-    fn list_blobs(&self) -> errors::Result<Self::Iter>;
+    fn list_blobs(&self) -> errors::Result<BlobIterator>;
+    /// Finds the blob whose ID starts with `prefix`.
+    ///
+    /// Fails with `Error::AmbiguousPrefix` if there are several.
+    fn resolve_prefix(&self, prefix: &str) -> errors::Result<Option<BlobId>> {
+        unique_match(prefix, self.list_blobs()?)
+    }
     /// Removes the blobs whose hash are not in the given set.
-    fn collect_garbage(&mut self, alive: HashSet<ID>) -> errors::Result<()> {
+    ///
+    /// Fails with `Error::Cancelled` if `cancel` is cancelled, which is
+    /// checked between blobs.
+    fn collect_garbage(&mut self, alive: HashSet<BlobId>, cancel: &Cancel)
+        -> errors::Result<()>
+    {
         for blob in self.list_blobs()? {
+            cancel.check()?;
             let blob = blob?;
-            if alive.contains(&blob) {
+            if !alive.contains(&blob) {
                 self.delete_blob(&blob)?;
             }
         }
@@ -151,17 +566,365 @@ pub trait EnumerableBlobStorage: BlobStorage {
     }
 }
 
+impl<B: EnumerableBlobStorage + ?Sized> EnumerableBlobStorage for Box<B> {
+    fn list_blobs(&self) -> errors::Result<BlobIterator> {
+        (**self).list_blobs()
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> errors::Result<Option<BlobId>> {
+        (**self).resolve_prefix(prefix)
+    }
+
+    fn collect_garbage(&mut self, alive: HashSet<BlobId>, cancel: &Cancel)
+        -> errors::Result<()>
+    {
+        (**self).collect_garbage(alive, cancel)
+    }
+}
+
+/// Gets the only ID starting with `prefix`, for `resolve_prefix()`.
+pub fn unique_match<T, I>(prefix: &str, ids: I) -> errors::Result<Option<T>>
+    where T: AsRef<ID>, I: Iterator<Item = errors::Result<T>>
+{
+    let mut found = None;
+    for id in ids {
+        let id = id?;
+        if id.as_ref().str().starts_with(prefix) {
+            if found.is_some() {
+                return Err(errors::Error::AmbiguousPrefix(prefix.to_owned()));
+            }
+            found = Some(id);
+        }
+    }
+    Ok(found)
+}
+
+/// Iterator over objects, returned by `ObjectIndex::list_objects()`.
+///
+/// An index keeping its objects in memory lends them, one reading them from
+/// disk hands out copies.
+pub type ObjectIterator<'a> =
+    Box<dyn Iterator<Item = errors::Result<Cow<'a, Object>>> + 'a>;
+
 /// Trait for the index of schema objects.
 ///
 /// This is a sort of database that can store `Object`s and knows how to make
 /// sense of them and query them efficiently.
 pub trait ObjectIndex {
     /// Hashes an object and adds it to the index.
-    fn add(&mut self, data: ObjectData) -> errors::Result<ID>;
+    fn add(&mut self, data: ObjectData) -> errors::Result<ObjectId>;
     /// Gets an object from its hash.
-    fn get_object(&self, id: &ID) -> errors::Result<Option<&Object>>;
+    ///
+    /// The object is borrowed from the index if it has it in memory, or
+    /// read into a copy.
+    fn get_object(&self, id: &ObjectId)
+        -> errors::Result<Option<Cow<'_, Object>>>;
+    /// Iterates on all the objects in the index, live or not.
+    fn list_objects(&self) -> errors::Result<ObjectIterator<'_>>;
+    /// Iterates on the objects whose `dhstore_kind` is `kind`, such as
+    /// "permanode" or "claim", live or not.
+    fn list_objects_of_kind<'a>(&'a self, kind: &'a str)
+        -> errors::Result<ObjectIterator<'a>>
+    {
+        Ok(Box::new(self.list_objects()?.filter(move |o| match o {
+            Ok(o) => o.kind() == Some(kind),
+            Err(_) => true,
+        })))
+    }
+    /// Returns the ID of the root config, from which live objects are found.
+    fn root(&self) -> &ObjectId;
     /// Checks the index for errors.
     fn verify(&mut self) -> errors::Result<()>;
     /// Deletes unreferenced objects and returns the set of blobs to keep.
-    fn collect_garbage(&mut self) -> errors::Result<HashSet<ID>>;
+    ///
+    /// This should fail if `quarantined()` is not empty.
+    fn collect_garbage(&mut self) -> errors::Result<HashSet<BlobId>>;
+    /// Returns the set of blobs referenced from live objects, i.e. the blobs
+    /// garbage collection would keep.
+    fn live_blobs(&self) -> errors::Result<HashSet<BlobId>>;
+    /// Lists the values of a permanode, ordered by its sort field.
+    ///
+    /// This returns at most `limit` values, starting after the sort value
+    /// `after` (in the permanode's sort direction), or `None` if the ID is not
+    /// a known permanode. Deleted values are left out.
+    fn list_permanode(&self, id: &ObjectId, after: Option<&Property>,
+                      limit: usize)
+        -> errors::Result<Option<MemberPage>>
+    {
+        self.list_permanode_with(id, after, limit, false)
+    }
+    /// Lists the values of a permanode, including the deleted ones if
+    /// `include_deleted` is set.
+    ///
+    /// See `list_permanode()`.
+    fn list_permanode_with(&self, id: &ObjectId, after: Option<&Property>,
+                           limit: usize, include_deleted: bool)
+        -> errors::Result<Option<MemberPage>>;
+    /// Whether an object is marked as deleted by a tombstone.
+    ///
+    /// This is the case for a permanode or claim that a tombstone targets,
+    /// and for the claims on a deleted permanode. The default implementation
+    /// goes over all the tombstones.
+    fn is_deleted(&self, id: &ObjectId) -> errors::Result<bool> {
+        let mut targets = HashSet::new();
+        for object in self.list_objects_of_kind("tombstone")? {
+            if let ObjectData::Dict(ref dict) = object?.data {
+                if let Some(Property::Reference(target)) = dict.get("target") {
+                    targets.insert(target.clone());
+                }
+            }
+        }
+        if targets.contains(id) {
+            return Ok(true);
+        }
+        Ok(match self.get_object(id)?.as_deref() {
+            Some(object) if object.kind() == Some("claim") => {
+                match object.data {
+                    ObjectData::Dict(ref dict) => match dict.get("node") {
+                        Some(Property::Reference(node)) => {
+                            targets.contains(node)
+                        }
+                        _ => false,
+                    },
+                    ObjectData::List(_) => false,
+                }
+            }
+            _ => false,
+        })
+    }
+    /// Lists the objects that couldn't be read when opening the index.
+    ///
+    /// This is only non-empty for an index opened in a permissive mode, such
+    /// as `MemoryIndex::open_permissive()`.
+    fn quarantined(&self) -> &[Quarantined] {
+        &[]
+    }
+    /// Finds the object whose ID starts with `prefix`.
+    ///
+    /// Fails with `Error::AmbiguousPrefix` if there are several.
+    fn resolve_prefix(&self, prefix: &str)
+        -> errors::Result<Option<ObjectId>>
+    {
+        unique_match(prefix, self.list_objects()?.map(|o| Ok(o?.id.clone())))
+    }
+    /// Lists the objects referencing an object or blob, and where.
+    ///
+    /// The default implementation goes over all the objects.
+    fn referrers(&self, id: &ID)
+        -> errors::Result<Vec<(Backkey, ObjectId)>>
+    {
+        let mut referrers = Vec::new();
+        for object in self.list_objects()? {
+            let object = object?;
+            let properties: Box<dyn Iterator<Item = (Backkey, &Property)>> =
+                match object.data {
+                    ObjectData::Dict(ref dict) => Box::new(dict.iter()
+                        .map(|(k, v)| (Backkey::Key(k.clone()), v))),
+                    ObjectData::List(ref list) => Box::new(list.iter()
+                        .enumerate()
+                        .map(|(i, v)| (Backkey::Index(i), v))),
+                };
+            for (key, value) in properties {
+                if value.target() == Some(id) {
+                    referrers.push((key, object.id.clone()));
+                }
+            }
+        }
+        Ok(referrers)
+    }
+    /// Lists the signature objects for an object, valid or not.
+    ///
+    /// The default implementation goes over all the objects.
+    fn signatures(&self, id: &ObjectId)
+        -> errors::Result<Vec<ObjectId>> {
+        let mut signatures = Vec::new();
+        for object in self.list_objects()? {
+            let object = object?;
+            if let ObjectData::Dict(ref dict) = object.data {
+                if signed_object(dict) == Some(id) {
+                    signatures.push(object.id.clone());
+                }
+            }
+        }
+        Ok(signatures)
+    }
+}
+
+impl<I: ObjectIndex + ?Sized> ObjectIndex for Box<I> {
+    fn add(&mut self, data: ObjectData) -> errors::Result<ObjectId> {
+        (**self).add(data)
+    }
+
+    fn get_object(&self, id: &ObjectId)
+        -> errors::Result<Option<Cow<'_, Object>>>
+    {
+        (**self).get_object(id)
+    }
+
+    fn list_objects(&self) -> errors::Result<ObjectIterator<'_>> {
+        (**self).list_objects()
+    }
+
+    fn list_objects_of_kind<'a>(&'a self, kind: &'a str)
+        -> errors::Result<ObjectIterator<'a>>
+    {
+        (**self).list_objects_of_kind(kind)
+    }
+
+    fn root(&self) -> &ObjectId {
+        (**self).root()
+    }
+
+    fn verify(&mut self) -> errors::Result<()> {
+        (**self).verify()
+    }
+
+    fn collect_garbage(&mut self) -> errors::Result<HashSet<BlobId>> {
+        (**self).collect_garbage()
+    }
+
+    fn live_blobs(&self) -> errors::Result<HashSet<BlobId>> {
+        (**self).live_blobs()
+    }
+
+    fn list_permanode(&self, id: &ObjectId, after: Option<&Property>,
+                      limit: usize)
+        -> errors::Result<Option<MemberPage>>
+    {
+        (**self).list_permanode(id, after, limit)
+    }
+
+    fn list_permanode_with(&self, id: &ObjectId, after: Option<&Property>,
+                           limit: usize, include_deleted: bool)
+        -> errors::Result<Option<MemberPage>>
+    {
+        (**self).list_permanode_with(id, after, limit, include_deleted)
+    }
+
+    fn is_deleted(&self, id: &ObjectId) -> errors::Result<bool> {
+        (**self).is_deleted(id)
+    }
+
+    fn quarantined(&self) -> &[Quarantined] {
+        (**self).quarantined()
+    }
+
+    fn resolve_prefix(&self, prefix: &str)
+        -> errors::Result<Option<ObjectId>>
+    {
+        (**self).resolve_prefix(prefix)
+    }
+
+    fn referrers(&self, id: &ID)
+        -> errors::Result<Vec<(Backkey, ObjectId)>>
+    {
+        (**self).referrers(id)
+    }
+
+    fn signatures(&self, id: &ObjectId)
+        -> errors::Result<Vec<ObjectId>> {
+        (**self).signatures(id)
+    }
+}
+
+/// Where a reference is in the referencing object, see
+/// `ObjectIndex::referrers()`.
+///
+/// A reference is a value, and can appear in both types of schema objects: in a
+/// dict, it is associated with a string key, and in a list, with an index.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Backkey {
+    /// Reference from a dict under this key.
+    Key(String),
+    /// Reference from a list from this index.
+    Index(usize),
+}
+
+/// An object file that couldn't be read, see `ObjectIndex::quarantined()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Quarantined {
+    /// Path of the file, relative to the index's directory
+    pub path: PathBuf,
+    /// ID of the object, if the file name is a valid one
+    pub id: Option<ObjectId>,
+    /// Why the file couldn't be read
+    pub error: String,
+}
+
+/// Blob storage in memory, for tests.
+///
+/// Clones share the same blobs, so a test can look inside a storage it handed
+/// over.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub(crate) struct MemoryBlobStorage(
+    pub(crate) std::rc::Rc<std::cell::RefCell<
+        std::collections::HashMap<BlobId, Vec<u8>>>>);
+
+#[cfg(test)]
+impl BlobStorage for MemoryBlobStorage {
+    fn get_blob(&self, id: &BlobId) -> errors::Result<Option<Box<[u8]>>> {
+        Ok(self.0.borrow().get(id).map(|b| b.clone().into_boxed_slice()))
+    }
+
+    fn add_blob(&mut self, blob: &[u8]) -> errors::Result<BlobId> {
+        let id = crate::serialize::hash_blob(blob);
+        self.add_known_blob(&id, blob)?;
+        Ok(id)
+    }
+
+    fn add_known_blob(&mut self, id: &BlobId, blob: &[u8])
+        -> errors::Result<()>
+    {
+        self.0.borrow_mut().insert(id.clone(), blob.to_vec());
+        Ok(())
+    }
+
+    fn delete_blob(&mut self, id: &BlobId) -> errors::Result<()> {
+        self.0.borrow_mut().remove(id);
+        Ok(())
+    }
+
+    fn verify(&mut self, _cancel: &Cancel) -> errors::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+impl EnumerableBlobStorage for MemoryBlobStorage {
+    fn list_blobs(&self) -> errors::Result<BlobIterator> {
+        let ids: Vec<BlobId> = self.0.borrow().keys().cloned().collect();
+        Ok(Box::new(ids.into_iter().map(Ok)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use crate::errors::Error;
+    use crate::queries::Cancel;
+    use super::{BlobStorage, EnumerableBlobStorage, MemoryBlobStorage};
+
+    #[test]
+    fn test_collect_garbage() {
+        let mut storage = MemoryBlobStorage::default();
+        let live = storage.add_blob(b"live").unwrap();
+        let dead = storage.add_blob(b"dead").unwrap();
+        let mut alive = HashSet::new();
+        alive.insert(live.clone());
+
+        // Cancelled before the first blob, nothing is deleted
+        let cancel = Cancel::new();
+        cancel.cancel();
+        match storage.collect_garbage(alive.clone(), &cancel) {
+            Err(Error::Cancelled) => {}
+            r => panic!("Expected Cancelled, got {:?}", r),
+        }
+        assert!(storage.get_blob(&dead).unwrap().is_some());
+
+        storage.collect_garbage(alive, &Cancel::new()).unwrap();
+        assert!(storage.get_blob(&live).unwrap().is_some());
+        assert!(storage.get_blob(&dead).unwrap().is_none());
+    }
 }
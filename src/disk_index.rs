@@ -0,0 +1,1295 @@
+//! Implementation of an object indexer that keeps its data on disk.
+//!
+//! `MemoryIndex` loads every object when it is opened, which doesn't scale
+//! to large stores. This index keeps the objects in a log, appended to as
+//! they are added, and finds them through sorted tables of fixed-size
+//! records, so that only what is looked up is read:
+//!
+//! * `objects.log`: a header with the generation of the log, then the
+//!   serialized objects, each after its length on 4 bytes;
+//! * `offsets`: the ID of each object, with the position of its record in
+//!   the log;
+//! * `links`: the kind of link, the ID of the target and the ID of the
+//!   object linking to it, for the references from one object to another
+//!   object or blob, and the claims, checkpoints, tombstones and signatures
+//!   attached to an object.
+//!
+//! The records added since a table was written are kept in memory, and
+//! merged into its file once there are enough of them. The tables only
+//! summarize the log: a table's header records the generation of the log it
+//! was made from and how much of it it covers, and the rest of the log is
+//! read again when opening the index.
+//!
+//! The values of permanodes are not stored, they are worked out from their
+//! claims and checkpoints when listed, and kept for the next pages.
+
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::ops::Bound;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use log::{debug, info, warn};
+
+use crate::common::{HASH_SIZE, ID, BlobId, ObjectId, Backkey, MemberPage,
+                    Object, ObjectData, ObjectIterator, Property,
+                    ObjectIndex};
+use crate::errors::{self, Error};
+use crate::memory_index::{self, Graph, KeepPolicy, Permanode, Policy,
+                          checkpoint_fields, checkpoint_members, claim_node};
+use crate::serialize;
+use crate::signing;
+use crate::span::Span;
+
+const LOG: &str = "objects.log";
+const LOG_MAGIC: &[u8; 8] = b"dhstlog1";
+const LOG_HEADER: u64 = 16;
+
+const OFFSETS: &str = "offsets";
+const LINKS: &str = "links";
+const TABLE_MAGIC: &[u8; 8] = b"dhsttab1";
+const TABLE_HEADER: u64 = 24;
+
+/// Suffix of the files written by garbage collection, until they replace
+/// the current ones.
+const NEW: &str = "new";
+
+/// Records added to a table before it is written.
+const MAX_PENDING: usize = 65536;
+
+/// Permanodes whose values are kept between listings.
+const MAX_CACHED_PERMANODES: usize = 1024;
+
+/// Kind of record in the `links` table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+enum Link {
+    /// From an object to an object or blob it references
+    Reference = b'r',
+    /// From a permanode to a claim on it
+    Claim = b'c',
+    /// From a permanode to a checkpoint of its values
+    Checkpoint = b'k',
+    /// From a permanode or claim to a tombstone deleting it
+    Tombstone = b't',
+    /// From an object to a signature of it
+    Signature = b's',
+}
+
+fn read_u64(buf: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(buf);
+    u64::from_le_bytes(bytes)
+}
+
+/// Lists the links from an object, see `Link`.
+fn object_links(object: &Object) -> Vec<(Link, &ID)> {
+    let mut links = Vec::new();
+    let values: Box<dyn Iterator<Item = &Property>> = match object.data {
+        ObjectData::Dict(ref dict) => Box::new(dict.values()),
+        ObjectData::List(ref list) => Box::new(list.iter()),
+    };
+    for value in values {
+        if let Some(target) = value.target() {
+            links.push((Link::Reference, target));
+        }
+    }
+    let dict = match object.data {
+        ObjectData::Dict(ref dict) => dict,
+        ObjectData::List(_) => return links,
+    };
+    match object.kind() {
+        Some("claim") => match claim_node(dict) {
+            Some(node) => links.push((Link::Claim, node.id())),
+            None => warn!("Invalid claim {}: wrong content", object.id),
+        },
+        Some("checkpoint") => match checkpoint_fields(dict) {
+            Some((node, _, _)) => links.push((Link::Checkpoint, node.id())),
+            None => warn!("Invalid checkpoint {}: wrong content", object.id),
+        },
+        Some("tombstone") => match dict.get("target") {
+            Some(Property::Reference(target)) => {
+                links.push((Link::Tombstone, target.id()));
+            }
+            _ => warn!("Invalid tombstone {}: wrong content", object.id),
+        },
+        Some("signature") => match signing::signed_object(dict) {
+            Some(signed) => links.push((Link::Signature, signed.id())),
+            None => warn!("Invalid signature {}: wrong content", object.id),
+        },
+        _ => {}
+    }
+    links
+}
+
+/// Creates an empty log.
+fn create_log(path: &Path, generation: u64) -> io::Result<File> {
+    let mut log = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    log.write_all(LOG_MAGIC)?;
+    log.write_all(&generation.to_le_bytes())?;
+    Ok(log)
+}
+
+/// Reads the header of a log, returning its generation.
+fn read_log_header<R: Read>(log: &mut R) -> errors::Result<u64> {
+    let mut header = [0u8; LOG_HEADER as usize];
+    log.read_exact(&mut header)
+        .map_err(|e| ("Error reading index log", e))?;
+    if &header[..8] != LOG_MAGIC {
+        return Err(Error::CorruptedStore("Invalid index log"));
+    }
+    Ok(read_u64(&header[8..]))
+}
+
+/// Appends an object to a log, returning the length of its record.
+fn append(log: &mut File, object: &Object) -> io::Result<u64> {
+    let mut record = vec![0u8; 4];
+    serialize::serialize(&mut record, object)?;
+    let len = (record.len() - 4) as u32;
+    record[..4].copy_from_slice(&len.to_le_bytes());
+    log.write_all(&record)?;
+    Ok(record.len() as u64)
+}
+
+/// Reads the records of a log in order.
+struct LogReader {
+    reader: BufReader<File>,
+    /// Position of the next record
+    pos: u64,
+    /// Where to stop
+    end: u64,
+}
+
+impl LogReader {
+    /// Reads a log from a position, up to `end` or to the end of the file.
+    fn open(path: &Path, from: u64, end: Option<u64>)
+        -> errors::Result<LogReader>
+    {
+        let mut file = File::open(path)
+            .map_err(|e| ("Can't open index log", e))?;
+        let end = match end {
+            Some(end) => end,
+            None => {
+                file.metadata()
+                    .map_err(|e| ("Can't open index log", e))?
+                    .len()
+            }
+        };
+        if from > end {
+            return Err(Error::CorruptedStore(
+                "Index table covers more than the log"));
+        }
+        file.seek(SeekFrom::Start(from))
+            .map_err(|e| ("Error reading index log", e))?;
+        Ok(LogReader { reader: BufReader::new(file), pos: from, end })
+    }
+
+    /// Reads the next object, with its position.
+    ///
+    /// Returns `None` at the end, or if the last record is incomplete.
+    fn next_record(&mut self) -> errors::Result<Option<(u64, Object)>> {
+        if self.end - self.pos < 4 {
+            return Ok(None);
+        }
+        let mut len = [0u8; 4];
+        self.reader.read_exact(&mut len)
+            .map_err(|e| ("Error reading index log", e))?;
+        let len = u32::from_le_bytes(len) as u64;
+        if self.end - self.pos - 4 < len {
+            return Ok(None);
+        }
+        let mut data = vec![0u8; len as usize];
+        self.reader.read_exact(&mut data)
+            .map_err(|e| ("Error reading index log", e))?;
+        let object = serialize::deserialize(&data[..])
+            .map_err(|e| ("Error deserializing object", e))?;
+        let offset = self.pos;
+        self.pos += 4 + len;
+        Ok(Some((offset, object)))
+    }
+}
+
+impl Iterator for LogReader {
+    type Item = errors::Result<Object>;
+
+    fn next(&mut self) -> Option<errors::Result<Object>> {
+        match self.next_record() {
+            Ok(record) => record.map(|(_, object)| Ok(object)),
+            Err(e) => {
+                // Don't read on from an unknown position
+                self.pos = self.end;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// A file of sorted records of the same size, with the records added since
+/// it was written kept in memory.
+struct Table {
+    path: PathBuf,
+    record: usize,
+    generation: u64,
+    file: Option<RefCell<File>>,
+    /// Number of records in the file
+    len: u64,
+    /// Length of the log the file was written from
+    covered: u64,
+    pending: BTreeSet<Box<[u8]>>,
+    max_pending: usize,
+}
+
+impl Table {
+    /// Opens a table, which is empty if it has no file yet, or a file for
+    /// another generation of the log.
+    fn open(path: PathBuf, record: usize, generation: u64,
+            max_pending: usize)
+        -> errors::Result<Table>
+    {
+        let mut table = Table {
+            path,
+            record,
+            generation,
+            file: None,
+            len: 0,
+            covered: LOG_HEADER,
+            pending: BTreeSet::new(),
+            max_pending,
+        };
+        let mut file = match File::open(&table.path) {
+            Ok(file) => file,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(table);
+            }
+            Err(e) => return Err(("Can't open index table", e).into()),
+        };
+        let mut header = [0u8; TABLE_HEADER as usize];
+        file.read_exact(&mut header)
+            .map_err(|e| ("Error reading index table", e))?;
+        if &header[..8] != TABLE_MAGIC {
+            return Err(Error::CorruptedStore("Invalid index table"));
+        }
+        if read_u64(&header[8..16]) != generation {
+            info!("Index table {:?} is from another log, rebuilding it",
+                  table.path);
+            return Ok(table);
+        }
+        let size = file.metadata()
+            .map_err(|e| ("Error reading index table", e))?
+            .len();
+        if !(size - TABLE_HEADER).is_multiple_of(record as u64) {
+            return Err(Error::CorruptedStore(
+                "Index table has an incomplete record"));
+        }
+        table.len = (size - TABLE_HEADER) / record as u64;
+        table.covered = read_u64(&header[16..]);
+        table.file = Some(RefCell::new(file));
+        Ok(table)
+    }
+
+    fn insert(&mut self, record: Box<[u8]>) {
+        self.pending.insert(record);
+    }
+
+    /// Lists the records starting with `prefix`, in order.
+    fn find(&self, prefix: &[u8]) -> errors::Result<Vec<Box<[u8]>>> {
+        let mut found = BTreeSet::new();
+        if let Some(ref file) = self.file {
+            self.find_in_file(&mut file.borrow_mut(), prefix, &mut found)
+                .map_err(|e| ("Error reading index table", e))?;
+        }
+        found.extend(
+            self.pending
+                .range::<[u8], _>((Bound::Included(prefix), Bound::Unbounded))
+                .take_while(|r| r.starts_with(prefix))
+                .cloned());
+        Ok(found.into_iter().collect())
+    }
+
+    fn find_in_file(&self, file: &mut File, prefix: &[u8],
+                    found: &mut BTreeSet<Box<[u8]>>)
+        -> io::Result<()>
+    {
+        let mut record = vec![0u8; self.record];
+        // Find the first record that is not before the prefix
+        let (mut low, mut high) = (0, self.len);
+        while low < high {
+            let middle = low + (high - low) / 2;
+            file.seek(SeekFrom::Start(
+                TABLE_HEADER + middle * self.record as u64))?;
+            file.read_exact(&mut record)?;
+            if record[..prefix.len()] < *prefix {
+                low = middle + 1;
+            } else {
+                high = middle;
+            }
+        }
+        file.seek(SeekFrom::Start(TABLE_HEADER + low * self.record as u64))?;
+        let mut reader = BufReader::new(file);
+        for _ in low..self.len {
+            reader.read_exact(&mut record)?;
+            if !record.starts_with(prefix) {
+                break;
+            }
+            found.insert(record.clone().into_boxed_slice());
+        }
+        Ok(())
+    }
+
+    /// Merges the pending records into the file, which then covers the log
+    /// up to `covered`.
+    fn write(&mut self, covered: u64) -> errors::Result<()> {
+        let temp = self.path.with_extension("tmp");
+        let len = self.merge(&temp, covered)
+            .map_err(|e| ("Couldn't write index table", e))?;
+        self.file = None;
+        fs::rename(&temp, &self.path)
+            .map_err(|e| ("Couldn't move index table in place", e))?;
+        let file = File::open(&self.path)
+            .map_err(|e| ("Can't open index table", e))?;
+        self.file = Some(RefCell::new(file));
+        self.len = len;
+        self.covered = covered;
+        self.pending.clear();
+        Ok(())
+    }
+
+    /// Writes the records of the file and the pending ones to `temp`,
+    /// returning their number.
+    fn merge(&self, temp: &Path, covered: u64) -> io::Result<u64> {
+        let mut out = BufWriter::new(File::create(temp)?);
+        out.write_all(TABLE_MAGIC)?;
+        out.write_all(&self.generation.to_le_bytes())?;
+        out.write_all(&covered.to_le_bytes())?;
+        let mut pending = self.pending.iter().peekable();
+        let mut len = 0;
+        if let Some(ref file) = self.file {
+            let mut file = file.borrow_mut();
+            file.seek(SeekFrom::Start(TABLE_HEADER))?;
+            let mut reader = BufReader::new(&mut *file);
+            let mut record = vec![0u8; self.record];
+            for _ in 0..self.len {
+                reader.read_exact(&mut record)?;
+                while let Some(p) = pending.next_if(|p| ***p < *record) {
+                    out.write_all(p)?;
+                    len += 1;
+                }
+                // Records read back from the log can be there already
+                pending.next_if(|p| ***p == *record);
+                out.write_all(&record)?;
+                len += 1;
+            }
+        }
+        for p in pending {
+            out.write_all(p)?;
+            len += 1;
+        }
+        out.into_inner()?;
+        Ok(len)
+    }
+
+    /// Moves the file to another name.
+    fn rename(&mut self, path: PathBuf) -> errors::Result<()> {
+        self.file = None;
+        fs::rename(&self.path, &path)
+            .map_err(|e| ("Couldn't move index table in place", e))?;
+        let file = File::open(&path)
+            .map_err(|e| ("Can't open index table", e))?;
+        self.file = Some(RefCell::new(file));
+        self.path = path;
+        Ok(())
+    }
+}
+
+/// The tables of an index, see the module documentation.
+struct Tables {
+    offsets: Table,
+    links: Table,
+}
+
+impl Tables {
+    fn open(dir: &Path, extension: &str, generation: u64,
+            max_pending: usize)
+        -> errors::Result<Tables>
+    {
+        let path = |name: &str| dir.join(name).with_extension(extension);
+        Ok(Tables {
+            offsets: Table::open(path(OFFSETS), HASH_SIZE + 8, generation,
+                                 max_pending)?,
+            links: Table::open(path(LINKS), 1 + 2 * HASH_SIZE, generation,
+                               max_pending)?,
+        })
+    }
+
+    /// How much of the log both tables cover.
+    fn covered(&self) -> u64 {
+        self.offsets.covered.min(self.links.covered)
+    }
+
+    /// Adds the records for an object at this position in the log.
+    fn insert(&mut self, object: &Object, offset: u64) {
+        let source = &object.id.id().bytes;
+        let mut record = Vec::with_capacity(self.offsets.record);
+        record.extend_from_slice(source);
+        record.extend_from_slice(&offset.to_le_bytes());
+        self.offsets.insert(record.into_boxed_slice());
+        for (link, target) in object_links(object) {
+            let mut record = Vec::with_capacity(self.links.record);
+            record.push(link as u8);
+            record.extend_from_slice(&target.bytes);
+            record.extend_from_slice(source);
+            self.links.insert(record.into_boxed_slice());
+        }
+    }
+
+    /// Writes the tables that have enough pending records, or any if `all`
+    /// is set.
+    fn write(&mut self, covered: u64, all: bool) -> errors::Result<()> {
+        for table in [&mut self.offsets, &mut self.links] {
+            if table.pending.len() >= table.max_pending ||
+                (all && !table.pending.is_empty())
+            {
+                table.write(covered)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// The on-disk index, that reads objects from its log as they are needed.
+pub struct DiskIndex {
+    /// Directory of the log and tables.
+    path: PathBuf,
+    /// The log, read at the positions from the offsets table, and appended
+    /// to.
+    log: RefCell<File>,
+    /// Length of the log, up to the end of its last record.
+    log_len: u64,
+    /// Incremented each time garbage collection writes a new log.
+    generation: u64,
+    tables: Tables,
+    root: ObjectId,
+    /// Shared with the objects it hands its decisions down to, while marking
+    policy: Rc<RefCell<Box<dyn Policy>>>,
+    /// Permanodes that were listed, kept up to date as claims are added.
+    permanodes: RefCell<HashMap<ObjectId, Permanode>>,
+    /// Cached permanodes with a checkpoint waiting for its list of members,
+    /// indexed by the ID of that list.
+    waiting: RefCell<HashMap<ObjectId, Vec<ObjectId>>>,
+}
+
+impl DiskIndex {
+    /// Opens the index in a directory.
+    ///
+    /// The objects added to the log since the tables were last written are
+    /// read again. An incomplete object at the end of the log, left by an
+    /// interrupted write, is dropped.
+    pub fn open<P: AsRef<Path>>(path: P, root: ObjectId)
+        -> errors::Result<DiskIndex>
+    {
+        DiskIndex::load(path.as_ref(), root, MAX_PENDING)
+    }
+
+    fn load(path: &Path, root: ObjectId, max_pending: usize)
+        -> errors::Result<DiskIndex>
+    {
+        // Left over by an interrupted garbage collection or table write
+        for name in &[LOG, OFFSETS, LINKS] {
+            for extension in &[NEW, "tmp"] {
+                let file = path.join(name).with_extension(extension);
+                match fs::remove_file(&file) {
+                    Ok(()) => info!("Removed leftover file {:?}", file),
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(("Couldn't remove leftover file", e)
+                                   .into());
+                    }
+                }
+            }
+        }
+
+        let mut log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(path.join(LOG))
+            .map_err(|e| ("Can't open index log", e))?;
+        let generation = read_log_header(&mut log)?;
+        let mut tables = Tables::open(path, "", generation, max_pending)?;
+
+        // Index the objects added since the tables were written
+        let mut reader = LogReader::open(&path.join(LOG), tables.covered(),
+                                         None)?;
+        let mut count = 0;
+        while let Some((offset, object)) = reader.next_record()? {
+            tables.insert(&object, offset);
+            tables.write(reader.pos, false)?;
+            count += 1;
+        }
+        debug!("Read {} objects from the end of the log", count);
+        if reader.pos < reader.end {
+            warn!("Dropping incomplete object at the end of the log");
+            log.set_len(reader.pos)
+                .map_err(|e| ("Couldn't truncate index log", e))?;
+        }
+
+        let index = DiskIndex {
+            path: path.to_path_buf(),
+            log: RefCell::new(log),
+            log_len: reader.pos,
+            generation,
+            tables,
+            root: root.clone(),
+            policy: Rc::new(RefCell::new(Box::new(KeepPolicy::new()))),
+            permanodes: RefCell::new(HashMap::new()),
+            waiting: RefCell::new(HashMap::new()),
+        };
+        memory_index::root_log(&index, &root)?;
+        Ok(index)
+    }
+
+    /// Creates an index in a new directory, with the given objects.
+    pub fn create<'a, P: AsRef<Path>, I: Iterator<Item=&'a Object>>(
+            path: P, objects: I)
+        -> errors::Result<()>
+    {
+        let path = path.as_ref();
+        fs::create_dir(path)
+            .map_err(|e| ("Couldn't create index directory", e))?;
+        let mut log = create_log(&path.join(LOG), 0)
+            .map_err(|e| ("Couldn't create index log", e))?;
+        for object in objects {
+            append(&mut log, object)
+                .map_err(|e| ("Couldn't write object to disk", e))?;
+        }
+        Ok(())
+    }
+
+    /// Creates an index in a new directory, with the objects of another
+    /// one.
+    ///
+    /// Returns the number of objects copied.
+    pub fn copy_objects<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q)
+        -> errors::Result<usize>
+    {
+        let source = source.as_ref().join(LOG);
+        read_log_header(&mut File::open(&source)
+            .map_err(|e| ("Can't open index log", e))?)?;
+        let mut count = 0;
+        let mut result = Ok(());
+        let objects = LogReader::open(&source, LOG_HEADER, None)?
+            .map_while(|object| match object {
+                Ok(object) => {
+                    count += 1;
+                    Some(object)
+                }
+                Err(e) => {
+                    result = Err(e);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        result?;
+        DiskIndex::create(dest, objects.iter())?;
+        Ok(count)
+    }
+
+    /// Creates an index in a new directory, with the objects stored as
+    /// files for a `MemoryIndex` in `objects`.
+    ///
+    /// Returns the number of objects copied.
+    pub fn import<P: AsRef<Path>, Q: AsRef<Path>>(objects: P, dest: Q)
+        -> errors::Result<usize>
+    {
+        let dest = dest.as_ref();
+        DiskIndex::create(dest, None.into_iter())?;
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dest.join(LOG))
+            .map_err(|e| ("Can't open index log", e))?;
+        let mut count = 0;
+        let mut result = Ok(());
+        memory_index::read_objects(objects.as_ref(), false, |object| {
+            if result.is_ok() {
+                result = append(&mut log, &object).map(|_| ());
+                count += 1;
+            }
+        })?;
+        result.map_err(|e| ("Couldn't write object to disk", e))?;
+        Ok(count)
+    }
+
+    /// Finds the objects that could be the root config of a store.
+    ///
+    /// This reads all the objects from the log, without needing a root, and
+    /// picks them like `MemoryIndex::root_candidates()`.
+    pub fn root_candidates<P: AsRef<Path>>(path: P)
+        -> errors::Result<Vec<Object>>
+    {
+        let log = path.as_ref().join(LOG);
+        read_log_header(&mut File::open(&log)
+            .map_err(|e| ("Can't open index log", e))?)?;
+        let objects = LogReader::open(&log, LOG_HEADER, None)?
+            .collect::<errors::Result<Vec<_>>>()?;
+        Ok(memory_index::root_candidates(objects))
+    }
+
+    /// Replaces the policy deciding which objects are valid and kept.
+    ///
+    /// The budget of the policy is not checked, the objects not being in
+    /// memory.
+    pub fn set_policy(&mut self, policy: Box<dyn Policy>) {
+        self.policy = Rc::new(RefCell::new(policy));
+    }
+
+    /// Finds the position of an object in the log.
+    fn offset(&self, id: &ObjectId) -> errors::Result<Option<u64>> {
+        Ok(self.tables.offsets.find(&id.id().bytes)?
+            .first()
+            .map(|record| read_u64(&record[HASH_SIZE..])))
+    }
+
+    fn read_object(&self, offset: u64) -> io::Result<Object> {
+        let mut log = self.log.borrow_mut();
+        log.seek(SeekFrom::Start(offset))?;
+        let mut len = [0u8; 4];
+        log.read_exact(&mut len)?;
+        let mut data = vec![0u8; u32::from_le_bytes(len) as usize];
+        log.read_exact(&mut data)?;
+        serialize::deserialize(&data[..])
+    }
+
+    /// Lists the objects linking to an object or blob.
+    fn links(&self, link: Link, target: &ID)
+        -> errors::Result<Vec<ObjectId>>
+    {
+        let mut prefix = Vec::with_capacity(1 + HASH_SIZE);
+        prefix.push(link as u8);
+        prefix.extend_from_slice(&target.bytes);
+        Ok(self.tables.links.find(&prefix)?
+            .iter()
+            .map(|record| {
+                let source = ID::from_bytes(&record[1 + HASH_SIZE..]);
+                ObjectId::from_id(source.unwrap())
+            })
+            .collect())
+    }
+
+    /// Whether a tombstone targets this object.
+    fn has_tombstone(&self, id: &ObjectId) -> errors::Result<bool> {
+        Ok(!self.links(Link::Tombstone, id.id())?.is_empty())
+    }
+
+    /// Works out the values of a permanode from its claims and checkpoints.
+    ///
+    /// Checkpoints whose list of members is not there yet are recorded in
+    /// `waiting`.
+    fn load_permanode(&self, id: &ObjectId)
+        -> errors::Result<Option<Permanode>>
+    {
+        let object = match self.get_object(id)? {
+            Some(object) => object,
+            None => return Ok(None),
+        };
+        let mut node = match object.data {
+            ObjectData::Dict(ref dict)
+                if object.kind() == Some("permanode") =>
+            {
+                match Permanode::parse(id, dict) {
+                    Some(node) => node,
+                    None => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        };
+        for claim_id in self.links(Link::Claim, id.id())? {
+            let claim = self.get_object(&claim_id)?;
+            if let Some(Object { data: ObjectData::Dict(claim), .. }) =
+                claim.as_deref()
+            {
+                node.index_claim(claim, id, &claim_id);
+            }
+        }
+        for checkpoint_id in self.links(Link::Checkpoint, id.id())? {
+            let checkpoint = self.get_object(&checkpoint_id)?;
+            let (upto, members) = match checkpoint.as_deref() {
+                Some(Object { data: ObjectData::Dict(dict), .. }) => {
+                    match checkpoint_fields(dict) {
+                        Some((_, upto, members)) => (upto, members),
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            let list = self.get_object(members)?;
+            let list = match list.as_deref() {
+                Some(Object { data: ObjectData::List(list), .. }) => {
+                    checkpoint_members(list)
+                }
+                Some(_) => None,
+                None => {
+                    debug!("Members of checkpoint {} are not there yet",
+                           checkpoint_id);
+                    self.waiting.borrow_mut().entry(members.clone())
+                        .or_default()
+                        .push(id.clone());
+                    continue;
+                }
+            };
+            match list {
+                Some(list) => node.index_checkpoint(upto, &checkpoint_id,
+                                                    list),
+                None => {
+                    warn!("Invalid checkpoint {}: wrong members",
+                          checkpoint_id);
+                }
+            }
+        }
+        Ok(Some(node))
+    }
+
+    /// Calls `f` with a permanode, loading it if it is not cached.
+    fn with_permanode<T, F>(&self, id: &ObjectId, f: F)
+        -> errors::Result<Option<T>>
+        where F: FnOnce(&Permanode) -> errors::Result<T>
+    {
+        if let Some(node) = self.permanodes.borrow().get(id) {
+            return f(node).map(Some);
+        }
+        if self.permanodes.borrow().len() >= MAX_CACHED_PERMANODES {
+            self.permanodes.borrow_mut().clear();
+            self.waiting.borrow_mut().clear();
+        }
+        let node = match self.load_permanode(id)? {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        let result = f(&node)?;
+        self.permanodes.borrow_mut().insert(id.clone(), node);
+        Ok(Some(result))
+    }
+
+    /// Keeps the cached permanodes up to date with a new object.
+    fn update_permanodes(&mut self, object: &Object) {
+        let permanodes = self.permanodes.get_mut();
+        if let Some(nodes) = self.waiting.get_mut().remove(&object.id) {
+            for node in nodes {
+                permanodes.remove(&node);
+            }
+        }
+        let dict = match object.data {
+            ObjectData::Dict(ref dict) => dict,
+            ObjectData::List(_) => return,
+        };
+        match object.kind() {
+            Some("claim") => {
+                if let Some(id) = claim_node(dict) {
+                    if let Some(node) = permanodes.get_mut(id) {
+                        node.index_claim(dict, id, &object.id);
+                    }
+                }
+            }
+            Some("checkpoint") => {
+                if let Some((id, _, _)) = checkpoint_fields(dict) {
+                    permanodes.remove(id);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+impl Drop for DiskIndex {
+    fn drop(&mut self) {
+        // Saves reading those objects again next time
+        if let Err(e) = self.tables.write(self.log_len, true) {
+            warn!("Couldn't write index tables: {}", e);
+        }
+    }
+}
+
+impl ObjectIndex for DiskIndex {
+    fn add(&mut self, data: ObjectData) -> errors::Result<ObjectId> {
+        let object = serialize::hash_object(data);
+        let id = object.id.clone();
+        if self.offset(&id)?.is_none() {
+            let mut span = Span::enter("index_add");
+            span.detail(&id);
+            info!("Adding object to index: {}", id);
+            let log = self.log.get_mut();
+            let len = match append(log, &object) {
+                Ok(len) => len,
+                Err(e) => {
+                    // Don't leave part of the record before the next ones
+                    let _ = log.set_len(self.log_len);
+                    return Err(("Couldn't write object to disk", e).into());
+                }
+            };
+            self.tables.insert(&object, self.log_len);
+            self.log_len += len;
+            self.tables.write(self.log_len, false)?;
+            self.update_permanodes(&object);
+        }
+        Ok(id)
+    }
+
+    fn get_object(&self, id: &ObjectId)
+        -> errors::Result<Option<Cow<'_, Object>>>
+    {
+        let offset = match self.offset(id)? {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+        let object = self.read_object(offset)
+            .map_err(|e| ("Error reading object from index log", e))?;
+        if object.id != *id {
+            return Err(Error::CorruptedStore(
+                "Index table points to the wrong object"));
+        }
+        Ok(Some(Cow::Owned(object)))
+    }
+
+    fn list_objects(&self) -> errors::Result<ObjectIterator<'_>> {
+        let reader = LogReader::open(&self.path.join(LOG), LOG_HEADER,
+                                     Some(self.log_len))?;
+        Ok(Box::new(reader.map(|object| object.map(Cow::Owned))))
+    }
+
+    fn root(&self) -> &ObjectId {
+        &self.root
+    }
+
+    fn verify(&mut self) -> errors::Result<()> {
+        let (alive, _) = memory_index::mark(self, &self.policy)?;
+        info!("Found {} live objects", alive.len());
+        Ok(())
+    }
+
+    /// Writes a new log with the live objects, and the tables for it.
+    ///
+    /// The set of live objects is kept in memory meanwhile. The current log
+    /// is replaced after the tables; if that doesn't happen, the tables are
+    /// for a generation that doesn't exist, and are rebuilt from the current
+    /// log when the index is opened.
+    fn collect_garbage(&mut self) -> errors::Result<HashSet<BlobId>> {
+        let (mut alive, live_blobs) = memory_index::mark(self, &self.policy)?;
+        info!("Found {} live objects", alive.len());
+
+        let generation = self.generation + 1;
+        let new_log = self.path.join(LOG).with_extension(NEW);
+        let mut log = create_log(&new_log, generation)
+            .map_err(|e| ("Couldn't create index log", e))?;
+        let max_pending = self.tables.offsets.max_pending;
+        let mut tables = Tables::open(&self.path, NEW, generation,
+                                      max_pending)?;
+        let mut log_len = LOG_HEADER;
+        let mut dead = 0;
+        for object in LogReader::open(&self.path.join(LOG), LOG_HEADER,
+                                      Some(self.log_len))?
+        {
+            let object = object?;
+            if !alive.remove(&object.id) {
+                dead += 1;
+                continue;
+            }
+            let len = append(&mut log, &object)
+                .map_err(|e| ("Couldn't write object to disk", e))?;
+            tables.insert(&object, log_len);
+            log_len += len;
+            tables.write(log_len, false)?;
+        }
+        for table in [&mut tables.offsets, &mut tables.links] {
+            table.write(log_len)?;
+        }
+        drop(log);
+
+        tables.offsets.rename(self.path.join(OFFSETS))?;
+        tables.links.rename(self.path.join(LINKS))?;
+        fs::rename(&new_log, self.path.join(LOG))
+            .map_err(|e| ("Couldn't move index log in place", e))?;
+        info!("Removed {} dead objects", dead);
+
+        let log = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .open(self.path.join(LOG))
+            .map_err(|e| ("Can't open index log", e))?;
+        self.log = RefCell::new(log);
+        self.log_len = log_len;
+        self.generation = generation;
+        self.tables = tables;
+        self.permanodes.get_mut().clear();
+        self.waiting.get_mut().clear();
+        Ok(live_blobs)
+    }
+
+    fn live_blobs(&self) -> errors::Result<HashSet<BlobId>> {
+        Ok(memory_index::mark(self, &self.policy)?.1)
+    }
+
+    fn list_permanode_with(&self, id: &ObjectId, after: Option<&Property>,
+                           limit: usize, include_deleted: bool)
+        -> errors::Result<Option<MemberPage>>
+    {
+        let node_deleted = self.has_tombstone(id)?;
+        self.with_permanode(id, |node| {
+            node.page(after, limit, include_deleted, |claim| {
+                Ok(node_deleted || self.has_tombstone(claim)?)
+            })
+        })
+    }
+
+    fn is_deleted(&self, id: &ObjectId) -> errors::Result<bool> {
+        if self.has_tombstone(id)? {
+            return Ok(true);
+        }
+        let object = self.get_object(id)?;
+        let claim = match object.as_deref() {
+            Some(object) if object.kind() == Some("claim") => object,
+            _ => return Ok(false),
+        };
+        match claim.data {
+            ObjectData::Dict(ref dict) => match dict.get("node") {
+                Some(Property::Reference(node)) => self.has_tombstone(node),
+                _ => Ok(false),
+            },
+            ObjectData::List(_) => Ok(false),
+        }
+    }
+
+    fn signatures(&self, id: &ObjectId) -> errors::Result<Vec<ObjectId>> {
+        self.links(Link::Signature, id.id())
+    }
+
+    fn referrers(&self, id: &ID)
+        -> errors::Result<Vec<(Backkey, ObjectId)>>
+    {
+        // The links only have the source, the keys are found in it again
+        let mut referrers = Vec::new();
+        for source in self.links(Link::Reference, id)? {
+            let object = match self.get_object(&source)? {
+                Some(object) => object,
+                None => continue,
+            };
+            match object.data {
+                ObjectData::Dict(ref dict) => {
+                    for (k, v) in dict {
+                        if v.target() == Some(id) {
+                            referrers.push((Backkey::Key(k.clone()),
+                                            source.clone()));
+                        }
+                    }
+                }
+                ObjectData::List(ref list) => {
+                    for (i, v) in list.iter().enumerate() {
+                        if v.target() == Some(id) {
+                            referrers.push((Backkey::Index(i),
+                                            source.clone()));
+                        }
+                    }
+                }
+            }
+        }
+        referrers.sort_by_key(|(key, source)| {
+            (source.id().str(), key.clone())
+        });
+        Ok(referrers)
+    }
+}
+
+impl Graph for DiskIndex {
+    fn permanode(&self, id: &ObjectId)
+        -> errors::Result<Option<Cow<'_, Permanode>>>
+    {
+        Ok(self.with_permanode(id, |node| Ok(node.clone()))?
+           .map(Cow::Owned))
+    }
+
+    fn node_claims(&self, id: &ObjectId) -> errors::Result<Vec<ObjectId>> {
+        self.links(Link::Claim, id.id())
+    }
+
+    fn tombstones(&self, id: &ObjectId) -> errors::Result<Vec<ObjectId>> {
+        self.links(Link::Tombstone, id.id())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use crate::common::{Backkey, Dict, Object, ObjectData, ObjectId,
+                        ObjectIndex, Property, Sort};
+    use crate::memory_index::{Graph, Policy, PolicyDecision};
+    use crate::serialize::hash_object;
+    use crate::{checkpoint, claim, permanode, tombstone};
+    use super::{DiskIndex, LINKS, LOG, OFFSETS, TABLE_MAGIC};
+
+    /// Small enough that the tables get written while adding the fixture.
+    const MAX_PENDING: usize = 5;
+
+    /// Drops the claims that are deleted, keeps everything else.
+    struct DropDeleted;
+
+    impl Policy for DropDeleted {
+        fn handle(&mut self, property: &str, _object: &Object)
+                  -> (PolicyDecision, Box<dyn Policy>) {
+            let decision = if property == "dhstore_deleted" {
+                PolicyDecision::Drop
+            } else {
+                PolicyDecision::Keep
+            };
+            (decision, Box::new(DropDeleted))
+        }
+    }
+
+    fn dated(date: i64) -> Dict {
+        let mut data = Dict::new();
+        data.insert("date".into(), Property::Integer(date));
+        data
+    }
+
+    /// Index with a set permanode, claimed from the log so it's alive, and
+    /// two values claimed on it.
+    struct Fixture {
+        path: PathBuf,
+        index: DiskIndex,
+        node: ObjectId,
+        claims: [ObjectId; 2],
+        values: [ObjectId; 2],
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            let path = std::env::temp_dir().join(format!(
+                "dhstore-test-disk-index-{}-{}", name, std::process::id()));
+            if path.exists() {
+                fs::remove_dir_all(&path).unwrap();
+            }
+            let mut log = Dict::new();
+            log.insert("type".into(), Property::String("set".into()));
+            let log = permanode(log, Sort::Ascending("date".into()));
+            let mut config = Dict::new();
+            config.insert("log".into(), Property::Reference(log.id.clone()));
+            let config = hash_object(ObjectData::Dict(config));
+            DiskIndex::create(&path, [log.clone(), config.clone()].iter())
+                .unwrap();
+            let mut index = DiskIndex::load(&path, config.id, MAX_PENDING)
+                .unwrap();
+
+            let mut node = Dict::new();
+            node.insert("type".into(), Property::String("set".into()));
+            let node = index.add(
+                permanode(node, Sort::Ascending("date".into())).data)
+                .unwrap();
+            index.add(claim(&log.id, &node, dated(1))).unwrap();
+            let mut claims = Vec::new();
+            let mut values = Vec::new();
+            for i in 1..3 {
+                let value = index.add(ObjectData::List(vec![
+                    Property::Integer(i)])).unwrap();
+                claims.push(index.add(claim(&node, &value, dated(i)))
+                            .unwrap());
+                values.push(value);
+            }
+            Fixture {
+                path,
+                index,
+                node,
+                claims: [claims[0].clone(), claims[1].clone()],
+                values: [values[0].clone(), values[1].clone()],
+            }
+        }
+
+        fn reopen(&mut self) {
+            let root = self.index.root().clone();
+            // The current index would only write its tables once dropped,
+            // after the new one is opened
+            self.index.tables.write(self.index.log_len, true).unwrap();
+            self.index = DiskIndex::load(&self.path, root, MAX_PENDING)
+                .unwrap();
+        }
+
+        fn listed(&self, include_deleted: bool) -> Vec<(ObjectId, bool)> {
+            self.index.list_permanode_with(&self.node, None, 10,
+                                           include_deleted)
+                .unwrap().unwrap()
+                .members.into_iter()
+                .map(|m| (m.value, m.deleted))
+                .collect()
+        }
+
+        fn has(&self, id: &ObjectId) -> bool {
+            self.index.get_object(id).unwrap().is_some()
+        }
+
+        fn count(&self) -> usize {
+            self.index.list_objects().unwrap().count()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn test_reopen() {
+        let mut f = Fixture::new("reopen");
+        // Some records are only in memory
+        assert!(!f.index.tables.offsets.pending.is_empty());
+        let check = |f: &Fixture| {
+            assert_eq!(f.count(), 8);
+            for id in f.claims.iter().chain(&f.values) {
+                assert_eq!(f.index.get_object(id).unwrap().unwrap().id, *id);
+            }
+            assert_eq!(f.index.referrers(f.values[0].id()).unwrap(),
+                       vec![(Backkey::Key("value".into()),
+                             f.claims[0].clone())]);
+            assert_eq!(f.index.referrers(f.node.id()).unwrap().len(), 3);
+            assert_eq!(f.listed(false), vec![(f.values[0].clone(), false),
+                                             (f.values[1].clone(), false)]);
+        };
+        check(&f);
+
+        // Adding an object again doesn't write it
+        f.index.add(ObjectData::List(vec![Property::Integer(1)])).unwrap();
+        assert_eq!(f.count(), 8);
+
+        // Lose the records not written yet, as if the process was killed;
+        // they are read again from the log
+        f.index.tables.offsets.pending.clear();
+        f.index.tables.links.pending.clear();
+        f.reopen();
+        check(&f);
+        assert_eq!(f.index.tables.offsets.len, 5);
+        assert_eq!(f.index.tables.offsets.pending.len(), 3);
+
+        f.reopen();
+        check(&f);
+        assert_eq!(f.index.tables.offsets.len, 8);
+        assert!(f.index.tables.offsets.pending.is_empty());
+    }
+
+    #[test]
+    fn test_cached_permanode() {
+        let mut f = Fixture::new("cached-permanode");
+        assert_eq!(f.listed(false).len(), 2);
+        assert!(f.index.permanodes.borrow().contains_key(&f.node));
+
+        // New claims update the cached values
+        let value = f.index.add(ObjectData::List(vec![
+            Property::Integer(3)])).unwrap();
+        f.index.add(claim(&f.node, &value, dated(3))).unwrap();
+        assert!(f.index.permanodes.borrow().contains_key(&f.node));
+        assert_eq!(f.listed(false).len(), 3);
+
+        // The checkpoint is only used once its members are there
+        let members = vec![Property::Integer(2),
+                           Property::Reference(f.values[1].clone())];
+        let members_id = hash_object(ObjectData::List(members.clone())).id;
+        f.index.add(checkpoint(&f.node, Property::Integer(2), &members_id))
+            .unwrap();
+        assert_eq!(f.listed(false).len(), 3);
+        f.index.add(ObjectData::List(members)).unwrap();
+        assert_eq!(f.listed(false), vec![(f.values[1].clone(), false),
+                                         (value, false)]);
+    }
+
+    #[test]
+    fn test_gc_reopen() {
+        let mut f = Fixture::new("gc-reopen");
+        let dead = f.index.add(ObjectData::List(vec![
+            Property::Integer(42)])).unwrap();
+        // The first value is only held by the checkpoint, which makes its
+        // claim dead
+        let members = f.index.add(ObjectData::List(vec![
+            Property::Integer(1),
+            Property::Reference(f.values[0].clone())])).unwrap();
+        let checkpoint = f.index.add(checkpoint(
+            &f.node, Property::Integer(1), &members)).unwrap();
+        // This claim is deleted, and only the tombstone references it
+        let value = f.index.add(ObjectData::List(vec![
+            Property::Integer(3)])).unwrap();
+        let deleted = f.index.add(claim(&f.node, &value, dated(3))).unwrap();
+        let tombstone = f.index.add(tombstone(&deleted, dated(4))).unwrap();
+        assert_eq!(f.index.tombstones(&deleted).unwrap(),
+                   vec![tombstone.clone()]);
+
+        f.index.collect_garbage().unwrap();
+        assert_eq!(f.index.generation, 1);
+        let check = |f: &Fixture| {
+            assert!(!f.has(&dead));
+            assert!(!f.has(&f.claims[0]));
+            for id in [&f.node, &f.values[0], &members, &checkpoint,
+                       &f.claims[1], &f.values[1], &deleted, &value,
+                       &tombstone]
+            {
+                assert!(f.has(id));
+            }
+            assert_eq!(f.count(), 12);
+            assert_eq!(f.listed(false), vec![(f.values[0].clone(), false),
+                                             (f.values[1].clone(), false)]);
+            assert_eq!(f.listed(true).len(), 3);
+            assert!(f.index.is_deleted(&deleted).unwrap());
+        };
+        check(&f);
+        f.reopen();
+        check(&f);
+
+        // This one lets the deleted claim go, with its value
+        f.index.set_policy(Box::new(DropDeleted));
+        f.index.collect_garbage().unwrap();
+        assert!(!f.has(&deleted) && !f.has(&value));
+        assert_eq!(f.listed(true).len(), 2);
+    }
+
+    #[test]
+    fn test_truncated_log() {
+        let mut f = Fixture::new("truncated-log");
+        let len = fs::metadata(f.path.join(LOG)).unwrap().len();
+        // The beginning of a record, as left by an interrupted write
+        OpenOptions::new().append(true).open(f.path.join(LOG)).unwrap()
+            .write_all(&[100, 0, 0, 0, 1, 2]).unwrap();
+        f.reopen();
+        assert_eq!(fs::metadata(f.path.join(LOG)).unwrap().len(), len);
+        assert_eq!(f.count(), 8);
+
+        let value = f.index.add(ObjectData::List(vec![
+            Property::Integer(3)])).unwrap();
+        f.reopen();
+        assert!(f.has(&value));
+        assert_eq!(f.count(), 9);
+    }
+
+    #[test]
+    fn test_rebuild_tables() {
+        let mut f = Fixture::new("rebuild-tables");
+        f.reopen();
+        // Tables for another generation of the log, as left by a garbage
+        // collection interrupted before moving the new log in place
+        for name in [OFFSETS, LINKS] {
+            let mut header = TABLE_MAGIC.to_vec();
+            header.extend_from_slice(&7u64.to_le_bytes());
+            header.extend_from_slice(&[0; 8]);
+            fs::write(f.path.join(name), header).unwrap();
+        }
+        fs::write(f.path.join(LOG).with_extension(super::NEW), b"partial")
+            .unwrap();
+        f.reopen();
+        assert!(!f.path.join(LOG).with_extension(super::NEW).exists());
+        assert_eq!(f.count(), 8);
+        for id in f.claims.iter().chain(&f.values) {
+            assert!(f.has(id));
+        }
+        assert_eq!(f.listed(false).len(), 2);
+    }
+}
@@ -0,0 +1,292 @@
+//! Blob storage wrapper encrypting blob contents.
+//!
+//! This allows keeping a store on untrusted disks. Blobs are encrypted with
+//! XChaCha20-Poly1305 before being handed to the wrapped storage, with a
+//! random nonce stored in a small header in front of each one. The
+//! cryptography is that of the `chacha20poly1305` and `pbkdf2` crates.
+//!
+//! Blobs are still stored under the ID of their plaintext, since that is how
+//! objects refer to them. Someone reading the disk can therefore tell whether
+//! it contains a blob they already know. What they can't do is read or forge
+//! contents.
+
+use std::collections::HashSet;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use chacha20poly1305::{Key, KeyInit, XChaCha20Poly1305, XNonce};
+use chacha20poly1305::aead::{Aead, Payload};
+use log::{error, info, warn};
+use rand::{OsRng, Rng};
+use sha2::{Digest, Sha256};
+
+use crate::common::{BlobId, BlobIterator, BlobStorage, CacheStats,
+                    EnumerableBlobStorage};
+use crate::errors::{self, Error};
+use crate::queries::Cancel;
+use crate::serialize::hash_blob;
+
+/// Magic bytes and format version at the start of each encrypted blob.
+const HEADER: &[u8; 5] = b"DHSE\x01";
+
+/// Length of the nonce of XChaCha20-Poly1305.
+const NONCE_SIZE: usize = 24;
+
+/// Length of the authentication tag appended to ciphertexts.
+const TAG_SIZE: usize = 16;
+
+/// Default number of PBKDF2 iterations for `EncryptionKey::from_passphrase`.
+pub const PBKDF2_ITERATIONS: u32 = 100_000;
+
+/// Key used by `EncryptedBlobStorage`.
+#[derive(Clone)]
+pub struct EncryptionKey([u8; 32]);
+
+impl EncryptionKey {
+    /// Uses these bytes as the key directly.
+    pub fn from_bytes(bytes: [u8; 32]) -> EncryptionKey {
+        EncryptionKey(bytes)
+    }
+
+    /// Derives a key from a passphrase using PBKDF2-HMAC-SHA256.
+    ///
+    /// The salt should be specific to the store; its root config ID is a
+    /// good choice.
+    pub fn from_passphrase(passphrase: &str, salt: &[u8], iterations: u32)
+        -> EncryptionKey
+    {
+        let mut key = [0u8; 32];
+        pbkdf2::pbkdf2_hmac::<Sha256>(passphrase.as_bytes(), salt,
+                                      iterations, &mut key);
+        EncryptionKey(key)
+    }
+
+    /// Derives a key from the contents of a file, which should be random.
+    pub fn from_keyfile<P: AsRef<Path>>(path: P)
+        -> errors::Result<EncryptionKey>
+    {
+        let mut contents = Vec::new();
+        File::open(path)
+            .and_then(|mut fp| fp.read_to_end(&mut contents))
+            .map_err(|e| ("Can't read key file", e))?;
+        if contents.len() < 16 {
+            return Err(Error::InvalidInput("Key file is too short"));
+        }
+        Ok(EncryptionKey(Sha256::digest(&contents).into()))
+    }
+}
+
+/// Blob storage wrapper encrypting blobs with XChaCha20-Poly1305.
+///
+/// Each blob is stored as a header, a random 24-byte nonce, then the
+/// ciphertext and authentication tag. The blob ID is authenticated as well,
+/// so blobs can't be swapped around.
+pub struct EncryptedBlobStorage<S: EnumerableBlobStorage> {
+    inner: S,
+    cipher: XChaCha20Poly1305,
+}
+
+impl<S: EnumerableBlobStorage> EncryptedBlobStorage<S> {
+    /// Wraps a storage, encrypting blobs with the given key.
+    pub fn new(inner: S, key: EncryptionKey) -> EncryptedBlobStorage<S> {
+        let cipher = XChaCha20Poly1305::new(Key::from_slice(&key.0));
+        EncryptedBlobStorage { inner, cipher }
+    }
+
+    fn encrypt(&self, id: &BlobId, blob: &[u8]) -> errors::Result<Vec<u8>> {
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng::new()
+            .map_err(|e| ("Can't get random numbers for nonce", e))?
+            .fill_bytes(&mut nonce);
+        let payload = Payload { msg: blob, aad: &id.id().bytes };
+        let sealed = self.cipher.encrypt(XNonce::from_slice(&nonce), payload)
+            .map_err(|_| Error::InvalidInput("Blob is too large to encrypt"))?;
+        let mut out = Vec::with_capacity(
+            HEADER.len() + NONCE_SIZE + sealed.len());
+        out.extend_from_slice(HEADER);
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&sealed);
+        Ok(out)
+    }
+
+    fn decrypt(&self, id: &BlobId, data: &[u8]) -> errors::Result<Box<[u8]>> {
+        if data.len() < HEADER.len() + NONCE_SIZE ||
+            &data[..HEADER.len()] != HEADER
+        {
+            return Err(Error::CorruptedStore("Blob is not encrypted"));
+        }
+        let nonce =
+            XNonce::from_slice(&data[HEADER.len()..HEADER.len() + NONCE_SIZE]);
+        let payload = Payload {
+            msg: &data[HEADER.len() + NONCE_SIZE..],
+            aad: &id.id().bytes,
+        };
+        self.cipher.decrypt(nonce, payload)
+            .map(Vec::into_boxed_slice)
+            .map_err(|_| Error::CorruptedStore(
+                "Can't decrypt blob, wrong key or corrupted data"))
+    }
+}
+
+impl<S: EnumerableBlobStorage> BlobStorage for EncryptedBlobStorage<S> {
+    fn get_blob(&self, id: &BlobId) -> errors::Result<Option<Box<[u8]>>> {
+        match self.inner.get_blob(id)? {
+            Some(data) => Ok(Some(self.decrypt(id, &data)?)),
+            None => Ok(None),
+        }
+    }
+
+    fn add_blob(&mut self, blob: &[u8]) -> errors::Result<BlobId> {
+        let id = hash_blob(blob);
+        self.add_known_blob(&id, blob)?;
+        Ok(id)
+    }
+
+    fn add_known_blob(&mut self, id: &BlobId, blob: &[u8])
+        -> errors::Result<()>
+    {
+        if self.inner.contains(id)? {
+            return Ok(());
+        }
+        let data = self.encrypt(id, blob)?;
+        self.inner.add_known_blob(id, &data)
+    }
+
+    fn add_known_blobs(&mut self, blobs: &[(BlobId, &[u8])])
+        -> errors::Result<()>
+    {
+        let mut encrypted = Vec::with_capacity(blobs.len());
+        for (id, blob) in blobs {
+            if !self.inner.contains(id)? {
+                encrypted.push((id.clone(), self.encrypt(id, blob)?));
+            }
+        }
+        let batch: Vec<(BlobId, &[u8])> = encrypted.iter()
+            .map(|(id, data)| (id.clone(), &data[..]))
+            .collect();
+        self.inner.add_known_blobs(&batch)
+    }
+
+    fn delete_blob(&mut self, id: &BlobId) -> errors::Result<()> {
+        self.inner.delete_blob(id)
+    }
+
+    /// Checks that each blob decrypts and matches its ID.
+    ///
+    /// The wrapped storage's own `verify()` can't be used, since it would
+    /// hash the ciphertext.
+    fn verify(&mut self, cancel: &Cancel) -> errors::Result<()> {
+        for blob in self.inner.list_blobs()? {
+            cancel.check()?;
+            match blob {
+                Err(e) => error!("Error listing blobs: {}", e),
+                Ok(id) => match self.get_blob(&id) {
+                    Err(e) => error!("Error getting blob {}: {}", id, e),
+                    Ok(None) => error!("Error getting blob {}", id),
+                    Ok(Some(blob)) => {
+                        if id != hash_blob(&blob) {
+                            warn!("Blob has the wrong hash: {}", id);
+                        } else {
+                            info!("Checked {}", id);
+                        }
+                    }
+                },
+            }
+        }
+        Ok(())
+    }
+
+    fn contains(&self, id: &BlobId) -> errors::Result<bool> {
+        self.inner.contains(id)
+    }
+
+    /// Gets the size of the plaintext from that of the stored blob.
+    fn size(&self, id: &BlobId) -> errors::Result<Option<u64>> {
+        let overhead = (HEADER.len() + NONCE_SIZE + TAG_SIZE) as u64;
+        match self.inner.size(id)? {
+            Some(size) if size >= overhead => Ok(Some(size - overhead)),
+            Some(_) => Err(Error::CorruptedStore("Blob is not encrypted")),
+            None => Ok(None),
+        }
+    }
+
+    fn prefetch(&self, ids: &[BlobId]) -> errors::Result<()> {
+        self.inner.prefetch(ids)
+    }
+
+    fn is_local(&self, id: &BlobId) -> errors::Result<bool> {
+        self.inner.is_local(id)
+    }
+
+    fn free_space(&self) -> errors::Result<Option<u64>> {
+        self.inner.free_space()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats> {
+        self.inner.cache_stats()
+    }
+}
+
+impl<S: EnumerableBlobStorage> EnumerableBlobStorage
+    for EncryptedBlobStorage<S>
+{
+    fn list_blobs(&self) -> errors::Result<BlobIterator> {
+        self.inner.list_blobs()
+    }
+
+    fn resolve_prefix(&self, prefix: &str) -> errors::Result<Option<BlobId>> {
+        self.inner.resolve_prefix(prefix)
+    }
+
+    fn collect_garbage(&mut self, alive: HashSet<BlobId>, cancel: &Cancel)
+        -> errors::Result<()>
+    {
+        self.inner.collect_garbage(alive, cancel)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::file_storage::FileBlobStorage;
+    use crate::serialize::hash_blob;
+    use super::{EncryptedBlobStorage, EncryptionKey, HEADER, NONCE_SIZE};
+
+    fn unhex(s: &str) -> Vec<u8> {
+        (0..s.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_passphrase() {
+        // RFC 7914 section 11, first block
+        let key = EncryptionKey::from_passphrase("passwd", b"salt", 1);
+        assert_eq!(key.0.to_vec(),
+                   unhex("55ac046e56e3089fec1691c22544b605\
+                          f94185216dde0465e68b9d57c20dacbc"));
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let storage = EncryptedBlobStorage::new(
+            FileBlobStorage::open("/nonexistent"),
+            EncryptionKey::from_bytes([7u8; 32]));
+        let id = hash_blob(b"dhstore");
+        let data = storage.encrypt(&id, b"dhstore").unwrap();
+        assert_eq!(&storage.decrypt(&id, &data).unwrap()[..], b"dhstore");
+        let other = hash_blob(b"other");
+        assert!(storage.decrypt(&other, &data).is_err());
+        let mut tampered = data.clone();
+        tampered[HEADER.len() + NONCE_SIZE] ^= 1;
+        assert!(storage.decrypt(&id, &tampered).is_err());
+
+        // Blob written by earlier versions, which must stay readable
+        let mut data = HEADER.to_vec();
+        data.extend_from_slice(&[9u8; NONCE_SIZE]);
+        data.extend_from_slice(&unhex("da1a91fe13d81cfe71975802a8849216\
+                                       b9c21c86618e43"));
+        assert_eq!(&storage.decrypt(&id, &data).unwrap()[..], b"dhstore");
+    }
+}
@@ -6,6 +6,8 @@
 use std::fmt::{Display, Formatter};
 use std::io;
 
+use crate::hash::{BlobId, ObjectId};
+
 /// An error from dhstore.
 ///
 /// This represents all the errors that can happen anywhere.
@@ -14,6 +16,23 @@ pub enum Error {
     IoError(&'static str, io::Error),
     CorruptedStore(&'static str),
     InvalidInput(&'static str),
+    MissingObject(ObjectId),
+    MissingBlob(BlobId),
+    /// Writing was refused because free space is below the threshold, given
+    /// as (available, threshold) in bytes.
+    LowDiskSpace(u64, u64),
+    /// An addition was refused up front because it would bring free space
+    /// below the threshold, given as (needed, available) in bytes.
+    NotEnoughSpace(u64, u64),
+    /// An object references itself, directly or through other objects.
+    ReferenceCycle(ObjectId),
+    /// An abbreviated ID matches more than one object or blob.
+    AmbiguousPrefix(String),
+    /// The operation was stopped through its `Cancel` handle.
+    Cancelled,
+    /// A thread panicked while using a `SharedStore`, which might have left
+    /// it inconsistent.
+    Poisoned,
 }
 
 impl Display for Error {
@@ -31,6 +50,30 @@ impl Display for Error {
             Error::InvalidInput(msg) => {
                 write!(f, "Invalid input: {}", msg)
             }
+            Error::MissingObject(ref id) => {
+                write!(f, "Missing object: {}", id)
+            }
+            Error::MissingBlob(ref id) => {
+                write!(f, "Missing blob: {}", id)
+            }
+            Error::LowDiskSpace(available, threshold) => {
+                write!(f, "Low disk space: {} bytes available, refusing \
+                           writes below {}", available, threshold)
+            }
+            Error::NotEnoughSpace(needed, available) => {
+                write!(f, "Not enough disk space: about {} bytes needed, {} \
+                           available", needed, available)
+            }
+            Error::ReferenceCycle(ref id) => {
+                write!(f, "Reference cycle through object: {}", id)
+            }
+            Error::AmbiguousPrefix(ref prefix) => {
+                write!(f, "Ambiguous ID prefix: {}", prefix)
+            }
+            Error::Cancelled => write!(f, "Operation was cancelled"),
+            Error::Poisoned => {
+                write!(f, "A thread panicked while using the store")
+            }
         }
     }
 }
@@ -41,6 +84,14 @@ impl ::std::error::Error for Error {
             Error::IoError(_, _) => "I/O error",
             Error::CorruptedStore(_) => "Corrupted store",
             Error::InvalidInput(_) => "Invalid input",
+            Error::MissingObject(_) => "Missing object",
+            Error::MissingBlob(_) => "Missing blob",
+            Error::LowDiskSpace(_, _) => "Low disk space",
+            Error::NotEnoughSpace(_, _) => "Not enough disk space",
+            Error::ReferenceCycle(_) => "Reference cycle",
+            Error::AmbiguousPrefix(_) => "Ambiguous ID prefix",
+            Error::Cancelled => "Cancelled",
+            Error::Poisoned => "Poisoned",
         }
     }
 
@@ -0,0 +1,122 @@
+//! Reading back the contents of files cut into chunks.
+//!
+//! `Store::add_file()` stores a file as a list object of chunk offsets and
+//! blobs. `FileReader` follows such a list and reads the blobs one after the
+//! other, so the contents can be streamed without holding them in memory.
+//! Small files are stored as a single blob, and tiny chunks are inlined in
+//! the list, which it can read as well.
+
+use std::io::{self, Read};
+
+use crate::common::{BlobId, BlobStorage, Object, ObjectData, Property};
+use crate::errors::{self, Error};
+
+/// Reader over the contents of a file, returned by `Store::get_file()`.
+///
+/// Blobs are streamed one at a time as the data is read. A blob that is
+/// missing from the storage results in a read error of kind `NotFound`.
+pub struct FileReader<'a, S: BlobStorage> {
+    storage: &'a S,
+    /// The chunks, either `Blob` or `Inline`
+    chunks: Vec<Property>,
+    next_chunk: usize,
+    current: Option<Box<dyn Read + 'a>>,
+}
+
+impl<'a, S: BlobStorage> FileReader<'a, S> {
+    /// Makes a reader from a list object of offsets and blobs.
+    pub fn new(storage: &'a S, list: &Object)
+        -> errors::Result<FileReader<'a, S>>
+    {
+        let list = match list.data {
+            ObjectData::List(ref l) => l,
+            ObjectData::Dict(_) => return Err(Error::CorruptedStore(
+                "File contents is not a list")),
+        };
+        if list.len() % 2 != 0 {
+            return Err(Error::CorruptedStore(
+                "File contents list has an odd length"));
+        }
+        let mut chunks = Vec::with_capacity(list.len() / 2);
+        for pair in list.chunks(2) {
+            match (&pair[0], &pair[1]) {
+                (Property::Integer(_), chunk @ Property::Blob(_)) |
+                (Property::Integer(_), chunk @ Property::Inline(_)) => {
+                    chunks.push(chunk.clone());
+                }
+                _ => return Err(Error::CorruptedStore(
+                    "File contents list is not offsets and blobs")),
+            }
+        }
+        Ok(FileReader::from_chunks(storage, chunks))
+    }
+
+    /// Makes a reader over a single blob.
+    pub fn from_blob(storage: &'a S, id: &BlobId) -> FileReader<'a, S> {
+        FileReader::from_chunks(storage, vec![Property::Blob(id.clone())])
+    }
+
+    /// Makes a reader over inline data.
+    pub fn from_inline(storage: &'a S, data: &[u8]) -> FileReader<'a, S> {
+        FileReader::from_chunks(storage, vec![Property::Inline(data.to_vec())])
+    }
+
+    fn from_chunks(storage: &'a S, chunks: Vec<Property>)
+        -> FileReader<'a, S>
+    {
+        FileReader {
+            storage,
+            chunks,
+            next_chunk: 0,
+            current: None,
+        }
+    }
+}
+
+impl<'a, S: BlobStorage> Read for FileReader<'a, S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        loop {
+            if let Some(ref mut current) = self.current {
+                let len = current.read(buf)?;
+                if len > 0 {
+                    return Ok(len);
+                }
+            }
+            let storage = self.storage;
+            let next: Box<dyn Read + 'a> =
+                match self.chunks.get(self.next_chunk) {
+                    Some(Property::Blob(id)) => {
+                        match storage.get_blob_reader(id) {
+                            Ok(Some(reader)) => reader,
+                            Ok(None) => return Err(io::Error::new(
+                                io::ErrorKind::NotFound,
+                                Error::MissingBlob(id.clone()))),
+                            Err(e) => return Err(io::Error::other(e)),
+                        }
+                    }
+                    Some(Property::Inline(data)) => {
+                        Box::new(io::Cursor::new(data.clone()))
+                    }
+                    Some(_) => unreachable!(),
+                    None => return Ok(0),
+                };
+            self.current = Some(next);
+            self.next_chunk += 1;
+        }
+    }
+}
+
+/// Turns an error from `FileReader` back into the library's error.
+///
+/// Errors from the store are wrapped in `io::Error` by `read()`; this
+/// unwraps them, so that a missing blob is reported as such.
+pub(crate) fn read_error(e: io::Error) -> Error {
+    if e.get_ref().is_some_and(|inner| inner.is::<Error>()) {
+        *e.into_inner().unwrap().downcast::<Error>().unwrap()
+    } else {
+        Error::IoError("Error reading file contents", e)
+    }
+}
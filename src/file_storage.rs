@@ -3,15 +3,23 @@
 //! This stores each blob in a separate file, and lists them by listing
 //! directory contents. It is very similar to Git's loose objects directory.
 
+use std::collections::HashSet;
 use std::fs::{self, File, OpenOptions};
-use std::io::{Read, Write};
+use std::io::{self, Read, Write};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrdering};
+use std::sync::Mutex;
+use std::thread;
 
 use log::{error, info, warn};
 
-use crate::common::{ID, EnumerableBlobStorage, BlobStorage};
+use crate::common::{BlobId, BlobIterator, EnumerableBlobStorage, BlobStorage,
+                    unique_match};
 use crate::errors::{self, Error};
-use crate::hash::Hasher;
+use crate::hash::{Hasher, HasherWriter};
+use crate::queries::Cancel;
+use crate::serialize::hash_blob;
+use crate::span::Span;
 
 /// Filesystem-based blob storage implementation.
 ///
@@ -19,69 +27,212 @@ use crate::hash::Hasher;
 /// directory contents. It is very similar to Git's loose object directory.
 pub struct FileBlobStorage {
     path: PathBuf,
+    threads: usize,
 }
 
 impl FileBlobStorage {
     /// Opens the blob storage from a path.
     pub fn open<P: AsRef<Path>>(path: P) -> FileBlobStorage {
-        FileBlobStorage { path: path.as_ref().to_path_buf() }
+        let threads = thread::available_parallelism()
+            .map(|n| n.get())
+            .unwrap_or(1);
+        FileBlobStorage {
+            path: path.as_ref().to_path_buf(),
+            threads,
+        }
+    }
+
+    /// Sets the number of threads used to sweep blobs during garbage
+    /// collection, and to read batches of blobs.
+    ///
+    /// The default is the number of CPUs.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
     }
 
     /// Builds the path to an object from its ID.
-    fn filename(&self, id: &ID) -> PathBuf {
+    fn filename(&self, id: &BlobId) -> PathBuf {
         let mut path = self.path.to_path_buf();
-        let hashstr = id.str();
+        let hashstr = id.id().str();
         path.push(&hashstr[..4]);
         path.push(&hashstr[4..]);
         path
     }
+
+    /// Writes a blob, unless it's already there.
+    ///
+    /// `dirs` holds the directories known to exist, so that a batch only
+    /// checks each of them once.
+    fn write_blob(&self, id: &BlobId, blob: &[u8], dirs: &mut HashSet<PathBuf>)
+        -> errors::Result<()>
+    {
+        let path = self.filename(id);
+        if path.exists() {
+            return Ok(());
+        }
+        let mut span = Span::enter("write_blob");
+        span.detail(id);
+        span.add_bytes(blob.len() as u64);
+        let parent = path.parent().unwrap();
+        if !dirs.contains(parent) {
+            if !parent.exists() {
+                fs::create_dir(parent)
+                    .map_err(|e| ("Couldn't create blob directory", e))?;
+            }
+            dirs.insert(parent.to_path_buf());
+        }
+        let mut fp = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(path)
+            .map_err(|e| ("Can't open new blob file", e))?;
+        fp.write_all(blob).map_err(|e| ("Error writing blob file", e))?;
+        Ok(())
+    }
 }
 
+/// Smallest number of blobs worth giving a thread in `get_blobs()`.
+const MIN_BLOBS_PER_THREAD: usize = 16;
+
 impl BlobStorage for FileBlobStorage {
-    fn get_blob(&self, id: &ID) -> errors::Result<Option<Box<[u8]>>> {
+    fn get_blob(&self, id: &BlobId) -> errors::Result<Option<Box<[u8]>>> {
         let path = self.filename(id);
         if path.exists() {
+            let mut span = Span::enter("read_blob");
+            span.detail(id);
             let mut fp = File::open(path)
                 .map_err(|e| ("Can't open blob file", e))?;
             let mut buf = Vec::new();
             fp.read_to_end(&mut buf)
                 .map_err(|e| ("Error reading blob file", e))?;
+            span.add_bytes(buf.len() as u64);
             Ok(Some(buf.into_boxed_slice()))
         } else {
             Ok(None)
         }
     }
 
-    fn add_blob(&mut self, blob: &[u8]) -> errors::Result<ID> {
-        let mut hasher = Hasher::new();
-        hasher.write_all(b"blob\n").unwrap();
-        hasher.write_all(blob).unwrap();
-        let id = hasher.result();
+    fn get_blob_reader(&self, id: &BlobId)
+        -> errors::Result<Option<Box<dyn Read + '_>>>
+    {
+        match File::open(self.filename(id)) {
+            Ok(fp) => Ok(Some(Box::new(fp))),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(("Can't open blob file", e).into()),
+        }
+    }
+
+    fn add_blob(&mut self, blob: &[u8]) -> errors::Result<BlobId> {
+        let id = hash_blob(blob);
         self.add_known_blob(&id, blob)?;
         Ok(id)
     }
 
-    fn add_known_blob(&mut self, id: &ID, blob: &[u8]) -> errors::Result<()> {
-        let path = self.filename(id);
-        if !path.exists() {
-            {
-                let parent = path.parent().unwrap();
-                if !parent.exists() {
-                    fs::create_dir(parent)
-                        .map_err(|e| ("Couldn't create blob directory", e))?;
-                }
+    fn contains(&self, id: &BlobId) -> errors::Result<bool> {
+        Ok(self.filename(id).exists())
+    }
+
+    fn size(&self, id: &BlobId) -> errors::Result<Option<u64>> {
+        match fs::metadata(self.filename(id)) {
+            Ok(metadata) => Ok(Some(metadata.len())),
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(("Can't get size of blob file", e).into()),
+        }
+    }
+
+    fn add_blob_from_reader(&mut self, reader: &mut dyn Read)
+        -> errors::Result<BlobId>
+    {
+        let mut span = Span::enter("write_blob");
+        // Write to a temporary file while hashing, then move it in place
+        let temp = self.path.join(format!(".incoming-{:016x}",
+                                          rand::random::<u64>()));
+        let fp = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .map_err(|e| ("Can't open temporary blob file", e))?;
+        let mut hasher = Hasher::new();
+        hasher.write_all(b"blob\n").unwrap();
+        let mut writer = HasherWriter::with_hasher(fp, hasher);
+        match io::copy(reader, &mut writer) {
+            Ok(bytes) => span.add_bytes(bytes),
+            Err(e) => {
+                fs::remove_file(&temp).ok();
+                return Err(Error::IoError("Error writing blob file", e));
             }
-            let mut fp = OpenOptions::new()
-                .write(true)
-                .create_new(true)
-                .open(path)
-                .map_err(|e| ("Can't open new blob file", e))?;
-            fp.write_all(blob).map_err(|e| ("Error writing blob file", e))?;
+        }
+        let id = BlobId::from_id(writer.result());
+        span.detail(&id);
+        let path = self.filename(&id);
+        if path.exists() {
+            fs::remove_file(&temp)
+                .map_err(|e| ("Couldn't remove temporary blob file", e))?;
+        } else {
+            let parent = path.parent().unwrap();
+            if !parent.exists() {
+                fs::create_dir(parent)
+                    .map_err(|e| ("Couldn't create blob directory", e))?;
+            }
+            fs::rename(&temp, &path)
+                .map_err(|e| ("Couldn't move blob file in place", e))?;
+        }
+        Ok(id)
+    }
+
+    fn add_known_blob(&mut self, id: &BlobId, blob: &[u8])
+        -> errors::Result<()>
+    {
+        self.write_blob(id, blob, &mut HashSet::new())
+    }
+
+    fn add_blobs(&mut self, blobs: &[&[u8]]) -> errors::Result<Vec<BlobId>> {
+        let mut dirs = HashSet::new();
+        blobs.iter()
+            .map(|blob| {
+                let id = hash_blob(blob);
+                self.write_blob(&id, blob, &mut dirs)?;
+                Ok(id)
+            })
+            .collect()
+    }
+
+    fn add_known_blobs(&mut self, blobs: &[(BlobId, &[u8])])
+        -> errors::Result<()>
+    {
+        let mut dirs = HashSet::new();
+        for (id, blob) in blobs {
+            self.write_blob(id, blob, &mut dirs)?;
         }
         Ok(())
     }
 
-    fn delete_blob(&mut self, id: &ID) -> errors::Result<()> {
+    /// Reads the blobs with several threads, for large batches.
+    fn get_blobs(&self, ids: &[BlobId])
+        -> errors::Result<Vec<Option<Box<[u8]>>>>
+    {
+        let threads = self.threads.min(ids.len() / MIN_BLOBS_PER_THREAD);
+        if threads <= 1 {
+            return ids.iter().map(|id| self.get_blob(id)).collect();
+        }
+        let per_thread = ids.len().div_ceil(threads);
+        thread::scope(|scope| {
+            let workers: Vec<_> = ids.chunks(per_thread)
+                .map(|ids| scope.spawn(move || {
+                    ids.iter()
+                        .map(|id| self.get_blob(id))
+                        .collect::<errors::Result<Vec<_>>>()
+                }))
+                .collect();
+            let mut blobs = Vec::with_capacity(ids.len());
+            for worker in workers {
+                blobs.extend(worker.join().unwrap()?);
+            }
+            Ok(blobs)
+        })
+    }
+
+    fn delete_blob(&mut self, id: &BlobId) -> errors::Result<()> {
         let path = self.filename(id);
         if path.exists() {
             fs::remove_file(path)
@@ -90,19 +241,17 @@ impl BlobStorage for FileBlobStorage {
         Ok(())
     }
 
-    fn verify(&mut self) -> errors::Result<()> {
+    fn verify(&mut self, cancel: &Cancel) -> errors::Result<()> {
         for blob in self.list_blobs()? {
+            cancel.check()?;
             match blob {
                 Err(e) => error!("Error listing blobs: {}", e),
                 Ok(id) => {
-                    let mut hasher = Hasher::new();
                     match self.get_blob(&id) {
                         Err(e) => error!("Error getting blob: {}", e),
                         Ok(None) => error!("Error gettting blob"),
                         Ok(Some(blob)) => {
-                            hasher.write_all(b"blob\n").unwrap();
-                            hasher.write_all(&blob).unwrap();
-                            if id != hasher.result() {
+                            if id != hash_blob(&blob) {
                                 warn!("Blob has the wrong hash: {:?}",
                                       self.filename(&id));
                             } else {
@@ -115,27 +264,211 @@ impl BlobStorage for FileBlobStorage {
         }
         Ok(())
     }
+
+    fn free_space(&self) -> errors::Result<Option<u64>> {
+        free_space(&self.path)
+            .map_err(|e| ("Can't get free space of blobs directory", e).into())
+    }
 }
 
-impl EnumerableBlobStorage for FileBlobStorage {
-    type Iter = FileBlobIterator;
+/// Gets the space available to unprivileged users on a filesystem.
+#[cfg(unix)]
+fn free_space(path: &Path) -> io::Result<Option<u64>> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(path.as_os_str().as_bytes())?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Ok(Some(stat.f_bavail as u64 * stat.f_frsize as u64))
+}
+
+#[cfg(not(unix))]
+fn free_space(_path: &Path) -> io::Result<Option<u64>> {
+    Ok(None)
+}
 
-    fn list_blobs(&self) -> errors::Result<FileBlobIterator> {
+impl EnumerableBlobStorage for FileBlobStorage {
+    fn list_blobs(&self) -> errors::Result<BlobIterator> {
         let first = self.path
             .read_dir()
             .map_err(|e| ("Blobs directory doesn't exist", e))?;
-        Ok(FileBlobIterator {
+        Ok(Box::new(FileBlobIterator {
             first: first,
             first_val: [0u8; 4],
             second: None,
-        })
+        }))
+    }
+
+    /// Only lists the first-level directory the prefix is in, if it is long
+    /// enough to know.
+    fn resolve_prefix(&self, prefix: &str) -> errors::Result<Option<BlobId>> {
+        if !prefix.bytes().all(|b| b.is_ascii_alphanumeric() ||
+                                   b == b'-' || b == b'_')
+        {
+            return Ok(None);
+        }
+        if prefix.len() < 4 {
+            return unique_match(prefix, self.list_blobs()?);
+        }
+        let dir = match self.path.join(&prefix[..4]).read_dir() {
+            Ok(dir) => dir,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(None);
+            }
+            Err(e) => {
+                return Err(("Error reading subdirectory in blobs", e).into());
+            }
+        };
+        let ids = dir.filter_map(|entry| {
+            let name = match entry {
+                Ok(entry) => entry.file_name(),
+                Err(e) => {
+                    return Some(Err(Error::IoError(
+                        "Error reading subdirectory in blobs", e)));
+                }
+            };
+            let name = name.to_str()?;
+            // Leaves out temporary files
+            BlobId::parse(format!("{}{}", &prefix[..4], name).as_bytes())
+                .map(Ok)
+        });
+        unique_match(prefix, ids)
+    }
+
+    /// Removes the blobs whose hash are not in the given set.
+    ///
+    /// The first-level directories are handed out to worker threads, which
+    /// list and delete blobs in parallel.
+    fn collect_garbage(&mut self, alive: HashSet<BlobId>, cancel: &Cancel)
+        -> errors::Result<()>
+    {
+        let mut dirs = Vec::new();
+        for entry in self.path.read_dir()
+            .map_err(|e| ("Blobs directory doesn't exist", e))?
+        {
+            let entry = entry.map_err(|e| ("Error reading blobs directory",
+                                           e))?;
+            let name = entry.file_name();
+            match name.to_str() {
+                Some(name) if name.starts_with('.') => {}
+                Some(name) if name.len() == 4 => dirs.push(name.to_owned()),
+                _ => return Err(Error::CorruptedStore(
+                    "First-level entry in blobs is invalid")),
+            }
+        }
+
+        let sweep = Sweep {
+            path: &self.path,
+            alive: &alive,
+            cancel,
+            dirs: &dirs,
+            next_dir: AtomicUsize::new(0),
+            swept: AtomicUsize::new(0),
+            deleted: AtomicUsize::new(0),
+            error: Mutex::new(None),
+        };
+        let threads = self.threads.min(dirs.len()).max(1);
+        info!("Sweeping {} blob directories with {} threads",
+              dirs.len(), threads);
+        thread::scope(|scope| {
+            for _ in 0..threads {
+                scope.spawn(|| sweep.work());
+            }
+        });
+        if let Some(e) = sweep.error.into_inner().unwrap() {
+            return Err(e);
+        }
+        info!("Swept {} blobs, deleted {}",
+              sweep.swept.into_inner(), sweep.deleted.into_inner());
+        Ok(())
+    }
+}
+
+/// State shared by the threads of `FileBlobStorage::collect_garbage()`.
+struct Sweep<'a> {
+    path: &'a Path,
+    alive: &'a HashSet<BlobId>,
+    cancel: &'a Cancel,
+    dirs: &'a [String],
+    /// Index of the next directory to be picked up by a worker
+    next_dir: AtomicUsize,
+    swept: AtomicUsize,
+    deleted: AtomicUsize,
+    /// First error encountered, which stops the other workers
+    error: Mutex<Option<Error>>,
+}
+
+/// Number of blobs between progress messages.
+const SWEEP_PROGRESS: usize = 10000;
+
+impl<'a> Sweep<'a> {
+    fn work(&self) {
+        loop {
+            if self.error.lock().unwrap().is_some() {
+                return;
+            }
+            let i = self.next_dir.fetch_add(1, AtomicOrdering::Relaxed);
+            let dir = match self.dirs.get(i) {
+                Some(dir) => dir,
+                None => return,
+            };
+            if let Err(e) = self.sweep_dir(dir) {
+                let mut error = self.error.lock().unwrap();
+                if error.is_none() {
+                    *error = Some(e);
+                }
+                return;
+            }
+        }
+    }
+
+    fn sweep_dir(&self, dir: &str) -> errors::Result<()> {
+        let entries = self.path.join(dir).read_dir()
+            .map_err(|e| ("Error reading subdirectory in blobs", e))?;
+        let mut swept = 0;
+        let mut deleted = 0;
+        for entry in entries {
+            self.cancel.check()?;
+            let entry = entry
+                .map_err(|e| ("Error reading subdirectory in blobs", e))?;
+            let name = entry.file_name();
+            let id = name.to_str()
+                .map(|name| format!("{}{}", dir, name))
+                .and_then(|id| BlobId::parse(id.as_bytes()));
+            let id = match id {
+                Some(id) => id,
+                None => {
+                    warn!("Invalid blob file {:?}", entry.path());
+                    continue;
+                }
+            };
+            swept += 1;
+            if !self.alive.contains(&id) {
+                fs::remove_file(entry.path())
+                    .map_err(|e| ("Couldn't remove blob file", e))?;
+                deleted += 1;
+            }
+        }
+        self.deleted.fetch_add(deleted, AtomicOrdering::Relaxed);
+        let before = self.swept.fetch_add(swept, AtomicOrdering::Relaxed);
+        if (before + swept) / SWEEP_PROGRESS > before / SWEEP_PROGRESS {
+            info!("Swept {} blobs, deleted {}",
+                  before + swept,
+                  self.deleted.load(AtomicOrdering::Relaxed));
+        }
+        Ok(())
     }
 }
 
 /// Iterator on blobs returned by `FileBlobStorage::list_blobs()`.
 ///
 /// Simply uses `Path::read_dir()` to list directory contents and parse the
-/// paths back into `ID`s.
+/// paths back into `ID`s. Hidden files at the top level are temporary files
+/// and are skipped.
 ///
 /// Note that filesystem operations can fail. If during iteration, one element
 /// is `Err(...)`, you should abort iteration.
@@ -146,9 +479,9 @@ pub struct FileBlobIterator {
 }
 
 impl Iterator for FileBlobIterator {
-    type Item = errors::Result<ID>;
+    type Item = errors::Result<BlobId>;
 
-    fn next(&mut self) -> Option<errors::Result<ID>> {
+    fn next(&mut self) -> Option<errors::Result<BlobId>> {
         if self.second.is_none() {
             if let Some(entry) = self.first.next() {
                 let entry = match entry {
@@ -166,6 +499,10 @@ impl Iterator for FileBlobIterator {
                             "First-level entry in blobs is invalid unicode")));
                     }
                 };
+                if name.starts_with('.') {
+                    // Temporary file from add_blob_from_reader()
+                    return self.next();
+                }
                 let slice = name.as_bytes();
                 if slice.len() != 4 {
                     return Some(Err(Error::CorruptedStore(
@@ -206,7 +543,7 @@ impl Iterator for FileBlobIterator {
                     "Second-level entry has invalid length")));
             }
             id[4..].clone_from_slice(slice);
-            Some(ID::from_str(&id)
+            Some(BlobId::parse(&id)
                  .ok_or(Error::CorruptedStore("Path is not a valid ID")))
         } else {
             self.second = None;
@@ -214,3 +551,59 @@ impl Iterator for FileBlobIterator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::fs;
+
+    use crate::common::{BlobStorage, EnumerableBlobStorage};
+    use crate::errors::Error;
+    use crate::queries::Cancel;
+    use super::FileBlobStorage;
+
+    #[test]
+    fn test_collect_garbage() {
+        let path = std::env::temp_dir().join(format!(
+            "dhstore-test-file-storage-gc-{}", std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        fs::create_dir(&path).unwrap();
+        let mut storage = FileBlobStorage::open(&path);
+        storage.set_threads(4);
+        let ids: Vec<_> = (0..64)
+            .map(|i| storage.add_blob(format!("blob {}", i).as_bytes())
+                 .unwrap())
+            .collect();
+        let prefixes: HashSet<_> = ids.iter()
+            .map(|id| id.id().str()[..4].to_owned())
+            .collect();
+        assert!(prefixes.len() > 4);
+        // Left by an interrupted addition, or one in progress
+        let temp = path.join(".incoming-0123456789abcdef");
+        fs::write(&temp, b"partial").unwrap();
+        let alive: HashSet<_> = ids.iter().step_by(2).cloned().collect();
+
+        // Nothing is deleted once cancelled
+        let cancel = Cancel::new();
+        cancel.cancel();
+        match storage.collect_garbage(alive.clone(), &cancel) {
+            Err(Error::Cancelled) => {}
+            r => panic!("Expected Cancelled, got {:?}", r),
+        }
+        assert_eq!(storage.list_blobs().unwrap().count(), ids.len());
+
+        storage.collect_garbage(alive.clone(), &Cancel::new()).unwrap();
+        let left = storage.list_blobs().unwrap()
+            .collect::<Result<HashSet<_>, _>>()
+            .unwrap();
+        assert_eq!(left, alive);
+        for id in &ids {
+            assert_eq!(storage.contains(id).unwrap(), alive.contains(id));
+        }
+        assert!(temp.exists());
+
+        fs::remove_dir_all(path).unwrap();
+    }
+}
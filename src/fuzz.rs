@@ -0,0 +1,34 @@
+//! Entry points for fuzzing the parsers, used by the targets in `fuzz/`.
+//!
+//! Each of these takes arbitrary bytes, such as could come from the network
+//! or a damaged store, and checks the properties the parser should have,
+//! panicking if they don't hold. They should never panic otherwise.
+
+use crate::common::ID;
+use crate::hash::IdEncoding;
+use crate::serialize;
+
+/// Reads an object; if it is valid, it serializes back to the same bytes.
+pub fn deserialize(data: &[u8]) {
+    if let Ok(object) = serialize::deserialize(data) {
+        let mut out = Vec::new();
+        serialize::serialize(&mut out, &object).unwrap();
+        assert_eq!(out, data);
+    }
+}
+
+/// Parses an ID; if it is valid, it formats back to the same string.
+///
+/// The other encodings are also tried, base32 being case-insensitive.
+pub fn id_from_str(data: &[u8]) {
+    if let Some(id) = ID::from_str(data) {
+        assert_eq!(id.str().as_bytes(), data);
+    }
+    if let Some(id) = ID::decode(data) {
+        let encodings = [IdEncoding::Base64, IdEncoding::Base32,
+                         IdEncoding::Base58];
+        assert!(encodings.iter().any(|&e| {
+            id.encode(e).as_bytes().eq_ignore_ascii_case(data)
+        }));
+    }
+}
@@ -1,7 +1,12 @@
 //! Structures and functions related to hashing.
 //!
 //! This module contains the `ID` type used to addres blobs and objects by their
-//! content, as well as `Hasher` used to build it from bytes.
+//! content, with the `BlobId` and `ObjectId` wrappers telling which one it
+//! is, as well as `Hasher` used to build it from bytes.
+//!
+//! IDs are stored and displayed in base64, see `ID::str()`. They can also be
+//! written in base32 or base58 for places that don't deal well with `-`, `_`
+//! or case, see `ID::encode()`; `ID::decode()` accepts all of them.
 
 use sha2::{Digest, Sha256};
 use std::fmt;
@@ -36,6 +41,46 @@ pub const HASH_SIZE: usize = 32;
 /// Size of the hash when represented in base64.
 pub const HASH_STR_SIZE: usize = 44;
 
+const BASE32_CHARS: &[u8] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+const BASE58_CHARS: &[u8] =
+    b"123456789ABCDEFGHJKLMNPQRSTUVWXYZabcdefghijkmnopqrstuvwxyz";
+
+/// A textual encoding of IDs.
+///
+/// The alternative encodings start with their multibase prefix, which tells
+/// them apart from the base64 form and from each other.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum IdEncoding {
+    /// The canonical form, see `ID::str()`
+    #[default]
+    Base64,
+    /// RFC 4648 base32 in lowercase, without padding, prefixed with `b`;
+    /// uppercase is accepted when parsing
+    Base32,
+    /// Base58 with the Bitcoin alphabet, prefixed with `z`
+    Base58,
+}
+
+impl IdEncoding {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            IdEncoding::Base64 => "base64",
+            IdEncoding::Base32 => "base32",
+            IdEncoding::Base58 => "base58",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<IdEncoding> {
+        match name {
+            "base64" => Some(IdEncoding::Base64),
+            "base32" => Some(IdEncoding::Base32),
+            "base58" => Some(IdEncoding::Base58),
+            _ => None,
+        }
+    }
+}
+
 impl ID {
     /// Make an ID from raw bytes.
     pub fn from_bytes(buf: &[u8]) -> Option<ID> {
@@ -74,6 +119,27 @@ impl ID {
         unsafe { String::from_utf8_unchecked(hashstr) }
     }
 
+    /// Returns the representation of the ID in the given encoding.
+    pub fn encode(&self, encoding: IdEncoding) -> String {
+        match encoding {
+            IdEncoding::Base64 => self.str(),
+            IdEncoding::Base32 => self.base32(),
+            IdEncoding::Base58 => self.base58(),
+        }
+    }
+
+    /// Parses an ID in any of the encodings of `IdEncoding`.
+    ///
+    /// This is for IDs entered by users; IDs read from the store should only
+    /// be in the canonical form, use `from_str()` for those.
+    pub fn decode(text: &[u8]) -> Option<ID> {
+        match text.first() {
+            Some(b'b') | Some(b'B') => ID::from_base32(&text[1..]),
+            Some(b'z') => ID::from_base58(&text[1..]),
+            _ => ID::from_str(text),
+        }
+    }
+
     /// Parses the string representation into a ID.
     ///
     /// This returns an `ID` if the string was valid, else None.
@@ -115,6 +181,153 @@ impl ID {
         }
         Some(ID { bytes: out })
     }
+
+    fn base32(&self) -> String {
+        let mut out = String::with_capacity(53);
+        out.push('b');
+        let mut buffer = 0u16;
+        let mut bits = 0;
+        for &byte in &self.bytes {
+            buffer = buffer << 8 | byte as u16;
+            bits += 8;
+            while bits >= 5 {
+                bits -= 5;
+                out.push(BASE32_CHARS[(buffer >> bits) as usize & 31] as char);
+            }
+        }
+        out.push(BASE32_CHARS[(buffer << (5 - bits)) as usize & 31] as char);
+        out
+    }
+
+    fn from_base32(encoded: &[u8]) -> Option<ID> {
+        if encoded.len() != 52 {
+            return None;
+        }
+        let mut out = [0u8; 32];
+        let mut pos = 0;
+        let mut buffer = 0u16;
+        let mut bits = 0;
+        for &c in encoded {
+            let c = c.to_ascii_lowercase();
+            let value = BASE32_CHARS.iter().position(|&b| b == c)?;
+            buffer = buffer << 5 | value as u16;
+            bits += 5;
+            if bits >= 8 {
+                bits -= 8;
+                out[pos] = (buffer >> bits) as u8;
+                pos += 1;
+            }
+        }
+        // The bits past the end have to be zero
+        if buffer & ((1 << bits) - 1) != 0 {
+            return None;
+        }
+        Some(ID { bytes: out })
+    }
+
+    fn base58(&self) -> String {
+        // Digits in base 58, least significant first
+        let mut digits: Vec<u8> = Vec::with_capacity(44);
+        for &byte in &self.bytes {
+            let mut carry = byte as u32;
+            for digit in &mut digits {
+                carry += (*digit as u32) << 8;
+                *digit = (carry % 58) as u8;
+                carry /= 58;
+            }
+            while carry > 0 {
+                digits.push((carry % 58) as u8);
+                carry /= 58;
+            }
+        }
+        let zeros = self.bytes.iter().take_while(|&&b| b == 0).count();
+        let mut out = String::with_capacity(1 + zeros + digits.len());
+        out.push('z');
+        for _ in 0..zeros {
+            out.push('1');
+        }
+        for &digit in digits.iter().rev() {
+            out.push(BASE58_CHARS[digit as usize] as char);
+        }
+        out
+    }
+
+    fn from_base58(encoded: &[u8]) -> Option<ID> {
+        if encoded.is_empty() || encoded.len() > 44 {
+            return None;
+        }
+        // Bytes, least significant first
+        let mut bytes: Vec<u8> = Vec::with_capacity(32);
+        for &c in encoded {
+            let mut carry = BASE58_CHARS.iter().position(|&b| b == c)? as u32;
+            for byte in &mut bytes {
+                carry += *byte as u32 * 58;
+                *byte = carry as u8;
+                carry >>= 8;
+            }
+            while carry > 0 {
+                bytes.push(carry as u8);
+                carry >>= 8;
+            }
+        }
+        let zeros = encoded.iter().take_while(|&&c| c == b'1').count();
+        if zeros + bytes.len() != 32 {
+            return None;
+        }
+        let mut out = [0u8; 32];
+        for (o, &b) in out[zeros..].iter_mut().zip(bytes.iter().rev()) {
+            *o = b;
+        }
+        Some(ID { bytes: out })
+    }
+}
+
+/// Encodes bytes in base64, with the same alphabet as IDs and no padding.
+pub fn base64_encode(data: &[u8]) -> String {
+    let mut out = String::with_capacity((data.len() * 4).div_ceil(3));
+    for chunk in data.chunks(3) {
+        let b = [chunk[0],
+                 chunk.get(1).cloned().unwrap_or(0),
+                 chunk.get(2).cloned().unwrap_or(0)];
+        let sextets = [b[0] >> 2,
+                       (b[0] << 4 | b[1] >> 4) & 63,
+                       (b[1] << 2 | b[2] >> 6) & 63,
+                       b[2] & 63];
+        for &s in &sextets[..chunk.len() + 1] {
+            out.push(BASE64_CHARS[s as usize] as char);
+        }
+    }
+    out
+}
+
+/// Decodes the output of `base64_encode()`.
+///
+/// Only the canonical encoding is accepted, so that decoded data always
+/// encodes back to the same string.
+pub fn base64_decode(encoded: &[u8]) -> Option<Vec<u8>> {
+    if encoded.len() % 4 == 1 {
+        return None;
+    }
+    let mut out = Vec::with_capacity(encoded.len() * 3 / 4);
+    for chunk in encoded.chunks(4) {
+        let mut sextets = [0u8; 4];
+        for (s, &c) in sextets.iter_mut().zip(chunk) {
+            *s = *BASE64_BYTES.get(c as usize)?;
+            if *s == 64 {
+                return None;
+            }
+        }
+        let bytes = [sextets[0] << 2 | sextets[1] >> 4,
+                     sextets[1] << 4 | sextets[2] >> 2,
+                     sextets[2] << 6 | sextets[3]];
+        let len = chunk.len() - 1;
+        // Bits past the end have to be zero
+        if len < 3 && bytes[len] != 0 {
+            return None;
+        }
+        out.extend_from_slice(&bytes[..len]);
+    }
+    Some(out)
 }
 
 impl hash::Hash for ID {
@@ -136,6 +349,82 @@ impl fmt::Debug for ID {
     }
 }
 
+macro_rules! typed_id {
+    ( $(#[$meta:meta])* $name:ident ) => {
+        $(#[$meta])*
+        #[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+        pub struct $name(ID);
+
+        impl $name {
+            /// Marks an `ID` as being of this kind.
+            pub fn from_id(id: ID) -> $name {
+                $name(id)
+            }
+
+            /// Parses the string representation, see `ID::from_str()`.
+            pub fn parse(hashstr: &[u8]) -> Option<$name> {
+                ID::from_str(hashstr).map($name)
+            }
+
+            /// Parses an ID in any encoding, see `ID::decode()`.
+            pub fn decode(text: &[u8]) -> Option<$name> {
+                ID::decode(text).map($name)
+            }
+
+            /// Writes the ID in the given encoding, see `ID::encode()`.
+            pub fn encode(&self, encoding: IdEncoding) -> String {
+                self.0.encode(encoding)
+            }
+
+            pub fn id(&self) -> &ID {
+                &self.0
+            }
+
+            pub fn into_id(self) -> ID {
+                self.0
+            }
+        }
+
+        impl AsRef<ID> for $name {
+            fn as_ref(&self) -> &ID {
+                &self.0
+            }
+        }
+
+        impl From<$name> for ID {
+            fn from(id: $name) -> ID {
+                id.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+                self.0.fmt(f)
+            }
+        }
+
+        impl fmt::Debug for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+                write!(f, "{}({})", stringify!($name), self.0)
+            }
+        }
+    };
+}
+
+typed_id!(
+    /// Identifier of a blob, in a `BlobStorage`.
+    ///
+    /// This is the same hash as an `ID`, but keeping the two kinds apart
+    /// means a blob can't be looked up in the index by mistake, or the
+    /// reverse.
+    BlobId
+);
+
+typed_id!(
+    /// Identifier of an object, in an `ObjectIndex`.
+    ObjectId
+);
+
 /// Content to ID code.
 ///
 /// Abstracted to make it easier to swap it out, or use multiple hashes,
@@ -156,14 +445,14 @@ impl Hasher {
     /// Consume this `Hasher` and return an `ID`.
     pub fn result(self) -> ID {
         let mut bytes = [0u8; 32];
-        bytes.copy_from_slice(self.hasher.result().as_slice());
+        bytes.copy_from_slice(&self.hasher.finalize());
         ID { bytes: bytes }
     }
 }
 
 impl Write for Hasher {
     fn write(&mut self, msg: &[u8]) -> io::Result<usize> {
-        self.hasher.input(msg);
+        self.hasher.update(msg);
         Ok(msg.len())
     }
 
@@ -260,7 +549,7 @@ impl<R: Read> Read for HasherReader<R> {
 
 #[cfg(test)]
 mod tests {
-    use super::ID;
+    use super::{ID, IdEncoding, base64_decode, base64_encode};
 
     fn run_tests(check: &Fn(&[u8], &str)) {
         check(b"abcdefghijklmnopqrstuvwxyz123456",
@@ -288,4 +577,40 @@ mod tests {
         }
         run_tests(&check);
     }
+
+    #[test]
+    fn test_encodings() {
+        let id = ID::from_bytes(
+            b"\x00\x00cd\xF4fg\x7Fijkl\x88nop\x00rstuvwxyz123\xC9\xFF\xDE")
+            .unwrap();
+        let base32 = id.encode(IdEncoding::Base32);
+        assert_eq!(base32,
+                   "baaaggzhumztx62lknnwiq3tpoaahe43uov3ho6dzpiytem6j77pa");
+        let base58 = id.encode(IdEncoding::Base58);
+        assert_eq!(base58, "z11Lv8FJm7uTbYy91YGDMwsnW6CMA5F8kPfGYv3s2QFo");
+        for encoded in &[id.str(), base32.clone(), base32.to_uppercase(),
+                         base58.clone()] {
+            assert_eq!(ID::decode(encoded.as_bytes()), Some(id.clone()));
+        }
+        assert_eq!(ID::from_str(base32.as_bytes()), None);
+        // Non-zero bits past the end
+        assert_eq!(ID::decode(
+            b"baaaggzhumztx62lknnwiq3tpoaahe43uov3ho6dzpiytem6j77pb"), None);
+        assert_eq!(ID::decode(
+            b"z11Lv8FJm7uTbYy91YGDMwsnW6CMA5F8kPfGYv3s2QF0"), None);
+        assert_eq!(ID::decode(b"z1"), None);
+    }
+
+    #[test]
+    fn test_base64() {
+        for data in &[&b""[..], b"a", b"ab", b"abc", b"\xFF\xFE\xFD\xFC"] {
+            let encoded = base64_encode(data);
+            assert_eq!(base64_decode(encoded.as_bytes()).unwrap(), *data);
+        }
+        assert_eq!(base64_encode(b"\xFB\xFFab"), "-_9hYg");
+        assert_eq!(base64_decode(b"YQ"), Some(b"a".to_vec()));
+        assert_eq!(base64_decode(b"YR"), None);
+        assert_eq!(base64_decode(b"YWJjZ"), None);
+        assert_eq!(base64_decode(b"YW=="), None);
+    }
 }
@@ -0,0 +1,537 @@
+//! Compact encodings of sets of IDs.
+//!
+//! Exchanging sets of IDs comes up whenever two stores compare what they
+//! have, and when a set needs to be written down, for example the live set
+//! of a garbage collection. This module provides:
+//!
+//! * `encode_sorted()` / `decode_sorted()`: an exact encoding of a set,
+//!   storing the differences between consecutive sorted IDs;
+//! * `BloomFilter`: an approximate set, for "do you have these?" queries
+//!   where false positives are acceptable;
+//! * `Iblt`: an invertible Bloom lookup table, from which the difference
+//!   between two similar sets can be recovered without sending either.
+
+use crate::errors::{self, Error};
+use crate::hash::{HASH_SIZE, ID};
+
+/// Appends an unsigned LEB128 varint.
+pub fn write_varint(mut value: u64, out: &mut Vec<u8>) {
+    while value >= 0x80 {
+        out.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    out.push(value as u8);
+}
+
+/// Reads an unsigned LEB128 varint at `*pos`, advancing it.
+pub fn read_varint(buf: &[u8], pos: &mut usize) -> Option<u64> {
+    let mut value = 0u64;
+    let mut shift = 0;
+    loop {
+        let byte = *buf.get(*pos)?;
+        *pos += 1;
+        if shift == 63 && byte > 1 {
+            return None;
+        }
+        value |= ((byte & 0x7f) as u64) << shift;
+        if byte & 0x80 == 0 {
+            return Some(value);
+        }
+        shift += 7;
+        if shift > 63 {
+            return None;
+        }
+    }
+}
+
+/// Subtracts big-endian 256-bit numbers, `a - b`, assuming `a >= b`.
+fn sub256(a: &[u8; HASH_SIZE], b: &[u8; HASH_SIZE]) -> [u8; HASH_SIZE] {
+    let mut out = [0u8; HASH_SIZE];
+    let mut borrow = 0i16;
+    for i in (0..HASH_SIZE).rev() {
+        let mut d = a[i] as i16 - b[i] as i16 - borrow;
+        borrow = if d < 0 { d += 256; 1 } else { 0 };
+        out[i] = d as u8;
+    }
+    out
+}
+
+/// Adds big-endian 256-bit numbers, returns `None` on overflow.
+fn add256(a: &[u8; HASH_SIZE], b: &[u8; HASH_SIZE])
+    -> Option<[u8; HASH_SIZE]>
+{
+    let mut out = [0u8; HASH_SIZE];
+    let mut carry = 0u16;
+    for i in (0..HASH_SIZE).rev() {
+        let s = a[i] as u16 + b[i] as u16 + carry;
+        out[i] = s as u8;
+        carry = s >> 8;
+    }
+    if carry != 0 { None } else { Some(out) }
+}
+
+/// Appends a big-endian 256-bit number, without its leading zero bytes,
+/// after its length as a varint.
+fn write_number256(value: &[u8; HASH_SIZE], out: &mut Vec<u8>) {
+    let start = value.iter().position(|&b| b != 0).unwrap_or(HASH_SIZE);
+    write_varint((HASH_SIZE - start) as u64, out);
+    out.extend_from_slice(&value[start..]);
+}
+
+/// Reads a number written by `write_number256()`.
+fn read_number256(buf: &[u8], pos: &mut usize) -> Option<[u8; HASH_SIZE]> {
+    let len = read_varint(buf, pos)? as usize;
+    if len > HASH_SIZE || buf.len() - *pos < len {
+        return None;
+    }
+    let mut value = [0u8; HASH_SIZE];
+    value[HASH_SIZE - len..].copy_from_slice(&buf[*pos..*pos + len]);
+    *pos += len;
+    Some(value)
+}
+
+/// Encodes a set of IDs exactly.
+///
+/// The IDs are sorted and deduplicated, then each one is written as its
+/// difference with the previous one, prefixed with its length as a varint.
+/// Random IDs take about as much space as writing them out, but IDs that
+/// are close to each other, like a range, get much smaller.
+pub fn encode_sorted<'a, I>(ids: I) -> Vec<u8>
+    where I: IntoIterator<Item = &'a ID>
+{
+    let mut ids: Vec<&ID> = ids.into_iter().collect();
+    ids.sort();
+    ids.dedup();
+    let mut out = Vec::new();
+    write_varint(ids.len() as u64, &mut out);
+    let mut previous = [0u8; HASH_SIZE];
+    for id in ids {
+        write_number256(&sub256(&id.bytes, &previous), &mut out);
+        previous = id.bytes;
+    }
+    out
+}
+
+/// Decodes a set of IDs written by `encode_sorted()`, in sorted order.
+pub fn decode_sorted(buf: &[u8]) -> errors::Result<Vec<ID>> {
+    const INVALID: Error = Error::InvalidInput("Invalid ID set encoding");
+    let mut pos = 0;
+    let count = read_varint(buf, &mut pos).ok_or(INVALID)?;
+    // Each ID takes at least one byte, don't trust count for allocation
+    if count > (buf.len() - pos) as u64 {
+        return Err(INVALID);
+    }
+    let mut ids = Vec::with_capacity(count as usize);
+    let mut previous = [0u8; HASH_SIZE];
+    for i in 0..count {
+        let delta = read_number256(buf, &mut pos).ok_or(INVALID)?;
+        if i > 0 && delta.iter().all(|&b| b == 0) {
+            return Err(INVALID);
+        }
+        previous = add256(&previous, &delta).ok_or(INVALID)?;
+        ids.push(ID { bytes: previous });
+    }
+    if pos != buf.len() {
+        return Err(INVALID);
+    }
+    Ok(ids)
+}
+
+/// Reads a little-endian u64 from 8 bytes of an ID.
+fn id_u64(id: &[u8; HASH_SIZE], offset: usize) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&id[offset..offset + 8]);
+    u64::from_le_bytes(bytes)
+}
+
+/// Approximate set of IDs, which can have false positives.
+///
+/// IDs are hashes already, so the bit positions are derived from the ID
+/// bytes directly, using double hashing.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct BloomFilter {
+    bits: Vec<u8>,
+    hashes: u32,
+}
+
+impl BloomFilter {
+    /// Creates a filter sized for `expected` IDs and the given false
+    /// positive rate.
+    pub fn new(expected: usize, false_positive_rate: f64) -> BloomFilter {
+        let expected = expected.max(1) as f64;
+        let ln2 = std::f64::consts::LN_2;
+        let bits = (-expected * false_positive_rate.ln() / (ln2 * ln2))
+            .ceil()
+            .max(8.0) as usize;
+        let hashes = ((bits as f64 / expected) * ln2).round().max(1.0);
+        BloomFilter {
+            bits: vec![0; bits.div_ceil(8)],
+            hashes: hashes as u32,
+        }
+    }
+
+    fn positions(&self, id: &ID) -> impl Iterator<Item = usize> {
+        let nbits = self.bits.len() as u64 * 8;
+        let h1 = id_u64(&id.bytes, 0);
+        let h2 = id_u64(&id.bytes, 8) | 1;
+        (0..self.hashes as u64).map(move |i| {
+            (h1.wrapping_add(i.wrapping_mul(h2)) % nbits) as usize
+        })
+    }
+
+    pub fn insert(&mut self, id: &ID) {
+        for pos in self.positions(id).collect::<Vec<_>>() {
+            self.bits[pos / 8] |= 1 << (pos % 8);
+        }
+    }
+
+    /// Whether the ID might be in the set. `false` is always right.
+    pub fn contains(&self, id: &ID) -> bool {
+        self.positions(id)
+            .all(|pos| self.bits[pos / 8] & (1 << (pos % 8)) != 0)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.bits.len() + 10);
+        write_varint(self.hashes as u64, &mut out);
+        out.extend_from_slice(&self.bits);
+        out
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> errors::Result<BloomFilter> {
+        let mut pos = 0;
+        let hashes = read_varint(buf, &mut pos)
+            .filter(|h| (1..=64).contains(h))
+            .ok_or(Error::InvalidInput("Invalid Bloom filter"))?;
+        if pos == buf.len() {
+            return Err(Error::InvalidInput("Invalid Bloom filter"));
+        }
+        Ok(BloomFilter {
+            bits: buf[pos..].to_vec(),
+            hashes: hashes as u32,
+        })
+    }
+}
+
+/// Number of cells each ID is added to in an `Iblt`.
+const IBLT_HASHES: usize = 3;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct IbltCell {
+    count: i64,
+    id_sum: [u8; HASH_SIZE],
+    hash_sum: u64,
+}
+
+impl IbltCell {
+    fn toggle(&mut self, id: &[u8; HASH_SIZE], count: i64) {
+        self.count += count;
+        for (s, b) in self.id_sum.iter_mut().zip(id.iter()) {
+            *s ^= b;
+        }
+        self.hash_sum ^= Iblt::check_hash(id);
+    }
+
+    fn is_pure(&self) -> bool {
+        (self.count == 1 || self.count == -1) &&
+            self.hash_sum == Iblt::check_hash(&self.id_sum)
+    }
+
+    fn is_empty(&self) -> bool {
+        self.count == 0 && self.hash_sum == 0 &&
+            self.id_sum.iter().all(|&b| b == 0)
+    }
+}
+
+/// Invertible Bloom lookup table of IDs.
+///
+/// Two peers each build one from their set, with the same size. Subtracting
+/// them leaves only the IDs that are in one set but not the other, which
+/// `decode()` recovers as long as there are not many more of them than about
+/// two thirds of the number of cells.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Iblt {
+    cells: Vec<IbltCell>,
+}
+
+impl Iblt {
+    /// Creates a table with about `cells` cells.
+    pub fn new(cells: usize) -> Iblt {
+        let cells = cells.max(1).div_ceil(IBLT_HASHES) * IBLT_HASHES;
+        Iblt {
+            cells: vec![IbltCell {
+                count: 0,
+                id_sum: [0; HASH_SIZE],
+                hash_sum: 0,
+            }; cells],
+        }
+    }
+
+    /// Checksum used to recognize cells holding a single ID.
+    fn check_hash(id: &[u8; HASH_SIZE]) -> u64 {
+        // Not the bytes used for the positions, and mixed so that the XOR of
+        // several IDs is unlikely to pass for one
+        id_u64(id, 24).wrapping_mul(0x9e37_79b9_7f4a_7c15).rotate_left(31)
+            ^ id_u64(id, 16)
+    }
+
+    /// Cell positions of an ID, one in each third of the table.
+    fn positions(&self, id: &[u8; HASH_SIZE]) -> [usize; IBLT_HASHES] {
+        let part = self.cells.len() / IBLT_HASHES;
+        let mut positions = [0; IBLT_HASHES];
+        for (i, p) in positions.iter_mut().enumerate() {
+            *p = i * part + (id_u64(id, i * 8) % part as u64) as usize;
+        }
+        positions
+    }
+
+    fn toggle(&mut self, id: &[u8; HASH_SIZE], count: i64) {
+        for pos in self.positions(id).iter() {
+            self.cells[*pos].toggle(id, count);
+        }
+    }
+
+    pub fn insert(&mut self, id: &ID) {
+        self.toggle(&id.bytes, 1);
+    }
+
+    pub fn remove(&mut self, id: &ID) {
+        self.toggle(&id.bytes, -1);
+    }
+
+    /// Subtracts another table of the same size from this one.
+    pub fn subtract(&mut self, other: &Iblt) -> errors::Result<()> {
+        if self.cells.len() != other.cells.len() {
+            return Err(Error::InvalidInput("IBLT sizes differ"));
+        }
+        for (cell, other) in self.cells.iter_mut().zip(other.cells.iter()) {
+            cell.count -= other.count;
+            for (s, b) in cell.id_sum.iter_mut().zip(other.id_sum.iter()) {
+                *s ^= b;
+            }
+            cell.hash_sum ^= other.hash_sum;
+        }
+        Ok(())
+    }
+
+    /// Lists the IDs in the table, as (inserted, removed).
+    ///
+    /// Returns `None` if the table holds too many to be recovered.
+    pub fn decode(mut self) -> Option<(Vec<ID>, Vec<ID>)> {
+        let mut inserted = Vec::new();
+        let mut removed = Vec::new();
+        let mut pure: Vec<usize> = (0..self.cells.len())
+            .filter(|&i| self.cells[i].is_pure())
+            .collect();
+        while let Some(i) = pure.pop() {
+            if !self.cells[i].is_pure() {
+                continue;
+            }
+            let id = self.cells[i].id_sum;
+            let count = self.cells[i].count;
+            if count == 1 {
+                inserted.push(ID { bytes: id });
+            } else {
+                removed.push(ID { bytes: id });
+            }
+            for pos in self.positions(&id).iter() {
+                self.cells[*pos].toggle(&id, -count);
+                if self.cells[*pos].is_pure() {
+                    pure.push(*pos);
+                }
+            }
+        }
+        if self.cells.iter().all(IbltCell::is_empty) {
+            Some((inserted, removed))
+        } else {
+            None
+        }
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut out = Vec::with_capacity(self.cells.len() * 42 + 10);
+        write_varint(self.cells.len() as u64, &mut out);
+        for cell in &self.cells {
+            // Zigzag encoding for the signed count
+            write_varint(((cell.count << 1) ^ (cell.count >> 63)) as u64,
+                         &mut out);
+            out.extend_from_slice(&cell.id_sum);
+            out.extend_from_slice(&cell.hash_sum.to_le_bytes());
+        }
+        out
+    }
+
+    pub fn from_bytes(buf: &[u8]) -> errors::Result<Iblt> {
+        const INVALID: Error = Error::InvalidInput("Invalid IBLT");
+        let mut pos = 0;
+        let len = read_varint(buf, &mut pos).ok_or(INVALID)?;
+        if len == 0 || !(len as usize).is_multiple_of(IBLT_HASHES) ||
+            len > (buf.len() / (HASH_SIZE + 9)) as u64
+        {
+            return Err(INVALID);
+        }
+        let mut cells = Vec::with_capacity(len as usize);
+        for _ in 0..len {
+            let zigzag = read_varint(buf, &mut pos).ok_or(INVALID)?;
+            let count = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+            if buf.len() < pos + HASH_SIZE + 8 {
+                return Err(INVALID);
+            }
+            let mut id_sum = [0u8; HASH_SIZE];
+            id_sum.copy_from_slice(&buf[pos..pos + HASH_SIZE]);
+            let mut hash_sum = [0u8; 8];
+            hash_sum.copy_from_slice(
+                &buf[pos + HASH_SIZE..pos + HASH_SIZE + 8]);
+            let hash_sum = u64::from_le_bytes(hash_sum);
+            pos += HASH_SIZE + 8;
+            cells.push(IbltCell { count, id_sum, hash_sum });
+        }
+        if pos != buf.len() {
+            return Err(INVALID);
+        }
+        Ok(Iblt { cells })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use rand::{Rng, SeedableRng, XorShiftRng};
+
+    use super::*;
+
+    fn random_ids(rng: &mut XorShiftRng, count: usize) -> Vec<ID> {
+        (0..count).map(|_| {
+            let mut bytes = [0u8; HASH_SIZE];
+            rng.fill_bytes(&mut bytes);
+            ID { bytes }
+        }).collect()
+    }
+
+    #[test]
+    fn test_varint() {
+        let mut rng = XorShiftRng::from_seed([1, 2, 3, 4]);
+        let mut values = vec![0, 1, 127, 128, 300, u64::MAX];
+        for _ in 0..1000 {
+            let shift = rng.gen_range(0, 64);
+            values.push(rng.next_u64() >> shift);
+        }
+        let mut buf = Vec::new();
+        for &v in &values {
+            write_varint(v, &mut buf);
+        }
+        let mut pos = 0;
+        for &v in &values {
+            assert_eq!(read_varint(&buf, &mut pos), Some(v));
+        }
+        assert_eq!(pos, buf.len());
+        assert_eq!(read_varint(&[0x80], &mut 0), None);
+        assert_eq!(read_varint(&[0xff; 11], &mut 0), None);
+    }
+
+    #[test]
+    fn test_sorted_roundtrip() {
+        let mut rng = XorShiftRng::from_seed([5, 6, 7, 8]);
+        for &count in &[0, 1, 2, 10, 1000] {
+            let mut ids = random_ids(&mut rng, count);
+            // Edge values, and a duplicate
+            ids.push(ID { bytes: [0; HASH_SIZE] });
+            ids.push(ID { bytes: [0xff; HASH_SIZE] });
+            if count > 0 {
+                ids.push(ids[0].clone());
+            }
+            let encoded = encode_sorted(&ids);
+            let mut expected = ids.clone();
+            expected.sort();
+            expected.dedup();
+            assert_eq!(decode_sorted(&encoded).unwrap(), expected);
+            assert!(encoded.len() <= 1 + expected.len() * (HASH_SIZE + 1));
+        }
+    }
+
+    #[test]
+    fn test_sorted_dense() {
+        // IDs sharing a long prefix only cost their differences
+        let ids: Vec<ID> = (0..1000u32).map(|i| {
+            let mut bytes = [0xab; HASH_SIZE];
+            bytes[HASH_SIZE - 4..].copy_from_slice(&(i * 3).to_be_bytes());
+            ID { bytes }
+        }).collect();
+        let encoded = encode_sorted(&ids);
+        assert!(encoded.len() < 3 * ids.len() + HASH_SIZE + 4);
+        assert_eq!(decode_sorted(&encoded).unwrap(), ids);
+    }
+
+    #[test]
+    fn test_sorted_invalid() {
+        let mut rng = XorShiftRng::from_seed([9, 10, 11, 12]);
+        let ids = random_ids(&mut rng, 20);
+        let encoded = encode_sorted(&ids);
+        assert!(decode_sorted(&encoded[..encoded.len() - 1]).is_err());
+        let mut extra = encoded.clone();
+        extra.push(0);
+        assert!(decode_sorted(&extra).is_err());
+        assert!(decode_sorted(&[0xff, 0xff, 0xff, 0x0f]).is_err());
+        // Random garbage must not panic
+        for _ in 0..1000 {
+            let len = rng.gen_range(0, 100);
+            let garbage: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            let _ = decode_sorted(&garbage);
+        }
+    }
+
+    #[test]
+    fn test_bloom() {
+        let mut rng = XorShiftRng::from_seed([13, 14, 15, 16]);
+        let ids = random_ids(&mut rng, 1000);
+        let mut filter = BloomFilter::new(ids.len(), 0.01);
+        for id in &ids {
+            filter.insert(id);
+        }
+        let filter = BloomFilter::from_bytes(&filter.to_bytes()).unwrap();
+        assert!(ids.iter().all(|id| filter.contains(id)));
+        let others = random_ids(&mut rng, 10000);
+        let false_positives = others.iter()
+            .filter(|id| filter.contains(id))
+            .count();
+        assert!(false_positives < 300, "{} false positives",
+                false_positives);
+    }
+
+    #[test]
+    fn test_iblt_difference() {
+        let mut rng = XorShiftRng::from_seed([17, 18, 19, 20]);
+        for _ in 0..20 {
+            let common = random_ids(&mut rng, 500);
+            let (len_a, len_b) = (rng.gen_range(0, 20), rng.gen_range(0, 20));
+            let only_a = random_ids(&mut rng, len_a);
+            let only_b = random_ids(&mut rng, len_b);
+            let mut a = Iblt::new(90);
+            let mut b = Iblt::new(90);
+            for id in common.iter().chain(only_a.iter()) {
+                a.insert(id);
+            }
+            for id in common.iter().chain(only_b.iter()) {
+                b.insert(id);
+            }
+            let b = Iblt::from_bytes(&b.to_bytes()).unwrap();
+            a.subtract(&b).unwrap();
+            let (inserted, removed) = a.decode().unwrap();
+            let set = |v: &[ID]| v.iter().cloned().collect::<HashSet<ID>>();
+            assert_eq!(set(&inserted), set(&only_a));
+            assert_eq!(set(&removed), set(&only_b));
+        }
+    }
+
+    #[test]
+    fn test_iblt_overfull() {
+        let mut rng = XorShiftRng::from_seed([21, 22, 23, 24]);
+        let mut table = Iblt::new(30);
+        for id in random_ids(&mut rng, 200) {
+            table.insert(&id);
+        }
+        assert!(table.decode().is_none());
+        assert!(Iblt::from_bytes(&[3, 0]).is_err());
+    }
+}
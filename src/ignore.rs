@@ -0,0 +1,230 @@
+//! Patterns of files to leave out when adding a directory.
+//!
+//! The patterns follow the syntax of gitignore files:
+//!
+//! * `*` matches anything but a slash, `?` a single character, and `[a-z]`
+//!   or `[!a-z]` a character in a set or not in it;
+//! * a pattern with no slash, other than at the end, matches a name at any
+//!   depth, e.g. `*.tmp`; one with a slash is matched against the path from
+//!   the added directory, e.g. `/build` or `docs/*.pdf`;
+//! * `**` as a whole component matches any number of directories, e.g.
+//!   `**/cache` or `logs/**`;
+//! * a trailing slash only matches directories, e.g. `node_modules/`;
+//! * a leading `!` includes again what an earlier pattern excluded.
+//!
+//! The last pattern matching a path decides. The contents of an excluded
+//! directory are not looked at, so they can't be included again.
+
+use crate::errors::{self, Error};
+
+struct Rule {
+    /// The components of the pattern
+    components: Vec<String>,
+    /// Whether the pattern is matched against the whole path, rather than
+    /// the name
+    anchored: bool,
+    dir_only: bool,
+    negate: bool,
+}
+
+/// A list of patterns, see the module documentation.
+#[derive(Default)]
+pub struct IgnoreRules {
+    rules: Vec<Rule>,
+}
+
+impl IgnoreRules {
+    pub fn new() -> IgnoreRules {
+        Default::default()
+    }
+
+    /// Adds a pattern, that comes after the existing ones.
+    pub fn add(&mut self, pattern: &str) -> errors::Result<()> {
+        let (negate, pattern) = match pattern.strip_prefix('!') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let (dir_only, pattern) = match pattern.strip_suffix('/') {
+            Some(rest) => (true, rest),
+            None => (false, pattern),
+        };
+        let anchored = pattern.contains('/');
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        if pattern.is_empty() {
+            return Err(Error::InvalidInput("Empty exclude pattern"));
+        }
+        let components: Vec<String> = pattern.split('/')
+            .map(|c| c.to_owned())
+            .collect();
+        if components.iter().any(|c| c.is_empty()) {
+            return Err(Error::InvalidInput(
+                "Empty component in exclude pattern"));
+        }
+        self.rules.push(Rule { components, anchored, dir_only, negate });
+        Ok(())
+    }
+
+    /// Adds the patterns in the lines of a gitignore file.
+    ///
+    /// Blank lines and comments starting with `#` are skipped.
+    pub fn add_lines(&mut self, text: &str) -> errors::Result<()> {
+        for line in text.lines() {
+            let line = line.trim_end();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.add(line)?;
+        }
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether a path, relative to the added directory and separated by
+    /// slashes, is excluded.
+    pub fn is_ignored(&self, path: &str, is_dir: bool) -> bool {
+        let components: Vec<&str> = path.split('/')
+            .filter(|c| !c.is_empty())
+            .collect();
+        let mut ignored = false;
+        for rule in &self.rules {
+            if rule.dir_only && !is_dir {
+                continue;
+            }
+            let matched = if rule.anchored {
+                match_components(&rule.components, &components)
+            } else {
+                components.last()
+                    .is_some_and(|name| match_glob(&rule.components[0], name))
+            };
+            if matched {
+                ignored = !rule.negate;
+            }
+        }
+        ignored
+    }
+}
+
+/// Matches the components of a pattern against those of a path.
+fn match_components(pattern: &[String], path: &[&str]) -> bool {
+    match pattern.split_first() {
+        None => path.is_empty(),
+        Some((first, rest)) if first == "**" => {
+            (0..=path.len()).any(|skip| match_components(rest, &path[skip..]))
+        }
+        Some((first, rest)) => match path.split_first() {
+            Some((name, path_rest)) => {
+                match_glob(first, name) && match_components(rest, path_rest)
+            }
+            None => false,
+        },
+    }
+}
+
+/// Matches a single component against a glob.
+fn match_glob(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    match_chars(&pattern, &name)
+}
+
+fn match_chars(pattern: &[char], name: &[char]) -> bool {
+    match pattern.split_first() {
+        None => name.is_empty(),
+        Some(('*', rest)) => {
+            (0..=name.len()).any(|skip| match_chars(rest, &name[skip..]))
+        }
+        Some(('?', rest)) => {
+            !name.is_empty() && match_chars(rest, &name[1..])
+        }
+        Some(('[', rest)) => {
+            let end = match rest.iter().skip(1).position(|&c| c == ']') {
+                Some(end) => end + 1,
+                // Not a set, a literal bracket
+                None => {
+                    return name.first() == Some(&'[') &&
+                        match_chars(rest, &name[1..]);
+                }
+            };
+            match name.first() {
+                Some(&c) => {
+                    match_set(&rest[..end], c) &&
+                        match_chars(&rest[end + 1..], &name[1..])
+                }
+                None => false,
+            }
+        }
+        Some((&p, rest)) => {
+            name.first() == Some(&p) && match_chars(rest, &name[1..])
+        }
+    }
+}
+
+/// Matches a character against the inside of a `[...]` set.
+fn match_set(set: &[char], c: char) -> bool {
+    let (negate, set) = match set.split_first() {
+        Some(('!', rest)) | Some(('^', rest)) => (true, rest),
+        _ => (false, set),
+    };
+    let mut found = false;
+    let mut i = 0;
+    while i < set.len() {
+        if i + 2 < set.len() && set[i + 1] == '-' {
+            if set[i] <= c && c <= set[i + 2] {
+                found = true;
+            }
+            i += 3;
+        } else {
+            if set[i] == c {
+                found = true;
+            }
+            i += 1;
+        }
+    }
+    found != negate
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IgnoreRules, match_glob};
+
+    #[test]
+    fn test_glob() {
+        assert!(match_glob("*.tmp", "a.tmp"));
+        assert!(match_glob("*.tmp", ".tmp"));
+        assert!(!match_glob("*.tmp", "a.tmp.gz"));
+        assert!(match_glob("f?o", "foo"));
+        assert!(!match_glob("f?o", "fo"));
+        assert!(match_glob("[a-c]x", "bx"));
+        assert!(!match_glob("[!a-c]x", "bx"));
+        assert!(match_glob("[!a-c]x", "dx"));
+        assert!(match_glob("a[", "a["));
+    }
+
+    #[test]
+    fn test_rules() {
+        let mut rules = IgnoreRules::new();
+        rules.add_lines("# Dependencies\n\
+                         node_modules/\n\
+                         *.log\n\
+                         !keep.log\n\
+                         /build\n\
+                         docs/**/*.pdf\n").unwrap();
+        assert!(rules.is_ignored("node_modules", true));
+        assert!(rules.is_ignored("web/node_modules", true));
+        assert!(!rules.is_ignored("node_modules", false));
+        assert!(rules.is_ignored("a/b/error.log", false));
+        assert!(!rules.is_ignored("a/keep.log", false));
+        assert!(rules.is_ignored("build", true));
+        assert!(!rules.is_ignored("src/build", true));
+        assert!(rules.is_ignored("docs/manual.pdf", false));
+        assert!(rules.is_ignored("docs/a/b/manual.pdf", false));
+        assert!(!rules.is_ignored("other/manual.pdf", false));
+        assert!(!rules.is_ignored("src/main.rs", false));
+
+        assert!(rules.add("/").is_err());
+        assert!(rules.add("a//b").is_err());
+    }
+}
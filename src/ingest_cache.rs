@@ -0,0 +1,174 @@
+//! Cache of the files already added, to skip them when adding them again.
+//!
+//! Adding a directory reads and hashes all of its files, even if most of them
+//! haven't changed since the last time. The cache remembers the size and
+//! modification time of each file added, with the ID of its file object; a
+//! file that still has the same size and modification time is assumed to be
+//! unchanged, and its file object is reused without reading it.
+//!
+//! The cache is a text file in the store's directory, with a line per file:
+//! the ID of the file object, the size, the modification time in
+//! microseconds since the Unix epoch, and the absolute path, separated by
+//! spaces. Paths that are not valid UTF-8 or contain a newline are not
+//! cached.
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::{Path, PathBuf};
+
+use log::warn;
+
+use crate::common::ObjectId;
+use crate::errors;
+
+/// What is known of a file when it was added.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct CachedFile {
+    size: u64,
+    mtime: i64,
+    id: ObjectId,
+}
+
+/// Maps the paths of added files to their file objects.
+///
+/// Opened with `dhstore::ingest_cache()`, and passed to the store with
+/// `Store::set_ingest_cache()`.
+pub struct IngestCache {
+    path: PathBuf,
+    entries: HashMap<String, CachedFile>,
+    changed: bool,
+}
+
+impl IngestCache {
+    /// Reads the cache from a file, which doesn't have to exist yet.
+    ///
+    /// Invalid lines are skipped with a warning, the cache only being a
+    /// shortcut.
+    pub fn open<P: AsRef<Path>>(path: P) -> errors::Result<IngestCache> {
+        let path = path.as_ref();
+        let mut text = String::new();
+        match File::open(path) {
+            Ok(mut fp) => {
+                fp.read_to_string(&mut text)
+                    .map_err(|e| ("Error reading ingest cache", e))?;
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(("Can't open ingest cache", e).into()),
+        }
+        Ok(IngestCache {
+            path: path.to_path_buf(),
+            entries: parse_entries(&text),
+            changed: false,
+        })
+    }
+
+    /// Gets the file object of a file, if it was added with this size and
+    /// modification time.
+    pub fn lookup(&self, path: &Path, size: u64, mtime: i64)
+        -> Option<&ObjectId>
+    {
+        let entry = self.entries.get(path.to_str()?)?;
+        if entry.size == size && entry.mtime == mtime {
+            Some(&entry.id)
+        } else {
+            None
+        }
+    }
+
+    /// Records the file object added for a file.
+    pub fn insert(&mut self, path: &Path, size: u64, mtime: i64,
+                  id: ObjectId)
+    {
+        let path = match path.to_str() {
+            Some(p) if !p.contains('\n') => p,
+            _ => return,
+        };
+        let entry = CachedFile { size, mtime, id };
+        if self.entries.get(path) != Some(&entry) {
+            self.entries.insert(path.to_owned(), entry);
+            self.changed = true;
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Writes the cache back to its file, if it changed.
+    ///
+    /// The file is replaced at once, so an interrupted write leaves the
+    /// previous version.
+    pub fn save(&mut self) -> errors::Result<()> {
+        if !self.changed {
+            return Ok(());
+        }
+        let temp = self.path.with_extension("tmp");
+        let mut fp = File::create(&temp)
+            .map_err(|e| ("Can't write ingest cache", e))?;
+        fp.write_all(format_entries(&self.entries).as_bytes())
+            .map_err(|e| ("Error writing ingest cache", e))?;
+        drop(fp);
+        fs::rename(&temp, &self.path)
+            .map_err(|e| ("Couldn't move ingest cache in place", e))?;
+        self.changed = false;
+        Ok(())
+    }
+}
+
+fn parse_entries(text: &str) -> HashMap<String, CachedFile> {
+    let mut entries = HashMap::new();
+    for line in text.lines() {
+        let mut fields = line.splitn(4, ' ');
+        let id = fields.next().and_then(|s| ObjectId::parse(s.as_bytes()));
+        let size = fields.next().and_then(|s| s.parse().ok());
+        let mtime = fields.next().and_then(|s| s.parse().ok());
+        match (id, size, mtime, fields.next()) {
+            (Some(id), Some(size), Some(mtime), Some(path)) => {
+                entries.insert(path.to_owned(),
+                               CachedFile { size, mtime, id });
+            }
+            _ => warn!("Invalid line in ingest cache: {:?}", line),
+        }
+    }
+    entries
+}
+
+fn format_entries(entries: &HashMap<String, CachedFile>) -> String {
+    let mut paths: Vec<&String> = entries.keys().collect();
+    paths.sort();
+    let mut text = String::new();
+    for path in paths {
+        let entry = &entries[path];
+        text.push_str(&format!("{} {} {} {}\n",
+                               entry.id.id().str(), entry.size, entry.mtime,
+                               path));
+    }
+    text
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{ID, ObjectId};
+    use super::{CachedFile, format_entries, parse_entries};
+
+    #[test]
+    fn test_format() {
+        let id = ObjectId::from_id(ID::from_bytes(&[3u8; 32]).unwrap());
+        let text = format!("{} 12 1500000000000000 /data/a file.txt\n\
+                            garbage\n\
+                            {} 0 -5 /data/empty\n",
+                           id, id);
+        let entries = parse_entries(&text);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries["/data/a file.txt"],
+                   CachedFile { size: 12, mtime: 1500000000000000,
+                                id: id.clone() });
+        assert_eq!(entries["/data/empty"].mtime, -5);
+        assert_eq!(parse_entries(&format_entries(&entries)), entries);
+    }
+}
@@ -0,0 +1,114 @@
+//! JSON encoding of objects, for programs outside of DHStore.
+//!
+//! Dicts become JSON objects and lists arrays. Strings and integers map
+//! directly; references, blobs and inline data become objects with a single
+//! "ref", "blob" or "inline" key, as in the storage format.
+
+use std::fmt::Write;
+
+use crate::common::{ObjectData, Property};
+use crate::hash;
+
+/// Writes a string as a JSON string literal.
+pub fn write_str(out: &mut String, string: &str) {
+    out.push('"');
+    for c in string.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => {
+                write!(out, "\\u{:04x}", c as u32).unwrap();
+            }
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+}
+
+fn write_tagged(out: &mut String, tag: &str, value: &str) {
+    out.push('{');
+    write_str(out, tag);
+    out.push(':');
+    write_str(out, value);
+    out.push('}');
+}
+
+/// Writes a property as a JSON value.
+pub fn write_property(out: &mut String, prop: &Property) {
+    match *prop {
+        Property::String(ref s) => write_str(out, s),
+        Property::Integer(i) => write!(out, "{}", i).unwrap(),
+        Property::Reference(ref id) => {
+            write_tagged(out, "ref", &id.id().str())
+        }
+        Property::Blob(ref id) => write_tagged(out, "blob", &id.id().str()),
+        Property::Inline(ref data) => {
+            write_tagged(out, "inline", &hash::base64_encode(data))
+        }
+    }
+}
+
+/// Writes the contents of an object as a JSON object or array.
+pub fn write_data(out: &mut String, data: &ObjectData) {
+    match *data {
+        ObjectData::Dict(ref d) => {
+            out.push('{');
+            for (i, (key, value)) in d.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_str(out, key);
+                out.push(':');
+                write_property(out, value);
+            }
+            out.push('}');
+        }
+        ObjectData::List(ref l) => {
+            out.push('[');
+            for (i, value) in l.iter().enumerate() {
+                if i > 0 {
+                    out.push(',');
+                }
+                write_property(out, value);
+            }
+            out.push(']');
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{BlobId, Dict, ID, ObjectData, ObjectId, Property};
+    use super::write_data;
+
+    #[test]
+    fn test_json() {
+        let id = ID::from_bytes(&[0u8; 32]).unwrap();
+        let mut dict = Dict::new();
+        dict.insert("name".into(),
+                    Property::String("a \"quoted\"\n\u{1}".into()));
+        dict.insert("size".into(), Property::Integer(-12));
+        dict.insert("list".into(),
+                    Property::Reference(ObjectId::from_id(id.clone())));
+        let mut out = String::new();
+        write_data(&mut out, &ObjectData::Dict(dict));
+        assert_eq!(
+            out,
+            format!("{{\"list\":{{\"ref\":\"{}\"}},\
+                     \"name\":\"a \\\"quoted\\\"\\n\\u0001\",\
+                     \"size\":-12}}",
+                    id.str()));
+
+        let blob = Property::Blob(BlobId::from_id(id.clone()));
+        let list = ObjectData::List(vec![blob,
+                                         Property::Inline(vec![1, 2, 3])]);
+        let mut out = String::new();
+        write_data(&mut out, &list);
+        assert_eq!(out, format!("[{{\"blob\":\"{}\"}},\
+                                 {{\"inline\":\"AQID\"}}]",
+                                id.str()));
+    }
+}
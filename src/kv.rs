@@ -0,0 +1,229 @@
+//! A key-value store, kept in a permanode of the store.
+//!
+//! This gives applications embedding dhstore a place for their settings and
+//! state, next to the data they describe. Each change is a claim on the
+//! permanode, dated with the current time, whose value is an entry object:
+//!
+//! ```text
+//! {"key": "theme", "value": "dark"}
+//! ```
+//!
+//! An entry without a `value` records that the key was deleted. The current
+//! value of a key is that of its latest entry, and the earlier ones are its
+//! history. Values can reference other objects, which are then kept alive
+//! as long as the value is current or in the history.
+
+use std::collections::BTreeMap;
+
+use log::info;
+
+use crate::common::{BlobStorage, Dict, ObjectData, ObjectId, ObjectIndex,
+                    Property, Sort};
+use crate::errors::{self, Error};
+use crate::{Store, claim, permanode, timestamp_now};
+
+/// A change to the value of a key, see `KeyValue::history()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Change {
+    /// When the change was made, in microseconds since the Unix epoch.
+    pub date: i64,
+    /// The new value, or `None` if the key was deleted.
+    pub value: Option<Property>,
+    /// The claim recording the change.
+    pub claim: ObjectId,
+}
+
+/// Key-value store over a permanode, with the current values in memory.
+pub struct KeyValue<'a, S: BlobStorage, I: ObjectIndex> {
+    store: &'a mut Store<S, I>,
+    node: ObjectId,
+    values: BTreeMap<String, Property>,
+    /// Date of the last claim, to keep them in order
+    last_date: i64,
+}
+
+impl<'a, S: BlobStorage, I: ObjectIndex> KeyValue<'a, S, I> {
+    /// Creates a new, empty key-value store.
+    ///
+    /// Its permanode is recorded in the store's log under `name`, which
+    /// keeps it from being garbage-collected and lets `find()` get it back.
+    pub fn create(store: &'a mut Store<S, I>, name: &str)
+        -> errors::Result<KeyValue<'a, S, I>>
+    {
+        let mut data = Dict::new();
+        data.insert("type".into(), Property::String("set".into()));
+        let node = permanode(data, Sort::Ascending("date".into()));
+        let node = store.index.add(node.data)?;
+        let mut details = Dict::new();
+        details.insert("name".into(), Property::String(name.into()));
+        details.insert("node".into(), Property::Reference(node.clone()));
+        store.record_event("kv", details)?;
+        info!("Created key-value store {:?}, permanode = {}", name, node);
+        Ok(KeyValue {
+            store,
+            node,
+            values: BTreeMap::new(),
+            last_date: 0,
+        })
+    }
+
+    /// Finds the permanode of the key-value store created with this name.
+    ///
+    /// If several were, this is the latest one.
+    pub fn find(store: &Store<S, I>, name: &str)
+        -> errors::Result<Option<ObjectId>>
+    {
+        store.find_named_node("kv", name)
+    }
+
+    /// Opens the key-value store kept in this permanode.
+    pub fn open(store: &'a mut Store<S, I>, node: &ObjectId)
+        -> errors::Result<KeyValue<'a, S, I>>
+    {
+        let mut kv = KeyValue {
+            store,
+            node: node.clone(),
+            values: BTreeMap::new(),
+            last_date: 0,
+        };
+        for (key, change) in kv.changes()? {
+            kv.last_date = kv.last_date.max(change.date);
+            match change.value {
+                Some(value) => kv.values.insert(key, value),
+                None => kv.values.remove(&key),
+            };
+        }
+        Ok(kv)
+    }
+
+    /// The permanode this is kept in.
+    pub fn node(&self) -> &ObjectId {
+        &self.node
+    }
+
+    /// Gets the current value of a key.
+    pub fn get(&self, key: &str) -> Option<&Property> {
+        self.values.get(key)
+    }
+
+    /// Sets the value of a key.
+    pub fn set(&mut self, key: &str, value: Property)
+        -> errors::Result<()>
+    {
+        if self.values.get(key) == Some(&value) {
+            return Ok(());
+        }
+        self.record(key, Some(value.clone()))?;
+        self.values.insert(key.into(), value);
+        Ok(())
+    }
+
+    /// Deletes a key, returning whether it was set.
+    pub fn delete(&mut self, key: &str) -> errors::Result<bool> {
+        if !self.values.contains_key(key) {
+            return Ok(false);
+        }
+        self.record(key, None)?;
+        self.values.remove(key);
+        Ok(true)
+    }
+
+    /// Lists the keys and their current values, ordered by key.
+    pub fn list(&self) -> impl Iterator<Item = (&str, &Property)> {
+        self.values.iter().map(|(k, v)| (&k[..], v))
+    }
+
+    /// Lists the changes to the value of a key, oldest first.
+    pub fn history(&self, key: &str) -> errors::Result<Vec<Change>> {
+        Ok(self.changes()?
+            .into_iter()
+            .filter(|(k, _)| k == key)
+            .map(|(_, change)| change)
+            .collect())
+    }
+
+    /// Reads all the entries of the permanode with their keys, oldest
+    /// first.
+    fn changes(&self) -> errors::Result<Vec<(String, Change)>> {
+        let mut changes = Vec::new();
+        let mut after = None;
+        loop {
+            let page = self.store.index
+                .list_permanode(&self.node, after.as_ref(), 100)?
+                .ok_or(Error::InvalidInput(
+                    "Key-value store is not a permanode"))?;
+            for member in page.members {
+                let date = match member.key {
+                    Property::Integer(date) => date,
+                    _ => continue,
+                };
+                let entry = self.store.index.get_object(&member.value)?
+                    .ok_or_else(|| {
+                        Error::MissingObject(member.value.clone())
+                    })?;
+                if let Some((key, value)) = parse_entry(&entry.data) {
+                    let claim = member.claim;
+                    changes.push((key, Change { date, value, claim }));
+                }
+            }
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        Ok(changes)
+    }
+
+    /// Adds the entry for a change, and claims it for the permanode.
+    fn record(&mut self, key: &str, value: Option<Property>)
+        -> errors::Result<()>
+    {
+        let entry = self.store.index.add(make_entry(key, value))?;
+        // Claims are keyed by date, so two changes can't have the same one
+        self.last_date = timestamp_now().max(self.last_date + 1);
+        let mut data = Dict::new();
+        data.insert("date".into(), Property::Integer(self.last_date));
+        self.store.index.add(claim(&self.node, &entry, data))?;
+        Ok(())
+    }
+}
+
+/// Makes the entry object recording a change.
+fn make_entry(key: &str, value: Option<Property>) -> ObjectData {
+    let mut data = Dict::new();
+    data.insert("key".into(), Property::String(key.into()));
+    if let Some(value) = value {
+        data.insert("value".into(), value);
+    }
+    ObjectData::Dict(data)
+}
+
+/// Reads the key and new value from an entry object.
+fn parse_entry(data: &ObjectData) -> Option<(String, Option<Property>)> {
+    let dict = match *data {
+        ObjectData::Dict(ref dict) => dict,
+        _ => return None,
+    };
+    let key = match dict.get("key") {
+        Some(Property::String(key)) => key.clone(),
+        _ => return None,
+    };
+    Some((key, dict.get("value").cloned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{ObjectData, Property};
+    use super::{make_entry, parse_entry};
+
+    #[test]
+    fn test_entry() {
+        let entry = make_entry("theme", Some(Property::String("dark".into())));
+        assert_eq!(parse_entry(&entry),
+                   Some(("theme".into(),
+                         Some(Property::String("dark".into())))));
+        assert_eq!(parse_entry(&make_entry("theme", None)),
+                   Some(("theme".into(), None)));
+        assert_eq!(parse_entry(&ObjectData::List(vec![])), None);
+    }
+}
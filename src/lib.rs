@@ -1,113 +1,2264 @@
 //! DHStore: A personal content management system.
 
+mod blob_index;
+mod builder;
+mod cached_storage;
+mod chunk_reader;
+mod chunking;
 mod common;
+mod disk_index;
+mod encrypted_storage;
 pub mod errors;
+mod file_reader;
 mod file_storage;
+#[doc(hidden)]
+pub mod fuzz;
 pub mod hash;
+pub mod idset;
+mod ignore;
+mod ingest_cache;
+pub mod json;
+pub mod kv;
 pub mod logger;
 mod memory_index;
+mod mirrored_storage;
+mod policy_hook;
+pub mod queries;
+pub mod registry;
+mod restic;
+mod s3_storage;
 mod serialize;
+mod signing;
+mod span;
+mod staging;
+pub mod testvectors;
+mod walk;
 
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet, btree_map};
 use std::fs::{self, File, OpenOptions};
 use std::io::{self, Read, Write};
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, MutexGuard, mpsc};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
-use cdchunking::{Chunker, ZPAQ, ChunkInput};
-use log::info;
+use log::{debug, info, warn};
 use rand::Rng;
 
-use common::{HASH_SIZE, Sort};
-pub use common::{ID, Dict, List, Property, ObjectData, Object,
-                 BlobStorage, EnumerableBlobStorage, ObjectIndex};
+use common::{HASH_SIZE, HASH_STR_SIZE, Sort};
+use hash::IdEncoding;
+use queries::{Cancel, Query, QueryResults};
+use span::Span;
+pub use common::{ID, BlobId, ObjectId, Backkey, Dict, List, Property,
+                 ObjectData, Object, Member, MemberPage, BlobStorage,
+                 BlobIterator, CacheStats, EnumerableBlobStorage, ObjectIndex,
+                 ObjectIterator, Quarantined, SelfTest};
 pub use errors::Error;
-pub use memory_index::MemoryIndex;
+pub use blob_index::{BlobIndex, BlobUse};
+pub use builder::{StoreBuilder, StorageWrapper};
+pub use disk_index::DiskIndex;
+pub use memory_index::{Budget, MemoryIndex, Policy, PolicyDecision};
+pub use policy_hook::PolicyHook;
+pub use cached_storage::CachedBlobStorage;
+pub use mirrored_storage::MirroredBlobStorage;
+pub use s3_storage::{S3BlobStorage, S3Config};
+pub use chunking::{Chunking, ChunkingConfig};
+pub use encrypted_storage::{EncryptedBlobStorage, EncryptionKey,
+                            PBKDF2_ITERATIONS};
+pub use file_reader::FileReader;
 pub use file_storage::FileBlobStorage;
+pub use ignore::IgnoreRules;
+pub use ingest_cache::IngestCache;
+pub use signing::{PublicKey, SignatureCheck, SigningKey};
+pub use staging::Staging;
+pub use walk::{Walk, WalkItem, WalkOrder};
+pub use registry::Registry;
+pub use restic::ResticServer;
+
+/// How much of a tree can be read from local storage.
+///
+/// Returned by `Store::availability()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Availability {
+    /// Number of reachable blobs that are stored locally
+    pub present: usize,
+    /// Number of reachable blobs
+    pub total: usize,
+}
+
+impl Availability {
+    /// Fraction of the blobs that are stored locally, between 0 and 1.
+    ///
+    /// A tree without blobs is fully available.
+    pub fn fraction(&self) -> f64 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.present as f64 / self.total as f64
+        }
+    }
+}
+
+/// What `Store::copy_object()` had to copy.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Copied {
+    /// Number of objects that were missing from the destination
+    pub objects: usize,
+    /// Number of blobs that were missing from the destination
+    pub blobs: usize,
+}
+
+/// Number of objects listed in `Stats::largest`.
+const LARGEST_OBJECTS: usize = 10;
+
+/// Summary of what a store holds, returned by `Store::stats()`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Stats {
+    /// Number of objects in the index, live or not
+    pub objects: usize,
+    /// Number of objects of each type: the `dhstore_kind` of dicts that have
+    /// one, then "file", "directory", "dict" or "list"
+    pub object_types: BTreeMap<String, usize>,
+    /// Total size of the objects, serialized
+    pub object_bytes: u64,
+    /// Number of blobs in the storage
+    pub blobs: usize,
+    /// Total size of the blobs, as stored
+    pub blob_bytes: u64,
+    /// Total size of the file objects; identical files are the same object,
+    /// so they only count once
+    pub file_bytes: u64,
+    /// The largest objects, serialized size first, largest first
+    pub largest: Vec<(u64, ObjectId)>,
+}
+
+impl Stats {
+    /// How many bytes of files there are for each byte of blobs, which is
+    /// more than 1 when files share chunks.
+    ///
+    /// A store without blobs has a ratio of 1.
+    pub fn dedup_ratio(&self) -> f64 {
+        if self.blob_bytes == 0 {
+            1.0
+        } else {
+            self.file_bytes as f64 / self.blob_bytes as f64
+        }
+    }
+}
+
+/// The type of an object, for `Stats::object_types`.
+fn object_type(object: &Object) -> &str {
+    match object.data {
+        ObjectData::Dict(ref d) => match d.get("dhstore_kind") {
+            Some(Property::String(kind)) => kind,
+            _ if file_contents(d).is_some() => "file",
+            _ if symlink_target(d).is_some() => "symlink",
+            _ if dir_entries(d).is_some() => "directory",
+            _ => "dict",
+        },
+        ObjectData::List(_) => "list",
+    }
+}
+
+/// What `Store::walk_tree()` does with an object taken from its stack.
+enum Step {
+    /// Look at the object, and push the objects it references
+    Enter,
+    /// Visit the object, once everything it references was
+    Leave,
+    /// Visit a claim of the permanode that was just left
+    Claim,
+}
+
+/// What to do with existing files when extracting, see
+/// `Store::extract_with()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Existing {
+    /// Fail on the first path that exists
+    Fail,
+    /// Replace existing files; directories are never replaced, only
+    /// extracted into
+    Overwrite,
+    /// Leave existing files alone, extracting into existing directories
+    Keep,
+}
+
+/// What `Store::extract_with()` wrote.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Extracted {
+    /// Number of files written
+    pub files: usize,
+    /// Number of directories created or extracted into
+    pub directories: usize,
+    /// Total size of the files written
+    pub bytes: u64,
+    /// Number of symbolic links created
+    pub symlinks: usize,
+    /// Number of existing files and directories left alone
+    pub skipped: usize,
+}
+
+/// IDs present in one or both of two stores, see `Comparison`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Difference {
+    /// IDs only in the first store, sorted
+    pub only_first: Vec<ID>,
+    /// IDs only in the second store, sorted
+    pub only_second: Vec<ID>,
+    /// Number of IDs in both stores
+    pub both: usize,
+}
+
+impl Difference {
+    fn new(first: HashSet<ID>, mut second: HashSet<ID>) -> Difference {
+        let mut only_first = Vec::new();
+        let mut both = 0;
+        for id in first {
+            if second.remove(&id) {
+                both += 1;
+            } else {
+                only_first.push(id);
+            }
+        }
+        let mut only_second: Vec<ID> = second.into_iter().collect();
+        only_first.sort();
+        only_second.sort();
+        Difference { only_first, only_second, both }
+    }
+
+    /// Whether both stores have the same IDs.
+    pub fn is_empty(&self) -> bool {
+        self.only_first.is_empty() && self.only_second.is_empty()
+    }
+}
+
+/// How the contents of two stores differ.
+///
+/// Returned by `Store::compare()`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Comparison {
+    pub objects: Difference,
+    pub blobs: Difference,
+}
+
+/// Where a chunk of a file is, see `Store::add_file_with_manifest()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChunkInfo {
+    /// Position of the chunk in the file
+    pub offset: u64,
+    pub length: u64,
+    /// Blob holding the chunk, `None` if it's stored inline in the list
+    pub blob: Option<BlobId>,
+}
+
+/// The kind of an entry in a directory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+}
+
+impl EntryKind {
+    /// The name used in directory objects.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            EntryKind::File => "file",
+            EntryKind::Dir => "dir",
+            EntryKind::Symlink => "symlink",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<EntryKind> {
+        match name {
+            "file" => Some(EntryKind::File),
+            "dir" => Some(EntryKind::Dir),
+            "symlink" => Some(EntryKind::Symlink),
+            _ => None,
+        }
+    }
+}
+
+/// An entry in a directory, see `dir_entries()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub id: ObjectId,
+    /// Kind of the entry, unknown for directories added by older versions
+    pub kind: Option<EntryKind>,
+    /// Size of the file, if it was recorded
+    pub size: Option<u64>,
+    /// Modification time in microseconds since the Unix epoch, if recorded
+    pub mtime: Option<i64>,
+    /// Permission bits, if recorded
+    pub mode: Option<u32>,
+}
+
+/// How an entry differs between two directories.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    /// Only in the second directory
+    Added,
+    /// Only in the first directory
+    Removed,
+    /// In both, with different contents
+    Modified,
+}
+
+impl ChangeKind {
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            ChangeKind::Added => "added",
+            ChangeKind::Removed => "removed",
+            ChangeKind::Modified => "modified",
+        }
+    }
+}
+
+/// An entry that differs between two directories, see `Store::diff()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeChange {
+    /// Path of the entry from the directories, separated by `/`
+    pub path: String,
+    pub change: ChangeKind,
+    /// Kind of the entry, in the second directory if it is there
+    pub kind: Option<EntryKind>,
+    /// The entry in the first directory, if it is there
+    pub old: Option<ObjectId>,
+    /// The entry in the second directory, if it is there
+    pub new: Option<ObjectId>,
+}
+
+/// A version of a file in the values of a permanode, see
+/// `Store::file_history()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FileVersion {
+    /// The sort key of the value, usually its date
+    pub key: Property,
+    /// The value of the permanode, a directory
+    pub tree: ObjectId,
+    /// The file object, or `None` if the path doesn't exist in that tree
+    pub file: Option<ObjectId>,
+    /// Size of the file, if it is one
+    pub size: Option<u64>,
+}
+
+/// A snapshot taken with `Store::snapshot()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    /// When it was taken, in microseconds since the Unix epoch
+    pub date: i64,
+    /// The file or directory
+    pub tree: ObjectId,
+    /// The claim associating it with the permanode of its name
+    pub claim: ObjectId,
+}
+
+/// What adding a file or directory would store, see `Store::estimate()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Estimate {
+    /// Number of files that would be added
+    pub files: usize,
+    /// Total size of the files
+    pub bytes: u64,
+    /// Number of files the ingest cache knows to be unchanged
+    pub unchanged_files: usize,
+    /// Total size of the unchanged files, which won't be read again
+    pub unchanged_bytes: u64,
+}
+
+impl Estimate {
+    /// Size of the files that would be read and stored.
+    ///
+    /// This is an upper bound: chunks that are already in the store are
+    /// not stored again.
+    pub fn new_bytes(&self) -> u64 {
+        self.bytes - self.unchanged_bytes
+    }
+}
+
+/// Space used by an entry of a directory, see `Store::disk_usage()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiskUsage {
+    pub name: String,
+    pub id: ObjectId,
+    /// Size of the blobs only this entry uses, which dropping it would free
+    pub unique: u64,
+    /// Size of the blobs this entry shares with other objects
+    pub shared: u64,
+}
+
+/// Limits for `Store::write_object()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PrintOptions {
+    /// Maximum depth of nested objects to expand, 0 being the first object
+    pub max_depth: Option<usize>,
+    /// Maximum number of objects to expand, over the whole output
+    pub max_objects: Option<usize>,
+    /// Expand an object every time it is referenced, instead of only the
+    /// first time
+    pub repeat: bool,
+    /// Encoding of the IDs, in `Format::Pretty`
+    pub ids: IdEncoding,
+}
+
+/// Output formats for `Store::render_value()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Format {
+    /// Indented text, see `Store::write_value()`
+    #[default]
+    Pretty,
+    /// JSON, with expanded references as `{"ref": ID, "object": ...}`; see
+    /// the `json` module for the other values
+    Json,
+    /// The serialized object in hexadecimal, as it is stored; references are
+    /// not expanded
+    BencodeHex,
+}
+
+impl Format {
+    /// The name used on the command line.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            Format::Pretty => "pretty",
+            Format::Json => "json",
+            Format::BencodeHex => "bencode-hex",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<Format> {
+        match name {
+            "pretty" => Some(Format::Pretty),
+            "json" => Some(Format::Json),
+            "bencode-hex" => Some(Format::BencodeHex),
+            _ => None,
+        }
+    }
+}
 
 /// Main structure, representing the whole system.
 pub struct Store<S: BlobStorage, I: ObjectIndex> {
     storage: S,
     index: I,
+    min_free_space: Option<u64>,
+    warned_free_space: bool,
+    chunking: ChunkingConfig,
+    threads: usize,
+    metadata: bool,
+    follow_symlinks: bool,
+    reserve: bool,
+    cancel: Cancel,
+    ignore: IgnoreRules,
+    ingest_cache: Option<IngestCache>,
+}
+
+fn indent<W: Write + ?Sized>(out: &mut W, level: usize) -> errors::Result<()> {
+    for _ in 0..level {
+        out.write_all(b"  ").map_err(output_error)?;
+    }
+    Ok(())
+}
+
+fn output_error(e: io::Error) -> Error {
+    Error::IoError("Error writing output", e)
 }
 
-fn indent(level: usize) {
-    for _ in 0..level {
-        print!("  ");
+impl<S: BlobStorage, I: ObjectIndex> Store<S, I> {
+    /// Creates a store from a given blob storage and object index.
+    pub fn new(storage: S, index: I) -> Store<S, I> {
+        Store {
+            storage: storage,
+            index: index,
+            min_free_space: None,
+            warned_free_space: false,
+            chunking: ChunkingConfig::default(),
+            threads: thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+            metadata: true,
+            follow_symlinks: false,
+            reserve: false,
+            cancel: Cancel::new(),
+            ignore: IgnoreRules::new(),
+            ingest_cache: None,
+        }
+    }
+
+    /// Refuses to add blobs when the free space of the blob storage is below
+    /// this many bytes.
+    ///
+    /// A warning is logged once free space gets below twice that.
+    pub fn set_min_free_space(&mut self, bytes: Option<u64>) {
+        self.min_free_space = bytes;
+        self.warned_free_space = false;
+    }
+
+    /// Sets the chunking strategies used by `Store::add()`.
+    pub fn set_chunking(&mut self, chunking: ChunkingConfig) {
+        self.chunking = chunking;
+    }
+
+    /// Sets the number of threads hashing chunks when adding files.
+    ///
+    /// Defaults to the number of CPUs. Objects are created in the same
+    /// order either way, this only changes how fast chunks are hashed.
+    pub fn set_threads(&mut self, threads: usize) {
+        self.threads = threads.max(1);
+    }
+
+    /// Sets whether `Store::add()` records the modification time and
+    /// permissions of the entries of directories.
+    ///
+    /// This is on by default. Without it, adding the same files always gives
+    /// the same IDs, wherever and whenever they were written.
+    pub fn set_metadata(&mut self, metadata: bool) {
+        self.metadata = metadata;
+    }
+
+    /// Sets whether `Store::add()` adds what symbolic links point to,
+    /// instead of the links themselves.
+    pub fn set_follow_symlinks(&mut self, follow: bool) {
+        self.follow_symlinks = follow;
+    }
+
+    /// Sets the patterns of files and directories `Store::add()` leaves out.
+    ///
+    /// They are matched against the paths from the added directory, see
+    /// `IgnoreRules`.
+    pub fn set_ignore(&mut self, ignore: IgnoreRules) {
+        self.ignore = ignore;
+    }
+
+    /// Sets a cache of the files already added, that `Store::add()` uses to
+    /// skip the files whose size and modification time didn't change.
+    ///
+    /// The files it adds are recorded in it; get it back with
+    /// `take_ingest_cache()` to save it.
+    pub fn set_ingest_cache(&mut self, cache: IngestCache) {
+        self.ingest_cache = Some(cache);
+    }
+
+    /// Removes the ingest cache set with `set_ingest_cache()`.
+    pub fn take_ingest_cache(&mut self) -> Option<IngestCache> {
+        self.ingest_cache.take()
+    }
+
+    /// Sets a handle to stop long operations from another thread, or a
+    /// signal handler.
+    ///
+    /// `add()` and `merge()` check it between objects, `verify()` and
+    /// `collect_garbage()` between blobs. They fail with `Error::Cancelled`,
+    /// leaving the store consistent: what was written so far is garbage that
+    /// the next collection deletes, or is picked up by running the operation
+    /// again.
+    pub fn set_cancel(&mut self, cancel: Cancel) {
+        self.cancel = cancel;
+    }
+
+    /// Turns this into a handle that can be shared between threads.
+    ///
+    /// This needs the blob storage and the index to be `Send`. See
+    /// `SharedStore`.
+    pub fn into_shared(self) -> SharedStore<S, I>
+        where S: Send, I: Send
+    {
+        SharedStore { store: Arc::new(Mutex::new(self)) }
+    }
+
+    /// Fails with `Error::Cancelled` if the handle set with `set_cancel()`
+    /// was cancelled.
+    fn check_cancel(&self) -> errors::Result<()> {
+        self.cancel.check()
+    }
+
+    /// Sets whether `Store::add()` checks that there is enough free space
+    /// before starting, see `Store::reserve()`.
+    ///
+    /// This is off by default: it walks the files twice, and as the estimate
+    /// doesn't know which chunks are already stored, it can refuse an
+    /// addition that would mostly be deduplicated.
+    pub fn set_reserve(&mut self, reserve: bool) {
+        self.reserve = reserve;
+    }
+
+    /// Checks the free space before writing, see `set_min_free_space()`.
+    fn check_free_space(&mut self) -> errors::Result<()> {
+        let threshold = match self.min_free_space {
+            Some(t) => t,
+            None => return Ok(()),
+        };
+        let available = match self.storage.free_space()? {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        if available < threshold {
+            return Err(Error::LowDiskSpace(available, threshold));
+        }
+        if available < threshold.saturating_mul(2) && !self.warned_free_space
+        {
+            warn!("Low disk space: {} bytes available, writes will be \
+                   refused below {}", available, threshold);
+            self.warned_free_space = true;
+        }
+        Ok(())
+    }
+
+    /// Low-level; adds a blob to the blob storage.
+    ///
+    /// To cut a blob into chunks, add them to the blob storage, and return a
+    /// list object of them, use `Store::add_file()`.
+    pub fn add_blob<R: Read>(&mut self, mut reader: R)
+        -> errors::Result<BlobId>
+    {
+        self.check_free_space()?;
+        self.storage.add_blob_from_reader(&mut reader)
+    }
+
+    /// Low-level; gets a single blob from the blob storage.
+    pub fn get_blob(&self, id: &BlobId) -> errors::Result<Option<Box<[u8]>>> {
+        self.storage.get_blob(id)
+    }
+
+    /// Low-level; gets the size of a blob without reading it, if it exists.
+    pub fn blob_size(&self, id: &BlobId) -> errors::Result<Option<u64>> {
+        self.storage.size(id)
+    }
+
+    /// Low-level; gets a reader on a single blob from the blob storage.
+    pub fn get_blob_reader(&self, id: &BlobId)
+        -> errors::Result<Option<Box<dyn Read + '_>>>
+    {
+        self.storage.get_blob_reader(id)
+    }
+
+    /// Lists the objects that couldn't be read when opening the store.
+    ///
+    /// This is only non-empty for a store opened in permissive mode, see
+    /// `StoreBuilder::permissive()`.
+    pub fn quarantined(&self) -> &[Quarantined] {
+        self.index.quarantined()
+    }
+
+    /// Statistics of the blob cache, if the store has one.
+    pub fn cache_stats(&self) -> Option<CacheStats> {
+        self.storage.cache_stats()
+    }
+
+    /// Checks that the blob storage works, by storing and removing a blob.
+    pub fn self_test(&mut self) -> errors::Result<SelfTest> {
+        self.storage.self_test()
+    }
+
+    /// Low-level; gets a single object from the index by its ID.
+    pub fn get_object(&self, id: &ObjectId)
+        -> errors::Result<Option<Cow<'_, Object>>>
+    {
+        self.index.get_object(id)
+    }
+
+    /// Low-level; iterates on the objects of a `dhstore_kind`, live or not.
+    pub fn list_objects_of_kind<'a>(&'a self, kind: &'a str)
+        -> errors::Result<ObjectIterator<'a>>
+    {
+        self.index.list_objects_of_kind(kind)
+    }
+
+    /// Finds the dict objects matching a query, live or not.
+    ///
+    /// The objects are looked at as the results are read; see
+    /// `QueryResults` to limit or cancel the search. Deleted permanodes and
+    /// claims are left out, see `query_with()`.
+    pub fn query<'a>(&'a self, query: &'a Query)
+        -> errors::Result<QueryResults<'a>>
+    {
+        self.query_with(query, false)
+    }
+
+    /// Finds the dict objects matching a query, including the deleted ones
+    /// if `include_deleted` is set.
+    pub fn query_with<'a>(&'a self, query: &'a Query, include_deleted: bool)
+        -> errors::Result<QueryResults<'a>>
+    {
+        let objects = self.index.list_objects()?;
+        if include_deleted {
+            return Ok(QueryResults::new(objects, query));
+        }
+        let objects = objects.filter(move |o| match o {
+            Ok(o) => !matches!(self.index.is_deleted(&o.id), Ok(true)),
+            Err(_) => true,
+        });
+        Ok(QueryResults::new(Box::new(objects), query))
+    }
+
+    /// Follows a slash-separated path of dict keys and list indices from an
+    /// object, e.g. "photos/2021/img.jpg".
+    ///
+    /// Every component but the last has to lead to another object. Returns
+    /// `None` if a key or index doesn't exist, or leads to a value that is
+    /// not a reference while there are components left. Empty components are
+    /// ignored, so an empty path gives a reference to the object itself.
+    pub fn resolve_path(&self, id: &ObjectId, path: &str)
+        -> errors::Result<Option<Property>>
+    {
+        let mut value = Property::Reference(id.clone());
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            let id = match value {
+                Property::Reference(id) => id,
+                _ => return Ok(None),
+            };
+            let object = self.index.get_object(&id)?
+                .ok_or(Error::MissingObject(id))?;
+            let next = match object.data {
+                ObjectData::Dict(ref dict) => dict.get(component),
+                ObjectData::List(ref list) => component.parse::<usize>().ok()
+                    .and_then(|i| list.get(i)),
+            };
+            value = match next {
+                Some(next) => next.clone(),
+                None => return Ok(None),
+            };
+        }
+        Ok(Some(value))
+    }
+
+    /// Lists the objects referencing an object or blob, and under which key
+    /// or index.
+    pub fn referrers(&self, id: &ID)
+        -> errors::Result<Vec<(Backkey, ObjectId)>>
+    {
+        self.index.referrers(id)
+    }
+
+    /// Low-level; iterates on all the objects in the index, live or not.
+    pub fn list_objects(&self) -> errors::Result<ObjectIterator<'_>> {
+        self.index.list_objects()
+    }
+
+    /// Indexes the objects by the blobs they use, to find affected files.
+    ///
+    /// This goes over all the objects, live or not, so it should be built
+    /// once to look up several blobs.
+    pub fn blob_index(&self) -> errors::Result<BlobIndex<'_>> {
+        BlobIndex::new(self.index.list_objects()?)
+    }
+
+    /// Gets the value of a key in the root config.
+    fn config_value(&self, key: &str) -> errors::Result<Option<Property>> {
+        let config = self.index.get_object(self.index.root())?
+            .ok_or(Error::CorruptedStore("Missing root object"))?;
+        match config.data {
+            ObjectData::Dict(ref dict) => Ok(dict.get(key).cloned()),
+            _ => Err(Error::CorruptedStore("Root object is not a dict")),
+        }
+    }
+
+    /// Gets the ID of the log permanode from the root config, if any.
+    fn log(&self) -> errors::Result<Option<ObjectId>> {
+        match self.config_value("log")? {
+            Some(Property::Reference(id)) => Ok(Some(id)),
+            Some(_) => Err(Error::CorruptedStore("Log is not a reference")),
+            None => Ok(None),
+        }
+    }
+
+    /// Records an administrative operation in the store's log.
+    ///
+    /// This adds an event object, with the `event` key set to the given kind
+    /// in addition to `details`, and a claim associating it with the log
+    /// permanode, dated with the current time in microseconds since the Unix
+    /// epoch. Returns the ID of the claim, or `None` if the store has no log.
+    pub fn record_event(&mut self, event: &str, mut details: Dict)
+        -> errors::Result<Option<ObjectId>>
+    {
+        let log = match self.log()? {
+            Some(log) => log,
+            None => {
+                warn!("Store has no log, not recording {:?} event", event);
+                return Ok(None);
+            }
+        };
+        details.insert("event".into(), Property::String(event.into()));
+        let event_id = self.index.add(ObjectData::Dict(details))?;
+        let mut data = Dict::new();
+        data.insert("date".into(), Property::Integer(timestamp_now()));
+        let claim_id = self.index.add(claim(&log, &event_id, data))?;
+        info!("Recorded {:?} event {}, claim = {}", event, event_id, claim_id);
+        Ok(Some(claim_id))
+    }
+
+    /// Lists the events recorded in the log, oldest first.
+    pub fn list_events(&self) -> errors::Result<Vec<Member>> {
+        self.list_events_with(false)
+    }
+
+    /// Lists the events recorded in the log, including the deleted ones if
+    /// `include_deleted` is set.
+    pub fn list_events_with(&self, include_deleted: bool)
+        -> errors::Result<Vec<Member>>
+    {
+        let mut events = Vec::new();
+        let log = match self.log()? {
+            Some(log) => log,
+            None => return Ok(events),
+        };
+        let mut after = None;
+        loop {
+            let page = self.index.list_permanode_with(&log, after.as_ref(),
+                                                      100, include_deleted)?
+                .ok_or(Error::CorruptedStore("Log is not a permanode"))?;
+            events.extend(page.members);
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        Ok(events)
+    }
+
+    /// Summarizes the current values of a permanode in a checkpoint.
+    ///
+    /// The index then gets those values from the checkpoint, and only reads
+    /// the claims coming after it in sort order. Claims coming before it,
+    /// including ones made later, are ignored, so this is meant for
+    /// permanodes sorted by date. The claims it covers are deleted by the
+    /// next garbage collection. Returns `None` if the permanode has no
+    /// values.
+    pub fn compact_claims(&mut self, id: &ObjectId)
+        -> errors::Result<Option<ObjectId>>
+    {
+        let mut members = Vec::new();
+        let mut after = None;
+        loop {
+            let page = self.index.list_permanode(id, after.as_ref(), 100)?
+                .ok_or(Error::InvalidInput("Object is not a permanode"))?;
+            members.extend(page.members);
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        let upto = match members.last() {
+            Some(last) => last.key.clone(),
+            None => return Ok(None),
+        };
+        let count = members.len();
+        let list = members.into_iter()
+            .flat_map(|m| vec![m.key, Property::Reference(m.value)])
+            .collect();
+        let list = self.index.add(ObjectData::List(list))?;
+        let checkpoint = self.index.add(checkpoint(id, upto, &list))?;
+        info!("Checkpoint of {} values: {}", count, checkpoint);
+        Ok(Some(checkpoint))
+    }
+
+    /// Lists the values of a permanode, ordered by its sort field.
+    ///
+    /// Returns at most `limit` values. To get the next page, pass the `next`
+    /// cursor of the returned page as `after`. Returns `None` if the ID is
+    /// not a known permanode.
+    pub fn list_permanode(&self, id: &ObjectId, after: Option<&Property>,
+                          limit: usize)
+        -> errors::Result<Option<MemberPage>>
+    {
+        self.index.list_permanode(id, after, limit)
+    }
+
+    /// Lists the values of a permanode, including the deleted ones if
+    /// `include_deleted` is set; those have `Member::deleted` set.
+    pub fn list_permanode_with(&self, id: &ObjectId, after: Option<&Property>,
+                               limit: usize, include_deleted: bool)
+        -> errors::Result<Option<MemberPage>>
+    {
+        self.index.list_permanode_with(id, after, limit, include_deleted)
+    }
+
+    /// Marks a permanode or a claim as deleted, by adding a tombstone.
+    ///
+    /// Nothing is removed: the values are hidden from `list_permanode()`,
+    /// queries and everything built on them, but can still be listed with
+    /// `list_permanode_with()`. Deleting a permanode hides all its values.
+    /// Garbage collection asks the policy whether to keep deleted claims,
+    /// see `Policy`. Values summarized by a checkpoint can't be deleted one
+    /// by one. Returns the ID of the tombstone.
+    pub fn delete(&mut self, id: &ObjectId) -> errors::Result<ObjectId> {
+        let object = self.index.get_object(id)?
+            .ok_or_else(|| Error::MissingObject(id.clone()))?;
+        match object.kind() {
+            Some("permanode") | Some("claim") => {}
+            _ => return Err(Error::InvalidInput(
+                "Only permanodes and claims can be deleted")),
+        }
+        let mut data = Dict::new();
+        data.insert("date".into(), Property::Integer(timestamp_now()));
+        let tombstone = self.index.add(tombstone(id, data))?;
+        info!("Deleted {}, tombstone = {}", id, tombstone);
+        Ok(tombstone)
+    }
+
+    /// Finds the permanode recorded in the log by an event of this kind, with
+    /// this name, as `kv::KeyValue::create()` and `snapshot()` do.
+    ///
+    /// If there are several, this is the latest one.
+    pub(crate) fn find_named_node(&self, event: &str, name: &str)
+        -> errors::Result<Option<ObjectId>>
+    {
+        let mut found = None;
+        for member in self.list_events()? {
+            let object = self.index.get_object(&member.value)?;
+            let dict = match object.as_deref() {
+                Some(Object { data: ObjectData::Dict(dict), .. }) => dict,
+                _ => continue,
+            };
+            if dict.get("event") != Some(&Property::String(event.into())) ||
+                dict.get("name") != Some(&Property::String(name.into()))
+            {
+                continue;
+            }
+            if let Some(Property::Reference(node)) = dict.get("node") {
+                found = Some(node.clone());
+            }
+        }
+        Ok(found)
+    }
+
+    /// Adds a file or directory with `add()`, and records it as a snapshot
+    /// under `name`, see `snapshot_tree()`.
+    pub fn snapshot<P: AsRef<Path>>(&mut self, path: P, name: &str)
+        -> errors::Result<Snapshot>
+    {
+        let tree = self.add(path)?;
+        self.snapshot_tree(&tree, name)
+    }
+
+    /// Records an object as a snapshot under `name`.
+    ///
+    /// The snapshots with the same name are the values of a permanode,
+    /// sorted by date, which is created with the first one and recorded in
+    /// the store's log. That keeps it and its snapshots from being
+    /// garbage-collected, and lets `snapshots()` find it back. This fails if
+    /// the store has no log.
+    pub fn snapshot_tree(&mut self, tree: &ObjectId, name: &str)
+        -> errors::Result<Snapshot>
+    {
+        if self.index.get_object(tree)?.is_none() {
+            return Err(Error::MissingObject(tree.clone()));
+        }
+        let (node, last) = match self.snapshot_node(name)? {
+            Some(node) => {
+                let last = self.snapshots(name)?.last().map(|s| s.date);
+                (node, last)
+            }
+            None => {
+                if self.log()?.is_none() {
+                    return Err(Error::InvalidInput(
+                        "Store has no log to record snapshots in"));
+                }
+                let mut data = Dict::new();
+                data.insert("type".into(), Property::String("set".into()));
+                let node = permanode(data, Sort::Ascending("date".into()));
+                let node = self.index.add(node.data)?;
+                let mut details = Dict::new();
+                details.insert("name".into(), Property::String(name.into()));
+                details.insert("node".into(),
+                               Property::Reference(node.clone()));
+                self.record_event("snapshots", details)?;
+                (node, None)
+            }
+        };
+        // Claims are keyed by date, so two snapshots can't have the same one
+        let date = match last {
+            Some(last) => timestamp_now().max(last + 1),
+            None => timestamp_now(),
+        };
+        let mut data = Dict::new();
+        data.insert("date".into(), Property::Integer(date));
+        let claim = self.index.add(claim(&node, tree, data))?;
+        info!("Snapshot {:?} of {}, claim = {}", name, tree, claim);
+        Ok(Snapshot { date, tree: tree.clone(), claim })
+    }
+
+    /// The permanode of the snapshots taken under `name`, if any were.
+    ///
+    /// Its values are the snapshots, so it can be given to `file_history()`.
+    pub fn snapshot_node(&self, name: &str)
+        -> errors::Result<Option<ObjectId>>
+    {
+        self.find_named_node("snapshots", name)
+    }
+
+    /// Lists the snapshots taken under `name`, oldest first.
+    pub fn snapshots(&self, name: &str) -> errors::Result<Vec<Snapshot>> {
+        let mut snapshots = Vec::new();
+        let node = match self.snapshot_node(name)? {
+            Some(node) => node,
+            None => return Ok(snapshots),
+        };
+        let mut after = None;
+        loop {
+            let page = self.index.list_permanode(&node, after.as_ref(), 100)?
+                .ok_or(Error::CorruptedStore(
+                    "Snapshots are not a permanode"))?;
+            for member in page.members {
+                if let Property::Integer(date) = member.key {
+                    snapshots.push(Snapshot {
+                        date,
+                        tree: member.value,
+                        claim: member.claim,
+                    });
+                }
+            }
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        Ok(snapshots)
+    }
+
+    /// Finds when the file at a path changed, over the values of a
+    /// permanode whose values are directories, such as successive backups.
+    ///
+    /// The values are gone over in increasing order of their sort key, and
+    /// one is returned each time the path leads to a different object than
+    /// in the previous one, including when it appears or disappears.
+    pub fn file_history(&self, id: &ObjectId, path: &str)
+        -> errors::Result<Vec<FileVersion>>
+    {
+        let mut members = Vec::new();
+        let mut after = None;
+        loop {
+            let page = self.index.list_permanode(id, after.as_ref(), 100)?
+                .ok_or(Error::InvalidInput("Object is not a permanode"))?;
+            members.extend(page.members);
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        members.sort_by(|a, b| a.key.cmp(&b.key));
+        let mut history: Vec<FileVersion> = Vec::new();
+        for member in members {
+            let file = match self.resolve_path(&member.value, path)? {
+                Some(Property::Reference(file)) => Some(file),
+                _ => None,
+            };
+            let previous = history.last().map(|v| &v.file);
+            if previous == Some(&file) ||
+                (previous.is_none() && file.is_none())
+            {
+                continue;
+            }
+            let size = match file {
+                Some(ref file) => match self.index.get_object(file)?
+                    .as_deref()
+                {
+                    Some(Object { data: ObjectData::Dict(dict), .. }) => {
+                        file_contents(dict).map(|(size, _)| size as u64)
+                    }
+                    _ => None,
+                },
+                None => None,
+            };
+            history.push(FileVersion {
+                key: member.key,
+                tree: member.value,
+                file,
+                size,
+            });
+        }
+        Ok(history)
+    }
+
+    /// Hints that a blob is going to be needed soon.
+    ///
+    /// If the blob storage is a cache, the blob is fetched now so it is
+    /// available later.
+    pub fn prefetch(&self, id: &BlobId) -> errors::Result<()> {
+        self.storage.prefetch(std::slice::from_ref(id))
+    }
+
+    /// Hints that everything reachable from an object is going to be needed.
+    ///
+    /// This follows references and the values of permanodes, and passes all
+    /// the blobs found to the blob storage at once. Returns the number of
+    /// blobs.
+    pub fn prefetch_tree(&self, id: &ObjectId) -> errors::Result<usize> {
+        let blobs: Vec<BlobId> = self.reachable_blobs(id)?
+            .into_iter()
+            .collect();
+        self.storage.prefetch(&blobs)?;
+        Ok(blobs.len())
+    }
+
+    /// Counts the blobs reachable from an object that are stored locally.
+    ///
+    /// With a cached blob storage, this tells how much of the tree will work
+    /// offline.
+    pub fn availability(&self, id: &ObjectId) -> errors::Result<Availability> {
+        let blobs = self.reachable_blobs(id)?;
+        let mut present = 0;
+        for blob in &blobs {
+            if self.storage.is_local(blob)? {
+                present += 1;
+            }
+        }
+        Ok(Availability { present, total: blobs.len() })
+    }
+
+    /// Visits the objects reachable from an object, each one once.
+    ///
+    /// This follows references, and the values and claims of permanodes.
+    /// Objects are visited after everything they reference, and claims
+    /// right after their permanode. Objects that are referenced but missing
+    /// from the index are passed as `None`. Fails with
+    /// `Error::ReferenceCycle` if an object can reach itself.
+    fn walk_tree<'s, F>(&'s self, id: &ObjectId, mut visit: F)
+        -> errors::Result<()>
+        where F: FnMut(&ObjectId, Option<Cow<'s, Object>>)
+            -> errors::Result<()>
+    {
+        if self.index.get_object(id)?.is_none() {
+            return Err(Error::MissingObject(id.clone()));
+        }
+        // Objects are visited depth-first; they are done once everything
+        // they reference has been walked, so a reference to an object that
+        // is visited but not done goes back up the path, which is a cycle
+        let mut visited = HashSet::new();
+        let mut done = HashSet::new();
+        let mut open = vec![(id.clone(), Step::Enter)];
+        while let Some((id, step)) = open.pop() {
+            match step {
+                Step::Enter => {}
+                Step::Leave => {
+                    visit(&id, self.index.get_object(&id)?)?;
+                    done.insert(id);
+                    continue;
+                }
+                // Claims reference their permanode, which is done by now
+                Step::Claim => {
+                    if visited.insert(id.clone()) {
+                        visit(&id, self.index.get_object(&id)?)?;
+                        done.insert(id);
+                    }
+                    continue;
+                }
+            }
+            if !visited.insert(id.clone()) {
+                continue;
+            }
+            let object = match self.index.get_object(&id)? {
+                Some(o) => o,
+                None => {
+                    visit(&id, None)?;
+                    done.insert(id);
+                    continue;
+                }
+            };
+            let mut refs: Vec<ObjectId> = object_properties(&object)
+                .filter_map(|p| match p {
+                    Property::Reference(r) => Some(r.clone()),
+                    _ => None,
+                })
+                .collect();
+            let mut after = None;
+            while let Some(page) = self.index.list_permanode(
+                &id, after.as_ref(), 100)?
+            {
+                for member in page.members {
+                    open.push((member.claim, Step::Claim));
+                    refs.push(member.value);
+                }
+                match page.next {
+                    Some(next) => after = Some(next),
+                    None => break,
+                }
+            }
+            open.push((id, Step::Leave));
+            for r in refs {
+                if !visited.contains(&r) {
+                    open.push((r, Step::Enter));
+                } else if !done.contains(&r) {
+                    return Err(Error::ReferenceCycle(r));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Iterates on the objects and blobs reachable from an object.
+    ///
+    /// This follows references and the values of permanodes, starting with
+    /// `root` itself, which doesn't have to be in the index.
+    pub fn walk_from(&self, root: &ObjectId, order: WalkOrder)
+        -> Walk<'_, S, I>
+    {
+        Walk::new(self, root, order)
+    }
+
+    /// Finds the blobs reachable from an object.
+    fn reachable_blobs(&self, id: &ObjectId)
+        -> errors::Result<HashSet<BlobId>>
+    {
+        let mut blobs = HashSet::new();
+        self.walk_tree(id, |id, object| {
+            match object {
+                Some(object) => blobs.extend(object_blobs(&object)),
+                None => warn!("Missing object {}", id),
+            }
+            Ok(())
+        })?;
+        Ok(blobs)
+    }
+
+    /// Verifies only the objects and blobs reachable from an object.
+    ///
+    /// Problems are logged as they are found: missing objects and blobs, and
+    /// objects and blobs whose contents don't match their ID. Returns the
+    /// number of problems.
+    pub fn verify_tree(&self, id: &ObjectId) -> errors::Result<usize> {
+        let mut problems = 0;
+        let mut objects = 0;
+        let mut blobs = HashSet::new();
+        self.walk_tree(id, |id, object| {
+            let object = match object {
+                Some(o) => o,
+                None => {
+                    warn!("Missing object {}", id);
+                    problems += 1;
+                    return Ok(());
+                }
+            };
+            objects += 1;
+            if serialize::object_id(&object.data) != *id {
+                warn!("Object has the wrong hash: {}", id);
+                problems += 1;
+            }
+            blobs.extend(object_blobs(&object));
+            Ok(())
+        })?;
+        info!("Checked {} objects, verifying {} blobs...",
+              objects, blobs.len());
+        for blob_id in &blobs {
+            match self.storage.get_blob_reader(blob_id)? {
+                None => {
+                    warn!("Missing blob {}", blob_id);
+                    problems += 1;
+                }
+                Some(mut reader) => {
+                    let id = serialize::hash_blob_reader(&mut reader)
+                        .map_err(|e| ("Error reading blob", e))?;
+                    if id != *blob_id {
+                        warn!("Blob has the wrong hash: {}", blob_id);
+                        problems += 1;
+                    } else {
+                        debug!("Checked {}", blob_id);
+                    }
+                }
+            }
+        }
+        Ok(problems)
+    }
+
+    /// Signs an object, adding a signature object for it.
+    ///
+    /// The signature is kept as long as the object is. Returns its ID.
+    pub fn sign(&mut self, id: &ObjectId, key: &SigningKey)
+        -> errors::Result<ObjectId>
+    {
+        let object = self.index.get_object(id)?
+            .ok_or_else(|| Error::MissingObject(id.clone()))?;
+        let data = signing::signature(key, &object);
+        self.index.add(data)
+    }
+
+    /// Checks the signatures of an object.
+    ///
+    /// Returns one entry per signature object of the object, including the
+    /// ones that don't match; which keys to trust is up to the caller.
+    pub fn verify_signature(&self, id: &ObjectId)
+        -> errors::Result<Vec<SignatureCheck>>
+    {
+        let object = self.index.get_object(id)?
+            .ok_or_else(|| Error::MissingObject(id.clone()))?;
+        let mut checks = Vec::new();
+        for sig_id in self.index.signatures(id)? {
+            let check = self.index.get_object(&sig_id)?
+                .and_then(|sig| signing::check_signature(&sig, &object));
+            match check {
+                Some(check) => {
+                    if !check.valid {
+                        warn!("Bad signature {} on {}", sig_id, id);
+                    }
+                    checks.push(check);
+                }
+                None => warn!("Invalid signature object {}", sig_id),
+            }
+        }
+        Ok(checks)
+    }
+
+    /// Copies an object and everything reachable from it to another store.
+    ///
+    /// Objects and blobs that `dst` already has are not copied again. Blobs
+    /// are copied first, then each object after those it references, so an
+    /// interrupted copy doesn't leave objects referring to something missing
+    /// from `dst`. The claims of permanodes and the signatures of the copied
+    /// objects are copied along.
+    pub fn copy_object<S2, I2>(&self, dst: &mut Store<S2, I2>, id: &ObjectId)
+        -> errors::Result<Copied>
+        where S2: BlobStorage, I2: ObjectIndex
+    {
+        let mut objects = Vec::new();
+        let mut blobs = HashSet::new();
+        self.walk_tree(id, |id, object| {
+            let object = object
+                .ok_or_else(|| Error::MissingObject(id.clone()))?;
+            blobs.extend(object_blobs(&object));
+            objects.push(object);
+            Ok(())
+        })?;
+        let mut signatures = Vec::new();
+        for object in &objects {
+            for sig_id in self.index.signatures(&object.id)? {
+                signatures.extend(self.index.get_object(&sig_id)?);
+            }
+        }
+
+        let objects_len = objects.len() + signatures.len();
+        let mut copied = Copied { objects: 0, blobs: 0 };
+        for blob_id in &blobs {
+            if dst.storage.contains(blob_id)? {
+                continue;
+            }
+            let mut reader = self.storage.get_blob_reader(blob_id)?
+                .ok_or_else(|| Error::MissingBlob(blob_id.clone()))?;
+            dst.check_free_space()?;
+            if dst.storage.add_blob_from_reader(&mut reader)? != *blob_id {
+                return Err(Error::CorruptedStore("Blob has the wrong hash"));
+            }
+            copied.blobs += 1;
+        }
+        // Objects come after what they reference, so an interrupted copy
+        // leaves no dangling reference; signatures come last
+        for object in objects.into_iter().chain(signatures) {
+            if dst.index.get_object(&object.id)?.is_some() {
+                continue;
+            }
+            if dst.index.add(object.data.clone())? != object.id {
+                return Err(Error::CorruptedStore(
+                    "Copied object has the wrong hash"));
+            }
+            copied.objects += 1;
+        }
+        info!("Copied {} objects and {} blobs, {} objects and {} blobs \
+               were already there",
+              copied.objects, copied.blobs,
+              objects_len - copied.objects, blobs.len() - copied.blobs);
+        Ok(copied)
+    }
+
+    /// Imports all the objects and blobs of another store.
+    ///
+    /// Objects and blobs this store already has are skipped, so merging the
+    /// same store again only brings what's new. A permanode in both stores
+    /// has the same ID in both, so importing the claims merges its values.
+    /// The other store's log is a distinct permanode though: its events are
+    /// claimed again on this store's log, keeping their dates.
+    ///
+    /// The merge is recorded in the log, referencing the other store's root
+    /// config so that garbage collection keeps the imported objects.
+    /// `source` describes the other store in that event, e.g. its path.
+    pub fn merge<S2, I2>(&mut self, other: &Store<S2, I2>, source: &str)
+        -> errors::Result<Copied>
+        where S2: EnumerableBlobStorage, I2: ObjectIndex
+    {
+        let mut span = Span::enter("merge");
+        span.detail(source);
+        let mut copied = Copied { objects: 0, blobs: 0 };
+        for blob_id in other.storage.list_blobs()? {
+            let blob_id = blob_id?;
+            self.check_cancel()?;
+            if self.storage.contains(&blob_id)? {
+                continue;
+            }
+            match other.storage.get_blob_reader(&blob_id)? {
+                Some(mut reader) => {
+                    self.check_free_space()?;
+                    if self.storage.add_blob_from_reader(&mut reader)? !=
+                        blob_id
+                    {
+                        warn!("Blob has the wrong hash: {}", blob_id);
+                        continue;
+                    }
+                    copied.blobs += 1;
+                }
+                None => warn!("Blob disappeared during merge: {}", blob_id),
+            }
+        }
+        for object in other.index.list_objects()? {
+            let object = object?;
+            if self.index.get_object(&object.id)?.is_some() {
+                continue;
+            }
+            self.check_cancel()?;
+            if self.index.add(object.data.clone())? != object.id {
+                return Err(Error::CorruptedStore(
+                    "Merged object has the wrong hash"));
+            }
+            copied.objects += 1;
+        }
+
+        let mut events = 0;
+        if let Some(log) = self.log()? {
+            if other.log()?.as_ref() != Some(&log) {
+                for member in other.list_events()? {
+                    let mut data = Dict::new();
+                    data.insert("date".into(), member.key);
+                    self.index.add(claim(&log, &member.value, data))?;
+                    events += 1;
+                }
+            }
+        }
+        info!("Merged {} objects and {} blobs, {} events from the other log",
+              copied.objects, copied.blobs, events);
+
+        let mut details = Dict::new();
+        details.insert("source".into(), Property::String(source.into()));
+        details.insert("root".into(),
+                       Property::Reference(other.index.root().clone()));
+        details.insert("objects".into(),
+                       Property::Integer(copied.objects as i64));
+        details.insert("blobs".into(), Property::Integer(copied.blobs as i64));
+        self.record_event("merge", details)?;
+        Ok(copied)
+    }
+
+    /// Moves the objects and blobs of a staging area into the store.
+    ///
+    /// Every reference of the staged objects has to be staged or already in
+    /// the store, otherwise this fails with `Error::MissingObject` or
+    /// `Error::MissingBlob` before anything is moved. Blobs are moved before
+    /// the objects using them. If this is interrupted, the objects already
+    /// moved aren't referenced from anything yet, so they are garbage until
+    /// promoting again. On success, the staging area is emptied.
+    pub fn promote<B>(&mut self, staging: &mut Staging<B>)
+        -> errors::Result<Copied>
+        where B: EnumerableBlobStorage
+    {
+        let mut span = Span::enter("promote");
+        for object in staging.objects() {
+            for property in object_properties(object) {
+                match property {
+                    Property::Reference(id)
+                        if staging.get_object(id).is_none() &&
+                            self.index.get_object(id)?.is_none() =>
+                    {
+                        return Err(Error::MissingObject(id.clone()));
+                    }
+                    Property::Blob(id)
+                        if !staging.blobs().contains(id)? &&
+                            !self.storage.contains(id)? =>
+                    {
+                        return Err(Error::MissingBlob(id.clone()));
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        let mut copied = Copied { objects: 0, blobs: 0 };
+        let mut batch = Vec::new();
+        let mut batch_size = 0;
+        for blob_id in staging.blobs().list_blobs()? {
+            let blob_id = blob_id?;
+            if self.storage.contains(&blob_id)? {
+                continue;
+            }
+            let blob = staging.blobs().get_blob(&blob_id)?
+                .ok_or_else(|| Error::MissingBlob(blob_id.clone()))?;
+            if serialize::hash_blob(&blob) != blob_id {
+                return Err(Error::CorruptedStore(
+                    "Staged blob has the wrong hash"));
+            }
+            span.add_bytes(blob.len() as u64);
+            copied.blobs += 1;
+            batch_size += blob.len();
+            batch.push((blob_id, blob));
+            if batch_size >= MAX_BATCH_SIZE {
+                self.add_known_batch(&mut batch)?;
+                batch_size = 0;
+            }
+        }
+        self.add_known_batch(&mut batch)?;
+        for object in staging.objects() {
+            if self.index.get_object(&object.id)?.is_some() {
+                continue;
+            }
+            self.index.add(object.data.clone())?;
+            copied.objects += 1;
+        }
+        info!("Promoted {} objects and {} blobs", copied.objects,
+              copied.blobs);
+        staging.discard()?;
+        Ok(copied)
+    }
+
+    /// Gets the contents of a file.
+    ///
+    /// `id` is either the list object of chunks returned by
+    /// `Store::add_file()`, or a file object added with `Store::add()`. The
+    /// returned reader loads the blobs as needed.
+    pub fn get_file(&self, id: &ObjectId)
+        -> errors::Result<FileReader<'_, S>>
+    {
+        // The contents of a file object can reference another file object
+        let mut seen = HashSet::new();
+        let mut id = id.clone();
+        loop {
+            if !seen.insert(id.clone()) {
+                return Err(Error::ReferenceCycle(id));
+            }
+            let object = self.index.get_object(&id)?
+                .ok_or_else(|| Error::MissingObject(id.clone()))?;
+            let contents = match object.data {
+                ObjectData::Dict(ref dict) => match file_contents(dict) {
+                    Some((_, contents)) => contents,
+                    None => return Err(Error::InvalidInput(
+                        "Object is not a file")),
+                },
+                ObjectData::List(_) => {
+                    return FileReader::new(&self.storage, &object);
+                }
+            };
+            match contents {
+                Property::Reference(next) => id = next.clone(),
+                _ => return self.get_contents(contents),
+            }
+        }
+    }
+
+    /// Gets a reader on the `contents` of a file object.
+    fn get_contents(&self, contents: &Property)
+        -> errors::Result<FileReader<'_, S>>
+    {
+        match contents {
+            Property::Blob(id) => Ok(FileReader::from_blob(&self.storage, id)),
+            Property::Inline(data) => {
+                Ok(FileReader::from_inline(&self.storage, data))
+            }
+            Property::Reference(id) => self.get_file(id),
+            _ => Err(Error::CorruptedStore("Invalid file contents")),
+        }
+    }
+
+    /// Lists a directory added with `Store::add()`.
+    ///
+    /// Directories added by older versions don't record the kind and size of
+    /// their entries; those are loaded to fill them in.
+    pub fn list_dir(&self, id: &ObjectId) -> errors::Result<Vec<DirEntry>> {
+        let object = self.index.get_object(id)?
+            .ok_or_else(|| Error::MissingObject(id.clone()))?;
+        let mut entries = match object.data {
+            ObjectData::Dict(ref dict) if file_contents(dict).is_none() => {
+                dir_entries(dict)
+            }
+            _ => None,
+        }.ok_or(Error::InvalidInput("Object is not a directory"))?;
+        for entry in &mut entries {
+            if entry.kind.is_some() {
+                continue;
+            }
+            if let Some(Object { data: ObjectData::Dict(dict), .. }) =
+                self.index.get_object(&entry.id)?.as_deref()
+            {
+                match file_contents(dict) {
+                    Some((size, _)) => {
+                        entry.kind = Some(EntryKind::File);
+                        entry.size = Some(size as u64);
+                    }
+                    None if symlink_target(dict).is_some() => {
+                        entry.kind = Some(EntryKind::Symlink);
+                    }
+                    None => entry.kind = Some(EntryKind::Dir),
+                }
+            }
+        }
+        Ok(entries)
+    }
+
+    /// Lists the entries that differ between two directories, sorted by
+    /// path.
+    ///
+    /// Entries are compared by ID, so a file is only modified if its
+    /// contents changed, not its modification time or permissions.
+    /// Subdirectories in both are compared recursively, while a directory
+    /// only in one of them is reported as a single entry.
+    pub fn diff(&self, a: &ObjectId, b: &ObjectId)
+        -> errors::Result<Vec<TreeChange>>
+    {
+        let mut changes = Vec::new();
+        let mut stack = vec![(String::new(), a.clone(), b.clone())];
+        while let Some((prefix, a, b)) = stack.pop() {
+            let mut entries: BTreeMap<String, (Option<DirEntry>,
+                                               Option<DirEntry>)> =
+                BTreeMap::new();
+            for entry in self.list_dir(&a)? {
+                let name = entry.name.clone();
+                entries.entry(name).or_default().0 = Some(entry);
+            }
+            for entry in self.list_dir(&b)? {
+                let name = entry.name.clone();
+                entries.entry(name).or_default().1 = Some(entry);
+            }
+            for (name, pair) in entries {
+                let path = format!("{}{}", prefix, name);
+                let change = match pair {
+                    (Some(old), Some(new)) if old.id == new.id => continue,
+                    (Some(old), Some(new))
+                        if old.kind == Some(EntryKind::Dir) &&
+                            new.kind == Some(EntryKind::Dir) =>
+                    {
+                        stack.push((format!("{}/", path), old.id, new.id));
+                        continue;
+                    }
+                    (Some(old), Some(new)) => TreeChange {
+                        path,
+                        change: ChangeKind::Modified,
+                        kind: new.kind,
+                        old: Some(old.id),
+                        new: Some(new.id),
+                    },
+                    (Some(old), None) => TreeChange {
+                        path,
+                        change: ChangeKind::Removed,
+                        kind: old.kind,
+                        old: Some(old.id),
+                        new: None,
+                    },
+                    (None, Some(new)) => TreeChange {
+                        path,
+                        change: ChangeKind::Added,
+                        kind: new.kind,
+                        old: None,
+                        new: Some(new.id),
+                    },
+                    (None, None) => continue,
+                };
+                changes.push(change);
+            }
+        }
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(changes)
+    }
+
+    /// Measures the blobs used by each entry of a directory.
+    ///
+    /// A blob is unique to an entry if it is only referenced from objects
+    /// that are themselves only reachable through that entry. Objects that
+    /// are not live count until they are garbage collected, so the unique
+    /// size is what dropping the entry would free after that.
+    pub fn disk_usage(&self, id: &ObjectId) -> errors::Result<Vec<DiskUsage>> {
+        let mut sizes = HashMap::new();
+        let mut usage = Vec::new();
+        for entry in self.list_dir(id)? {
+            let exclusive = self.exclusive_objects(&entry.id)?;
+            let mut unique = 0;
+            let mut shared = 0;
+            for blob in self.reachable_blobs(&entry.id)? {
+                let size = match sizes.get(&blob) {
+                    Some(&size) => size,
+                    None => {
+                        let size = match self.storage.size(&blob)? {
+                            Some(size) => size,
+                            None => {
+                                warn!("Missing blob {}", blob);
+                                0
+                            }
+                        };
+                        sizes.insert(blob.clone(), size);
+                        size
+                    }
+                };
+                let only_here = self.index.referrers(blob.id())?.iter()
+                    .all(|(_, source)| exclusive.contains(source));
+                if only_here {
+                    unique += size;
+                } else {
+                    shared += size;
+                }
+            }
+            usage.push(DiskUsage {
+                name: entry.name,
+                id: entry.id,
+                unique,
+                shared,
+            });
+        }
+        Ok(usage)
+    }
+
+    /// Finds the objects reachable from an object only through it,
+    /// including itself.
+    fn exclusive_objects(&self, id: &ObjectId)
+        -> errors::Result<HashSet<ObjectId>>
+    {
+        let mut exclusive = HashSet::new();
+        self.walk_tree(id, |id, _| {
+            exclusive.insert(id.clone());
+            Ok(())
+        })?;
+        // Drop the objects referenced from outside, until none are left
+        loop {
+            let mut outside = Vec::new();
+            for object in &exclusive {
+                if object == id {
+                    continue;
+                }
+                let referrers = self.index.referrers(object.id())?;
+                if referrers.iter().any(|(_, r)| !exclusive.contains(r)) {
+                    outside.push(object.clone());
+                }
+            }
+            if outside.is_empty() {
+                return Ok(exclusive);
+            }
+            for object in outside {
+                exclusive.remove(&object);
+            }
+        }
+    }
+
+    /// Recreates a file or directory added with `Store::add()` on disk.
+    ///
+    /// `dest` must not exist yet. Directories are extracted recursively; a
+    /// directory containing itself fails with `Error::ReferenceCycle`.
+    pub fn extract<P: AsRef<Path>>(&self, id: &ObjectId, dest: P)
+        -> errors::Result<()>
+    {
+        self.extract_with(id, dest, Existing::Fail).map(|_| ())
+    }
+
+    /// Recreates a file or directory on disk, possibly over existing files.
+    ///
+    /// With `Existing::Overwrite` or `Existing::Keep`, the files are
+    /// extracted into existing directories; the files these already contain
+    /// that are not in the stored tree are left there.
+    pub fn extract_with<P: AsRef<Path>>(&self, id: &ObjectId, dest: P,
+                                        existing: Existing)
+        -> errors::Result<Extracted>
+    {
+        let mut extracted = Extracted::default();
+        self.extract_entry(id, dest.as_ref(), None, existing, &mut extracted,
+                           &mut Vec::new())?;
+        Ok(extracted)
+    }
+
+    /// Extracts an object, `parents` being the directories it's in.
+    ///
+    /// The modification time and permissions recorded in `entry`, if any,
+    /// are restored once the file or directory has been written.
+    fn extract_entry(&self, id: &ObjectId, dest: &Path,
+                     entry: Option<&DirEntry>,
+                     existing: Existing, extracted: &mut Extracted,
+                     parents: &mut Vec<ObjectId>)
+        -> errors::Result<()>
+    {
+        if parents.contains(id) {
+            return Err(Error::ReferenceCycle(id.clone()));
+        }
+        let object = self.index.get_object(id)?
+            .ok_or_else(|| Error::MissingObject(id.clone()))?;
+        let dict = match object.data {
+            ObjectData::Dict(ref dict) => dict,
+            ObjectData::List(_) => return Err(Error::InvalidInput(
+                "Object is not a file or directory")),
+        };
+        // Existing path, not following symlinks
+        let present = fs::symlink_metadata(dest).ok();
+        if let Some(target) = symlink_target(dict) {
+            match (present, existing) {
+                (None, _) | (Some(_), Existing::Fail) => {}
+                (Some(_), Existing::Keep) => {
+                    info!("Keeping existing {:?}", dest);
+                    extracted.skipped += 1;
+                    return Ok(());
+                }
+                (Some(m), Existing::Overwrite) if m.is_dir() => {
+                    return Err(Error::InvalidInput(
+                        "Can't replace a directory with a symbolic link"));
+                }
+                (Some(_), Existing::Overwrite) => {
+                    fs::remove_file(dest)
+                        .map_err(|e| ("Can't remove existing file", e))?;
+                }
+            }
+            make_symlink(target, dest)
+                .map_err(|e| ("Can't create symbolic link", e))?;
+            extracted.symlinks += 1;
+            info!("Extracted symbolic link {:?} -> {:?}", dest, target);
+            // The metadata would be set on the target
+            return Ok(());
+        } else if let Some((size, contents)) = file_contents(dict) {
+            match (present, existing) {
+                (None, _) | (Some(_), Existing::Fail) => {}
+                (Some(_), Existing::Keep) => {
+                    info!("Keeping existing {:?}", dest);
+                    extracted.skipped += 1;
+                    return Ok(());
+                }
+                (Some(m), Existing::Overwrite) if m.is_dir() => {
+                    return Err(Error::InvalidInput(
+                        "Can't replace a directory with a file"));
+                }
+                (Some(_), Existing::Overwrite) => {
+                    // Removing it rather than truncating, so that a symlink
+                    // is replaced, not written through
+                    fs::remove_file(dest)
+                        .map_err(|e| ("Can't remove existing file", e))?;
+                }
+            }
+            self.extract_file(contents, size, dest)?;
+            extracted.files += 1;
+            extracted.bytes += size as u64;
+            info!("Extracted file {:?}, size = {}", dest, size);
+        } else {
+            let entries = dir_entries(dict).ok_or(Error::InvalidInput(
+                "Object is not a file or directory"))?;
+            match (present, existing) {
+                (None, _) | (Some(_), Existing::Fail) => {
+                    fs::create_dir(dest)
+                        .map_err(|e| ("Can't create extracted directory", e))?;
+                }
+                (Some(m), _) if m.is_dir() => {
+                    debug!("Extracting into existing {:?}", dest);
+                }
+                (Some(_), Existing::Keep) => {
+                    info!("Keeping existing {:?}", dest);
+                    extracted.skipped += 1;
+                    return Ok(());
+                }
+                (Some(_), Existing::Overwrite) => {
+                    fs::remove_file(dest)
+                        .map_err(|e| ("Can't remove existing file", e))?;
+                    fs::create_dir(dest)
+                        .map_err(|e| ("Can't create extracted directory", e))?;
+                }
+            }
+            extracted.directories += 1;
+            parents.push(id.clone());
+            for entry in &entries {
+                let name = &entry.name;
+                if name.is_empty() || name == "." || name == ".." ||
+                    name.contains('\\')
+                {
+                    return Err(Error::CorruptedStore(
+                        "Invalid file name in directory"));
+                }
+                self.extract_entry(&entry.id, &dest.join(name), Some(entry),
+                                   existing, extracted, parents)?;
+            }
+            parents.pop();
+            info!("Extracted directory {:?}, {} entries", dest,
+                  entries.len());
+        }
+        if let Some(entry) = entry {
+            restore_metadata(dest, entry)?;
+        }
+        Ok(())
+    }
+
+    fn extract_file(&self, contents: &Property, size: i64, dest: &Path)
+        -> errors::Result<()>
+    {
+        let mut reader = self.get_contents(contents)?;
+        let mut fp = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(dest)
+            .map_err(|e| ("Can't create extracted file", e))?;
+        let mut buf = [0; 65536];
+        let mut written = 0;
+        loop {
+            let len = match reader.read(&mut buf) {
+                Ok(0) => break,
+                Ok(len) => len,
+                Err(e) => return Err(file_reader::read_error(e)),
+            };
+            fp.write_all(&buf[..len])
+                .map_err(|e| ("Error writing extracted file", e))?;
+            written += len as i64;
+        }
+        if written != size {
+            return Err(Error::CorruptedStore(
+                "File size doesn't match its contents"));
+        }
+        Ok(())
+    }
+
+    /// Cuts a file into chunks and add a list object of them to the index.
+    ///
+    /// Each chunk is streamed to the blob storage as it is read. This uses
+    /// the default strategy, see `Store::add_file_with()`.
+    pub fn add_file<R: Read>(&mut self, reader: R)
+        -> errors::Result<(ObjectId, usize)>
+    {
+        self.add_file_with(reader, &Chunking::default())
+    }
+
+    /// Cuts a file into chunks with the given strategy, and add a list
+    /// object of them to the index.
+    ///
+    /// The store's chunking salt, if it has one, applies to the strategy.
+    pub fn add_file_with<R: Read>(&mut self, reader: R, chunking: &Chunking)
+        -> errors::Result<(ObjectId, usize)>
+    {
+        let (id, size, _) = self.add_file_with_manifest(reader, chunking)?;
+        Ok((id, size))
+    }
+
+    /// Like `Store::add_file_with()`, also returning where each chunk is.
+    ///
+    /// The list object already pairs each chunk with its offset, this is
+    /// the same information with the lengths worked out, for tools that
+    /// sync files by chunk. `Store::file_manifest()` gets it back later.
+    pub fn add_file_with_manifest<R: Read>(&mut self, reader: R,
+                                           chunking: &Chunking)
+        -> errors::Result<(ObjectId, usize, Vec<ChunkInfo>)>
+    {
+        let (chunks, size) = self.add_chunks(reader, chunking)?;
+        let manifest = self.chunk_manifest(&chunks, Some(size as u64))?;
+        let id = self.index.add(ObjectData::List(chunks))?;
+        info!("Added file contents, {} chunks, id = {}", manifest.len(), id);
+        Ok((id, size, manifest))
+    }
+
+    /// Gets the chunks of a file, as a list of offsets, lengths and blobs.
+    ///
+    /// `id` is either a list of chunks or a file object, like for
+    /// `Store::get_file()`.
+    pub fn file_manifest(&self, id: &ObjectId)
+        -> errors::Result<Vec<ChunkInfo>>
+    {
+        let object = self.index.get_object(id)?
+            .ok_or_else(|| Error::MissingObject(id.clone()))?;
+        let (size, contents) = match object.data {
+            ObjectData::Dict(ref dict) => match file_contents(dict) {
+                Some((size, contents)) => (size as u64, contents),
+                None => return Err(Error::InvalidInput(
+                    "Object is not a file")),
+            },
+            ObjectData::List(ref list) => {
+                return self.chunk_manifest(list, None);
+            }
+        };
+        let blob = match contents {
+            Property::Blob(blob) => Some(blob.clone()),
+            Property::Inline(_) => None,
+            Property::Reference(list) => {
+                return match self.index.get_object(list)?.as_deref() {
+                    Some(Object { data: ObjectData::List(list), .. }) => {
+                        self.chunk_manifest(list, Some(size))
+                    }
+                    Some(_) => Err(Error::CorruptedStore(
+                        "File contents is not a list")),
+                    None => Err(Error::MissingObject(list.clone())),
+                };
+            }
+            _ => return Err(Error::CorruptedStore("Invalid file contents")),
+        };
+        Ok(vec![ChunkInfo { offset: 0, length: size, blob }])
+    }
+
+    /// Works out the chunks from a list of offsets and blobs.
+    ///
+    /// Each chunk goes to the offset of the next one. If the size of the file
+    /// isn't known, the size of the last chunk is looked up.
+    fn chunk_manifest(&self, list: &List, size: Option<u64>)
+        -> errors::Result<Vec<ChunkInfo>>
+    {
+        let invalid = || Error::CorruptedStore(
+            "File contents list is not offsets and blobs");
+        if !list.len().is_multiple_of(2) {
+            return Err(invalid());
+        }
+        let mut manifest: Vec<ChunkInfo> = Vec::with_capacity(list.len() / 2);
+        for pair in list.chunks(2) {
+            let (offset, blob) = match (&pair[0], &pair[1]) {
+                (&Property::Integer(o), Property::Blob(id)) if o >= 0 => {
+                    (o as u64, Some(id.clone()))
+                }
+                (&Property::Integer(o), Property::Inline(_)) if o >= 0 => {
+                    (o as u64, None)
+                }
+                _ => return Err(invalid()),
+            };
+            if let Some(previous) = manifest.last_mut() {
+                if offset < previous.offset {
+                    return Err(invalid());
+                }
+                previous.length = offset - previous.offset;
+            }
+            manifest.push(ChunkInfo { offset, length: 0, blob });
+        }
+        if let Some(last) = manifest.last_mut() {
+            last.length = match (size, &last.blob) {
+                (Some(size), _) => {
+                    size.checked_sub(last.offset).ok_or_else(invalid)?
+                }
+                (None, Some(blob)) => self.storage.size(blob)?
+                    .ok_or_else(|| Error::MissingBlob(blob.clone()))?,
+                (None, None) => match list[list.len() - 1] {
+                    Property::Inline(ref data) => data.len() as u64,
+                    _ => return Err(invalid()),
+                },
+            };
+        }
+        Ok(manifest)
     }
-}
 
-impl<S: BlobStorage, I: ObjectIndex> Store<S, I> {
-    /// Creates a store from a given blob storage and object index.
-    pub fn new(storage: S, index: I) -> Store<S, I> {
-        Store {
-            storage: storage,
-            index: index,
+    /// Adds the chunks of a file to the blob storage, returns the list of
+    /// offsets and blobs.
+    ///
+    /// Chunks of up to `MAX_INLINE_SIZE` bytes are put in the list as
+    /// `Inline` data instead, since a blob would take more space than them.
+    /// Chunks of up to `MAX_BATCH_SIZE` bytes are buffered and added together
+    /// with `add_blobs()`, larger ones are streamed to the storage.
+    fn add_chunks<R: Read>(&mut self, reader: R, chunking: &Chunking)
+        -> errors::Result<(List, usize)>
+    {
+        if self.threads > 1 {
+            return self.add_chunks_parallel(reader, chunking);
         }
+        // Copied, since the closure borrows the store
+        let salt = self.chunking.salt().copied();
+        let salt = salt.as_ref();
+        let mut chunks = Vec::new();
+        // Buffered chunks, and the position of their IDs in `chunks`
+        let mut batch = Vec::new();
+        let mut slots = Vec::new();
+        let mut batch_size = 0;
+        let size = chunking.for_each_chunk(salt, reader, |offset, chunk| {
+            let mut start = Vec::new();
+            chunk.take(MAX_BATCH_SIZE as u64 + 1)
+                .read_to_end(&mut start)
+                .map_err(|e| ("Error reading from blob", e))?;
+            chunks.push(Property::Integer(offset as i64));
+            if start.len() <= MAX_INLINE_SIZE {
+                chunks.push(Property::Inline(start));
+            } else if start.len() <= MAX_BATCH_SIZE {
+                batch_size += start.len();
+                slots.push(chunks.len());
+                // Replaced with the blob once the batch is added
+                chunks.push(Property::Integer(0));
+                batch.push(start);
+                if batch_size >= MAX_BATCH_SIZE {
+                    self.add_batch(&mut batch, &mut slots, &mut chunks)?;
+                    batch_size = 0;
+                }
+            } else {
+                self.check_free_space()?;
+                let mut rest = io::Cursor::new(start).chain(chunk);
+                let id = self.storage.add_blob_from_reader(&mut rest)?;
+                chunks.push(Property::Blob(id));
+            }
+            Ok(())
+        })?;
+        self.add_batch(&mut batch, &mut slots, &mut chunks)?;
+        Ok((chunks, size))
     }
 
-    /// Low-level; adds a blob to the blob storage.
+    /// Like `add_chunks()`, but hashes the chunks on worker threads.
     ///
-    /// To cut a blob into chunks, add them to the blob storage, and return a
-    /// list object of them, use `Store::add_file()`.
-    pub fn add_blob<R: Read>(&mut self, mut reader: R) -> errors::Result<ID> {
-        let mut blob = Vec::new();
-        reader.read_to_end(&mut blob).map_err(|e| ("Error reading blob", e))?;
-        self.storage.add_blob(&blob)
+    /// Chunks are sent to the workers through a bounded channel; this
+    /// thread keeps reading the file, and buffers the blobs as their hashes
+    /// come back, adding them in batches since the storage can't be shared.
+    /// Each ID goes to the position of its chunk in the list, so the result
+    /// doesn't depend on the order the hashes come back in.
+    fn add_chunks_parallel<R: Read>(&mut self, reader: R,
+                                    chunking: &Chunking)
+        -> errors::Result<(List, usize)>
+    {
+        let salt = self.chunking.salt().copied();
+        let salt = salt.as_ref();
+        let threads = self.threads;
+        let (sender, jobs) = mpsc::sync_channel(threads * 2);
+        let jobs = Mutex::new(jobs);
+        let (done, hashed) = mpsc::channel();
+        thread::scope(|scope| {
+            // Moved in, so the workers stop if this returns early
+            let sender = sender;
+            for _ in 0..threads {
+                let jobs = &jobs;
+                let done = done.clone();
+                scope.spawn(move || loop {
+                    let job = jobs.lock().unwrap().recv();
+                    let (slot, blob): (usize, Vec<u8>) = match job {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+                    let id = serialize::hash_blob(&blob);
+                    if done.send((slot, id, blob)).is_err() {
+                        break;
+                    }
+                });
+            }
+            drop(done);
+
+            let mut chunks = Vec::new();
+            // Hashed chunks, to be added together
+            let mut batch = Vec::new();
+            let mut batch_size = 0;
+            let size = chunking.for_each_chunk(salt, reader, |offset, chunk| {
+                for (slot, id, blob) in hashed.try_iter() {
+                    chunks[slot] = Property::Blob(id.clone());
+                    self.queue_known_blob(&mut batch, &mut batch_size, id,
+                                          blob)?;
+                }
+                let mut start = Vec::new();
+                chunk.take(MAX_BATCH_SIZE as u64 + 1)
+                    .read_to_end(&mut start)
+                    .map_err(|e| ("Error reading from blob", e))?;
+                chunks.push(Property::Integer(offset as i64));
+                if start.len() <= MAX_INLINE_SIZE {
+                    chunks.push(Property::Inline(start));
+                } else if start.len() <= MAX_BATCH_SIZE {
+                    // Replaced with the blob once it's hashed
+                    chunks.push(Property::Integer(0));
+                    sender.send((chunks.len() - 1, start)).unwrap();
+                } else {
+                    self.check_free_space()?;
+                    let mut rest = io::Cursor::new(start).chain(chunk);
+                    let id = self.storage.add_blob_from_reader(&mut rest)?;
+                    chunks.push(Property::Blob(id));
+                }
+                Ok(())
+            })?;
+            // Let the workers finish, then write what's left
+            drop(sender);
+            for (slot, id, blob) in hashed {
+                chunks[slot] = Property::Blob(id.clone());
+                self.queue_known_blob(&mut batch, &mut batch_size, id,
+                                      blob)?;
+            }
+            self.add_known_batch(&mut batch)?;
+            Ok((chunks, size))
+        })
     }
 
-    /// Low-level; gets a single blob from the blob storage.
-    pub fn get_blob(&self, id: &ID) -> errors::Result<Option<Box<[u8]>>> {
-        self.storage.get_blob(id)
+    /// Buffers a blob whose hash is known, adding the batch once it reaches
+    /// `MAX_BATCH_SIZE` bytes.
+    fn queue_known_blob(&mut self, batch: &mut Vec<(BlobId, Box<[u8]>)>,
+                        batch_size: &mut usize, id: BlobId, blob: Vec<u8>)
+        -> errors::Result<()>
+    {
+        *batch_size += blob.len();
+        batch.push((id, blob.into_boxed_slice()));
+        if *batch_size >= MAX_BATCH_SIZE {
+            self.add_known_batch(batch)?;
+            *batch_size = 0;
+        }
+        Ok(())
     }
 
-    /// Low-level; gets a single object from the index by its ID.
-    pub fn get_object(&self, id: &ID) -> errors::Result<Option<&Object>> {
-        self.index.get_object(id)
+    /// Adds buffered chunks to the blob storage, putting their IDs in the
+    /// list at the given positions.
+    fn add_batch(&mut self, batch: &mut Vec<Vec<u8>>, slots: &mut Vec<usize>,
+                 chunks: &mut List)
+        -> errors::Result<()>
+    {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let mut span = Span::enter("add_blobs");
+        span.detail(format_args!("{} blobs", batch.len()));
+        span.add_bytes(batch.iter().map(|b| b.len() as u64).sum());
+        self.check_free_space()?;
+        let blobs: Vec<&[u8]> = batch.iter().map(|b| &b[..]).collect();
+        let ids = self.storage.add_blobs(&blobs)?;
+        for (slot, id) in slots.drain(..).zip(ids) {
+            chunks[slot] = Property::Blob(id);
+        }
+        batch.clear();
+        Ok(())
     }
 
-    /// Cuts a file into chunks and add a list object of them to the index.
-    pub fn add_file<R: Read>(&mut self, reader: R)
-        -> errors::Result<(ID, usize)>
+    /// Adds buffered blobs whose hashes are known to the blob storage.
+    fn add_known_batch(&mut self, batch: &mut Vec<(BlobId, Box<[u8]>)>)
+        -> errors::Result<()>
     {
-        let mut blob = Vec::new();
-        let chunker = Chunker::new(ZPAQ::new(13)); // 8 KiB average
-        let chunker = chunker.max_size(64 * 1024); // 64 KiB hard maximum
-        let mut iter = chunker.stream(reader);
-        let mut chunks = Vec::new();
-        let mut size = 0;
-        while let Some(chunk) = iter.read() {
-            let chunk = chunk.map_err(|e| ("Error reading from blob", e))?;
-            match chunk {
-                ChunkInput::Data(d) => blob.extend_from_slice(d),
-                ChunkInput::End => {
-                    chunks.push(Property::Integer(size as i64));
-                    size += blob.len();
-                    let id = self.storage.add_blob(&blob)?;
-                    chunks.push(Property::Blob(id));
-                    blob.clear();
-                }
-            }
+        if batch.is_empty() {
+            return Ok(());
         }
-        assert!(chunks.len() % 2 == 0);
-        let nb_chunks = chunks.len() / 2;
-        let id = self.index.add(ObjectData::List(chunks))?;
-        info!("Added file contents, {} chunks, id = {}", nb_chunks, id);
-        Ok((id, size))
+        let mut span = Span::enter("add_blobs");
+        span.detail(format_args!("{} blobs", batch.len()));
+        span.add_bytes(batch.iter().map(|(_, b)| b.len() as u64).sum());
+        self.check_free_space()?;
+        let blobs: Vec<(BlobId, &[u8])> = batch.iter()
+            .map(|(id, blob)| (id.clone(), &blob[..]))
+            .collect();
+        self.storage.add_known_blobs(&blobs)?;
+        batch.clear();
+        Ok(())
     }
 
-    fn add_dir<P: AsRef<Path>>(&mut self, path: P)
-        -> errors::Result<ID>
+    /// Adds a directory, `relative` being its path from the added one.
+    fn add_dir<P: AsRef<Path>>(&mut self, path: P, relative: &str)
+        -> errors::Result<ObjectId>
     {
         let path = path.as_ref();
+        let mut span = Span::enter("add_dir");
+        span.detail(path.display());
         let mut contents = Dict::new();
         let entries = path.read_dir()
             .map_err(|e| ("Couldn't list directory to be added", e))?;
+        let mut nb_entries = 0;
         for entry in entries {
             let entry = entry.map_err(|e| ("Error reading directory", e))?;
-            let id = self.add(entry.path())?;
-            contents.insert(entry.file_name().to_string_lossy().into_owned(),
-                            Property::Reference(id));
+            self.check_cancel()?;
+            let name = entry.file_name().to_string_lossy().into_owned();
+            let relative = format!("{}/{}", relative, name);
+            if !self.ignore.is_empty() {
+                let is_dir = if self.follow_symlinks {
+                    entry.path().is_dir()
+                } else {
+                    entry.file_type().is_ok_and(|t| t.is_dir())
+                };
+                if self.ignore.is_ignored(&relative, is_dir) {
+                    info!("Excluding {:?}", entry.path());
+                    continue;
+                }
+            }
+            let (id, kind, size) = self.add_entry(&entry.path(), &relative)?;
+            contents.insert(format!("{}/type", name),
+                            Property::String(kind.as_str().into()));
+            if let Some(size) = size {
+                contents.insert(format!("{}/size", name),
+                                Property::Integer(size as i64));
+            }
+            if self.metadata {
+                let metadata = if kind == EntryKind::Symlink {
+                    fs::symlink_metadata(entry.path())
+                } else {
+                    fs::metadata(entry.path())
+                }.map_err(|e| ("Can't read metadata of added file", e))?;
+                if let Some(mtime) = metadata.modified().ok()
+                    .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                {
+                    let mtime = mtime.as_micros() as i64;
+                    contents.insert(format!("{}/mtime", name),
+                                    Property::Integer(mtime));
+                }
+                if let Some(mode) = file_mode(&metadata)
+                    .filter(|_| kind != EntryKind::Symlink)
+                {
+                    contents.insert(format!("{}/mode", name),
+                                    Property::Integer(mode as i64));
+                }
+            }
+            contents.insert(name, Property::Reference(id));
+            nb_entries += 1;
         }
-        let nb_entries = contents.len();
         let id = self.index.add(ObjectData::Dict(contents))?;
         info!("Added directory {:?}, {} entries, id = {}",
               path, nb_entries, id);
@@ -116,24 +2267,218 @@ impl<S: BlobStorage, I: ObjectIndex> Store<S, I> {
 
     /// Adds a file or directory recursively, representing directories as dicts
     /// and files as lists of blobs.
+    ///
+    /// Files that fit in a single chunk reference their blob directly,
+    /// instead of going through a list object. Directories record the kind
+    /// and size of their entries, see `dir_entries()`. The entries matching
+    /// the patterns set with `set_ignore()` are left out.
+    ///
+    /// If turned on with `set_reserve()`, this first checks that the files
+    /// fit in the free space of the blob storage, so that a large addition
+    /// fails right away rather than when the disk fills up.
     pub fn add<P: AsRef<Path>>(&mut self, path: P)
-        -> errors::Result<ID>
+        -> errors::Result<ObjectId>
     {
         let path = path.as_ref();
-        if path.is_dir() {
-            self.add_dir(path)
+        if self.reserve && self.storage.free_space()?.is_some() {
+            let estimate = self.estimate(path)?;
+            self.reserve(&estimate)?;
+        }
+        self.add_entry(path, "").map(|(id, _, _)| id)
+    }
+
+    /// Estimates what `Store::add()` would store, without reading the files.
+    ///
+    /// This walks the files the same way, with the same ignore patterns,
+    /// and counts the size of those the ingest cache knows to be unchanged
+    /// separately, as they won't be stored again.
+    pub fn estimate<P: AsRef<Path>>(&self, path: P)
+        -> errors::Result<Estimate>
+    {
+        let mut estimate = Estimate::default();
+        self.estimate_entry(path.as_ref(), "", &mut estimate)?;
+        info!("Estimated {} files, {} bytes, {} bytes unchanged",
+              estimate.files, estimate.bytes, estimate.unchanged_bytes);
+        Ok(estimate)
+    }
+
+    fn estimate_entry(&self, path: &Path, relative: &str,
+                      estimate: &mut Estimate)
+        -> errors::Result<()>
+    {
+        let is_symlink = fs::symlink_metadata(path)
+            .is_ok_and(|m| m.file_type().is_symlink());
+        if is_symlink && !self.follow_symlinks {
+            Ok(())
+        } else if path.is_dir() {
+            let entries = path.read_dir()
+                .map_err(|e| ("Couldn't list directory to be added", e))?;
+            for entry in entries {
+                let entry = entry
+                    .map_err(|e| ("Error reading directory", e))?;
+                self.check_cancel()?;
+                let name = entry.file_name();
+                let relative = format!("{}/{}", relative,
+                                       name.to_string_lossy());
+                if !self.ignore.is_empty() {
+                    let is_dir = if self.follow_symlinks {
+                        entry.path().is_dir()
+                    } else {
+                        entry.file_type().is_ok_and(|t| t.is_dir())
+                    };
+                    if self.ignore.is_ignored(&relative, is_dir) {
+                        continue;
+                    }
+                }
+                self.estimate_entry(&entry.path(), &relative, estimate)?;
+            }
+            Ok(())
         } else if path.is_file() {
-            let fp = File::open(path)
-                .map_err(|e| ("Can't open file to be added", e))?;
-            let (contents_id, size) = self.add_file(fp)?;
+            let size = fs::metadata(path)
+                .map_err(|e| ("Can't read metadata of added file", e))?
+                .len();
+            estimate.files += 1;
+            estimate.bytes += size;
+            let cache_key = match self.ingest_cache {
+                Some(_) => ingest_cache_key(path),
+                None => None,
+            };
+            if let Some((ref absolute, size, mtime)) = cache_key {
+                let cached = self.ingest_cache.as_ref()
+                    .and_then(|c| c.lookup(absolute, size, mtime));
+                if let Some(id) = cached {
+                    if self.index.get_object(id)?.is_some() {
+                        estimate.unchanged_files += 1;
+                        estimate.unchanged_bytes += size;
+                    }
+                }
+            }
+            Ok(())
+        } else {
+            Err(errors::Error::IoError("Can't find path to be added",
+                                       io::ErrorKind::NotFound.into()))
+        }
+    }
+
+    /// Checks that the blob storage has room for an addition.
+    ///
+    /// The free space has to cover the new bytes of the estimate, on top of
+    /// the threshold set with `set_min_free_space()`; otherwise this fails
+    /// with `Error::NotEnoughSpace`. Storages that don't report their free
+    /// space always have room.
+    pub fn reserve(&self, estimate: &Estimate) -> errors::Result<()> {
+        let available = match self.storage.free_space()? {
+            Some(a) => a,
+            None => return Ok(()),
+        };
+        let needed = estimate.new_bytes()
+            .saturating_add(self.min_free_space.unwrap_or(0));
+        if available < needed {
+            return Err(Error::NotEnoughSpace(needed, available));
+        }
+        info!("Addition needs about {} bytes, {} available",
+              needed, available);
+        Ok(())
+    }
+
+    /// Adds a file object, the way `Store::add()` does for files.
+    ///
+    /// Returns its ID, its size, and a description of its contents to log.
+    fn add_file_object<R: Read>(&mut self, reader: R, chunking: &Chunking)
+        -> errors::Result<(ObjectId, usize, String)>
+    {
+        let mut span = Span::enter("chunk");
+        let (chunks, size) = self.add_chunks(reader, chunking)?;
+        span.add_bytes(size as u64);
+        drop(span);
+        let (contents, described) = match chunks[..] {
+            [_, Property::Blob(ref id)] => {
+                (Property::Blob(id.clone()), id.id().str())
+            }
+            [_, Property::Inline(ref data)] => {
+                (Property::Inline(data.clone()), "inline".into())
+            }
+            _ => {
+                let id = self.index.add(ObjectData::List(chunks))?;
+                let described = id.id().str();
+                (Property::Reference(id), described)
+            }
+        };
+        let mut map = Dict::new();
+        map.insert("size".into(), Property::Integer(size as i64));
+        map.insert("contents".into(), contents);
+        let id = self.index.add(ObjectData::Dict(map))?;
+        Ok((id, size, described))
+    }
+
+    /// Adds a file or directory, returns its ID, kind and size if a file.
+    ///
+    /// `relative` is its path from the added directory, to match the
+    /// ignore patterns against.
+    ///
+    /// Symbolic links are added as a dict with their `target`, unless
+    /// following them was turned on with `Store::set_follow_symlinks()`.
+    fn add_entry(&mut self, path: &Path, relative: &str)
+        -> errors::Result<(ObjectId, EntryKind, Option<u64>)>
+    {
+        let is_symlink = fs::symlink_metadata(path)
+            .is_ok_and(|m| m.file_type().is_symlink());
+        if is_symlink && !self.follow_symlinks {
+            let target = fs::read_link(path)
+                .map_err(|e| ("Can't read symbolic link", e))?;
+            let target = match target.to_str() {
+                Some(target) => target.to_owned(),
+                None => {
+                    warn!("Symbolic link target is not valid UTF-8: {:?}",
+                          path);
+                    target.to_string_lossy().into_owned()
+                }
+            };
             let mut map = Dict::new();
-            map.insert("size".into(), Property::Integer(size as i64));
-            map.insert("contents".into(),
-                       Property::Reference(contents_id.clone()));
+            map.insert("target".into(), Property::String(target));
             let id = self.index.add(ObjectData::Dict(map))?;
+            info!("Added symbolic link {:?}, id = {}", path, id);
+            Ok((id, EntryKind::Symlink, None))
+        } else if path.is_dir() {
+            Ok((self.add_dir(path, relative)?, EntryKind::Dir, None))
+        } else if path.is_file() {
+            let cache_key = match self.ingest_cache {
+                Some(_) => ingest_cache_key(path),
+                None => None,
+            };
+            if let Some((ref absolute, size, mtime)) = cache_key {
+                let cached = self.ingest_cache.as_ref()
+                    .and_then(|c| c.lookup(absolute, size, mtime))
+                    .cloned();
+                if let Some(id) = cached {
+                    if self.index.get_object(&id)?.is_some() {
+                        info!("Unchanged file {:?}, id = {}", path, id);
+                        return Ok((id, EntryKind::File, Some(size)));
+                    }
+                }
+            }
+            let mut span = Span::enter("add_file");
+            span.detail(path.display());
+            let fp = File::open(path)
+                .map_err(|e| ("Can't open file to be added", e))?;
+            let chunking = self.chunking.for_path(path).clone();
+            let (id, size, described) = self.add_file_object(fp, &chunking)?;
+            span.add_bytes(size as u64);
             info!("Added file {:?}, size = {}, contents = {}, id = {}",
-                  path, size, contents_id, id);
-            Ok(id)
+                  path, size, described, id);
+            if let Some((absolute, cached_size, mtime)) = cache_key {
+                // A file modified within the resolution of its mtime could
+                // change again without it changing, so it's not cached
+                if cached_size == size as u64 &&
+                    mtime < timestamp_now() - 1_000_000
+                {
+                    if let Some(ref mut cache) = self.ingest_cache {
+                        cache.insert(&absolute, cached_size, mtime,
+                                     id.clone());
+                    }
+                }
+            }
+            Ok((id, EntryKind::File, Some(size as u64)))
         } else {
             return Err(errors::Error::IoError("Can't find path to be added",
                                               io::ErrorKind::NotFound.into()));
@@ -142,69 +2487,158 @@ impl<S: BlobStorage, I: ObjectIndex> Store<S, I> {
 
     /// Checks the blobs and objects for errors.
     pub fn verify(&mut self) -> errors::Result<()> {
+        let _span = Span::enter("verify");
         info!("Verifying objects...");
         self.index.verify()?;
+        self.check_cancel()?;
         info!("Verifying blobs...");
-        self.storage.verify()
+        self.storage.verify(&self.cancel)
+    }
+
+    /// Pretty-prints objects recursively to a writer.
+    ///
+    /// This uses an explicit stack rather than recursion, so deeply nested
+    /// objects don't overflow the call stack, and writes as it goes. Objects
+    /// past the limits of `options` are printed collapsed, as `{ ... }` or
+    /// `[ ... ]`. Unless `options.repeat` is set, objects that were already
+    /// expanded are only written as `(shown above)`, so that shared objects
+    /// don't make the output grow exponentially. An object referencing one of
+    /// the objects it's nested in fails with `Error::ReferenceCycle`.
+    pub fn write_object<W: Write + ?Sized>(&self, out: &mut W, id: &ObjectId,
+                                           options: &PrintOptions)
+        -> errors::Result<()>
+    {
+        self.write_value(out, &Property::Reference(id.clone()), options)
     }
 
-    fn print_property(&self, property: &Property,
-                      limit: Option<usize>,
-                      level: usize)
+    /// Pretty-prints a value, expanding it if it is a reference.
+    ///
+    /// See `write_object()`.
+    pub fn write_value<W: Write + ?Sized>(&self, out: &mut W,
+                                          value: &Property,
+                                          options: &PrintOptions)
         -> errors::Result<()>
     {
-        match *property {
-            Property::String(ref s) => print!("{:?}", s),
-            Property::Integer(i) => print!("{}", i),
-            Property::Reference(ref id) => {
-                match self.get_object(id)? {
-                    Some(obj) => self.print_obj_(obj, limit, level)?,
-                    None => print!("{} #missing#", id),
+        // The index can give owned objects, so the frames own their values
+        enum Frame {
+            Dict(ObjectId, btree_map::IntoIter<String, Property>),
+            List(ObjectId, std::vec::IntoIter<Property>),
+        }
+
+        impl Frame {
+            fn id(&self) -> &ObjectId {
+                match *self {
+                    Frame::Dict(ref id, _) | Frame::List(ref id, _) => id,
                 }
             }
-            Property::Blob(ref id) => print!("blob-{}", id),
         }
-        Ok(())
-    }
 
-    fn print_obj_(&self, object: &Object,
-                  limit: Option<usize>,
-                  mut level: usize)
-        -> errors::Result<()>
-    {
-        let recurse = limit.map_or(true, |l| level < l);
-
-        if recurse {
-            match object.data {
-                ObjectData::Dict(ref dict) => {
-                    println!("{} {{", object.id);
-                    level += 1;
-                    for (k, v) in dict {
-                        indent(level);
-                        print!("{:?} ", k);
-                        self.print_property(v, limit, level)?;
-                        println!();
+        let mut expanded = 0;
+        let mut shown = HashSet::new();
+        let mut stack = Vec::new();
+        // Writes a value, returns the frame to push if it's an object to
+        // expand
+        let mut write_value = |out: &mut W, property: &Property,
+                               stack: &[Frame]|
+            -> errors::Result<Option<Frame>>
+        {
+            let object = match *property {
+                Property::String(ref s) => {
+                    write!(out, "{:?}", s).map_err(output_error)?;
+                    return Ok(None);
+                }
+                Property::Integer(i) => {
+                    write!(out, "{}", i).map_err(output_error)?;
+                    return Ok(None);
+                }
+                Property::Blob(ref id) => {
+                    write!(out, "blob-{}", id.encode(options.ids))
+                        .map_err(output_error)?;
+                    return Ok(None);
+                }
+                Property::Inline(ref data) => {
+                    write!(out, "inline-{}", hash::base64_encode(data))
+                        .map_err(output_error)?;
+                    return Ok(None);
+                }
+                Property::Reference(ref id) => match self.get_object(id)? {
+                    Some(object) => object,
+                    None => {
+                        write!(out, "{} #missing#", id.encode(options.ids))
+                            .map_err(output_error)?;
+                        return Ok(None);
+                    }
+                },
+            };
+            if stack.iter().any(|f| *f.id() == object.id) {
+                return Err(Error::ReferenceCycle(object.id.clone()));
+            }
+            let level = stack.len();
+            let expand = options.max_depth.is_none_or(|m| level < m) &&
+                options.max_objects.is_none_or(|m| expanded < m);
+            let (open, close) = match object.data {
+                ObjectData::Dict(_) => ("{", "}"),
+                ObjectData::List(_) => ("[", "]"),
+            };
+            if !expand {
+                write!(out, "{} {} ... {}", object.id.encode(options.ids),
+                       open, close)
+                    .map_err(output_error)?;
+                return Ok(None);
+            }
+            if !options.repeat && !shown.insert(object.id.clone()) {
+                write!(out, "{} (shown above)",
+                       object.id.encode(options.ids))
+                    .map_err(output_error)?;
+                return Ok(None);
+            }
+            expanded += 1;
+            writeln!(out, "{} {}", object.id.encode(options.ids), open)
+                .map_err(output_error)?;
+            let object = object.into_owned();
+            Ok(Some(match object.data {
+                ObjectData::Dict(dict) => {
+                    Frame::Dict(object.id, dict.into_iter())
+                }
+                ObjectData::List(list) => {
+                    Frame::List(object.id, list.into_iter())
+                }
+            }))
+        };
+
+        if let Some(frame) = write_value(out, value, &stack)? {
+            stack.push(frame);
+        }
+        while !stack.is_empty() {
+            let level = stack.len();
+            let value = match stack.last_mut().unwrap() {
+                Frame::Dict(_, iter) => {
+                    iter.next().map(|(k, v)| (Some(k), v))
+                }
+                Frame::List(_, iter) => iter.next().map(|v| (None, v)),
+            };
+            match value {
+                Some((key, value)) => {
+                    indent(out, level)?;
+                    if let Some(key) = key {
+                        write!(out, "{:?} ", key).map_err(output_error)?;
                     }
-                    level -= 1;
-                    indent(level); print!("}}");
-                }
-                ObjectData::List(ref list) => {
-                    println!("{} [", object.id);
-                    level += 1;
-                    for v in list {
-                        indent(level);
-                        self.print_property(v, limit, level)?;
-                        println!();
+                    match write_value(out, &value, &stack)? {
+                        Some(frame) => stack.push(frame),
+                        None => writeln!(out).map_err(output_error)?,
+                    }
+                }
+                None => {
+                    let close = match stack.pop().unwrap() {
+                        Frame::Dict(..) => "}",
+                        Frame::List(..) => "]",
+                    };
+                    indent(out, level - 1)?;
+                    write!(out, "{}", close).map_err(output_error)?;
+                    if !stack.is_empty() {
+                        writeln!(out).map_err(output_error)?;
                     }
-                    level -= 1;
-                    indent(level);
-                    print!("]");
                 }
-            }
-        } else {
-            match object.data {
-                ObjectData::Dict(_) => println!("{} {{ ... }}", object.id),
-                ObjectData::List(_) => println!("{} [ ... ]", object.id),
             }
         }
         Ok(())
@@ -216,22 +2650,513 @@ impl<S: BlobStorage, I: ObjectIndex> Store<S, I> {
     /// we'll print; for example, `Some(1)` means that objects directly
     /// referenced from the given one will be expanded, but not objects
     /// referenced from those.
-    pub fn print_object(&self, id: &ID, limit: Option<usize>)
+    pub fn print_object(&self, id: &ObjectId, limit: Option<usize>)
+        -> errors::Result<()>
+    {
+        let options = PrintOptions { max_depth: limit, ..Default::default() };
+        let stdout = io::stdout();
+        let mut out = io::BufWriter::new(stdout.lock());
+        self.render_object(&mut out, id, Format::Pretty, &options)?;
+        writeln!(out).and_then(|()| out.flush()).map_err(output_error)
+    }
+
+    /// Writes an object in the given format, see `render_value()`.
+    pub fn render_object(&self, out: &mut dyn Write, id: &ObjectId,
+                         format: Format, options: &PrintOptions)
         -> errors::Result<()>
     {
-        self.print_property(&Property::Reference(id.clone()), limit, 0)?;
-        println!();
+        self.render_value(out, &Property::Reference(id.clone()), format,
+                          options)
+    }
+
+    /// Writes a value in the given format, without a final newline.
+    ///
+    /// `options` limits the expansion of references, in the formats that
+    /// expand them. `Format::BencodeHex` only applies to references, and
+    /// fails with `Error::MissingObject` if the object is not there.
+    pub fn render_value(&self, out: &mut dyn Write, value: &Property,
+                        format: Format, options: &PrintOptions)
+        -> errors::Result<()>
+    {
+        match format {
+            Format::Pretty => self.write_value(out, value, options),
+            Format::Json => {
+                let mut json = String::new();
+                self.write_json(&mut json, value, options, &mut 0,
+                                &mut HashSet::new(), &mut Vec::new())?;
+                out.write_all(json.as_bytes()).map_err(output_error)
+            }
+            Format::BencodeHex => {
+                let id = match *value {
+                    Property::Reference(ref id) => id,
+                    _ => return Err(Error::InvalidInput(
+                        "Only objects can be written as bencode")),
+                };
+                let object = self.get_object(id)?
+                    .ok_or_else(|| Error::MissingObject(id.clone()))?;
+                let mut bytes = Vec::new();
+                serialize::serialize(&mut bytes, &object)
+                    .map_err(output_error)?;
+                for byte in bytes {
+                    write!(out, "{:02x}", byte).map_err(output_error)?;
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes a value as JSON, expanding the objects it references.
+    ///
+    /// `expanded` counts the objects expanded so far, for
+    /// `options.max_objects`, `shown` holds them for `options.repeat`, and
+    /// `parents` are the objects it's nested in. Objects already shown are
+    /// written as `{"ref": ID, "shown": true}`.
+    fn write_json(&self, out: &mut String, value: &Property,
+                  options: &PrintOptions, expanded: &mut usize,
+                  shown: &mut HashSet<ObjectId>, parents: &mut Vec<ObjectId>)
+        -> errors::Result<()>
+    {
+        let id = match *value {
+            Property::Reference(ref id) => id,
+            _ => {
+                json::write_property(out, value);
+                return Ok(());
+            }
+        };
+        let object = match self.get_object(id)? {
+            Some(object) => object,
+            None => {
+                out.push_str("{\"ref\":");
+                json::write_str(out, &id.id().str());
+                out.push_str(",\"missing\":true}");
+                return Ok(());
+            }
+        };
+        if parents.contains(id) {
+            return Err(Error::ReferenceCycle(id.clone()));
+        }
+        let expand = options.max_depth.is_none_or(|m| parents.len() < m) &&
+            options.max_objects.is_none_or(|m| *expanded < m);
+        if !expand {
+            json::write_property(out, value);
+            return Ok(());
+        }
+        if !options.repeat && !shown.insert(id.clone()) {
+            out.push_str("{\"ref\":");
+            json::write_str(out, &id.id().str());
+            out.push_str(",\"shown\":true}");
+            return Ok(());
+        }
+        *expanded += 1;
+        out.push_str("{\"ref\":");
+        json::write_str(out, &id.id().str());
+        out.push_str(",\"object\":");
+        parents.push(id.clone());
+        match object.data {
+            ObjectData::Dict(ref d) => {
+                out.push('{');
+                for (i, (key, value)) in d.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    json::write_str(out, key);
+                    out.push(':');
+                    self.write_json(out, value, options, expanded, shown,
+                                    parents)?;
+                }
+                out.push('}');
+            }
+            ObjectData::List(ref l) => {
+                out.push('[');
+                for (i, value) in l.iter().enumerate() {
+                    if i > 0 {
+                        out.push(',');
+                    }
+                    self.write_json(out, value, options, expanded, shown,
+                                    parents)?;
+                }
+                out.push(']');
+            }
+        }
+        parents.pop();
+        out.push('}');
+        Ok(())
+    }
+}
+
+impl<S: EnumerableBlobStorage, I: ObjectIndex> Store<S, I> {
+    pub fn collect_garbage(&mut self) -> errors::Result<()> {
+        let _span = Span::enter("gc");
+        info!("Collecting objects...");
+        let live_blobs = self.index.collect_garbage()?;
+        self.check_cancel()?;
+        info!("Collecting blobs...");
+        self.storage.collect_garbage(live_blobs, &self.cancel)?;
+        self.record_event("gc", Dict::new())?;
         Ok(())
     }
+
+    /// Parses an ID, which can be abbreviated to its first characters.
+    ///
+    /// Full IDs can be in any encoding, see `ID::decode()`, while prefixes
+    /// are of the base64 form. A prefix is looked up in both the objects and
+    /// the blobs. Fails with `Error::AmbiguousPrefix` if it matches more than
+    /// one of them.
+    pub fn resolve_id(&self, id: &str) -> errors::Result<ID> {
+        if let Some(id) = ID::decode(id.as_bytes()) {
+            return Ok(id);
+        }
+        if id.is_empty() || id.len() >= HASH_STR_SIZE {
+            return Err(Error::InvalidInput("Input is not a valid ID"));
+        }
+        let object = self.index.resolve_prefix(id)?;
+        let blob = self.storage.resolve_prefix(id)?;
+        match (object, blob) {
+            (Some(a), Some(b)) if a.id() != b.id() => {
+                Err(Error::AmbiguousPrefix(id.to_owned()))
+            }
+            (Some(id), _) => Ok(id.into()),
+            (None, Some(id)) => Ok(id.into()),
+            (None, None) => Err(Error::InvalidInput(
+                "No object or blob matches this ID")),
+        }
+    }
+
+    /// Parses the ID of an object, which can be abbreviated.
+    ///
+    /// Unlike `resolve_id()`, a prefix is only looked up in the objects, and
+    /// a blob is refused.
+    pub fn resolve_object(&self, id: &str) -> errors::Result<ObjectId> {
+        let found = match ObjectId::decode(id.as_bytes()) {
+            Some(id) => Some(id),
+            None => {
+                if id.is_empty() || id.len() >= HASH_STR_SIZE {
+                    return Err(Error::InvalidInput(
+                        "Input is not a valid ID"));
+                }
+                self.index.resolve_prefix(id)?
+            }
+        };
+        match found {
+            Some(id) => {
+                if self.index.get_object(&id)?.is_none() &&
+                    self.storage.contains(&BlobId::from_id(id.id().clone()))?
+                {
+                    return Err(Error::InvalidInput(
+                        "This is a blob, not an object"));
+                }
+                Ok(id)
+            }
+            None => Err(Error::InvalidInput("No object matches this ID")),
+        }
+    }
+
+    /// Parses the ID of a blob, which can be abbreviated.
+    ///
+    /// A prefix is only looked up in the blobs, and an object is refused.
+    pub fn resolve_blob(&self, id: &str) -> errors::Result<BlobId> {
+        let found = match BlobId::decode(id.as_bytes()) {
+            Some(id) => Some(id),
+            None => {
+                if id.is_empty() || id.len() >= HASH_STR_SIZE {
+                    return Err(Error::InvalidInput(
+                        "Input is not a valid ID"));
+                }
+                self.storage.resolve_prefix(id)?
+            }
+        };
+        match found {
+            Some(id) => {
+                let object = ObjectId::from_id(id.id().clone());
+                if self.index.get_object(&object)?.is_some() {
+                    return Err(Error::InvalidInput(
+                        "This is an object, not a blob"));
+                }
+                Ok(id)
+            }
+            None => Err(Error::InvalidInput("No blob matches this ID")),
+        }
+    }
+
+    /// Compares the objects and blobs of this store with another one.
+    ///
+    /// All the objects are compared, live or not. This is useful to check
+    /// whether a replica can be retired, or what merging it would bring.
+    pub fn compare<S2, I2>(&self, other: &Store<S2, I2>)
+        -> errors::Result<Comparison>
+        where S2: EnumerableBlobStorage, I2: ObjectIndex
+    {
+        let objects = |store: &dyn ObjectIndex| {
+            store.list_objects()?
+                .map(|o| o.map(|o| ID::from(o.id.clone())))
+                .collect::<errors::Result<HashSet<ID>>>()
+        };
+        let blobs = |store: &dyn EnumerableBlobStorage| {
+            store.list_blobs()?
+                .map(|b| b.map(ID::from))
+                .collect::<errors::Result<HashSet<ID>>>()
+        };
+        let comparison = Comparison {
+            objects: Difference::new(objects(&self.index)?,
+                                     objects(&other.index)?),
+            blobs: Difference::new(blobs(&self.storage)?,
+                                   blobs(&other.storage)?),
+        };
+        info!("Compared stores: {} objects and {} blobs in both",
+              comparison.objects.both, comparison.blobs.both);
+        Ok(comparison)
+    }
+
+    /// Counts the objects and blobs, and how much space they take.
+    ///
+    /// This reads every object, and the size of every blob.
+    pub fn stats(&self) -> errors::Result<Stats> {
+        let mut stats = Stats::default();
+        for object in self.index.list_objects()? {
+            let object = object?;
+            stats.objects += 1;
+            *stats.object_types.entry(object_type(&object).to_owned())
+                .or_insert(0) += 1;
+            let size = serialize::canonical_bytes(&object.data).len() as u64;
+            stats.object_bytes += size;
+            stats.largest.push((size, object.id.clone()));
+            if stats.largest.len() > 2 * LARGEST_OBJECTS {
+                stats.largest.sort_by(|a, b| b.cmp(a));
+                stats.largest.truncate(LARGEST_OBJECTS);
+            }
+            if let ObjectData::Dict(ref d) = object.data {
+                if let Some((size, _)) = file_contents(d) {
+                    stats.file_bytes += size.max(0) as u64;
+                }
+            }
+        }
+        stats.largest.sort_by(|a, b| b.cmp(a));
+        stats.largest.truncate(LARGEST_OBJECTS);
+        for blob in self.storage.list_blobs()? {
+            let blob = blob?;
+            stats.blobs += 1;
+            match self.storage.size(&blob)? {
+                Some(size) => stats.blob_bytes += size,
+                None => warn!("Blob disappeared: {}", blob),
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Lists the blobs that are not referenced from any live object.
+    ///
+    /// These are the blobs `collect_garbage()` would delete.
+    pub fn orphan_blobs(&self) -> errors::Result<Vec<BlobId>> {
+        let live_blobs = self.index.live_blobs()?;
+        let mut orphans = Vec::new();
+        for blob in self.storage.list_blobs()? {
+            let blob = blob?;
+            if !live_blobs.contains(&blob) {
+                orphans.push(blob);
+            }
+        }
+        info!("Found {} orphan blobs", orphans.len());
+        Ok(orphans)
+    }
+
+    /// Copies the orphan blobs to a directory, as files named by their ID.
+    ///
+    /// The directory is created if needed; existing files are not
+    /// overwritten. Returns the number of blobs written.
+    pub fn export_orphans<P: AsRef<Path>>(&self, dest: P)
+        -> errors::Result<usize>
+    {
+        let dest = dest.as_ref();
+        fs::create_dir_all(dest)
+            .map_err(|e| ("Couldn't create export directory", e))?;
+        let mut count = 0;
+        for id in self.orphan_blobs()? {
+            let mut reader = match self.storage.get_blob_reader(&id)? {
+                Some(reader) => reader,
+                None => {
+                    warn!("Orphan blob disappeared: {}", id);
+                    continue;
+                }
+            };
+            let path = dest.join(id.id().str());
+            if path.exists() {
+                debug!("Already exported: {}", id);
+                continue;
+            }
+            let mut fp = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&path)
+                .map_err(|e| ("Couldn't create export file", e))?;
+            io::copy(&mut reader, &mut fp)
+                .map_err(|e| ("Error writing export file", e))?;
+            info!("Exported {}", id);
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Gets the permission bits of a file, to record them.
+#[cfg(unix)]
+fn file_mode(metadata: &fs::Metadata) -> Option<u32> {
+    use std::os::unix::fs::PermissionsExt;
+
+    Some(metadata.permissions().mode() & 0o7777)
+}
+
+#[cfg(not(unix))]
+fn file_mode(_metadata: &fs::Metadata) -> Option<u32> {
+    None
+}
+
+/// Sets the permission bits of an extracted file.
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    fs::set_permissions(path, fs::Permissions::from_mode(mode))
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: u32) -> io::Result<()> {
+    Ok(())
+}
+
+/// Creates an extracted symbolic link.
+#[cfg(unix)]
+fn make_symlink(target: &str, path: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, path)
+}
+
+#[cfg(not(unix))]
+fn make_symlink(_target: &str, _path: &Path) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported,
+                       "symbolic links are only extracted on Unix"))
+}
+
+/// Sets the modification time and permissions of an extracted file or
+/// directory, if they were recorded.
+///
+/// The time is set first, as the permissions might not allow opening it.
+/// The setuid, setgid and sticky bits are not restored, like `tar` without
+/// `-p`, since the extracted files might not belong to whoever added them.
+fn restore_metadata(path: &Path, entry: &DirEntry) -> errors::Result<()> {
+    if let Some(mtime) = entry.mtime {
+        let time = if mtime >= 0 {
+            UNIX_EPOCH + Duration::from_micros(mtime as u64)
+        } else {
+            UNIX_EPOCH - Duration::from_micros(mtime.unsigned_abs())
+        };
+        File::open(path)
+            .and_then(|f| f.set_modified(time))
+            .map_err(|e| ("Can't set modification time", e))?;
+    }
+    if let Some(mode) = entry.mode {
+        set_mode(path, mode & 0o777)
+            .map_err(|e| ("Can't set permissions", e))?;
+    }
+    Ok(())
+}
+
+/// Iterates on the properties of an object, the values for a dict.
+fn object_properties(object: &Object)
+    -> Box<dyn Iterator<Item = &Property> + '_>
+{
+    match object.data {
+        ObjectData::Dict(ref dict) => Box::new(dict.values()),
+        ObjectData::List(ref list) => Box::new(list.iter()),
+    }
+}
+
+/// Iterates on the blobs directly referenced by an object.
+fn object_blobs(object: &Object) -> impl Iterator<Item = BlobId> + '_ {
+    object_properties(object).filter_map(|p| match p {
+        Property::Blob(id) => Some(id.clone()),
+        _ => None,
+    })
+}
+
+/// Gets the size and contents of a file object, as added by `Store::add()`.
+///
+/// The contents are either a reference to a list of chunks or, for files
+/// that fit in a single chunk, a blob or inline data. Returns `None` if this
+/// isn't a file.
+pub fn file_contents(dict: &Dict) -> Option<(i64, &Property)> {
+    match (dict.len(), dict.get("size"), dict.get("contents")) {
+        (2, Some(&Property::Integer(size)), Some(contents)) => match contents {
+            Property::Reference(_) | Property::Blob(_) |
+            Property::Inline(_) => Some((size, contents)),
+            _ => None,
+        },
+        _ => None,
+    }
 }
 
-impl<S: EnumerableBlobStorage, I: ObjectIndex> Store<S, I> {
-    pub fn collect_garbage(&mut self) -> errors::Result<()> {
-        info!("Collecting objects...");
-        let live_blobs = self.index.collect_garbage()?;
-        info!("Collecting blobs...");
-        self.storage.collect_garbage(live_blobs)
+/// Gets the target of a symbolic link object, as added by `Store::add()`.
+///
+/// Returns `None` if this isn't a symbolic link.
+pub fn symlink_target(dict: &Dict) -> Option<&str> {
+    match (dict.len(), dict.get("target")) {
+        (1, Some(Property::String(target))) => Some(target),
+        _ => None,
+    }
+}
+
+/// Gets the entries of a directory object, as added by `Store::add()`.
+///
+/// A directory maps each name to a reference. Next to it, the key
+/// `name/type` gives the kind of the entry ("file", "dir" or "symlink"), and
+/// `name/size` the size of files, so they can be listed without loading
+/// each entry; since names can't contain slashes, these keys can't clash
+/// with entries. Directories added by older versions lack them. Unless
+/// disabled with `Store::set_metadata()`, `name/mtime` and `name/mode` give
+/// the modification time and permissions of the entry.
+///
+/// Returns `None` if this isn't a directory.
+pub fn dir_entries(dict: &Dict) -> Option<Vec<DirEntry>> {
+    let mut entries = Vec::new();
+    for (key, value) in dict {
+        if let Some((name, field)) = key.split_once('/') {
+            // Metadata about an entry
+            let valid = matches!((field, value),
+                                 ("type", Property::String(_)) |
+                                 ("size", Property::Integer(_)) |
+                                 ("mtime", Property::Integer(_)) |
+                                 ("mode", Property::Integer(_)));
+            match dict.get(name) {
+                Some(Property::Reference(_)) if valid => continue,
+                _ => return None,
+            }
+        }
+        let id = match value {
+            Property::Reference(id) => id.clone(),
+            _ => return None,
+        };
+        let kind = match dict.get(&format!("{}/type", key)) {
+            Some(Property::String(kind)) => Some(EntryKind::parse(kind)?),
+            _ => None,
+        };
+        let size = match dict.get(&format!("{}/size", key)) {
+            Some(&Property::Integer(size)) if size >= 0 => Some(size as u64),
+            Some(_) => return None,
+            None => None,
+        };
+        let mtime = match dict.get(&format!("{}/mtime", key)) {
+            Some(&Property::Integer(mtime)) => Some(mtime),
+            _ => None,
+        };
+        let mode = match dict.get(&format!("{}/mode", key)) {
+            Some(&Property::Integer(mode)) if (0..=0o7777).contains(&mode) => {
+                Some(mode as u32)
+            }
+            Some(_) => return None,
+            None => None,
+        };
+        entries.push(DirEntry { name: key.clone(), id, kind, size, mtime,
+                                mode });
     }
+    Some(entries)
 }
 
 pub fn permanode(mut data: Dict, sort: Sort) -> Object {
@@ -244,48 +3169,177 @@ pub fn permanode(mut data: Dict, sort: Sort) -> Object {
     serialize::hash_object(ObjectData::Dict(data))
 }
 
+/// A `Store` whose components are picked at runtime.
+///
+/// This is what `open()` returns. The blob storage has to be enumerable so
+/// that garbage can be collected.
+pub type DynStore = Store<Box<dyn EnumerableBlobStorage>,
+                         Box<dyn ObjectIndex>>;
+
+/// Handle on a store shared between threads.
+///
+/// Made with `Store::into_shared()`. More handles on the same store are made
+/// with `try_clone_handle()`, for example for a server and its background
+/// jobs; the store is only opened once, and its index loaded once. Each
+/// handle locks the store to use it, so operations from different threads
+/// run one at a time.
+pub struct SharedStore<S, I>
+    where S: BlobStorage + Send, I: ObjectIndex + Send
+{
+    store: Arc<Mutex<Store<S, I>>>,
+}
+
+impl<S, I> SharedStore<S, I>
+    where S: BlobStorage + Send, I: ObjectIndex + Send
+{
+    /// Makes another handle on the same store.
+    ///
+    /// Fails with `Error::Poisoned` if a thread panicked while it had the
+    /// store locked.
+    pub fn try_clone_handle(&self) -> errors::Result<SharedStore<S, I>> {
+        if self.store.is_poisoned() {
+            return Err(Error::Poisoned);
+        }
+        Ok(SharedStore { store: self.store.clone() })
+    }
+
+    /// Locks the store, waiting for other threads to be done with it.
+    ///
+    /// Fails with `Error::Poisoned` if a thread panicked while it had the
+    /// store locked.
+    pub fn lock(&self) -> errors::Result<MutexGuard<'_, Store<S, I>>> {
+        self.store.lock().map_err(|_| Error::Poisoned)
+    }
+
+    /// Number of handles on the store, including this one.
+    pub fn handles(&self) -> usize {
+        Arc::strong_count(&self.store)
+    }
+}
+
+/// Makes a claim, associating a value to a permanode.
+///
+/// `data` should contain the permanode's sort field.
+pub fn claim(node: &ObjectId, value: &ObjectId, mut data: Dict) -> ObjectData {
+    data.insert("dhstore_kind".into(), Property::String("claim".into()));
+    data.insert("node".into(), Property::Reference(node.clone()));
+    data.insert("value".into(), Property::Reference(value.clone()));
+    ObjectData::Dict(data)
+}
+
+/// Makes a checkpoint, summarizing the values of a permanode.
+///
+/// `members` is a list object of alternating sort keys and references to the
+/// values, for the values up to the sort key `upto`.
+pub fn checkpoint(node: &ObjectId, upto: Property, members: &ObjectId)
+    -> ObjectData
+{
+    let mut data = Dict::new();
+    data.insert("dhstore_kind".into(),
+                Property::String("checkpoint".into()));
+    data.insert("node".into(), Property::Reference(node.clone()));
+    data.insert("upto".into(), upto);
+    data.insert("members".into(), Property::Reference(members.clone()));
+    ObjectData::Dict(data)
+}
+
+/// Makes a tombstone, marking a permanode or claim as deleted.
+pub fn tombstone(target: &ObjectId, mut data: Dict) -> ObjectData {
+    data.insert("dhstore_kind".into(),
+                Property::String("tombstone".into()));
+    data.insert("target".into(), Property::Reference(target.clone()));
+    ObjectData::Dict(data)
+}
+
+/// Absolute path, size and modification time of a file, to look it up in the
+/// ingest cache.
+fn ingest_cache_key(path: &Path) -> Option<(PathBuf, u64, i64)> {
+    let metadata = fs::metadata(path).ok()?;
+    let mtime = metadata.modified().ok()?
+        .duration_since(UNIX_EPOCH).ok()?
+        .as_micros() as i64;
+    let absolute = std::path::absolute(path).ok()?;
+    Some((absolute, metadata.len(), mtime))
+}
+
+/// Current time, in microseconds since the Unix epoch.
+fn timestamp_now() -> i64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)
+        .expect("System clock is before the Unix epoch");
+    now.as_micros() as i64
+}
+
 /// Opens a directory.
 ///
-/// This uses the `FileBlobStorage` and `MemoryIndex` to create a `Store` from a
-/// filesystem directory.
-pub fn open<P: AsRef<Path>>(path: P)
-    -> errors::Result<Store<FileBlobStorage, MemoryIndex>>
+/// This creates a `Store` from a filesystem directory. The index is selected
+/// from the layout of the directory (a `DiskIndex` if it has an `index`
+/// directory, else a `MemoryIndex` of the files in `objects`), and the blob
+/// storage is built from the URL in the root config, using the
+/// backends built into dhstore; use `open_with()` to provide others.
+pub fn open<P: AsRef<Path>>(path: P) -> errors::Result<DynStore> {
+    open_with(path, &Registry::with_builtins())
+}
+
+/// Opens a directory, building the blob storage from the given `Registry`.
+///
+/// Use a `StoreBuilder` for more control over the components.
+pub fn open_with<P: AsRef<Path>>(path: P, registry: &Registry)
+    -> errors::Result<DynStore>
 {
     let path = path.as_ref();
 
     fs::metadata(path).map_err(|e| ("Store path doesn't exist", e))?;
 
-    // Get the ID of the root config -- the configuration is loaded from the
-    // index itself but we need a trust anchor
-    let root_config = {
-        let mut fp = File::open(path.join("root"))
-            .map_err(|e| ("Can't open root config file", e))?;
-        let mut buf = Vec::new();
-        fp.read_to_end(&mut buf)
-            .map_err(|e| ("Error reading root config file", e))?;
-        ID::from_str(&buf)
-            .ok_or(Error::CorruptedStore("Invalid root config file"))?
-    };
+    StoreBuilder::new()
+        .path(path)
+        .registry(registry)
+        .open()
+}
 
-    // Create a file blob storage, storing blobs as single files
-    let storage = {
-        FileBlobStorage::open(path.join("blobs"))
-    };
+/// Largest chunk stored inline in the list of chunks, rather than as a blob.
+const MAX_INLINE_SIZE: usize = 128;
 
-    // Create a memory index, that stores all the objects in memory, and
-    // has to load all of them everytime from simple files
-    let index = {
-        MemoryIndex::open(path.join("objects"), root_config)?
-    };
+/// Amount of chunk data buffered before adding it to the blob storage.
+const MAX_BATCH_SIZE: usize = 1024 * 1024;
 
-    // Create the Store object
-    Ok(Store::new(storage, index))
-}
+/// Location of the blobs, for stores that don't specify one in their config.
+const DEFAULT_BLOBS_URL: &str = "file://blobs";
 
-/// Creates a new store on disk.
-pub fn create<P: AsRef<Path>>(path: P) -> errors::Result<()> {
+/// Finds the objects that could be the root config of a store.
+///
+/// This is a disaster-recovery tool for stores whose root file was lost: it
+/// reads all the objects, and returns the dicts that are not referenced by any
+/// other object. Those that look like a root config come first. Use
+/// `set_root()` to make one of them the root.
+pub fn root_candidates<P: AsRef<Path>>(path: P)
+    -> errors::Result<Vec<Object>>
+{
     let path = path.as_ref();
+    if path.join("index").is_dir() {
+        DiskIndex::root_candidates(path.join("index"))
+    } else {
+        MemoryIndex::root_candidates(path.join("objects"))
+    }
+}
+
+/// Writes the root file of a store, pointing to the given root config.
+///
+/// This fails if the store already has a root file.
+pub fn set_root<P: AsRef<Path>>(path: P, root_config: &ObjectId)
+    -> errors::Result<()>
+{
+    let mut fp = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path.as_ref().join("root"))
+        .map_err(|e| ("Couldn't create root config file", e))?;
+    fp.write_all(root_config.id().str().as_bytes())
+        .map_err(|e| ("Couldn't write root config", e))?;
+    Ok(())
+}
 
+/// Creates the directory structure of a new store.
+fn create_layout(path: &Path) -> errors::Result<()> {
     // Create directory
     if path.is_dir() {
         if path.read_dir()
@@ -310,6 +3364,59 @@ pub fn create<P: AsRef<Path>>(path: P) -> errors::Result<()> {
     ::std::fs::create_dir(path.join("objects"))
         .map_err(|e| ("Couldn't create directory", e))?;
 
+    Ok(())
+}
+
+/// Creates a new store on disk.
+pub fn create<P: AsRef<Path>>(path: P) -> errors::Result<()> {
+    create_with_config(path.as_ref(), Dict::new())
+}
+
+/// Opens a staging area of the store at `path`, see `Staging`.
+///
+/// Its blobs are kept in `staging/NAME` in the store's directory, where they
+/// stay if the process exits before they are promoted; opening the same
+/// name again picks them up.
+pub fn staging_area<P: AsRef<Path>>(path: P, name: &str)
+    -> errors::Result<Staging<FileBlobStorage>>
+{
+    if name.is_empty() || name.starts_with('.') ||
+        name.contains(['/', '\\'])
+    {
+        return Err(Error::InvalidInput("Invalid staging area name"));
+    }
+    let dir = path.as_ref().join("staging").join(name);
+    fs::create_dir_all(&dir)
+        .map_err(|e| ("Couldn't create staging directory", e))?;
+    Ok(Staging::new(FileBlobStorage::open(dir)))
+}
+
+/// Opens the ingest cache of the store at `path`, see `IngestCache`.
+///
+/// It is kept in `ingest_cache` in the store's directory.
+pub fn ingest_cache<P: AsRef<Path>>(path: P)
+    -> errors::Result<IngestCache>
+{
+    IngestCache::open(path.as_ref().join("ingest_cache"))
+}
+
+/// Creates a new store on disk, with a random chunking salt.
+///
+/// Chunk boundaries then differ from other stores, so blob sizes don't
+/// reveal which files it contains; see `ChunkingConfig::set_salt()`.
+pub fn create_salted<P: AsRef<Path>>(path: P) -> errors::Result<()> {
+    let mut salt = [0u8; HASH_SIZE];
+    rand::thread_rng().fill_bytes(&mut salt);
+    let mut config = Dict::new();
+    config.insert("chunking_salt".into(),
+                  Property::String(hash::base64_encode(&salt)));
+    create_with_config(path.as_ref(), config)
+}
+
+/// Creates a new store, adding `config` to its root config.
+fn create_with_config(path: &Path, mut config: Dict) -> errors::Result<()> {
+    create_layout(path)?;
+
     // Create root config
     {
         let mut fp = OpenOptions::new()
@@ -324,10 +3431,11 @@ pub fn create<P: AsRef<Path>>(path: P) -> errors::Result<()> {
         let log = permanode(log, Sort::Ascending("date".into()));
 
         // Config object
-        let mut config = Dict::new();
         config.insert("log".into(), Property::Reference(log.id.clone()));
+        config.insert("blobs".into(),
+                      Property::String(DEFAULT_BLOBS_URL.into()));
         let config = serialize::hash_object(ObjectData::Dict(config));
-        let config_id = config.id.str();
+        let config_id = config.id.id().str();
 
         MemoryIndex::create(path.join("objects"), vec![log, config].iter())
             .map_err(|e| ("Couldn't write objects", e))?;
@@ -339,3 +3447,855 @@ pub fn create<P: AsRef<Path>>(path: P) -> errors::Result<()> {
 
     Ok(())
 }
+
+/// Creates a new store as a replica of an existing one.
+///
+/// All the objects are copied, including the root config, claims and log,
+/// so the history is preserved; then the blobs missing from the new store's
+/// blob storage are copied over. The clone is recorded in the new store's
+/// log.
+pub fn clone<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q)
+    -> errors::Result<()>
+{
+    let source = source.as_ref();
+    let dest = dest.as_ref();
+    let source_store = open(source)?;
+
+    create_layout(dest)?;
+
+    // Copy the objects, then point to the same root config
+    let nb_objects = if source.join("index").is_dir() {
+        fs::remove_dir(dest.join("objects"))
+            .map_err(|e| ("Couldn't remove objects directory", e))?;
+        DiskIndex::copy_objects(source.join("index"), dest.join("index"))?
+    } else {
+        MemoryIndex::copy_objects(source.join("objects"),
+                                  dest.join("objects"))?
+    };
+    info!("Copied {} objects", nb_objects);
+    set_root(dest, source_store.index.root())?;
+
+    // Copy the blobs
+    let mut dest_store = open(dest)?;
+    let mut nb_blobs = 0;
+    for id in source_store.storage.list_blobs()? {
+        let id = id?;
+        if dest_store.storage.contains(&id)? {
+            continue;
+        }
+        match source_store.storage.get_blob_reader(&id)? {
+            Some(mut reader) => {
+                if dest_store.storage.add_blob_from_reader(&mut reader)? != id
+                {
+                    warn!("Blob has the wrong hash: {}", id);
+                }
+                nb_blobs += 1;
+            }
+            None => warn!("Blob disappeared during clone: {}", id),
+        }
+    }
+    info!("Copied {} blobs", nb_blobs);
+
+    let mut details = Dict::new();
+    details.insert("source".into(),
+                   Property::String(source.to_string_lossy().into_owned()));
+    dest_store.record_event("clone", details)?;
+    Ok(())
+}
+
+/// Moves the objects of a store to a `DiskIndex`.
+///
+/// The object files are written to the log of the new index, which then
+/// replaces the `objects` directory. Opening the store afterwards uses the
+/// `DiskIndex`.
+pub fn convert_to_disk_index<P: AsRef<Path>>(path: P)
+    -> errors::Result<usize>
+{
+    let path = path.as_ref();
+    if path.join("index").exists() {
+        return Err(Error::InvalidInput("Store already has a disk index"));
+    }
+    let temp = path.join("index.new");
+    if temp.exists() {
+        info!("Removing leftover index {:?}", temp);
+        fs::remove_dir_all(&temp)
+            .map_err(|e| ("Couldn't remove leftover index", e))?;
+    }
+    let nb_objects = DiskIndex::import(path.join("objects"), &temp)?;
+    fs::rename(&temp, path.join("index"))
+        .map_err(|e| ("Couldn't move index in place", e))?;
+    fs::remove_dir_all(path.join("objects"))
+        .map_err(|e| ("Couldn't remove objects directory", e))?;
+    info!("Moved {} objects to the disk index", nb_objects);
+    Ok(nb_objects)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+    use std::fs::{self, File};
+    use std::io::Read;
+    use std::path::{Path, PathBuf};
+    use std::time::{Duration, SystemTime};
+
+    use std::collections::HashSet;
+
+    use crate::common::{BlobId, BlobIterator, BlobStorage, Dict,
+                        EnumerableBlobStorage, HASH_SIZE, ID, MemberPage,
+                        Object, ObjectData, ObjectId, ObjectIndex,
+                        ObjectIterator, Property, Sort};
+    use crate::errors::{self, Error};
+    use crate::memory_index::MemoryIndex;
+    use crate::queries::Cancel;
+    use super::{ChangeKind, DirEntry, DynStore, EntryKind, Estimate,
+                Existing, Extracted, IgnoreRules, IngestCache, StoreBuilder,
+                claim, convert_to_disk_index, create, dir_entries, open,
+                permanode, symlink_target};
+
+    /// Creates a store in a new temporary directory.
+    fn temp_store(name: &str) -> (PathBuf, DynStore) {
+        let path = std::env::temp_dir()
+            .join(format!("dhstore-test-{}-{}", name, std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        create(&path).unwrap();
+        let store = open(&path).unwrap();
+        (path, store)
+    }
+
+    /// Blob storage reporting a fixed amount of free space.
+    struct FreeSpace(Box<dyn EnumerableBlobStorage>, u64);
+
+    impl BlobStorage for FreeSpace {
+        fn get_blob(&self, id: &BlobId) -> errors::Result<Option<Box<[u8]>>> {
+            self.0.get_blob(id)
+        }
+
+        fn add_blob(&mut self, blob: &[u8]) -> errors::Result<BlobId> {
+            self.0.add_blob(blob)
+        }
+
+        fn add_known_blob(&mut self, id: &BlobId, blob: &[u8])
+            -> errors::Result<()>
+        {
+            self.0.add_known_blob(id, blob)
+        }
+
+        fn delete_blob(&mut self, id: &BlobId) -> errors::Result<()> {
+            self.0.delete_blob(id)
+        }
+
+        fn verify(&mut self, cancel: &Cancel) -> errors::Result<()> {
+            self.0.verify(cancel)
+        }
+
+        fn free_space(&self) -> errors::Result<Option<u64>> {
+            Ok(Some(self.1))
+        }
+    }
+
+    impl EnumerableBlobStorage for FreeSpace {
+        fn list_blobs(&self) -> errors::Result<BlobIterator> {
+            self.0.list_blobs()
+        }
+    }
+
+    /// Index with an extra object under a made-up ID, so that it can
+    /// reference itself.
+    struct Forged(MemoryIndex, Object);
+
+    impl ObjectIndex for Forged {
+        fn add(&mut self, data: ObjectData) -> errors::Result<ObjectId> {
+            self.0.add(data)
+        }
+
+        fn get_object(&self, id: &ObjectId)
+            -> errors::Result<Option<Cow<'_, Object>>>
+        {
+            if *id == self.1.id {
+                return Ok(Some(Cow::Borrowed(&self.1)));
+            }
+            self.0.get_object(id)
+        }
+
+        fn list_objects(&self) -> errors::Result<ObjectIterator<'_>> {
+            self.0.list_objects()
+        }
+
+        fn root(&self) -> &ObjectId {
+            self.0.root()
+        }
+
+        fn verify(&mut self) -> errors::Result<()> {
+            self.0.verify()
+        }
+
+        fn collect_garbage(&mut self) -> errors::Result<HashSet<BlobId>> {
+            self.0.collect_garbage()
+        }
+
+        fn live_blobs(&self) -> errors::Result<HashSet<BlobId>> {
+            self.0.live_blobs()
+        }
+
+        fn list_permanode_with(&self, id: &ObjectId, after: Option<&Property>,
+                               limit: usize, include_deleted: bool)
+            -> errors::Result<Option<MemberPage>>
+        {
+            self.0.list_permanode_with(id, after, limit, include_deleted)
+        }
+    }
+
+    /// Gets the entries of a directory object.
+    fn entries(store: &DynStore, id: &ObjectId) -> Vec<DirEntry> {
+        match store.get_object(id).unwrap().as_deref() {
+            Some(Object { data: ObjectData::Dict(dict), .. }) => {
+                dir_entries(dict).unwrap()
+            }
+            _ => panic!("Expected a directory"),
+        }
+    }
+
+    /// Writes a file, dated from long enough ago for the ingest cache.
+    fn old_file(path: &Path, size: usize) {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, vec![b'x'; size]).unwrap();
+        File::options().write(true).open(path).unwrap()
+            .set_modified(SystemTime::now() - Duration::from_secs(3600))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_estimate() {
+        let (path, mut store) = temp_store("estimate");
+        let input = path.join("input");
+        old_file(&input.join("a"), 10);
+        old_file(&input.join("sub/b"), 20);
+        old_file(&input.join("c.tmp"), 5);
+        let mut ignore = IgnoreRules::new();
+        ignore.add("*.tmp").unwrap();
+        store.set_ignore(ignore);
+
+        let estimate = store.estimate(&input).unwrap();
+        assert_eq!(estimate, Estimate {
+            files: 2, bytes: 30, unchanged_files: 0, unchanged_bytes: 0,
+        });
+        assert_eq!(estimate.new_bytes(), 30);
+
+        // Once added, the ingest cache knows the files are unchanged
+        store.set_ingest_cache(
+            IngestCache::open(path.join("ingest_cache")).unwrap());
+        store.add(&input).unwrap();
+        let estimate = store.estimate(&input).unwrap();
+        assert_eq!((estimate.unchanged_files, estimate.unchanged_bytes),
+                   (2, 30));
+        assert_eq!(estimate.new_bytes(), 0);
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_reserve() {
+        let (path, store) = temp_store("reserve");
+        drop(store);
+        let mut store = StoreBuilder::new()
+            .path(&path)
+            .wrap_storage(|storage| Ok(Box::new(FreeSpace(storage, 1000))))
+            .open()
+            .unwrap();
+        store.set_min_free_space(Some(100));
+
+        let estimate = |bytes, unchanged_bytes| Estimate {
+            files: 1, bytes, unchanged_files: 0, unchanged_bytes,
+        };
+        store.reserve(&estimate(900, 0)).unwrap();
+        match store.reserve(&estimate(901, 0)) {
+            Err(Error::NotEnoughSpace(1001, 1000)) => {}
+            r => panic!("Expected NotEnoughSpace, got {:?}", r),
+        }
+        // Only the bytes that would be read again count
+        store.reserve(&estimate(1400, 500)).unwrap();
+
+        // add() only checks if asked to
+        let input = path.join("input");
+        old_file(&input.join("big"), 2000);
+        store.set_reserve(true);
+        match store.add(&input) {
+            Err(Error::NotEnoughSpace(2100, 1000)) => {}
+            r => panic!("Expected NotEnoughSpace, got {:?}", r),
+        }
+        store.set_reserve(false);
+        store.add(&input).unwrap();
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_add_threads() {
+        let (path, mut store) = temp_store("add-threads");
+        let input = path.join("input");
+        fs::create_dir(&input).unwrap();
+        // Enough data for many chunks, a few batches of them
+        let mut state = 1u32;
+        let data: Vec<u8> = (0..3_000_000).map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        }).collect();
+        fs::write(input.join("big"), &data).unwrap();
+        fs::write(input.join("small"), b"small").unwrap();
+
+        store.set_threads(4);
+        let parallel = store.add(&input).unwrap();
+        assert_eq!(store.verify_tree(&parallel).unwrap(), 0);
+        store.set_threads(1);
+        let serial = store.add(&input).unwrap();
+        assert_eq!(parallel, serial);
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_extract() {
+        let (path, mut store) = temp_store("extract");
+        let input = path.join("input");
+        fs::create_dir_all(input.join("sub")).unwrap();
+        fs::create_dir(input.join("empty")).unwrap();
+        fs::write(input.join("a"), b"first").unwrap();
+        fs::write(input.join("sub/b"), vec![b'b'; 100_000]).unwrap();
+        let tree = store.add(&input).unwrap();
+        let read = |name: &str| fs::read(path.join("output").join(name))
+            .unwrap();
+
+        let output = path.join("output");
+        let extracted = store.extract_with(&tree, &output, Existing::Fail)
+            .unwrap();
+        assert_eq!(extracted, Extracted {
+            files: 2, directories: 3, bytes: 100_005, symlinks: 0,
+            skipped: 0,
+        });
+        assert_eq!(read("a"), b"first");
+        assert_eq!(read("sub/b"), vec![b'b'; 100_000]);
+        assert!(output.join("empty").is_dir());
+        assert!(store.extract(&tree, &output).is_err());
+
+        // Existing files are left alone, or replaced
+        fs::write(output.join("a"), b"changed").unwrap();
+        fs::write(output.join("extra"), b"extra").unwrap();
+        let extracted = store.extract_with(&tree, &output, Existing::Keep)
+            .unwrap();
+        assert_eq!((extracted.files, extracted.skipped), (0, 2));
+        assert_eq!(read("a"), b"changed");
+        let extracted = store.extract_with(&tree, &output,
+                                           Existing::Overwrite)
+            .unwrap();
+        assert_eq!((extracted.files, extracted.skipped), (2, 0));
+        assert_eq!(read("a"), b"first");
+        assert_eq!(read("extra"), b"extra");
+
+        // Names that would get out of the directory
+        let file = Property::Reference(entries(&store, &tree)[0].id.clone());
+        for name in ["..", ".", "", "a\\..\\b"] {
+            let mut dir = Dict::new();
+            dir.insert(name.into(), file.clone());
+            let dir = store.index.add(ObjectData::Dict(dir)).unwrap();
+            match store.extract(&dir, path.join("invalid")) {
+                Err(Error::CorruptedStore(_)) => {}
+                r => panic!("Expected CorruptedStore for {:?}, got {:?}",
+                            name, r),
+            }
+            fs::remove_dir(path.join("invalid")).unwrap();
+        }
+
+        // A directory containing itself can't happen with real hashes
+        let root = store.index.root().clone();
+        drop(store);
+        let id = ObjectId::from_id(ID::from_bytes(&[1; HASH_SIZE]).unwrap());
+        let mut dir = Dict::new();
+        dir.insert("a".into(), file);
+        dir.insert("loop".into(), Property::Reference(id.clone()));
+        let index = MemoryIndex::open(path.join("objects"), root).unwrap();
+        let store = StoreBuilder::new()
+            .path(&path)
+            .index(Box::new(Forged(index, Object {
+                id: id.clone(),
+                data: ObjectData::Dict(dir),
+            })))
+            .open()
+            .unwrap();
+        match store.extract(&id, path.join("cycle")) {
+            Err(Error::ReferenceCycle(i)) => assert_eq!(i, id),
+            r => panic!("Expected ReferenceCycle, got {:?}", r),
+        }
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_dir_entries() {
+        let id = |n| ObjectId::from_id(ID::from_bytes(&[n; HASH_SIZE])
+                                           .unwrap());
+        let dict = |items: &[(&str, Property)]| -> Dict {
+            items.iter().map(|(k, v)| (k.to_string(), v.clone())).collect()
+        };
+        let reference = |n| Property::Reference(id(n));
+
+        // Older directories only map names to references
+        let old = dir_entries(&dict(&[("a", reference(1)),
+                                      ("b", reference(2))])).unwrap();
+        assert_eq!(old, vec![
+            DirEntry { name: "a".into(), id: id(1), kind: None, size: None,
+                       mtime: None, mode: None },
+            DirEntry { name: "b".into(), id: id(2), kind: None, size: None,
+                       mtime: None, mode: None },
+        ]);
+
+        let new = dir_entries(&dict(&[
+            ("a", reference(1)),
+            ("a/type", Property::String("file".into())),
+            ("a/size", Property::Integer(12)),
+            ("a/mtime", Property::Integer(-5)),
+            ("a/mode", Property::Integer(0o644)),
+            ("b", reference(2)),
+            ("b/type", Property::String("dir".into())),
+            ("c", reference(3)),
+            ("c/type", Property::String("symlink".into())),
+        ])).unwrap();
+        assert_eq!(new, vec![
+            DirEntry { name: "a".into(), id: id(1),
+                       kind: Some(EntryKind::File), size: Some(12),
+                       mtime: Some(-5), mode: Some(0o644) },
+            DirEntry { name: "b".into(), id: id(2),
+                       kind: Some(EntryKind::Dir), size: None, mtime: None,
+                       mode: None },
+            DirEntry { name: "c".into(), id: id(3),
+                       kind: Some(EntryKind::Symlink), size: None,
+                       mtime: None, mode: None },
+        ]);
+
+        for bad in [
+            // Not a reference
+            vec![("a", Property::Integer(1))],
+            // Metadata with no entry
+            vec![("a", reference(1)), ("b/type", Property::String(
+                "file".into()))],
+            // Unknown or mistyped fields
+            vec![("a", reference(1)), ("a/owner", Property::Integer(0))],
+            vec![("a", reference(1)), ("a/size", Property::String(
+                "12".into()))],
+            vec![("a", reference(1)), ("a/mtime", Property::String(
+                "yesterday".into()))],
+            // Invalid values
+            vec![("a", reference(1)), ("a/type", Property::String(
+                "fifo".into()))],
+            vec![("a", reference(1)), ("a/size", Property::Integer(-1))],
+            vec![("a", reference(1)), ("a/mode", Property::Integer(
+                0o10000))],
+        ] {
+            assert_eq!(dir_entries(&dict(&bad)), None);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_symlinks() {
+        use std::os::unix::fs::symlink;
+
+        let (path, mut store) = temp_store("symlinks");
+        let input = path.join("input");
+        fs::create_dir(&input).unwrap();
+        fs::write(input.join("a"), b"contents").unwrap();
+        symlink("a", input.join("link")).unwrap();
+        symlink("nowhere", input.join("dangling")).unwrap();
+        let tree = store.add(&input).unwrap();
+        let listed = entries(&store, &tree);
+        let names: Vec<_> = listed.iter()
+            .map(|e| (e.name.as_str(), e.kind))
+            .collect();
+        assert_eq!(names, vec![("a", Some(EntryKind::File)),
+                               ("dangling", Some(EntryKind::Symlink)),
+                               ("link", Some(EntryKind::Symlink))]);
+        match store.get_object(&listed[2].id).unwrap().as_deref() {
+            Some(Object { data: ObjectData::Dict(dict), .. }) => {
+                assert_eq!(symlink_target(dict), Some("a"));
+            }
+            _ => panic!("Expected a symbolic link"),
+        }
+
+        let output = path.join("output");
+        let extracted = store.extract_with(&tree, &output, Existing::Fail)
+            .unwrap();
+        assert_eq!((extracted.files, extracted.symlinks), (1, 2));
+        assert_eq!(fs::read_link(output.join("link")).unwrap(),
+                   Path::new("a"));
+        assert_eq!(fs::read_link(output.join("dangling")).unwrap(),
+                   Path::new("nowhere"));
+        assert_eq!(fs::read(output.join("link")).unwrap(), b"contents");
+
+        // Overwriting replaces links, rather than writing where they point
+        let victim = path.join("victim");
+        fs::write(&victim, b"victim").unwrap();
+        fs::remove_file(output.join("a")).unwrap();
+        symlink(&victim, output.join("a")).unwrap();
+        fs::remove_file(output.join("link")).unwrap();
+        fs::write(output.join("link"), b"file").unwrap();
+        store.extract_with(&tree, &output, Existing::Overwrite).unwrap();
+        assert_eq!(fs::read(&victim).unwrap(), b"victim");
+        assert!(!fs::symlink_metadata(output.join("a")).unwrap()
+                .file_type().is_symlink());
+        assert_eq!(fs::read(output.join("a")).unwrap(), b"contents");
+        assert_eq!(fs::read_link(output.join("link")).unwrap(),
+                   Path::new("a"));
+
+        // Links can be followed instead
+        fs::remove_file(input.join("dangling")).unwrap();
+        store.set_follow_symlinks(true);
+        let tree = store.add(&input).unwrap();
+        let listed = entries(&store, &tree);
+        assert_eq!(listed[1].name, "link");
+        assert_eq!(listed[1].kind, Some(EntryKind::File));
+        assert_eq!(listed[1].id, listed[0].id);
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_extract_metadata() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let (path, mut store) = temp_store("extract-metadata");
+        let input = path.join("input");
+        old_file(&input.join("sub/a"), 10);
+        fs::set_permissions(input.join("sub/a"),
+                            fs::Permissions::from_mode(0o4751)).unwrap();
+        fs::set_permissions(input.join("sub"),
+                            fs::Permissions::from_mode(0o1750)).unwrap();
+        let tree = store.add(&input).unwrap();
+
+        let output = path.join("output");
+        store.extract(&tree, &output).unwrap();
+        for (name, mode) in [("sub/a", 0o751), ("sub", 0o750)] {
+            let original = fs::metadata(input.join(name)).unwrap();
+            let extracted = fs::metadata(output.join(name)).unwrap();
+            // Recorded to the microsecond
+            let mtime = |m: &fs::Metadata| {
+                m.modified().unwrap().duration_since(SystemTime::UNIX_EPOCH)
+                    .unwrap().as_micros()
+            };
+            assert_eq!(mtime(&extracted), mtime(&original));
+            // Without the special bits
+            assert_eq!(extracted.permissions().mode() & 0o7777, mode);
+        }
+        assert_eq!(fs::read(output.join("sub/a")).unwrap(), vec![b'x'; 10]);
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_diff() {
+        let (path, mut store) = temp_store("diff");
+        let input = path.join("input");
+        for (name, size) in [("same.txt", 1), ("mod.txt", 2), ("gone.txt", 3),
+                             ("sub/keep", 4), ("sub/changed", 5),
+                             ("sub/old", 6), ("olddir/f", 7)]
+        {
+            old_file(&input.join(name), size);
+        }
+        let first = store.add(&input).unwrap();
+        assert!(store.diff(&first, &first).unwrap().is_empty());
+
+        fs::remove_file(input.join("gone.txt")).unwrap();
+        fs::remove_file(input.join("sub/old")).unwrap();
+        fs::remove_dir_all(input.join("olddir")).unwrap();
+        for (name, size) in [("mod.txt", 20), ("new.txt", 8),
+                             ("sub/changed", 50), ("sub/new", 9),
+                             ("sub.txt", 10), ("newdir/g", 11)]
+        {
+            old_file(&input.join(name), size);
+        }
+        let second = store.add(&input).unwrap();
+
+        let changes = store.diff(&first, &second).unwrap();
+        let summary: Vec<_> = changes.iter()
+            .map(|c| (c.path.as_str(), c.change, c.kind))
+            .collect();
+        // Sorted by path, so "sub.txt" comes before the changes in "sub/"
+        // and unchanged files or directories are left out
+        assert_eq!(summary, vec![
+            ("gone.txt", ChangeKind::Removed, Some(EntryKind::File)),
+            ("mod.txt", ChangeKind::Modified, Some(EntryKind::File)),
+            ("new.txt", ChangeKind::Added, Some(EntryKind::File)),
+            ("newdir", ChangeKind::Added, Some(EntryKind::Dir)),
+            ("olddir", ChangeKind::Removed, Some(EntryKind::Dir)),
+            ("sub.txt", ChangeKind::Added, Some(EntryKind::File)),
+            ("sub/changed", ChangeKind::Modified, Some(EntryKind::File)),
+            ("sub/new", ChangeKind::Added, Some(EntryKind::File)),
+            ("sub/old", ChangeKind::Removed, Some(EntryKind::File)),
+        ]);
+
+        let id = |tree: &ObjectId, name: &str| {
+            entries(&store, tree).into_iter()
+                .find(|e| e.name == name).unwrap().id
+        };
+        assert_eq!(changes[0].old, Some(id(&first, "gone.txt")));
+        assert_eq!(changes[0].new, None);
+        assert_eq!(changes[1].old, Some(id(&first, "mod.txt")));
+        assert_eq!(changes[1].new, Some(id(&second, "mod.txt")));
+        assert_eq!(changes[3].old, None);
+        assert_eq!(changes[3].new, Some(id(&second, "newdir")));
+
+        // The other way around
+        let reverse: Vec<_> = store.diff(&second, &first).unwrap()
+            .into_iter()
+            .map(|c| (c.path, c.change))
+            .collect();
+        assert_eq!(reverse[0], ("gone.txt".into(), ChangeKind::Added));
+        assert_eq!(reverse[8], ("sub/old".into(), ChangeKind::Added));
+        assert_eq!(reverse[5], ("sub.txt".into(), ChangeKind::Removed));
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let (path, mut store) = temp_store("snapshots");
+        let input = path.join("input");
+        old_file(&input.join("a"), 10);
+        assert!(store.snapshots("home").unwrap().is_empty());
+        assert!(store.snapshot_node("home").unwrap().is_none());
+
+        let first = store.snapshot(&input, "home").unwrap();
+        old_file(&input.join("b"), 20);
+        let second = store.snapshot(&input, "home").unwrap();
+        // Same tree, taken again right away
+        let third = store.snapshot_tree(&second.tree, "home").unwrap();
+        let other = store.snapshot(&input, "other").unwrap();
+        assert_ne!(first.tree, second.tree);
+        assert_eq!(second.tree, third.tree);
+        assert!(first.date < second.date && second.date < third.date);
+        assert_eq!(store.snapshots("home").unwrap(),
+                   vec![first.clone(), second.clone(), third.clone()]);
+        assert_eq!(store.snapshots("other").unwrap(), vec![other.clone()]);
+        assert_ne!(store.snapshot_node("home").unwrap(),
+                   store.snapshot_node("other").unwrap());
+
+        // Dates stay increasing even if the last one is in the future
+        let node = store.snapshot_node("home").unwrap().unwrap();
+        let future = third.date + 3_600_000_000;
+        let mut data = Dict::new();
+        data.insert("date".into(), Property::Integer(future));
+        store.index.add(claim(&node, &first.tree, data)).unwrap();
+        let fourth = store.snapshot_tree(&first.tree, "home").unwrap();
+        assert_eq!(fourth.date, future + 1);
+        assert_eq!(store.snapshots("home").unwrap().len(), 5);
+
+        let missing = ObjectId::from_id(ID::from_bytes(&[1; HASH_SIZE])
+                                            .unwrap());
+        match store.snapshot_tree(&missing, "home") {
+            Err(Error::MissingObject(id)) => assert_eq!(id, missing),
+            r => panic!("Expected MissingObject, got {:?}", r),
+        }
+
+        // The snapshots are kept from the log
+        store.collect_garbage().unwrap();
+        drop(store);
+        let store = open(&path).unwrap();
+        assert_eq!(store.snapshots("home").unwrap().len(), 5);
+        assert_eq!(store.snapshots("other").unwrap(), vec![other]);
+        for snapshot in [&first, &second] {
+            assert!(store.get_object(&snapshot.tree).unwrap().is_some());
+        }
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_merge() {
+        let (src_path, mut src) = temp_store("merge-src");
+        let (dst_path, mut dst) = temp_store("merge-dst");
+        let input = src_path.join("input");
+        old_file(&input.join("a"), 1000);
+        let snapshot = src.snapshot(&input, "home").unwrap();
+        let objects = src.index.list_objects().unwrap().count();
+        let blobs = src.storage.list_blobs().unwrap().count();
+        assert_eq!(blobs, 1);
+
+        let copied = dst.merge(&src, "src").unwrap();
+        assert_eq!((copied.objects, copied.blobs), (objects, blobs));
+        for object in src.index.list_objects().unwrap() {
+            assert!(dst.get_object(&object.unwrap().id).unwrap().is_some());
+        }
+        for blob in src.storage.list_blobs().unwrap() {
+            assert!(dst.storage.contains(&blob.unwrap()).unwrap());
+        }
+
+        // The events of the other log are claimed on this one, with their
+        // dates, so the snapshots are found from this store
+        let dst_events = dst.list_events().unwrap();
+        for event in src.list_events().unwrap() {
+            assert!(dst_events.iter()
+                    .any(|e| e.key == event.key && e.value == event.value));
+        }
+        assert_eq!(dst.snapshots("home").unwrap(), vec![snapshot.clone()]);
+
+        // Only the merge event is new
+        let copied = dst.merge(&src, "src").unwrap();
+        assert_eq!((copied.objects, copied.blobs), (0, 0));
+        assert_eq!(dst.list_events().unwrap().len(), dst_events.len() + 1);
+
+        // The merged objects are live
+        dst.collect_garbage().unwrap();
+        let file = entries(&dst, &snapshot.tree)[0].id.clone();
+        let mut contents = Vec::new();
+        dst.get_file(&file).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![b'x'; 1000]);
+
+        drop((src, dst));
+        fs::remove_dir_all(src_path).unwrap();
+        fs::remove_dir_all(dst_path).unwrap();
+    }
+
+    #[test]
+    fn test_copy_object() {
+        let (src_path, mut src) = temp_store("copy-src");
+        let (dst_path, mut dst) = temp_store("copy-dst");
+
+        // a references b and c, and b references c
+        let blob = src.add_blob(&b"shared"[..]).unwrap();
+        let c = src.index.add(ObjectData::List(vec![
+            Property::Integer(0), Property::Blob(blob)])).unwrap();
+        let b = src.index.add(ObjectData::List(vec![
+            Property::Reference(c.clone())])).unwrap();
+        let a = src.index.add(ObjectData::List(vec![
+            Property::Reference(b.clone()),
+            Property::Reference(c.clone())])).unwrap();
+        let other = src.index.add(ObjectData::List(vec![
+            Property::Integer(1), Property::Reference(c.clone())])).unwrap();
+        let mut data = Dict::new();
+        data.insert("type".into(), Property::String("set".into()));
+        let node = permanode(data, Sort::Ascending("date".into()));
+        let node = src.index.add(node.data).unwrap();
+        let mut claims = Vec::new();
+        for (date, value) in [(1, &a), (2, &other)] {
+            let mut data = Dict::new();
+            data.insert("date".into(), Property::Integer(date));
+            claims.push(src.index.add(claim(&node, value, data)).unwrap());
+        }
+
+        let mut order = Vec::new();
+        src.walk_tree(&node, |id, _| {
+            order.push(id.clone());
+            Ok(())
+        }).unwrap();
+        assert_eq!(order.len(), 7);
+        let position = |id| order.iter().position(|o| o == id).unwrap();
+        assert!(position(&c) < position(&b));
+        assert!(position(&b) < position(&a));
+        assert!(position(&a) < position(&node));
+        assert!(position(&other) < position(&node));
+        for claim in &claims {
+            assert!(position(&node) < position(claim));
+        }
+
+        let copied = src.copy_object(&mut dst, &node).unwrap();
+        assert_eq!((copied.objects, copied.blobs), (7, 1));
+        let page = dst.list_permanode(&node, None, 10).unwrap().unwrap();
+        let members: Vec<_> = page.members.into_iter()
+            .map(|m| (m.claim, m.value))
+            .collect();
+        assert_eq!(members, vec![(claims[0].clone(), a.clone()),
+                                 (claims[1].clone(), other.clone())]);
+        assert_eq!(dst.verify_tree(&node).unwrap(), 0);
+        let copied = src.copy_object(&mut dst, &node).unwrap();
+        assert_eq!((copied.objects, copied.blobs), (0, 0));
+
+        drop((src, dst));
+        fs::remove_dir_all(src_path).unwrap();
+        fs::remove_dir_all(dst_path).unwrap();
+    }
+
+    #[test]
+    fn test_gc_quarantined() {
+        let (path, mut store) = temp_store("gc-quarantined");
+        let input = path.join("input");
+        old_file(&input.join("a"), 10);
+        let tree = store.snapshot(&input, "test").unwrap().tree;
+        let file = entries(&store, &tree)[0].id.clone();
+        drop(store);
+
+        // Corrupt the snapshot's tree, which alone references the file
+        let hashstr = tree.id().str();
+        fs::write(path.join("objects").join(&hashstr[..4])
+                      .join(&hashstr[4..]),
+                  b"garbage").unwrap();
+        let mut store = StoreBuilder::new()
+            .path(&path)
+            .permissive(true)
+            .open()
+            .unwrap();
+        assert_eq!(store.quarantined().len(), 1);
+        assert!(store.get_object(&tree).unwrap().is_none());
+        match store.collect_garbage() {
+            Err(Error::CorruptedStore(_)) => {}
+            r => panic!("Expected CorruptedStore, got {:?}", r),
+        }
+        drop(store);
+
+        // Nothing was deleted
+        let store = StoreBuilder::new()
+            .path(&path)
+            .permissive(true)
+            .open()
+            .unwrap();
+        assert!(store.get_object(&file).unwrap().is_some());
+        assert_eq!(store.verify_tree(&file).unwrap(), 0);
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_disk_index() {
+        let (path, mut store) = temp_store("disk-index");
+        let input = path.join("input");
+        old_file(&input.join("a"), 10);
+        let tree = store.snapshot(&input, "test").unwrap().tree;
+        drop(store);
+
+        assert_eq!(convert_to_disk_index(&path).unwrap(), 8);
+        assert!(!path.join("objects").exists());
+        match convert_to_disk_index(&path) {
+            Err(Error::InvalidInput(_)) => {}
+            r => panic!("Expected InvalidInput, got {:?}", r),
+        }
+
+        let mut store = open(&path).unwrap();
+        assert_eq!(store.snapshots("test").unwrap().len(), 1);
+        old_file(&input.join("b"), 20);
+        store.snapshot(&input, "test").unwrap();
+        let garbage = store.index.add(ObjectData::List(vec![
+            Property::Integer(42)])).unwrap();
+        store.collect_garbage().unwrap();
+        drop(store);
+
+        let store = open(&path).unwrap();
+        assert!(store.get_object(&garbage).unwrap().is_none());
+        assert_eq!(store.snapshots("test").unwrap().len(), 2);
+        let file = entries(&store, &tree)[0].id.clone();
+        let mut contents = Vec::new();
+        store.get_file(&file).unwrap().read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, vec![b'x'; 10]);
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+}
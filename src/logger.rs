@@ -2,13 +2,74 @@
 //!
 //! This provides the log implementation that uses `termcolor` to log to the
 //! terminal with colors.
+//!
+//! Warnings and errors are deduplicated, since a damaged store can produce
+//! thousands of them: a message identical to the previous one is only
+//! counted, and after `MAX_SIMILAR` messages differing only by numbers or
+//! IDs, the next ones are counted but not shown. Flushing the logger prints
+//! the counts.
 
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::io::Write;
+use std::sync::Mutex;
 
-use log::{Log, Level, LevelFilter, Metadata, Record,
+use log::{Log, Level, Metadata, Record,
           SetLoggerError, set_boxed_logger, set_max_level};
 use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
+/// Number of similar warnings shown before the next ones are only counted.
+const MAX_SIMILAR: usize = 10;
+
+/// The warnings seen so far, to leave out repeated ones.
+#[derive(Default)]
+struct Repeats {
+    /// Last warning shown, and how many times it was repeated since
+    last: Option<(Level, String)>,
+    repeated: usize,
+    /// Number of warnings with each pattern, and the first one
+    similar: HashMap<String, (usize, Level, String)>,
+}
+
+/// Replaces quoted strings, such as paths, and the words containing digits,
+/// such as IDs, with `#`.
+///
+/// Warnings with the same pattern only differ by what they are about.
+fn pattern(message: &str) -> String {
+    let mut pattern = String::with_capacity(message.len());
+    let mut word = String::new();
+    let end_word = |word: &mut String, pattern: &mut String| {
+        if word.bytes().any(|b| b.is_ascii_digit()) {
+            pattern.push('#');
+        } else {
+            pattern.push_str(word);
+        }
+        word.clear();
+    };
+    let mut chars = message.chars();
+    while let Some(c) = chars.next() {
+        if c == '"' {
+            end_word(&mut word, &mut pattern);
+            let mut escaped = false;
+            for c in chars.by_ref() {
+                match c {
+                    '"' if !escaped => break,
+                    '\\' => escaped = !escaped,
+                    _ => escaped = false,
+                }
+            }
+            pattern.push('#');
+        } else if c.is_alphanumeric() || c == '_' || c == '-' {
+            word.push(c);
+        } else {
+            end_word(&mut word, &mut pattern);
+            pattern.push(c);
+        }
+    }
+    end_word(&mut word, &mut pattern);
+    pattern
+}
+
 /// The logger that writes to stderr.
 ///
 /// This is an internal object passed to the `log` crate; you only have to use
@@ -16,6 +77,7 @@ use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 struct StderrLogger {
     stderr: StandardStream,
     level: Level,
+    repeats: Mutex<Repeats>,
 }
 
 impl StderrLogger {
@@ -23,8 +85,34 @@ impl StderrLogger {
         StderrLogger {
             stderr: StandardStream::stdout(ColorChoice::Auto),
             level: level,
+            repeats: Mutex::new(Repeats::default()),
         }
     }
+
+    fn write<W: WriteColor>(stderr: &mut W, level: Level, message: &str) {
+        let color = match level {
+            Level::Error => Color::Red,
+            Level::Warn => Color::Yellow,
+            Level::Info => Color::White,
+            Level::Debug => Color::Cyan,
+            Level::Trace => Color::Blue,
+        };
+        stderr.set_color(ColorSpec::new().set_fg(Some(color))).unwrap();
+        writeln!(stderr, "{}", message).unwrap();
+        stderr.reset().unwrap();
+    }
+
+    /// Reports how many times the last warning was repeated, if it was.
+    fn end_repeats<W: WriteColor>(stderr: &mut W, repeats: &mut Repeats) {
+        if let Some((level, _)) = repeats.last.take() {
+            if repeats.repeated > 0 {
+                Self::write(stderr, level,
+                            &format!("previous message repeated {} times",
+                                     repeats.repeated));
+            }
+        }
+        repeats.repeated = 0;
+    }
 }
 
 impl Log for StderrLogger {
@@ -33,30 +121,81 @@ impl Log for StderrLogger {
     }
 
     fn log(&self, record: &Record) {
-        if self.enabled(record.metadata()) {
-            let mut stderr = self.stderr.lock();
-            let color = match record.metadata().level() {
-                Level::Error => Color::Red,
-                Level::Warn => Color::Yellow,
-                Level::Info => Color::White,
-                Level::Debug => Color::Cyan,
-                Level::Trace => Color::Blue,
-            };
-            stderr.set_color(ColorSpec::new().set_fg(Some(color))).unwrap();
-            writeln!(stderr, "{} - {}", record.target(), record.args())
-                .unwrap();
-            stderr.reset().unwrap();
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let level = record.metadata().level();
+        let message = format!("{} - {}", record.target(), record.args());
+        let mut repeats = self.repeats.lock().unwrap();
+        let mut stderr = self.stderr.lock();
+        if level > Level::Warn {
+            Self::end_repeats(&mut stderr, &mut repeats);
+            Self::write(&mut stderr, level, &message);
+            return;
+        }
+        if let Some((l, ref m)) = repeats.last {
+            if l == level && *m == message {
+                repeats.repeated += 1;
+                return;
+            }
+        }
+        Self::end_repeats(&mut stderr, &mut repeats);
+        let count = {
+            let entry = repeats.similar.entry(pattern(&message))
+                .or_insert_with(|| (0, level, message.clone()));
+            entry.0 += 1;
+            entry.0
+        };
+        if count > MAX_SIMILAR {
+            return;
         }
+        Self::write(&mut stderr, level, &message);
+        if count == MAX_SIMILAR {
+            Self::write(&mut stderr, level,
+                        "further messages like this one will be counted \
+                         but not shown");
+        }
+        repeats.last = Some((level, message));
     }
 
+    /// Also reports the warnings that were left out.
     fn flush(&self) {
+        let mut repeats = self.repeats.lock().unwrap();
         let mut stderr = self.stderr.lock();
+        Self::end_repeats(&mut stderr, &mut repeats);
+        let mut similar: Vec<_> = repeats.similar.drain()
+            .filter(|(_, (count, _, _))| *count > MAX_SIMILAR)
+            .map(|(_, entry)| entry)
+            .collect();
+        similar.sort_by_key(|&(count, _, _)| Reverse(count));
+        for (count, level, first) in similar {
+            Self::write(&mut stderr, level,
+                        &format!("{} messages not shown, like: {}",
+                                 count - MAX_SIMILAR, first));
+        }
         stderr.flush().unwrap();
     }
 }
 
 /// Sets up the logger object to log on stderr with the given log level.
 pub fn init(level: Level) -> Result<(), SetLoggerError> {
-    set_max_level(LevelFilter::Info);
+    set_max_level(level.to_level_filter());
     set_boxed_logger(Box::new(StderrLogger::new(level)))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::pattern;
+
+    #[test]
+    fn test_pattern() {
+        assert_eq!(pattern("Replica 2 has a bad copy of blob \
+                            DKHgqyZgwMBvRmduoI3xWxRI29iUZVLWfPWqh4lfTMAL"),
+                   "Replica # has a bad copy of blob #");
+        assert_eq!(pattern("Error reading blob_x-y (3/7): no"),
+                   "Error reading blob_x-y (#/#): no");
+        assert_eq!(pattern("blob42"), "#");
+        assert_eq!(pattern(r#"Bad file: "blobs/DJxu/a \" b", skipping"#),
+                   "Bad file: #, skipping");
+    }
+}
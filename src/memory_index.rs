@@ -6,24 +6,41 @@
 //! This is very inefficient and should be backed by proper database code at
 //! some point.
 
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::cmp::Ordering::Greater;
 use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fs::{self, File, OpenOptions};
 use std::io;
 use std::mem::swap;
+use std::ops::Bound;
 use std::path::{PathBuf, Path};
+use std::rc::Rc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc;
+use std::thread;
 
 use log::Level;
 use log::{debug, error, info, log_enabled, warn};
 
-use crate::common::{HASH_STR_SIZE, Sort, ID, Dict, Object, ObjectData, Property,
-                    ObjectIndex};
+use crate::common::{HASH_STR_SIZE, Sort, ID, BlobId, ObjectId, Backkey, Dict,
+                    Member, MemberPage, Object,
+                    ObjectData, ObjectIterator, Property, ObjectIndex,
+                    Quarantined};
 use crate::errors::{self, Error};
 use crate::serialize;
+use crate::signing;
+use crate::span::Span;
 
 /// Return value from a Policy for some object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum PolicyDecision {
+    /// Fetch the object if missing, and keep it; as this index has all its
+    /// objects locally, the same as `Keep`
     Get,
+    /// Keep the object, and go on with the objects it references
     Keep,
+    /// Don't keep the object, unless kept through another reference
     Drop,
 }
 
@@ -36,52 +53,136 @@ pub enum PolicyDecision {
 /// A `Policy` object contains all this information for a specific place in the
 /// tree, and handles all the builtin, user-supplied, and recursive behaviors
 /// for the index.
+///
+/// While finding the live objects, the index calls `handle()` for each object
+/// reached, with the property referencing it. The policy of the root config
+/// is the one set with `MemoryIndex::set_policy()`; the policy for the
+/// objects referenced from some object is the one returned for that object.
+/// Claims, tombstones and signatures are kept along with their object, and
+/// use its policy. Deleted claims are not kept unconditionally, the policy is
+/// asked about them with the property "dhstore_deleted", so it can let
+/// garbage collection reclaim them and the values only they reference.
 pub trait Policy {
-    fn handle(&mut self, property: &str, object: Object)
+    fn handle(&mut self, property: &str, object: &Object)
               -> (PolicyDecision, Box<dyn Policy>);
+
+    /// Soft limits on the size of the index.
+    ///
+    /// Only the policy set on the index is asked. Going over them doesn't
+    /// refuse anything, the index logs a warning, once until it gets back
+    /// under them.
+    fn budget(&self) -> Budget {
+        Budget::default()
+    }
+}
+
+/// Soft limits on the size of the index, see `Policy::budget()`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Budget {
+    /// Number of objects, live or not
+    pub max_objects: Option<usize>,
+    /// Approximate memory taken by the objects, see `MemoryIndex::usage()`
+    pub max_bytes: Option<u64>,
 }
 
 /// Placeholder Policy that keeps everything.
-struct KeepPolicy;
+pub(crate) struct KeepPolicy;
 
 impl KeepPolicy {
-    fn new() -> KeepPolicy {
+    pub(crate) fn new() -> KeepPolicy {
         KeepPolicy
     }
 }
 
 impl Policy for KeepPolicy {
-    fn handle(&mut self, property: &str, object: Object)
+    fn handle(&mut self, property: &str, object: &Object)
               -> (PolicyDecision, Box<dyn Policy>) {
         (PolicyDecision::Keep, Box::new(KeepPolicy))
     }
 }
 
-/// Key of a reference, used in the backward reference map.
-///
-/// A reference is a value, and can appear in both types of schema objects: in a
-/// dict, it is associated with a string key, and in a list, with an index.
-#[derive(PartialEq, Eq, Hash)]
-enum Backkey {
-    /// Reference from a dict under this key.
-    Key(String),
-    /// Reference from a list from this index.
-    Index(usize),
-}
-
+#[derive(Clone)]
 enum PermanodeType {
     Set,
     Single,
 }
 
-struct Permanode {
+/// A permanode, with the values of its valid claims.
+#[derive(Clone)]
+pub(crate) struct Permanode {
     sort: Sort,
     nodetype: PermanodeType,
-    claims: BTreeMap<Property, ID>,
+    /// The values, by sort key, with the claim or checkpoint they come from
+    claims: BTreeMap<Property, (ObjectId, ObjectId)>,
+    /// The latest checkpoint, with the last sort key it covers
+    checkpoint: Option<(Property, ObjectId)>,
 }
 
 impl Permanode {
-    fn index_claim(&mut self, claim: &Dict, permanode_id: &ID, claim_id: &ID) {
+    /// Reads the sort and type of a permanode, with no values yet.
+    ///
+    /// Returns `None` if the permanode is invalid.
+    pub(crate) fn parse(id: &ObjectId, permanode: &Dict) -> Option<Permanode> {
+        match permanode.get("random") {
+            Some(&Property::String(ref s)) => {
+                if s.len() != HASH_STR_SIZE {
+                    warn!("Invalid permanode {}: invalid random size {}",
+                          id, s.len());
+                    return None;
+                }
+            }
+            _ => {
+                warn!("Invalid permanode {}: missing random", id);
+                return None;
+            }
+        }
+
+        let sort = match permanode.get("sort") {
+            Some(&Property::String(ref s)) => match s.parse() {
+                Ok(f) => f,
+                Err(()) => {
+                    warn!("Invalid permanode {}: invalid sort", id);
+                    return None;
+                }
+            },
+            _ => {
+                warn!("Invalid permanode {}: invalid sort", id);
+                return None;
+            }
+        };
+
+        let nodetype = match permanode.get("type") {
+            Some(&Property::String(ref s)) => match s as &str {
+                "set" | "single" => PermanodeType::Set,
+                _ => {
+                    warn!("Unknown permanode type {:?}, ignoring permanode {}",
+                          s, id);
+                    return None;
+                }
+            },
+            None => PermanodeType::Single,
+            Some(_) => {
+                warn!("Invalid permanode {}: invalid type", id);
+                return None;
+            }
+        };
+
+        Some(Permanode { sort: sort,
+                         nodetype: nodetype,
+                         claims: BTreeMap::new(),
+                         checkpoint: None })
+    }
+
+    /// Whether a claim with this sort key is summarized by the checkpoint.
+    fn covers(&self, key: &Property) -> bool {
+        match self.checkpoint {
+            Some((ref upto, _)) => self.sort.compare(key, upto) != Greater,
+            None => false,
+        }
+    }
+
+    pub(crate) fn index_claim(&mut self, claim: &Dict,
+                              permanode_id: &ObjectId, claim_id: &ObjectId) {
         // We require the claim to have the sort key
         let sort_value: &Property = match claim.get(self.sort.field()) {
             Some(ref prop) => prop,
@@ -92,10 +193,49 @@ impl Permanode {
                 return;
             }
         };
+        let value = match claim.get("value") {
+            Some(Property::Reference(value)) => value,
+            _ => return,
+        };
+        if self.covers(sort_value) {
+            debug!("Claim {} is covered by checkpoint", claim_id);
+            return;
+        }
         // Currently, no validation is done; every claim is accepted
         // In the future, we'd have ways of checking a claim, such as public
         // key signatures (permanode has key, claim has signature)
-        self.claims.insert(sort_value.clone(), claim_id.clone());
+        self.claims.insert(sort_value.clone(),
+                           (claim_id.clone(), value.clone()));
+        self.keep_values();
+    }
+
+    /// Replaces the values up to `upto` with those of a checkpoint, if it is
+    /// more recent than the current one.
+    pub(crate) fn index_checkpoint(&mut self, upto: &Property,
+                                   checkpoint_id: &ObjectId,
+                                   members: Vec<(Property, ObjectId)>) {
+        if let Some((ref current, _)) = self.checkpoint {
+            if self.sort.compare(upto, current) != Greater {
+                debug!("Checkpoint {} is older than the current one",
+                       checkpoint_id);
+                return;
+            }
+        }
+        self.checkpoint = Some((upto.clone(), checkpoint_id.clone()));
+        let claims = std::mem::take(&mut self.claims);
+        self.claims = claims.into_iter()
+            .filter(|(key, _)| !self.covers(key))
+            .collect();
+        for (key, value) in members {
+            if self.covers(&key) {
+                self.claims.insert(key, (checkpoint_id.clone(), value));
+            }
+        }
+        self.keep_values();
+    }
+
+    /// Drops the values that are not kept, according to the type.
+    fn keep_values(&mut self) {
         match self.nodetype {
             PermanodeType::Set => {
                 // Keep the whole set of values
@@ -116,6 +256,116 @@ impl Permanode {
             }
         }
     }
+
+    /// Lists the values, see `ObjectIndex::list_permanode_with()`.
+    ///
+    /// `is_deleted` tells whether the value of a claim is deleted.
+    pub(crate) fn page<F>(&self, after: Option<&Property>, limit: usize,
+                          include_deleted: bool, mut is_deleted: F)
+        -> errors::Result<MemberPage>
+        where F: FnMut(&ObjectId) -> errors::Result<bool>
+    {
+        let claims: Box<dyn Iterator<Item = (&Property,
+                                             &(ObjectId, ObjectId))>> =
+            match (&self.sort, after) {
+                (&Sort::Ascending(_), Some(after)) => Box::new(
+                    self.claims.range((Bound::Excluded(after),
+                                       Bound::Unbounded))),
+                (&Sort::Ascending(_), None) => Box::new(self.claims.iter()),
+                (&Sort::Descending(_), Some(after)) => Box::new(
+                    self.claims.range(..after).rev()),
+                (&Sort::Descending(_), None) => {
+                    Box::new(self.claims.iter().rev())
+                }
+            };
+        let mut members: Vec<Member> = Vec::new();
+        let mut next = None;
+        for (key, (claim_id, value)) in claims {
+            let deleted = is_deleted(claim_id)?;
+            if deleted && !include_deleted {
+                continue;
+            }
+            // There is more after this page
+            if members.len() == limit {
+                next = members.last().map(|m| m.key.clone());
+                break;
+            }
+            members.push(Member {
+                key: key.clone(),
+                claim: claim_id.clone(),
+                value: value.clone(),
+                deleted,
+            });
+        }
+        Ok(MemberPage { members, next })
+    }
+}
+
+/// Gets the permanode of a claim, if it is well-formed.
+///
+/// This doesn't mean it is valid; that is checked against the permanode.
+pub(crate) fn claim_node(claim: &Dict) -> Option<&ObjectId> {
+    match (claim.get("node"), claim.get("value")) {
+        (Some(Property::Reference(node)), Some(Property::Reference(_))) => {
+            Some(node)
+        }
+        _ => None,
+    }
+}
+
+/// Gets the permanode, last sort key and list of members of a checkpoint,
+/// if it is well-formed.
+pub(crate) fn checkpoint_fields(checkpoint: &Dict)
+    -> Option<(&ObjectId, &Property, &ObjectId)>
+{
+    match (checkpoint.get("node"), checkpoint.get("upto"),
+           checkpoint.get("members")) {
+        (Some(Property::Reference(node)), Some(upto),
+         Some(Property::Reference(members))) => Some((node, upto, members)),
+        _ => None,
+    }
+}
+
+/// Reads the members of a checkpoint from its list object.
+///
+/// Returns `None` if the list is not made of pairs of a sort key and a
+/// reference.
+pub(crate) fn checkpoint_members(list: &[Property])
+    -> Option<Vec<(Property, ObjectId)>>
+{
+    if !list.len().is_multiple_of(2) {
+        return None;
+    }
+    list.chunks(2)
+        .map(|pair| match pair[1] {
+            Property::Reference(ref value) => {
+                Some((pair[0].clone(), value.clone()))
+            }
+            _ => None,
+        })
+        .collect()
+}
+
+/// Approximate size of an object in memory.
+///
+/// This counts the keys and values, not the overhead of the maps.
+fn object_size(data: &ObjectData) -> u64 {
+    fn property_size(property: &Property) -> u64 {
+        match property {
+            Property::String(s) => s.len() as u64,
+            Property::Integer(_) => 8,
+            Property::Reference(_) | Property::Blob(_) => 32,
+            Property::Inline(data) => data.len() as u64,
+        }
+    }
+    let values: u64 = match data {
+        ObjectData::Dict(dict) => dict.iter()
+            .map(|(k, v)| k.len() as u64 + property_size(v))
+            .sum(),
+        ObjectData::List(list) => list.iter().map(property_size).sum(),
+    };
+    // The ID
+    32 + values
 }
 
 fn insert_into_multimap<K: Clone + Eq + ::std::hash::Hash,
@@ -133,101 +383,437 @@ fn insert_into_multimap<K: Clone + Eq + ::std::hash::Hash,
     multimap.insert(key.clone(), set);
 }
 
+/// What an index knows of the permanodes and the objects attached to
+/// others, for `mark()`.
+pub(crate) trait Graph: ObjectIndex {
+    /// Gets a valid permanode, with its values.
+    fn permanode(&self, id: &ObjectId)
+        -> errors::Result<Option<Cow<'_, Permanode>>>;
+    /// Lists the well-formed claims on a permanode, valid or not.
+    fn node_claims(&self, id: &ObjectId) -> errors::Result<Vec<ObjectId>>;
+    /// Lists the tombstones targeting an object.
+    fn tombstones(&self, id: &ObjectId) -> errors::Result<Vec<ObjectId>>;
+}
+
+/// Whether a claim is summarized by the checkpoint of its permanode.
+fn claim_covered<G: Graph + ?Sized>(index: &G, node: &Permanode,
+                                    claim: &ObjectId)
+    -> errors::Result<bool>
+{
+    Ok(match index.get_object(claim)?.as_deref() {
+        Some(Object { data: ObjectData::Dict(claim), .. }) => {
+            claim.get(node.sort.field()).is_some_and(|k| node.covers(k))
+        }
+        _ => false,
+    })
+}
+
+/// Goes over the tree of objects from the root, checking for errors.
+///
+/// Returns the set of live objects and the set of blobs they reference.
+/// Objects reached through a reference are only live if the policy keeps
+/// them.
+pub(crate) fn mark<G: Graph + ?Sized>(index: &G,
+                                      policy: &Rc<RefCell<Box<dyn Policy>>>)
+    -> errors::Result<(HashSet<ObjectId>, HashSet<BlobId>)>
+{
+    let mut alive = HashSet::new(); // ids
+    let mut live_blobs = HashSet::new(); // ids
+    // ids, with the property referencing them if the policy has to be
+    // asked, and the policy to use
+    let mut open: VecDeque<(ObjectId, Option<String>, Rc<RefCell<_>>)> =
+        VecDeque::new();
+    if index.get_object(index.root())?.is_none() {
+        error!("Root is missing: {}", index.root());
+    } else {
+        open.push_front((index.root().clone(), None, policy.clone()));
+    }
+    while let Some((id, property, policy)) = open.pop_front() {
+        debug!("Walking, open={}, alive={}, id={}",
+               open.len(), alive.len(), id);
+        if alive.contains(&id) {
+            debug!("  already alive");
+            continue;
+        }
+        let object = match index.get_object(&id)? {
+            Some(o) => o,
+            None => {
+                info!("Don't have object {}", id);
+                continue;
+            }
+        };
+        let policy = match property {
+            Some(property) => {
+                let (decision, child) =
+                    policy.borrow_mut().handle(&property, &object);
+                if decision == PolicyDecision::Drop {
+                    debug!("  dropped by policy");
+                    continue;
+                }
+                Rc::new(RefCell::new(child))
+            }
+            None => policy,
+        };
+        // The claims on a live permanode are alive too; they are not
+        // referenced from it, only reference it. Those summarized by the
+        // latest checkpoint are not needed anymore, the checkpoint is.
+        // The deleted ones are up to the policy
+        let node = if object.kind() == Some("permanode") {
+            index.permanode(&id)?
+        } else {
+            None
+        };
+        if let Some(node) = node {
+            if let Some((_, ref checkpoint)) = node.checkpoint {
+                open.push_back((checkpoint.clone(), None, policy.clone()));
+            }
+            let node_deleted = !index.tombstones(&id)?.is_empty();
+            for claim in index.node_claims(&id)? {
+                if claim_covered(index, &node, &claim)? {
+                    continue;
+                }
+                let property = if node_deleted ||
+                    !index.tombstones(&claim)?.is_empty()
+                {
+                    Some("dhstore_deleted".to_owned())
+                } else {
+                    None
+                };
+                open.push_back((claim, property, policy.clone()));
+            }
+        }
+        // Same for the signatures and tombstones of an object
+        for attached in index.signatures(&id)?.into_iter()
+            .chain(index.tombstones(&id)?)
+        {
+            open.push_back((attached, None, policy.clone()));
+        }
+        alive.insert(id);
+        let mut handle = |key: String, value: &Property| {
+            match *value {
+                Property::Reference(ref id) => {
+                    open.push_back((id.clone(), Some(key), policy.clone()));
+                }
+                Property::Blob(ref id) => {
+                    live_blobs.insert(id.clone());
+                }
+                _ => {}
+            }
+        };
+        match object.data {
+            ObjectData::Dict(ref dict) => {
+                debug!("  is dict, {} values", dict.len());
+                for (k, v) in dict {
+                    handle(k.clone(), v);
+                }
+            }
+            ObjectData::List(ref list) => {
+                debug!("  is list, {} values", list.len());
+                for (i, v) in list.iter().enumerate() {
+                    handle(i.to_string(), v);
+                }
+            }
+        }
+    }
+    Ok((alive, live_blobs))
+}
+
+/// Checks the root config, returning the ID of the log if it has one.
+pub(crate) fn root_log<I: ObjectIndex + ?Sized>(index: &I, root: &ObjectId)
+    -> errors::Result<Option<ObjectId>>
+{
+    let config = index.get_object(root)?
+        .ok_or(Error::CorruptedStore("Missing root object"))?;
+    let config = match config.data {
+        ObjectData::Dict(ref dict) => dict,
+        _ => return Err(Error::CorruptedStore(
+            "Root object is not a dict")),
+    };
+    match config.get("log") {
+        Some(&Property::Reference(ref id)) => {
+            let log_obj = index.get_object(id)?
+                .ok_or(Error::CorruptedStore("Missing log object"))?;
+            match log_obj.data {
+                ObjectData::Dict(_) => {
+                    debug!("Activated log: {}", id);
+                }
+                _ => {
+                    return Err(Error::CorruptedStore(
+                        "Log is not a permanode"));
+                }
+            }
+            Ok(Some(id.clone()))
+        }
+        Some(_) => Err(Error::CorruptedStore("Log is not a reference")),
+        None => Ok(None),
+    }
+}
+
+/// Picks the objects that could be the root config of a store.
+///
+/// These are the dicts that no other object references; the ones that look
+/// like a root config (they reference a log) come first.
+pub(crate) fn root_candidates(objects: Vec<Object>) -> Vec<Object> {
+    let mut referenced = HashSet::new();
+    for object in &objects {
+        let values: Box<dyn Iterator<Item = &Property>> = match object.data {
+            ObjectData::Dict(ref d) => Box::new(d.values()),
+            ObjectData::List(ref l) => Box::new(l.iter()),
+        };
+        for value in values {
+            if let Property::Reference(id) = value {
+                referenced.insert(id.clone());
+            }
+        }
+    }
+    let is_config = |object: &Object| match object.data {
+        ObjectData::Dict(ref d) => {
+            matches!(d.get("log"), Some(Property::Reference(_)))
+        }
+        ObjectData::List(_) => false,
+    };
+    let mut candidates: Vec<Object> = objects.into_iter()
+        .filter(|o| !referenced.contains(&o.id))
+        .filter(|o| matches!(o.data, ObjectData::Dict(_)))
+        .collect();
+    candidates.sort_by_key(|o| !is_config(o));
+    info!("Found {} candidate roots", candidates.len());
+    candidates
+}
+
+/// Reads all the objects from a directory, passing them to `handle`.
+///
+/// The subdirectories are read and deserialized by several threads, while
+/// this one passes the objects on as they come. If `permissive` is set, the
+/// object files that can't be read are returned instead of failing.
+pub(crate) fn read_objects<F: FnMut(Object)>(path: &Path, permissive: bool,
+                                             mut handle: F)
+    -> errors::Result<Vec<Quarantined>>
+{
+    let mut dirs = Vec::new();
+    let dirlist = path.read_dir()
+        .map_err(|e| ("Error listing objects directory", e))?;
+    for first in dirlist {
+        let first = first
+            .map_err(|e| ("Error listing objects directory", e))?;
+        dirs.push(first.path());
+    }
+
+    let threads = thread::available_parallelism()
+        .map_or(1, |n| n.get())
+        .min(dirs.len())
+        .max(1);
+    debug!("Reading {} object directories with {} threads",
+           dirs.len(), threads);
+    let next_dir = AtomicUsize::new(0);
+    thread::scope(|scope| {
+        let (sender, receiver) = mpsc::sync_channel(threads * 2);
+        for _ in 0..threads {
+            let sender = sender.clone();
+            let (dirs, next_dir) = (&dirs, &next_dir);
+            scope.spawn(move || {
+                while let Some(dir) =
+                    dirs.get(next_dir.fetch_add(1, Ordering::Relaxed))
+                {
+                    // Stop if the receiving end gave up on an error
+                    if sender.send(read_objects_dir(dir, permissive))
+                        .is_err()
+                    {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let mut quarantined = Vec::new();
+        for batch in receiver {
+            let (objects, bad) = batch?;
+            objects.into_iter().for_each(&mut handle);
+            quarantined.extend(bad);
+        }
+        Ok(quarantined)
+    })
+}
+
+/// Reads the objects from one subdirectory, see `read_objects()`.
+fn read_objects_dir(dir: &Path, permissive: bool)
+    -> errors::Result<(Vec<Object>, Vec<Quarantined>)>
+{
+    let mut objects = Vec::new();
+    let mut quarantined = Vec::new();
+    let first = dir.file_name().unwrap();
+    let dirlist = dir.read_dir()
+        .map_err(|e| ("Error listing objects subdirectory", e))?;
+    for second in dirlist {
+        let second = second
+            .map_err(|e| ("Error listing objects subdirectory", e))?;
+        let filename = second.path();
+
+        // Read object
+        let object = File::open(filename)
+            .and_then(serialize::deserialize);
+        match object {
+            Err(e) => {
+                let mut path: PathBuf = first.into();
+                path.push(second.file_name());
+                error!("Error deserializing object: {:?}", path);
+                if !permissive {
+                    return Err(("Error deserializing object", e).into());
+                }
+                let id = match (first.to_str(), second.file_name().to_str()) {
+                    (Some(a), Some(b)) => {
+                        ObjectId::parse(format!("{}{}", a, b).as_bytes())
+                    }
+                    _ => None,
+                };
+                quarantined.push(Quarantined {
+                    path,
+                    id,
+                    error: e.to_string(),
+                });
+            }
+            Ok(o) => objects.push(o),
+        }
+    }
+    Ok((objects, quarantined))
+}
+
 /// The in-memory index, that loads all objects from the disk on startup.
 pub struct MemoryIndex {
     /// Directory where objects are stored on disk.
     path: PathBuf,
     /// All objects, indexed by their ID.
-    objects: HashMap<ID, Object>,
-    /// Back references: value is all references pointing to the key.
-    backlinks: HashMap<ID, HashSet<(Backkey, ID)>>,
+    objects: HashMap<ObjectId, Object>,
+    /// Back references: value is all references pointing to the key, which
+    /// can be an object or a blob.
+    backlinks: HashMap<ID, HashSet<(Backkey, ObjectId)>>,
     /// All claim objects, whether they are valid for permanode or not.
-    claims: HashMap<ID, HashSet<ID>>,
+    claims: HashMap<ObjectId, HashSet<ObjectId>>,
     /// All permanodes, with valid associated claims.
-    permanodes: HashMap<ID, Permanode>,
-    root: ID,
-    log: Option<ID>,
-    policy: Box<dyn Policy>,
+    permanodes: HashMap<ObjectId, Permanode>,
+    /// All checkpoint objects, indexed by their permanode.
+    checkpoints: HashMap<ObjectId, HashSet<ObjectId>>,
+    /// Checkpoints waiting for their list of members to be added.
+    pending_checkpoints: HashMap<ObjectId, HashSet<ObjectId>>,
+    /// Signature objects, indexed by the object they sign.
+    signatures: HashMap<ObjectId, HashSet<ObjectId>>,
+    /// Tombstone objects, indexed by the permanode or claim they delete.
+    tombstones: HashMap<ObjectId, HashSet<ObjectId>>,
+    root: ObjectId,
+    log: Option<ObjectId>,
+    /// Shared with the objects it hands its decisions down to, while marking
+    policy: Rc<RefCell<Box<dyn Policy>>>,
+    /// Object files that couldn't be read, when opened in permissive mode.
+    quarantined: Vec<Quarantined>,
+    /// Approximate memory taken by the objects, see `object_size()`.
+    bytes: u64,
+    /// Whether the index was over the policy's budget last time it was
+    /// checked, so the warning is only logged once.
+    over_budget: bool,
 }
 
 impl MemoryIndex {
     /// Reads all the objects from a directory into memory.
-    pub fn open<P: AsRef<Path>>(path: P, root: ID)
+    ///
+    /// An object file that can't be read fails the whole open, see
+    /// `open_permissive()`.
+    pub fn open<P: AsRef<Path>>(path: P, root: ObjectId)
+        -> errors::Result<MemoryIndex>
+    {
+        MemoryIndex::load(path.as_ref(), root, false)
+    }
+
+    /// Reads the objects from a directory, skipping the unreadable ones.
+    ///
+    /// The files that can't be read are left in place and listed by
+    /// `quarantined()`, so the rest of a partially corrupt store can be
+    /// used. Adding one of these objects again replaces its file. Garbage
+    /// collection is refused while there are any, as what they reference
+    /// would look dead.
+    pub fn open_permissive<P: AsRef<Path>>(path: P, root: ObjectId)
+        -> errors::Result<MemoryIndex>
+    {
+        MemoryIndex::load(path.as_ref(), root, true)
+    }
+
+    fn load(path: &Path, root: ObjectId, permissive: bool)
         -> errors::Result<MemoryIndex>
     {
-        let path = path.as_ref();
         let mut index = MemoryIndex {
             path: path.to_path_buf(),
             objects: HashMap::new(),
             backlinks: HashMap::new(),
             claims: HashMap::new(),
             permanodes: HashMap::new(),
+            checkpoints: HashMap::new(),
+            pending_checkpoints: HashMap::new(),
+            signatures: HashMap::new(),
+            tombstones: HashMap::new(),
             root: root.clone(),
             log: None,
-            policy: Box::new(KeepPolicy::new()),
+            policy: Rc::new(RefCell::new(Box::new(KeepPolicy::new()))),
+            quarantined: Vec::new(),
+            bytes: 0,
+            over_budget: false,
         };
-        let dirlist = path.read_dir()
-            .map_err(|e| ("Error listing objects directory", e))?;
-        for first in dirlist {
-            let first = first
-                .map_err(|e| ("Error listing objects directory", e))?;
-            let dirlist = first.path().read_dir()
-                .map_err(|e| ("Error listing objects subdirectory", e))?;
-            for second in dirlist {
-                let second = second
-                    .map_err(|e| ("Error listing objects subdirectory", e))?;
-                let filename = second.path();
-
-                // Read object
-                let fp = File::open(filename)
-                    .map_err(|e| ("Error opening object", e))?;
-                let object = match serialize::deserialize(fp) {
-                    Err(e) => {
-                        let mut path: PathBuf = first.file_name().into();
-                        path.push(second.file_name());
-                        error!("Error deserializing object: {:?}", path);
-                        return Err(("Error deserializing object", e).into());
-                    }
-                    Ok(o) => o,
-                };
-
-                index.insert_object_in_index(object);
-            }
+        index.quarantined = read_objects(path, permissive, |object| {
+            index.insert_object_in_index(object)
+        })?;
+        if !index.quarantined.is_empty() {
+            warn!("Skipped {} unreadable objects", index.quarantined.len());
         }
 
-        // Parse root config
-        index.log = {
-            let config = index.get_object(&root)?
-                .ok_or(Error::CorruptedStore("Missing root object"))?;
-            let config = match config.data {
-                ObjectData::Dict(ref dict) => dict,
-                _ => return Err(Error::CorruptedStore(
-                    "Root object is not a dict")),
-            };
-            match config.get("log") {
-                Some(&Property::Reference(ref id)) => {
-                    let log_obj = index.get_object(id)?
-                        .ok_or(Error::CorruptedStore("Missing log object"))?;
-                    match log_obj.data {
-                        ObjectData::Dict(_) => {
-                            debug!("Activated log: {}", id);
-                        }
-                        _ => {
-                            return Err(Error::CorruptedStore(
-                                "Log is not a permanode"));
-                        }
-                    }
-                    Some(id.clone())
-                }
-                Some(_) => return Err(Error::CorruptedStore(
-                    "Log is not a reference")),
-                None => None,
-            }
-        };
+        index.log = root_log(&index, &root)?;
 
         Ok(index)
     }
 
+    /// Finds the objects that could be the root config of a store.
+    ///
+    /// This reads all the objects from a directory, without needing a root,
+    /// and picks them with `root_candidates()`. This is used to recover a
+    /// store whose root file was lost.
+    pub fn root_candidates<P: AsRef<Path>>(path: P)
+        -> errors::Result<Vec<Object>>
+    {
+        let mut objects = Vec::new();
+        read_objects(path.as_ref(), false, |object| objects.push(object))?;
+        Ok(root_candidates(objects))
+    }
+
+    /// Replaces the policy deciding which objects are valid and kept.
+    pub fn set_policy(&mut self, policy: Box<dyn Policy>) {
+        self.policy = Rc::new(RefCell::new(policy));
+        self.check_budget();
+    }
+
+    /// The number of objects in the index, and approximately how much
+    /// memory they take.
+    pub fn usage(&self) -> (usize, u64) {
+        (self.objects.len(), self.bytes)
+    }
+
+    /// Whether the index is over the budget of its policy.
+    pub fn is_over_budget(&self) -> bool {
+        self.over_budget
+    }
+
+    /// Compares the size of the index with the policy's budget, warning
+    /// when it goes over.
+    fn check_budget(&mut self) {
+        let budget = self.policy.borrow().budget();
+        let over = budget.max_objects.is_some_and(|m| self.objects.len() > m)
+            || budget.max_bytes.is_some_and(|m| self.bytes > m);
+        if over && !self.over_budget {
+            warn!("Index is over budget: {} objects, about {} bytes \
+                   (limits: {:?} objects, {:?} bytes)",
+                  self.objects.len(), self.bytes, budget.max_objects,
+                  budget.max_bytes);
+        }
+        self.over_budget = over;
+    }
+
     pub fn create<'a, P: AsRef<Path>, I: Iterator<Item=&'a Object>>(
             path: P, objects: I)
         -> io::Result<()>
@@ -238,13 +824,39 @@ impl MemoryIndex {
         Ok(())
     }
 
+    /// Copies all the objects from a directory to another.
+    ///
+    /// Objects are read back and checked before being written. Returns the
+    /// number of objects copied.
+    pub fn copy_objects<P: AsRef<Path>, Q: AsRef<Path>>(source: P, dest: Q)
+        -> errors::Result<usize>
+    {
+        let dest = dest.as_ref();
+        let mut count = 0;
+        let mut result = Ok(());
+        read_objects(source.as_ref(), false, |object| {
+            if result.is_ok() {
+                result = MemoryIndex::write_object(dest, &object);
+                count += 1;
+            }
+        })?;
+        result.map_err(|e| ("Couldn't write object", e))?;
+        Ok(count)
+    }
+
+    /// The file an object is written to, in a subdirectory named after the
+    /// start of its ID.
+    fn object_path(dir: &Path, id: &ObjectId) -> PathBuf {
+        let hashstr = id.id().str();
+        dir.join(&hashstr[..4]).join(&hashstr[4..])
+    }
+
     fn write_object(dir: &Path, object: &Object) -> io::Result<()> {
-        let hashstr = object.id.str();
-        let mut path = dir.join(&hashstr[..4]);
-        if !path.exists() {
-            fs::create_dir(&path)?;
+        let path = MemoryIndex::object_path(dir, &object.id);
+        let parent = path.parent().unwrap();
+        if !parent.exists() {
+            fs::create_dir(parent)?;
         }
-        path.push(&hashstr[4..]);
         let mut fp = OpenOptions::new()
             .write(true)
             .create_new(true)
@@ -262,7 +874,7 @@ impl MemoryIndex {
             // Record reverse references
             // This is run on all values of type reference on the object,
             // whether it is a list or a dict
-            let mut insert = |target: &ID, key: Backkey, source: ID| {
+            let mut insert = |target: &ID, key: Backkey, source: ObjectId| {
                 if log_enabled!(Level::Debug) {
                     match key {
                         Backkey::Key(ref k) => {
@@ -282,11 +894,11 @@ impl MemoryIndex {
             };
 
             // Go over the object, calling insert() above on all its values of
-            // type reference
+            // type reference or blob
             match object.data {
                 ObjectData::Dict(ref dict) => {
                     for (k, v) in dict {
-                        if let Property::Reference(ref id) = *v {
+                        if let Some(id) = v.target() {
                             insert(id,
                                    Backkey::Key(k.clone()),
                                    object.id.clone());
@@ -295,7 +907,7 @@ impl MemoryIndex {
                 }
                 ObjectData::List(ref list) => {
                     for (k, v) in list.into_iter().enumerate() {
-                        if let Property::Reference(ref id) = *v {
+                        if let Some(id) = v.target() {
                             insert(id,
                                    Backkey::Index(k),
                                    object.id.clone());
@@ -306,6 +918,7 @@ impl MemoryIndex {
         }
 
         // Check for special objects
+        let mut apply_checkpoint = false;
         if let ObjectData::Dict(ref dict) = object.data {
             match dict.get("dhstore_kind") {
                 Some(&Property::String(ref kind)) => match kind as &str {
@@ -317,6 +930,38 @@ impl MemoryIndex {
                         info!("Found claim: {}", object.id);
                         self.index_claim(&object);
                     }
+                    "checkpoint" => {
+                        info!("Found checkpoint: {}", object.id);
+                        apply_checkpoint = self.index_checkpoint(&object);
+                    }
+                    "tombstone" => {
+                        info!("Found tombstone: {}", object.id);
+                        match dict.get("target") {
+                            Some(Property::Reference(target)) => {
+                                insert_into_multimap(&mut self.tombstones,
+                                                     target,
+                                                     object.id.clone());
+                            }
+                            _ => {
+                                warn!("Invalid tombstone {}: wrong content",
+                                      object.id);
+                            }
+                        }
+                    }
+                    "signature" => {
+                        info!("Found signature: {}", object.id);
+                        match signing::signed_object(dict) {
+                            Some(signed) => {
+                                insert_into_multimap(&mut self.signatures,
+                                                     signed,
+                                                     object.id.clone());
+                            }
+                            None => {
+                                warn!("Invalid signature {}: wrong content",
+                                      object.id);
+                            }
+                        }
+                    }
                     kind => debug!("Found unknown kind {:?}", kind),
                 },
                 Some(_) => {
@@ -327,7 +972,19 @@ impl MemoryIndex {
         }
 
         // Now inserts the object
-        self.objects.insert(object.id.clone(), object);
+        let id = object.id.clone();
+        self.bytes += object_size(&object.data);
+        self.objects.insert(id.clone(), object);
+
+        // Checkpoints can only be used once their members are there
+        if apply_checkpoint {
+            self.apply_checkpoint(&id);
+        }
+        if let Some(checkpoints) = self.pending_checkpoints.remove(&id) {
+            for checkpoint in checkpoints {
+                self.apply_checkpoint(&checkpoint);
+            }
+        }
     }
 
     fn index_permanode(&mut self, permanode: &Object) {
@@ -339,54 +996,11 @@ impl MemoryIndex {
                 panic!("Invalid permanode {}: not a dict", id);
             }
         };
-        match permanode.get("random") {
-            Some(&Property::String(ref s)) => {
-                if s.len() != HASH_STR_SIZE {
-                    warn!("Invalid permanode {}: invalid random size {}",
-                          id, s.len());
-                    return;
-                }
-            }
-            _ => {
-                warn!("Invalid permanode {}: missing random", id);
-                return;
-            }
-        }
-
-        let sort = match permanode.get("sort") {
-            Some(&Property::String(ref s)) => match s.parse() {
-                Ok(f) => f,
-                Err(()) => {
-                    warn!("Invalid permanode {}: invalid sort", id);
-                    return;
-                }
-            },
-            _ => {
-                warn!("Invalid permanode {}: invalid sort", id);
-                return;
-            }
-        };
-
-        let nodetype = match permanode.get("type") {
-            Some(&Property::String(ref s)) => match s as &str {
-                "set" | "single" => PermanodeType::Set,
-                _ => {
-                    warn!("Unknown permanode type {:?}, ignoring permanode {}",
-                          s, id);
-                    return;
-                }
-            },
-            None => PermanodeType::Single,
-            Some(_) => {
-                warn!("Invalid permanode {}: invalid type", id);
-                return;
-            }
+        let mut node = match Permanode::parse(id, permanode) {
+            Some(node) => node,
+            None => return,
         };
-
         debug!("Permanode is well-formed, adding to index");
-        let mut node = Permanode { sort: sort,
-                                   nodetype: nodetype,
-                                   claims: BTreeMap::new() };
 
         // Process claims
         if let Some(set) = self.claims.get(id) {
@@ -402,6 +1016,12 @@ impl MemoryIndex {
 
         // Insert the permanode in the index
         self.permanodes.insert(id.clone(), node);
+
+        // Process checkpoints
+        let checkpoints = self.checkpoints.get(id).cloned();
+        for checkpoint in checkpoints.into_iter().flatten() {
+            self.apply_checkpoint(&checkpoint);
+        }
     }
 
     fn index_claim(&mut self, claim: &Object) {
@@ -411,10 +1031,9 @@ impl MemoryIndex {
             ObjectData::Dict(ref d) => d,
             _ => panic!("Invalid claim {}: not a dict", id),
         };
-        let permanode = match (claim.get("node"), claim.get("value")) {
-            (Some(&Property::Reference(ref r)),
-             Some(&Property::Reference(_))) => r,
-            _ => {
+        let permanode = match claim_node(claim) {
+            Some(node) => node,
+            None => {
                 warn!("Invalid claim {}: wrong content", id);
                 return;
             }
@@ -432,63 +1051,72 @@ impl MemoryIndex {
         }
     }
 
+    /// Indexes a checkpoint, returning whether it can be applied once it is
+    /// inserted, i.e. if its members are there.
+    fn index_checkpoint(&mut self, checkpoint: &Object) -> bool {
+        // Validate the checkpoint
+        let id = &checkpoint.id;
+        let checkpoint = match checkpoint.data {
+            ObjectData::Dict(ref d) => d,
+            _ => panic!("Invalid checkpoint {}: not a dict", id),
+        };
+        let (permanode, _, members) = match checkpoint_fields(checkpoint) {
+            Some(fields) => fields,
+            None => {
+                warn!("Invalid checkpoint {}: wrong content", id);
+                return false;
+            }
+        };
+
+        debug!("Checkpoint is well-formed, adding to index");
+        insert_into_multimap(&mut self.checkpoints, permanode, id.clone());
+        if self.objects.contains_key(members) {
+            true
+        } else {
+            debug!("Members of checkpoint {} are not there yet", id);
+            insert_into_multimap(&mut self.pending_checkpoints, members,
+                                 id.clone());
+            false
+        }
+    }
+
+    /// Indexes the values of a checkpoint in its permanode, if there.
+    fn apply_checkpoint(&mut self, id: &ObjectId) {
+        let checkpoint = match self.objects.get(id).map(|o| &o.data) {
+            Some(ObjectData::Dict(d)) => d,
+            _ => return,
+        };
+        let (permanode, upto, members) = match checkpoint_fields(checkpoint) {
+            Some(fields) => fields,
+            None => return,
+        };
+        let node = match self.permanodes.get_mut(permanode) {
+            Some(node) => node,
+            None => return,
+        };
+        let members = match self.objects.get(members).map(|o| &o.data) {
+            Some(ObjectData::List(list)) => checkpoint_members(list),
+            Some(ObjectData::Dict(_)) => None,
+            None => return,
+        };
+        match members {
+            Some(members) => node.index_checkpoint(upto, id, members),
+            None => warn!("Invalid checkpoint {}: wrong members", id),
+        }
+    }
+
+    /// Whether a tombstone targets this object.
+    fn has_tombstone(&self, id: &ObjectId) -> bool {
+        self.tombstones.get(id).is_some_and(|set| !set.is_empty())
+    }
+
     /// Common logic for `verify()` and `collect_garbage().`
     ///
     /// Goes over the tree of objects, checking for errors. If `collect` is
-    /// true, unreferenced objects are deleted, and the set of referenced blobs
-    /// is returned; else, an empty `HashSet` is returned.
-    fn walk(&mut self, collect: bool) -> errors::Result<HashSet<ID>> {
-        let mut alive = HashSet::new(); // ids
-        let mut live_blobs = HashSet::new(); // ids
-        let mut open = VecDeque::new(); // ids
-        if self.objects.get(&self.root).is_none() {
-            error!("Root is missing: {}", self.root);
-        } else {
-            open.push_front(self.root.clone());
-        }
-        while let Some(id) = open.pop_front() {
-            debug!("Walking, open={}, alive={}/{}, id={}",
-                   open.len(), alive.len(), self.objects.len(), id);
-            let object = match self.objects.get(&id) {
-                Some(o) => o,
-                None => {
-                    info!("Don't have object {}", id);
-                    continue;
-                }
-            };
-            if alive.contains(&id) {
-                debug!("  already alive");
-                continue;
-            }
-            alive.insert(id);
-            let mut handle = |value: &Property| {
-                match *value {
-                    Property::Reference(ref id) => {
-                        open.push_back(id.clone());
-                    }
-                    Property::Blob(ref id) => {
-                        if collect {
-                            live_blobs.insert(id.clone());
-                        }
-                    }
-                    _ => {}
-                }
-            };
-            match object.data {
-                ObjectData::Dict(ref dict) => {
-                    debug!("  is dict, {} values", dict.len());
-                    for v in dict.values() {
-                        handle(v);
-                    }
-                }
-                ObjectData::List(ref list) => {
-                    debug!("  is list, {} values", list.len());
-                    for v in list {
-                        handle(v);
-                    }
-                }
-            }
-        }
+    /// true, unreferenced objects are deleted, along with their file. Returns
+    /// the set of blobs that are referenced.
+    fn walk(&mut self, collect: bool) -> errors::Result<HashSet<BlobId>> {
+        let (alive, live_blobs) = mark(self, &self.policy)?;
         info!("Found {}/{} live objects", alive.len(), self.objects.len());
         if collect {
             let dead_objects = self.objects.keys()
@@ -497,35 +1125,423 @@ impl MemoryIndex {
                 .collect::<Vec<_>>();
             info!("Removing {} dead objects", dead_objects.len());
             for id in dead_objects {
-                self.objects.remove(&id);
+                if let Some(object) = self.objects.remove(&id) {
+                    self.bytes -= object_size(&object.data);
+                }
+                // Delete the file too, otherwise the object would be back
+                // the next time the index is opened, while the blobs it
+                // references are gone
+                let path = MemoryIndex::object_path(&self.path, &id);
+                match fs::remove_file(&path) {
+                    Err(ref e) if e.kind() == io::ErrorKind::NotFound => {}
+                    r => r.map_err(|e| ("Couldn't remove dead object", e))?,
+                }
             }
+            self.signatures.retain(|id, _| alive.contains(id));
+            // Forget the values of the claims that were dropped
+            self.permanodes.retain(|id, _| alive.contains(id));
+            for node in self.permanodes.values_mut() {
+                node.claims.retain(|_, (claim, _)| alive.contains(claim));
+            }
+            for set in self.claims.values_mut()
+                .chain(self.checkpoints.values_mut())
+                .chain(self.tombstones.values_mut())
+                .chain(self.pending_checkpoints.values_mut())
+            {
+                set.retain(|id| alive.contains(id));
+            }
+            self.check_budget();
         }
         Ok(live_blobs)
     }
 }
 
 impl ObjectIndex for MemoryIndex {
-    fn add(&mut self, data: ObjectData) -> errors::Result<ID> {
+    fn add(&mut self, data: ObjectData) -> errors::Result<ObjectId> {
         let object = serialize::hash_object(data);
         let id = object.id.clone();
         if !self.objects.contains_key(&id) {
+            let mut span = Span::enter("index_add");
+            span.detail(&id);
             info!("Adding object to index: {}", id);
-            MemoryIndex::write_object(&self.path, &object)
-                .map_err(|e| ("Couldn't write object to disk", e))?;
+            if let Some(pos) = self.quarantined.iter()
+                .position(|q| q.id.as_ref() == Some(&id))
+            {
+                info!("Replacing unreadable file {:?}",
+                      self.quarantined[pos].path);
+                fs::remove_file(self.path.join(&self.quarantined[pos].path))
+                    .map_err(|e| ("Couldn't remove unreadable object", e))?;
+                self.quarantined.remove(pos);
+            }
+            match MemoryIndex::write_object(&self.path, &object) {
+                // The object was left over, e.g. by an interrupted garbage
+                // collection; the file has the same contents
+                Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                    debug!("Object file already exists");
+                }
+                r => r.map_err(|e| ("Couldn't write object to disk", e))?,
+            }
             self.insert_object_in_index(object);
+            self.check_budget();
         }
         Ok(id)
     }
 
-    fn get_object(&self, id: &ID) -> errors::Result<Option<&Object>> {
-        Ok(self.objects.get(id))
+    fn get_object(&self, id: &ObjectId)
+        -> errors::Result<Option<Cow<'_, Object>>>
+    {
+        Ok(self.objects.get(id).map(Cow::Borrowed))
+    }
+
+    fn list_objects(&self) -> errors::Result<ObjectIterator<'_>> {
+        Ok(Box::new(self.objects.values().map(|o| Ok(Cow::Borrowed(o)))))
+    }
+
+    fn root(&self) -> &ObjectId {
+        &self.root
     }
 
     fn verify(&mut self) -> errors::Result<()> {
+        for q in &self.quarantined {
+            error!("Unreadable object {:?}: {}", q.path, q.error);
+        }
+        if self.over_budget {
+            warn!("Index is over budget: {} objects, about {} bytes",
+                  self.objects.len(), self.bytes);
+        }
         self.walk(false).map(|_| ())
     }
 
-    fn collect_garbage(&mut self) -> errors::Result<HashSet<ID>> {
+    fn quarantined(&self) -> &[Quarantined] {
+        &self.quarantined
+    }
+
+    fn signatures(&self, id: &ObjectId) -> errors::Result<Vec<ObjectId>> {
+        Ok(self.signatures.get(id).into_iter().flatten().cloned().collect())
+    }
+
+    fn referrers(&self, id: &ID)
+        -> errors::Result<Vec<(Backkey, ObjectId)>>
+    {
+        // Back references of collected objects are left in the map
+        let mut referrers: Vec<(Backkey, ObjectId)> = self.backlinks.get(id)
+            .into_iter()
+            .flatten()
+            .filter(|(_, source)| self.objects.contains_key(source))
+            .cloned()
+            .collect();
+        referrers.sort_by_key(|(key, source)| {
+            (source.id().str(), key.clone())
+        });
+        Ok(referrers)
+    }
+
+    fn collect_garbage(&mut self) -> errors::Result<HashSet<BlobId>> {
+        // What the unreadable objects reference can't be marked, and would
+        // be collected with them
+        if !self.quarantined.is_empty() {
+            return Err(Error::CorruptedStore(
+                "Can't collect garbage while objects are unreadable"));
+        }
         self.walk(true)
     }
+
+    fn live_blobs(&self) -> errors::Result<HashSet<BlobId>> {
+        Ok(mark(self, &self.policy)?.1)
+    }
+
+    fn list_permanode_with(&self, id: &ObjectId, after: Option<&Property>,
+                           limit: usize, include_deleted: bool)
+        -> errors::Result<Option<MemberPage>>
+    {
+        let node = match self.permanodes.get(id) {
+            Some(node) => node,
+            None => return Ok(None),
+        };
+        let node_deleted = self.has_tombstone(id);
+        node.page(after, limit, include_deleted, |claim| {
+            Ok(node_deleted || self.has_tombstone(claim))
+        }).map(Some)
+    }
+
+    fn is_deleted(&self, id: &ObjectId) -> errors::Result<bool> {
+        if self.has_tombstone(id) {
+            return Ok(true);
+        }
+        let object = match self.objects.get(id) {
+            Some(object) if object.kind() == Some("claim") => object,
+            _ => return Ok(false),
+        };
+        Ok(match object.data {
+            ObjectData::Dict(ref dict) => match dict.get("node") {
+                Some(Property::Reference(node)) => self.has_tombstone(node),
+                _ => false,
+            },
+            ObjectData::List(_) => false,
+        })
+    }
+}
+
+impl Graph for MemoryIndex {
+    fn permanode(&self, id: &ObjectId)
+        -> errors::Result<Option<Cow<'_, Permanode>>>
+    {
+        Ok(self.permanodes.get(id).map(Cow::Borrowed))
+    }
+
+    fn node_claims(&self, id: &ObjectId) -> errors::Result<Vec<ObjectId>> {
+        Ok(self.claims.get(id).into_iter().flatten().cloned().collect())
+    }
+
+    fn tombstones(&self, id: &ObjectId) -> errors::Result<Vec<ObjectId>> {
+        Ok(self.tombstones.get(id).into_iter().flatten().cloned().collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+
+    use std::collections::HashSet;
+    use std::fs::File;
+
+    use crate::common::{Dict, Object, ObjectData, ObjectId, ObjectIndex,
+                        Property, Sort};
+    use crate::errors::Error;
+    use crate::serialize::{self, hash_object};
+    use crate::{checkpoint, claim, permanode, tombstone};
+    use super::{MemoryIndex, Policy, PolicyDecision, read_objects};
+
+    /// Drops the claims that are deleted, keeps everything else.
+    struct DropDeleted;
+
+    impl Policy for DropDeleted {
+        fn handle(&mut self, property: &str, _object: &Object)
+                  -> (PolicyDecision, Box<dyn Policy>) {
+            let decision = if property == "dhstore_deleted" {
+                PolicyDecision::Drop
+            } else {
+                PolicyDecision::Keep
+            };
+            (decision, Box::new(DropDeleted))
+        }
+    }
+
+    fn dated(date: i64) -> Dict {
+        let mut data = Dict::new();
+        data.insert("date".into(), Property::Integer(date));
+        data
+    }
+
+    /// Index with a set permanode, claimed from the log so it's alive, and
+    /// two values claimed on it.
+    struct Fixture {
+        path: PathBuf,
+        index: MemoryIndex,
+        node: ObjectId,
+        claims: [ObjectId; 2],
+        values: [ObjectId; 2],
+    }
+
+    impl Fixture {
+        fn new(name: &str) -> Fixture {
+            let path = std::env::temp_dir().join(format!(
+                "dhstore-test-index-{}-{}", name, std::process::id()));
+            if path.exists() {
+                fs::remove_dir_all(&path).unwrap();
+            }
+            fs::create_dir_all(&path).unwrap();
+            let mut log = Dict::new();
+            log.insert("type".into(), Property::String("set".into()));
+            let log = permanode(log, Sort::Ascending("date".into()));
+            let mut config = Dict::new();
+            config.insert("log".into(), Property::Reference(log.id.clone()));
+            let config = hash_object(ObjectData::Dict(config));
+            MemoryIndex::create(&path, [log.clone(), config.clone()].iter())
+                .unwrap();
+            let mut index = MemoryIndex::open(&path, config.id).unwrap();
+
+            let mut node = Dict::new();
+            node.insert("type".into(), Property::String("set".into()));
+            let node = index.add(
+                permanode(node, Sort::Ascending("date".into())).data)
+                .unwrap();
+            index.add(claim(&log.id, &node, dated(1))).unwrap();
+            let mut claims = Vec::new();
+            let mut values = Vec::new();
+            for i in 1..3 {
+                let value = index.add(ObjectData::List(vec![
+                    Property::Integer(i)])).unwrap();
+                claims.push(index.add(claim(&node, &value, dated(i)))
+                            .unwrap());
+                values.push(value);
+            }
+            Fixture {
+                path,
+                index,
+                node,
+                claims: [claims[0].clone(), claims[1].clone()],
+                values: [values[0].clone(), values[1].clone()],
+            }
+        }
+
+        fn listed(&self, include_deleted: bool) -> Vec<(ObjectId, bool)> {
+            self.index.list_permanode_with(&self.node, None, 10,
+                                           include_deleted)
+                .unwrap().unwrap()
+                .members.into_iter()
+                .map(|m| (m.value, m.deleted))
+                .collect()
+        }
+
+        fn has(&self, id: &ObjectId) -> bool {
+            self.index.get_object(id).unwrap().is_some()
+        }
+    }
+
+    impl Drop for Fixture {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+
+    #[test]
+    fn test_delete_claim() {
+        let mut f = Fixture::new("delete-claim");
+        f.index.add(tombstone(&f.claims[0], dated(3))).unwrap();
+        assert!(f.index.is_deleted(&f.claims[0]).unwrap());
+        assert!(!f.index.is_deleted(&f.claims[1]).unwrap());
+        assert!(!f.index.is_deleted(&f.values[0]).unwrap());
+        assert!(!f.index.is_deleted(&f.node).unwrap());
+        assert_eq!(f.listed(false), vec![(f.values[1].clone(), false)]);
+        assert_eq!(f.listed(true), vec![(f.values[0].clone(), true),
+                                        (f.values[1].clone(), false)]);
+
+        // The default policy keeps deleted claims
+        f.index.collect_garbage().unwrap();
+        assert!(f.has(&f.claims[0]) && f.has(&f.values[0]));
+        assert_eq!(f.listed(true).len(), 2);
+
+        // This one lets them go, with the values only they reference
+        f.index.set_policy(Box::new(DropDeleted));
+        f.index.collect_garbage().unwrap();
+        assert!(!f.has(&f.claims[0]) && !f.has(&f.values[0]));
+        assert!(f.has(&f.claims[1]) && f.has(&f.values[1]));
+        assert_eq!(f.listed(true), vec![(f.values[1].clone(), false)]);
+    }
+
+    #[test]
+    fn test_delete_node() {
+        let mut f = Fixture::new("delete-node");
+        f.index.add(tombstone(&f.node, dated(3))).unwrap();
+        assert!(f.index.is_deleted(&f.node).unwrap());
+        // The claims of a deleted node are deleted, not their values
+        assert!(f.index.is_deleted(&f.claims[0]).unwrap());
+        assert!(f.index.is_deleted(&f.claims[1]).unwrap());
+        assert!(!f.index.is_deleted(&f.values[1]).unwrap());
+        assert!(f.listed(false).is_empty());
+        assert_eq!(f.listed(true), vec![(f.values[0].clone(), true),
+                                        (f.values[1].clone(), true)]);
+
+        f.index.collect_garbage().unwrap();
+        assert_eq!(f.listed(true).len(), 2);
+
+        // The node itself is still referenced from the log
+        f.index.set_policy(Box::new(DropDeleted));
+        f.index.collect_garbage().unwrap();
+        assert!(f.has(&f.node));
+        for id in f.claims.iter().chain(&f.values) {
+            assert!(!f.has(id));
+        }
+        assert!(f.listed(true).is_empty());
+    }
+
+    #[test]
+    fn test_gc_reopen() {
+        let mut f = Fixture::new("gc-reopen");
+        let dead = f.index.add(ObjectData::List(vec![
+            Property::Integer(42)])).unwrap();
+        // The first value is only held by the checkpoint, which makes its
+        // claim dead
+        let members = f.index.add(ObjectData::List(vec![
+            Property::Integer(1),
+            Property::Reference(f.values[0].clone())])).unwrap();
+        let checkpoint = f.index.add(checkpoint(
+            &f.node, Property::Integer(1), &members)).unwrap();
+        // This claim is deleted, and only the tombstone references it
+        let value = f.index.add(ObjectData::List(vec![
+            Property::Integer(3)])).unwrap();
+        let deleted = f.index.add(claim(&f.node, &value, dated(3))).unwrap();
+        let tombstone = f.index.add(tombstone(&deleted, dated(4))).unwrap();
+
+        f.index.collect_garbage().unwrap();
+        let root = f.index.root().clone();
+        f.index = MemoryIndex::open(&f.path, root).unwrap();
+        assert!(!f.has(&dead));
+        assert!(!f.has(&f.claims[0]));
+        for id in [&f.node, &f.values[0], &members, &checkpoint, &f.claims[1],
+                   &f.values[1], &deleted, &value, &tombstone]
+        {
+            assert!(f.has(id));
+        }
+        assert_eq!(f.listed(false), vec![(f.values[0].clone(), false),
+                                         (f.values[1].clone(), false)]);
+        assert_eq!(f.listed(true).len(), 3);
+        assert!(f.index.is_deleted(&deleted).unwrap());
+    }
+
+    #[test]
+    fn test_read_objects() {
+        let mut f = Fixture::new("read-objects");
+        // Enough objects to spread over many subdirectories
+        for i in 0..200 {
+            f.index.add(ObjectData::List(vec![
+                Property::Integer(i)])).unwrap();
+        }
+
+        // Read the files one by one
+        let mut serial = HashSet::new();
+        let mut dirs = 0;
+        for dir in fs::read_dir(&f.path).unwrap() {
+            dirs += 1;
+            for file in fs::read_dir(dir.unwrap().path()).unwrap() {
+                let file = File::open(file.unwrap().path()).unwrap();
+                serial.insert(serialize::deserialize(file).unwrap().id);
+            }
+        }
+        assert!(dirs > 100);
+        assert_eq!(serial.len(), 206);
+
+        let mut threaded = HashSet::new();
+        let quarantined = read_objects(&f.path, false, |object| {
+            assert!(threaded.insert(object.id));
+        }).unwrap();
+        assert!(quarantined.is_empty());
+        assert_eq!(threaded, serial);
+
+        // The index built from them is the same
+        let listed = f.listed(true);
+        let root = f.index.root().clone();
+        f.index = MemoryIndex::open(&f.path, root.clone()).unwrap();
+        let reopened: HashSet<ObjectId> = f.index.list_objects().unwrap()
+            .map(|o| o.unwrap().id.clone())
+            .collect();
+        assert_eq!(reopened, serial);
+        assert_eq!(f.listed(true), listed);
+
+        // One bad file fails a strict open, whichever thread reads it
+        let hashstr = f.values[0].id().str();
+        fs::write(f.path.join(&hashstr[..4]).join(&hashstr[4..]), b"garbage")
+            .unwrap();
+        match MemoryIndex::open(&f.path, root.clone()) {
+            Err(Error::IoError(..)) => {}
+            Err(e) => panic!("Expected IoError, got {:?}", e),
+            Ok(_) => panic!("Expected IoError"),
+        }
+        let index = MemoryIndex::open_permissive(&f.path, root).unwrap();
+        assert_eq!(index.quarantined().len(), 1);
+        assert_eq!(index.quarantined()[0].id.as_ref(), Some(&f.values[0]));
+        assert_eq!(index.list_objects().unwrap().count(), 205);
+    }
 }
@@ -0,0 +1,291 @@
+//! Blob storage writing every blob to several backends.
+//!
+//! This keeps full copies of the blobs on independent disks or servers, so
+//! that losing one of them doesn't lose data. Reads are served by the first
+//! replica that has the blob; after replacing a failed disk, `repair()`
+//! copies the missing blobs back onto it.
+
+use std::collections::HashSet;
+
+use log::{info, warn};
+
+use crate::common::{BlobId, BlobIterator, BlobStorage, EnumerableBlobStorage,
+                    SelfTest};
+use crate::errors::{self, Error};
+use crate::queries::Cancel;
+use crate::serialize::hash_blob;
+
+/// Blob storage duplicating its blobs across replicas.
+pub struct MirroredBlobStorage {
+    replicas: Vec<Box<dyn EnumerableBlobStorage>>,
+}
+
+impl MirroredBlobStorage {
+    /// Mirrors blobs across these storages, which can't be empty.
+    pub fn new(replicas: Vec<Box<dyn EnumerableBlobStorage>>)
+        -> MirroredBlobStorage
+    {
+        assert!(!replicas.is_empty());
+        MirroredBlobStorage { replicas }
+    }
+
+    /// Number of replicas.
+    pub fn replicas(&self) -> usize {
+        self.replicas.len()
+    }
+
+    /// Copies blobs between replicas, so that each one has all of them.
+    ///
+    /// Blobs are checked against their ID before being copied, and copies
+    /// that don't match are skipped in favor of another replica. Returns the
+    /// number of blobs copied.
+    pub fn repair(&mut self) -> errors::Result<usize> {
+        let mut contents = Vec::with_capacity(self.replicas.len());
+        for replica in &self.replicas {
+            let mut blobs = HashSet::new();
+            for id in replica.list_blobs()? {
+                blobs.insert(id?);
+            }
+            contents.push(blobs);
+        }
+        let all: HashSet<&BlobId> = contents.iter().flatten().collect();
+        info!("{} blobs across {} replicas", all.len(), self.replicas.len());
+
+        let mut copied = 0;
+        for &id in &all {
+            let missing: Vec<usize> = (0..self.replicas.len())
+                .filter(|&i| !contents[i].contains(id))
+                .collect();
+            if missing.is_empty() {
+                continue;
+            }
+            let mut blob = None;
+            for (i, replica) in self.replicas.iter().enumerate() {
+                if !contents[i].contains(id) {
+                    continue;
+                }
+                match replica.get_blob(id)? {
+                    Some(b) if hash_blob(&b) == *id => {
+                        blob = Some(b);
+                        break;
+                    }
+                    _ => warn!("Replica {} has a bad copy of blob {}", i, id),
+                }
+            }
+            let blob = match blob {
+                Some(b) => b,
+                None => {
+                    warn!("No good copy of blob {}, can't repair", id);
+                    continue;
+                }
+            };
+            for i in missing {
+                self.replicas[i].add_known_blob(id, &blob)?;
+                copied += 1;
+            }
+        }
+        info!("Copied {} blobs", copied);
+        Ok(copied)
+    }
+}
+
+impl BlobStorage for MirroredBlobStorage {
+    /// Gets the blob from the first replica that has it.
+    ///
+    /// Errors from a replica are logged and the next one is tried; they are
+    /// only returned if no replica could be read.
+    fn get_blob(&self, id: &BlobId) -> errors::Result<Option<Box<[u8]>>> {
+        let mut error = None;
+        for (i, replica) in self.replicas.iter().enumerate() {
+            match replica.get_blob(id) {
+                Ok(Some(blob)) => return Ok(Some(blob)),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!("Error reading blob {} from replica {}: {}",
+                          id, i, e);
+                    error = Some(e);
+                }
+            }
+        }
+        match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        }
+    }
+
+    fn add_blob(&mut self, blob: &[u8]) -> errors::Result<BlobId> {
+        let id = hash_blob(blob);
+        self.add_known_blob(&id, blob)?;
+        Ok(id)
+    }
+
+    fn add_known_blob(&mut self, id: &BlobId, blob: &[u8])
+        -> errors::Result<()>
+    {
+        for replica in &mut self.replicas {
+            replica.add_known_blob(id, blob)?;
+        }
+        Ok(())
+    }
+
+    fn add_known_blobs(&mut self, blobs: &[(BlobId, &[u8])])
+        -> errors::Result<()>
+    {
+        for replica in &mut self.replicas {
+            replica.add_known_blobs(blobs)?;
+        }
+        Ok(())
+    }
+
+    fn delete_blob(&mut self, id: &BlobId) -> errors::Result<()> {
+        for replica in &mut self.replicas {
+            replica.delete_blob(id)?;
+        }
+        Ok(())
+    }
+
+    fn verify(&mut self, cancel: &Cancel) -> errors::Result<()> {
+        for (i, replica) in self.replicas.iter_mut().enumerate() {
+            info!("Verifying replica {}...", i);
+            replica.verify(cancel)?;
+        }
+        Ok(())
+    }
+
+    /// Tests each replica, returning the slowest time for each step.
+    fn self_test(&mut self) -> errors::Result<SelfTest> {
+        let mut slowest: Option<SelfTest> = None;
+        for (i, replica) in self.replicas.iter_mut().enumerate() {
+            info!("Testing replica {}...", i);
+            let test = replica.self_test()?;
+            slowest = Some(match slowest {
+                Some(s) => SelfTest {
+                    write: s.write.max(test.write),
+                    read: s.read.max(test.read),
+                    delete: s.delete.max(test.delete),
+                },
+                None => test,
+            });
+        }
+        slowest.ok_or(Error::InvalidInput("No replicas to test"))
+    }
+
+    fn contains(&self, id: &BlobId) -> errors::Result<bool> {
+        for replica in &self.replicas {
+            if replica.contains(id)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    fn size(&self, id: &BlobId) -> errors::Result<Option<u64>> {
+        for replica in &self.replicas {
+            if let Some(size) = replica.size(id)? {
+                return Ok(Some(size));
+            }
+        }
+        Ok(None)
+    }
+
+    fn is_local(&self, id: &BlobId) -> errors::Result<bool> {
+        for replica in &self.replicas {
+            if replica.is_local(id)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Reports the smallest free space of the replicas, since writes go to
+    /// all of them.
+    fn free_space(&self) -> errors::Result<Option<u64>> {
+        let mut min = None;
+        for replica in &self.replicas {
+            if let Some(free) = replica.free_space()? {
+                min = Some(min.map_or(free, |m: u64| m.min(free)));
+            }
+        }
+        Ok(min)
+    }
+}
+
+impl EnumerableBlobStorage for MirroredBlobStorage {
+    /// Lists the blobs present on any replica.
+    fn list_blobs(&self) -> errors::Result<BlobIterator> {
+        let mut blobs = HashSet::new();
+        for replica in &self.replicas {
+            for id in replica.list_blobs()? {
+                blobs.insert(id?);
+            }
+        }
+        Ok(Box::new(blobs.into_iter().map(Ok)))
+    }
+
+    fn collect_garbage(&mut self, alive: HashSet<BlobId>, cancel: &Cancel)
+        -> errors::Result<()>
+    {
+        let mut errors = 0;
+        for (i, replica) in self.replicas.iter_mut().enumerate() {
+            cancel.check()?;
+            if let Err(e) = replica.collect_garbage(alive.clone(), cancel) {
+                warn!("Error collecting garbage on replica {}: {}", i, e);
+                errors += 1;
+            }
+        }
+        if errors > 0 {
+            return Err(Error::CorruptedStore(
+                "Couldn't collect garbage on all replicas"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{BlobId, BlobStorage, EnumerableBlobStorage,
+                        MemoryBlobStorage};
+    use crate::serialize::hash_blob;
+    use super::MirroredBlobStorage;
+
+    #[test]
+    fn test_repair() {
+        let replicas: [MemoryBlobStorage; 3] = Default::default();
+        let mut mirror = MirroredBlobStorage::new(
+            replicas.iter().map(|r| Box::new(r.clone()) as _).collect());
+        let one = mirror.add_blob(b"one").unwrap();
+        let two = mirror.add_blob(b"two").unwrap();
+        assert!(replicas.iter().all(|r| r.0.borrow().len() == 2));
+
+        // Lose a disk, and corrupt a copy on another
+        replicas[0].0.borrow_mut().clear();
+        replicas[1].0.borrow_mut().insert(two.clone(), b"bad".to_vec());
+        assert_eq!(&*mirror.get_blob(&one).unwrap().unwrap(), b"one");
+        assert_eq!(mirror.list_blobs().unwrap().count(), 2);
+
+        assert_eq!(mirror.repair().unwrap(), 2);
+        assert_eq!(replicas[0].0.borrow()[&one], b"one");
+        assert_eq!(replicas[0].0.borrow()[&two], b"two");
+
+        mirror.delete_blob(&one).unwrap();
+        assert!(!mirror.contains(&one).unwrap());
+    }
+
+    #[test]
+    fn test_add_known_blobs() {
+        let replicas: [MemoryBlobStorage; 2] = Default::default();
+        let mut mirror = MirroredBlobStorage::new(
+            replicas.iter().map(|r| Box::new(r.clone()) as _).collect());
+        let batch: Vec<(BlobId, &[u8])> = [&b"one"[..], b"two", b"three"]
+            .iter()
+            .map(|blob| (hash_blob(blob), *blob))
+            .collect();
+        mirror.add_known_blobs(&batch).unwrap();
+        for replica in &replicas {
+            assert_eq!(replica.0.borrow().len(), 3);
+            for (id, blob) in &batch {
+                assert_eq!(&replica.0.borrow()[id][..], *blob);
+            }
+        }
+    }
+}
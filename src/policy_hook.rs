@@ -0,0 +1,154 @@
+//! Policy delegated to an external program.
+//!
+//! Setting a `PolicyHook` on the index, with `StoreBuilder::policy()` or the
+//! `--policy-hook` option of the command-line client, makes it ask an
+//! executable about each object reached from the root while finding the
+//! live objects, for verification and garbage collection. This allows
+//! scripting retention rules without rebuilding DHStore. The program is
+//! picked by whoever opens the store, not recorded in it, so that a store
+//! can't make its user run anything.
+//!
+//! The program is run once per object, with a JSON document on its standard
+//! input:
+//!
+//! ```text
+//! {"path":["log","value"],"id":"...","object":{...}}
+//! ```
+//!
+//! "path" lists the properties followed from the root config to reach the
+//! object, the last one being the property referencing it; list positions
+//! are given as numbers in strings. The object is encoded as described in
+//! `json`. The program answers on its standard output with "keep", "drop"
+//! or "get". A dropped object is not followed, and is deleted by garbage
+//! collection unless it is kept through another path.
+//!
+//! If the program can't be run, fails, or gives another answer, the object
+//! is kept, so that a broken hook doesn't delete data.
+
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use log::{debug, error};
+
+use crate::common::Object;
+use crate::json;
+use crate::memory_index::{Policy, PolicyDecision};
+
+/// Policy asking an external program about each object.
+pub struct PolicyHook {
+    program: PathBuf,
+    /// Properties followed to get to the objects this policy handles
+    path: Vec<String>,
+}
+
+impl PolicyHook {
+    /// Asks this program, for the objects referenced from the root config.
+    pub fn new<P: AsRef<Path>>(program: P) -> PolicyHook {
+        PolicyHook {
+            program: program.as_ref().to_path_buf(),
+            path: Vec::new(),
+        }
+    }
+
+    fn ask(&self, path: &[String], object: &Object)
+        -> io::Result<PolicyDecision>
+    {
+        let mut child = Command::new(&self.program)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn()?;
+        let input = request(path, object);
+        match child.stdin.take().unwrap().write_all(input.as_bytes()) {
+            // The program can answer without reading the object
+            Err(ref e) if e.kind() == io::ErrorKind::BrokenPipe => {}
+            Err(e) => return Err(e),
+            Ok(()) => {}
+        }
+        let output = child.wait_with_output()?;
+        if !output.status.success() {
+            return Err(io::Error::other(
+                format!("exited with {}", output.status)));
+        }
+        parse_decision(&output.stdout).ok_or_else(|| io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("invalid answer {:?}",
+                    String::from_utf8_lossy(&output.stdout))))
+    }
+}
+
+impl Policy for PolicyHook {
+    fn handle(&mut self, property: &str, object: &Object)
+              -> (PolicyDecision, Box<dyn Policy>) {
+        let mut path = self.path.clone();
+        path.push(property.to_owned());
+        let decision = match self.ask(&path, object) {
+            Ok(decision) => decision,
+            Err(e) => {
+                error!("Policy hook {:?} failed on {}, keeping it: {}",
+                       self.program, object.id, e);
+                PolicyDecision::Keep
+            }
+        };
+        debug!("Policy hook: {:?} for {}", decision, object.id);
+        (decision, Box::new(PolicyHook {
+            program: self.program.clone(),
+            path,
+        }))
+    }
+}
+
+/// Builds the document sent to the program.
+fn request(path: &[String], object: &Object) -> String {
+    let mut out = String::from("{\"path\":[");
+    for (i, property) in path.iter().enumerate() {
+        if i > 0 {
+            out.push(',');
+        }
+        json::write_str(&mut out, property);
+    }
+    out.push_str("],\"id\":");
+    json::write_str(&mut out, &object.id.id().str());
+    out.push_str(",\"object\":");
+    json::write_data(&mut out, &object.data);
+    out.push_str("}\n");
+    out
+}
+
+/// Reads the answer of the program.
+fn parse_decision(output: &[u8]) -> Option<PolicyDecision> {
+    match std::str::from_utf8(output).ok()?.trim() {
+        "keep" => Some(PolicyDecision::Keep),
+        "drop" => Some(PolicyDecision::Drop),
+        "get" => Some(PolicyDecision::Get),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{ObjectData, Property};
+    use crate::memory_index::PolicyDecision;
+    use crate::serialize::hash_object;
+    use super::{parse_decision, request};
+
+    #[test]
+    fn test_request() {
+        let object = hash_object(ObjectData::List(vec![Property::Integer(4)]));
+        assert_eq!(
+            request(&["log".into(), "0".into()], &object),
+            format!("{{\"path\":[\"log\",\"0\"],\"id\":\"{}\",\
+                     \"object\":[4]}}\n",
+                    object.id));
+    }
+
+    #[test]
+    fn test_parse_decision() {
+        assert_eq!(parse_decision(b"keep\n"), Some(PolicyDecision::Keep));
+        assert_eq!(parse_decision(b" drop"), Some(PolicyDecision::Drop));
+        assert_eq!(parse_decision(b"get"), Some(PolicyDecision::Get));
+        assert_eq!(parse_decision(b"Keep"), None);
+        assert_eq!(parse_decision(b""), None);
+        assert_eq!(parse_decision(b"\xff"), None);
+    }
+}
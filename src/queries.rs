@@ -1,22 +1,277 @@
+//! Queries over the properties of objects.
+//!
+//! A `Query` matches a key of a dict object against a `Comparison`.
+//! Comparisons use the typed semantics of `Property::compare()`, so for
+//! example a string never matches a numeric range.
+//!
+//! `Store::query()` runs a query over the objects of the index, returning
+//! the matches as they are found. A query can be given a budget, the number
+//! of objects it may look at, and be cancelled from another thread, so that
+//! an expensive query doesn't hold up an interactive program.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering as AtomicOrdering};
+
+use log::info;
+
+use crate::common::{Dict, Object, ObjectData, ObjectId, ObjectIterator,
+                    Property};
+use crate::errors::{self, Error};
+
+/// Matches the value of a key in a dict object.
 pub struct Query {
-    key: String,
-    value: Comparison,
+    pub key: String,
+    pub value: Comparison,
 }
 
+impl Query {
+    pub fn new<K: Into<String>>(key: K, value: Comparison) -> Query {
+        Query {
+            key: key.into(),
+            value,
+        }
+    }
+
+    /// Checks whether a dict matches this query.
+    ///
+    /// A dict that doesn't have the key doesn't match.
+    pub fn matches(&self, dict: &Dict) -> bool {
+        match dict.get(&self.key) {
+            Some(value) => self.value.matches(value),
+            None => false,
+        }
+    }
+}
+
+/// The test applied to a property value.
 pub enum Comparison {
-    Equal(String),
+    /// Value is equal and of the same type, e.g. the same reference.
+    Equal(Property),
+    /// Value is a string starting with this prefix.
+    Prefix(String),
+    /// Value is a string matching this pattern (`%` and `_` wildcards).
     Like(String),
+    /// Value is an integer (or timestamp) in this inclusive range.
     Range(Option<i64>, Option<i64>),
     And(Vec<Comparison>),
     Or(Vec<Comparison>),
 }
 
+impl Comparison {
+    /// Checks whether a property matches this comparison.
+    pub fn matches(&self, value: &Property) -> bool {
+        match *self {
+            Comparison::Equal(ref other) => {
+                value.compare(other) == Some(Ordering::Equal)
+            }
+            Comparison::Prefix(ref prefix) => value.has_prefix(prefix),
+            Comparison::Like(ref pattern) => value.is_like(pattern),
+            Comparison::Range(min, max) => value.in_range(min, max),
+            Comparison::And(ref list) => list.iter().all(|c| c.matches(value)),
+            Comparison::Or(ref list) => list.iter().any(|c| c.matches(value)),
+        }
+    }
+}
+
+/// Handle to cancel a running query, possibly from another thread.
+#[derive(Clone, Default)]
+pub struct Cancel(Arc<AtomicBool>);
+
+impl Cancel {
+    pub fn new() -> Cancel {
+        Default::default()
+    }
+
+    /// Stops the queries using this handle; they return no more results.
+    pub fn cancel(&self) {
+        self.0.store(true, AtomicOrdering::Relaxed);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(AtomicOrdering::Relaxed)
+    }
+
+    /// Fails with `Error::Cancelled` if this handle was cancelled.
+    pub fn check(&self) -> errors::Result<()> {
+        if self.is_cancelled() {
+            info!("Operation cancelled");
+            return Err(Error::Cancelled);
+        }
+        Ok(())
+    }
+}
+
+/// Why a query stopped before going over all the objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stopped {
+    /// The budget was used up
+    Budget,
+    /// The query was cancelled
+    Cancelled,
+}
+
+/// Iterator over the objects matching a query, from `Store::query()`.
+///
+/// Objects are only looked at as the iterator is advanced. If it ends early,
+/// because of the budget or cancellation, `stopped()` tells why.
+pub struct QueryResults<'a> {
+    objects: ObjectIterator<'a>,
+    query: &'a Query,
+    budget: Option<usize>,
+    cancel: Option<Cancel>,
+    visited: usize,
+    stopped: Option<Stopped>,
+}
+
+impl<'a> QueryResults<'a> {
+    pub(crate) fn new(objects: ObjectIterator<'a>, query: &'a Query)
+        -> QueryResults<'a>
+    {
+        QueryResults {
+            objects,
+            query,
+            budget: None,
+            cancel: None,
+            visited: 0,
+            stopped: None,
+        }
+    }
+
+    /// Stops after looking at this many objects, matching or not.
+    pub fn with_budget(mut self, objects: usize) -> QueryResults<'a> {
+        self.budget = Some(objects);
+        self
+    }
+
+    /// Stops when this handle is cancelled.
+    pub fn with_cancel(mut self, cancel: Cancel) -> QueryResults<'a> {
+        self.cancel = Some(cancel);
+        self
+    }
+
+    /// The number of objects looked at so far.
+    pub fn visited(&self) -> usize {
+        self.visited
+    }
+
+    /// Why the query stopped early, or `None` if it is still running or
+    /// went over all the objects.
+    pub fn stopped(&self) -> Option<Stopped> {
+        self.stopped
+    }
+}
+
+impl<'a> Iterator for QueryResults<'a> {
+    type Item = errors::Result<Cow<'a, Object>>;
+
+    fn next(&mut self) -> Option<errors::Result<Cow<'a, Object>>> {
+        while self.stopped.is_none() {
+            if self.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+                self.stopped = Some(Stopped::Cancelled);
+            } else if self.budget.is_some_and(|b| self.visited >= b) {
+                self.stopped = Some(Stopped::Budget);
+            } else {
+                let object = match self.objects.next()? {
+                    Ok(o) => o,
+                    Err(e) => return Some(Err(e)),
+                };
+                self.visited += 1;
+                match object.data {
+                    ObjectData::Dict(ref dict) if self.query.matches(dict) => {
+                        return Some(Ok(object));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        None
+    }
+}
+
 pub struct Path {
-    root: ID,
-    components: Vec<PathComponent>,
+    pub root: ObjectId,
+    pub components: Vec<PathComponent>,
 }
 
 pub enum PathComponent {
-    Id(ID),
+    Id(ObjectId),
     Query(Query),
 }
+
+#[cfg(test)]
+mod tests {
+    use std::borrow::Cow;
+
+    use crate::common::{BlobId, Dict, ID, ObjectData, ObjectId, Property};
+    use crate::serialize::hash_object;
+    use super::{Cancel, Comparison, Query, QueryResults, Stopped};
+
+    #[test]
+    fn test_comparisons() {
+        let string = Property::String("DSC_2017.jpg".into());
+        let integer = Property::Integer(1488583864);
+        let id = ID::from_bytes(&[7u8; 32]).unwrap();
+        let reference = Property::Reference(ObjectId::from_id(id.clone()));
+
+        let equal = Comparison::Equal(Property::String("DSC_2017.jpg".into()));
+        assert!(equal.matches(&string));
+        assert!(!equal.matches(&integer));
+        let equal = Comparison::Equal(reference.clone());
+        assert!(equal.matches(&reference));
+        assert!(!equal.matches(&Property::Blob(BlobId::from_id(id))));
+
+        assert!(Comparison::Prefix("DSC_".into()).matches(&string));
+        assert!(!Comparison::Prefix("IMG_".into()).matches(&string));
+
+        assert!(Comparison::Like("DSC_%.jpg".into()).matches(&string));
+        assert!(Comparison::Like("%2017%".into()).matches(&string));
+        assert!(Comparison::Like("D_C_2017_jpg".into()).matches(&string));
+        assert!(!Comparison::Like("%.png".into()).matches(&string));
+        assert!(!Comparison::Like("DSC_".into()).matches(&string));
+
+        let range = Comparison::Range(Some(1483228800), Some(1514764799));
+        assert!(range.matches(&integer));
+        assert!(!range.matches(&string));
+        assert!(Comparison::Range(None, Some(1488583864)).matches(&integer));
+        assert!(!Comparison::Range(Some(1488583865), None).matches(&integer));
+
+        let or = Comparison::Or(vec![Comparison::Prefix("IMG_".into()),
+                                     Comparison::Like("%.jpg".into())]);
+        assert!(or.matches(&string));
+        let and = Comparison::And(vec![Comparison::Prefix("IMG_".into()),
+                                       Comparison::Like("%.jpg".into())]);
+        assert!(!and.matches(&string));
+    }
+
+    #[test]
+    fn test_results() {
+        let objects: Vec<_> = (0..10).map(|i| {
+            let mut dict = Dict::new();
+            dict.insert("n".into(), Property::Integer(i));
+            hash_object(ObjectData::Dict(dict))
+        }).collect();
+        let query = Query::new("n", Comparison::Range(Some(2), Some(5)));
+        let all = || QueryResults::new(
+            Box::new(objects.iter().map(|o| Ok(Cow::Borrowed(o)))), &query);
+
+        let mut results = all();
+        assert_eq!(results.by_ref().count(), 4);
+        assert_eq!(results.visited(), 10);
+        assert_eq!(results.stopped(), None);
+
+        let mut results = all().with_budget(4);
+        assert_eq!(results.by_ref().count(), 2);
+        assert_eq!(results.visited(), 4);
+        assert_eq!(results.stopped(), Some(Stopped::Budget));
+
+        let cancel = Cancel::new();
+        let mut results = all().with_cancel(cancel.clone());
+        assert!(results.next().is_some());
+        cancel.cancel();
+        assert!(results.next().is_none());
+        assert_eq!(results.visited(), 3);
+        assert_eq!(results.stopped(), Some(Stopped::Cancelled));
+    }
+}
@@ -0,0 +1,108 @@
+//! Registry of blob storage backends, by URL scheme.
+//!
+//! The root config of a store can give the location of its blobs as a URL,
+//! for example `file://blobs`. `open()` looks up the scheme of that URL in a
+//! `Registry` to build the right `BlobStorage`; other crates can register
+//! their own schemes and open stores with `open_with()`.
+
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::common::EnumerableBlobStorage;
+use crate::errors::{self, Error};
+use crate::file_storage::FileBlobStorage;
+use crate::s3_storage::{S3BlobStorage, S3Config};
+
+/// Function building a blob storage from the rest of a URL.
+///
+/// It receives the part of the URL after `scheme://`, and the path of the
+/// store, which relative locations should be resolved against.
+pub type StorageFactory = Box<dyn Fn(&str, &Path)
+    -> errors::Result<Box<dyn EnumerableBlobStorage>>>;
+
+/// Maps URL schemes to the functions building blob storage backends.
+pub struct Registry {
+    factories: HashMap<String, StorageFactory>,
+}
+
+impl Registry {
+    /// Creates an empty registry, that doesn't know any scheme.
+    pub fn new() -> Registry {
+        Registry { factories: HashMap::new() }
+    }
+
+    /// Creates a registry with the backends built into dhstore.
+    pub fn with_builtins() -> Registry {
+        let mut registry = Registry::new();
+        registry.register("file", |location, store| {
+            Ok(Box::new(FileBlobStorage::open(store.join(location))))
+        });
+        registry.register("s3+https", |location, _| {
+            let config = S3Config::from_url(location, true)?;
+            Ok(Box::new(S3BlobStorage::new(config)))
+        });
+        registry.register("s3+http", |location, _| {
+            let config = S3Config::from_url(location, false)?;
+            Ok(Box::new(S3BlobStorage::new(config)))
+        });
+        registry
+    }
+
+    /// Registers a backend for a scheme, replacing any previous one.
+    pub fn register<F>(&mut self, scheme: &str, factory: F)
+        where F: Fn(&str, &Path)
+                    -> errors::Result<Box<dyn EnumerableBlobStorage>>
+                 + 'static
+    {
+        self.factories.insert(scheme.into(), Box::new(factory));
+    }
+
+    /// Builds the blob storage for a URL.
+    ///
+    /// `store` is the path of the store, which relative locations are
+    /// resolved against.
+    pub fn open(&self, url: &str, store: &Path)
+        -> errors::Result<Box<dyn EnumerableBlobStorage>>
+    {
+        let (scheme, location) = split_url(url)
+            .ok_or(Error::InvalidInput("Invalid blob storage URL"))?;
+        let factory = self.factories.get(scheme)
+            .ok_or(Error::InvalidInput("Unknown blob storage URL scheme"))?;
+        factory(location, store)
+    }
+}
+
+impl Default for Registry {
+    fn default() -> Registry {
+        Registry::with_builtins()
+    }
+}
+
+/// Splits a URL into its scheme and the rest.
+fn split_url(url: &str) -> Option<(&str, &str)> {
+    let pos = url.find("://")?;
+    let scheme = &url[..pos];
+    if scheme.is_empty() ||
+        !scheme.chars().all(|c| c.is_ascii_alphanumeric() ||
+                                "+-.".contains(c))
+    {
+        return None;
+    }
+    Some((scheme, &url[pos + 3..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_url;
+
+    #[test]
+    fn test_split_url() {
+        assert_eq!(split_url("file://blobs"), Some(("file", "blobs")));
+        assert_eq!(split_url("s3+http://host/bucket"),
+                   Some(("s3+http", "host/bucket")));
+        assert_eq!(split_url("file:///abs/path"), Some(("file", "/abs/path")));
+        assert_eq!(split_url("blobs"), None);
+        assert_eq!(split_url("://blobs"), None);
+        assert_eq!(split_url("a b://blobs"), None);
+    }
+}
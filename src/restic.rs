@@ -0,0 +1,807 @@
+//! Serves a store as a restic repository, over restic's REST protocol.
+//!
+//! This lets restic back up into dhstore: point it at
+//! `rest:http://host:port/` and the files of the repository are kept in the
+//! store, where they get chunked and deduplicated like any other file.
+//!
+//! The repository is a directory, in the format of `Store::add()`: the
+//! `config` file and a subdirectory for each type of file restic writes. Its
+//! current version is the value of a permanode, updated after each change,
+//! so it can also be looked at or extracted with the usual commands.
+//!
+//! Each change writes the directory of its type again. The `data` files,
+//! by far the most numerous, are split in subdirectories named after the
+//! first two characters of their names, like restic lays them out itself,
+//! so that only one of these is written again. The claims of older versions
+//! are deleted, but garbage collection only reclaims them, and the files
+//! restic deleted, if the policy drops deleted claims.
+//!
+//! This only speaks plain HTTP, and handles one connection at a time. It
+//! has no authentication, so it should only listen on a trusted interface.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::{self, Cursor, Read, Write};
+use std::iter;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+use std::time::Duration;
+
+use log::{debug, info, warn};
+
+use crate::common::{ObjectId, BlobStorage, Dict, Object, ObjectData,
+                    ObjectIndex, Property, Sort};
+use crate::errors::{self, Error};
+use crate::{DirEntry, EntryKind, Store, claim, dir_entries, file_contents,
+            permanode, timestamp_now};
+
+/// The kinds of files in a restic repository, besides the config.
+const TYPES: [&str; 5] = ["data", "index", "keys", "locks", "snapshots"];
+
+/// Media type restic asks for to get sizes in listings.
+const LIST_V2: &str = "application/vnd.x.restic.rest.v2";
+
+/// Timeout for reading requests and writing responses.
+const TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest request head accepted.
+const MAX_HEAD: usize = 16 * 1024;
+
+/// Largest request body accepted. restic's packs are 128 MiB at most, plus
+/// the last blob added to them.
+const MAX_BODY: u64 = 256 * 1024 * 1024;
+
+/// The type whose files are in subdirectories, by the first two characters
+/// of their names.
+const SHARDED: &str = "data";
+
+/// A file of the repository: its file object and size.
+#[derive(Clone)]
+struct Entry {
+    id: ObjectId,
+    size: u64,
+}
+
+/// A parsed HTTP request.
+struct Request {
+    method: String,
+    path: String,
+    query: String,
+    /// Headers, with lowercase names
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl Request {
+    fn header(&self, name: &str) -> Option<&str> {
+        self.headers.iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| &v[..])
+    }
+}
+
+struct Response {
+    status: u16,
+    headers: Vec<(&'static str, String)>,
+    body: Vec<u8>,
+    /// Content length to announce for a HEAD request, without a body
+    head_length: Option<u64>,
+}
+
+impl Response {
+    fn empty(status: u16) -> Response {
+        Response {
+            status,
+            headers: Vec::new(),
+            body: Vec::new(),
+            head_length: None,
+        }
+    }
+}
+
+/// Server for the restic REST protocol, storing the repository in a store.
+pub struct ResticServer<'a, S: BlobStorage, I: ObjectIndex> {
+    store: &'a mut Store<S, I>,
+    /// The permanode whose value is the repository's directory
+    node: ObjectId,
+    config: Option<Entry>,
+    dirs: BTreeMap<&'static str, BTreeMap<String, Entry>>,
+    /// ID of each directory as last written
+    dir_ids: BTreeMap<&'static str, ObjectId>,
+    /// ID of each subdirectory of the sharded type, by name prefix
+    shard_ids: BTreeMap<String, ObjectId>,
+    /// Date of the last claim, to keep them in order
+    last_date: i64,
+    /// The claim making the current version the value, deleted once it is
+    /// replaced
+    claim: Option<ObjectId>,
+}
+
+impl<'a, S: BlobStorage, I: ObjectIndex> ResticServer<'a, S, I> {
+    /// Creates a new, empty repository.
+    ///
+    /// The repository's permanode is recorded in the store's log, which
+    /// keeps it from being garbage-collected; its ID is `node()`.
+    pub fn create(store: &'a mut Store<S, I>)
+        -> errors::Result<ResticServer<'a, S, I>>
+    {
+        let node = permanode(Dict::new(), Sort::Ascending("date".into()));
+        let node = store.index.add(node.data)?;
+        let mut details = Dict::new();
+        details.insert("repository".into(), Property::Reference(node.clone()));
+        store.record_event("restic", details)?;
+        info!("Created restic repository {}", node);
+        let mut server = ResticServer::empty(store, node);
+        server.save(None)?;
+        Ok(server)
+    }
+
+    /// Opens the repository that is the value of this permanode.
+    pub fn open(store: &'a mut Store<S, I>, node: &ObjectId)
+        -> errors::Result<ResticServer<'a, S, I>>
+    {
+        let page = store.index.list_permanode(node, None, 1)?
+            .ok_or(Error::InvalidInput("Repository is not a permanode"))?;
+        let mut server = ResticServer::empty(store, node.clone());
+        let member = match page.members.into_iter().next() {
+            Some(member) => member,
+            None => return Ok(server),
+        };
+        if let Property::Integer(date) = member.key {
+            server.last_date = date;
+        }
+        server.claim = Some(member.claim);
+        let top = server.read_dir(&member.value)?;
+        server.config = top.get("config").cloned();
+        for kind in &TYPES {
+            let dir = match top.get(*kind) {
+                Some(entry) => entry.id.clone(),
+                None => continue,
+            };
+            let files = if *kind == SHARDED {
+                server.read_shards(&dir)?
+            } else {
+                server.read_dir(&dir)?
+            };
+            server.dir_ids.insert(kind, dir);
+            server.dirs.insert(kind, files);
+        }
+        Ok(server)
+    }
+
+    fn empty(store: &'a mut Store<S, I>, node: ObjectId)
+        -> ResticServer<'a, S, I>
+    {
+        ResticServer {
+            store,
+            node,
+            config: None,
+            dirs: TYPES.iter().map(|&t| (t, BTreeMap::new())).collect(),
+            dir_ids: BTreeMap::new(),
+            shard_ids: BTreeMap::new(),
+            last_date: 0,
+            claim: None,
+        }
+    }
+
+    /// The permanode holding the repository.
+    pub fn node(&self) -> &ObjectId {
+        &self.node
+    }
+
+    /// Reads the entries of one of our directories.
+    fn read_dir(&self, id: &ObjectId)
+        -> errors::Result<BTreeMap<String, Entry>>
+    {
+        let mut files = BTreeMap::new();
+        for entry in self.dir_entries(id)? {
+            let size = match entry.size {
+                Some(size) => size,
+                None => self.file_size(&entry.id)?.unwrap_or(0),
+            };
+            files.insert(entry.name, Entry { id: entry.id, size });
+        }
+        Ok(files)
+    }
+
+    /// Reads the files of the sharded type from its subdirectories.
+    ///
+    /// Versions that didn't shard it have the files directly in the
+    /// directory; these are moved to subdirectories.
+    fn read_shards(&mut self, id: &ObjectId)
+        -> errors::Result<BTreeMap<String, Entry>>
+    {
+        let mut files = BTreeMap::new();
+        let mut unsharded = BTreeMap::new();
+        for entry in self.dir_entries(id)? {
+            if entry.kind == Some(EntryKind::Dir) {
+                files.extend(self.read_dir(&entry.id)?);
+                self.shard_ids.insert(entry.name, entry.id);
+            } else {
+                let size = match entry.size {
+                    Some(size) => size,
+                    None => self.file_size(&entry.id)?.unwrap_or(0),
+                };
+                unsharded.insert(entry.name, Entry { id: entry.id, size });
+            }
+        }
+        if !unsharded.is_empty() {
+            info!("Moving {} files of {} to subdirectories",
+                  unsharded.len(), SHARDED);
+            let prefixes: BTreeSet<String> = unsharded.keys()
+                .map(|name| shard(name).to_owned())
+                .collect();
+            files.extend(unsharded);
+            for prefix in &prefixes {
+                self.write_shard(&files, prefix)?;
+            }
+        }
+        Ok(files)
+    }
+
+    /// Writes the subdirectory of the sharded type for a prefix again.
+    fn write_shard(&mut self, files: &BTreeMap<String, Entry>, prefix: &str)
+        -> errors::Result<()>
+    {
+        let shard: BTreeMap<String, Entry> = files
+            .range(prefix.to_owned()..)
+            .take_while(|(name, _)| name.starts_with(prefix))
+            .filter(|(name, _)| shard(name) == prefix)
+            .map(|(name, entry)| (name.clone(), entry.clone()))
+            .collect();
+        if shard.is_empty() {
+            self.shard_ids.remove(prefix);
+        } else {
+            let id = self.store.index.add(directory(&shard, iter::empty()))?;
+            self.shard_ids.insert(prefix.to_owned(), id);
+        }
+        Ok(())
+    }
+
+    fn dir_entries(&self, id: &ObjectId) -> errors::Result<Vec<DirEntry>> {
+        let object = self.store.index.get_object(id)?;
+        let dict = match object.as_deref() {
+            Some(object) => match object.data {
+                ObjectData::Dict(ref dict) => dict,
+                ObjectData::List(_) => {
+                    return Err(Error::CorruptedStore(
+                        "Repository directory is a list"));
+                }
+            },
+            None => return Err(Error::MissingObject(id.clone())),
+        };
+        dir_entries(dict).ok_or(Error::CorruptedStore(
+            "Repository directory is not a directory"))
+    }
+
+    fn file_size(&self, id: &ObjectId) -> errors::Result<Option<u64>> {
+        Ok(match self.store.index.get_object(id)?.as_deref() {
+            Some(Object { data: ObjectData::Dict(dict), .. }) => {
+                file_contents(dict).map(|(size, _)| size as u64)
+            }
+            _ => None,
+        })
+    }
+
+    /// Writes the directory of the type of a changed file if given, then
+    /// the top directory, and makes it the value of the permanode.
+    ///
+    /// The previous claim is deleted, so that the previous version can be
+    /// garbage-collected if the policy doesn't keep deleted claims.
+    fn save(&mut self, changed: Option<(&'static str, &str)>)
+        -> errors::Result<()>
+    {
+        if let Some((kind, name)) = changed {
+            let data = if kind == SHARDED {
+                let files = std::mem::take(self.dirs.get_mut(kind).unwrap());
+                let written = self.write_shard(&files, shard(name));
+                self.dirs.insert(kind, files);
+                written?;
+                directory(&BTreeMap::new(), self.shard_ids.iter()
+                          .map(|(prefix, id)| (&prefix[..], id)))
+            } else {
+                directory(&self.dirs[kind], iter::empty())
+            };
+            let id = self.store.index.add(data)?;
+            self.dir_ids.insert(kind, id);
+        }
+        let mut top = BTreeMap::new();
+        if let Some(ref config) = self.config {
+            top.insert("config".to_owned(), config.clone());
+        }
+        let top = self.store.index.add(directory(&top, self.dir_ids.iter()
+            .map(|(kind, id)| (*kind, id))))?;
+        self.last_date = timestamp_now().max(self.last_date + 1);
+        let mut claim_data = Dict::new();
+        claim_data.insert("date".into(), Property::Integer(self.last_date));
+        let claim = self.store.index.add(claim(&self.node, &top,
+                                               claim_data))?;
+        if let Some(previous) = self.claim.replace(claim) {
+            self.store.delete(&previous)?;
+        }
+        debug!("Repository {} is now {}", self.node, top);
+        Ok(())
+    }
+
+    /// Accepts connections and answers requests, until an error.
+    pub fn serve(&mut self, listener: TcpListener) -> errors::Result<()> {
+        for stream in listener.incoming() {
+            let stream = stream
+                .map_err(|e| ("Error accepting connection", e))?;
+            if let Err(e) = self.handle(stream) {
+                warn!("Error handling request: {}", e);
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers one request on a connection, then closes it.
+    fn handle(&mut self, mut stream: TcpStream) -> io::Result<()> {
+        stream.set_read_timeout(Some(TIMEOUT))?;
+        stream.set_write_timeout(Some(TIMEOUT))?;
+        let response = match read_request(&mut stream)? {
+            Ok(request) => {
+                debug!("{} {}", request.method, request.path);
+                match self.respond(&request) {
+                    Ok(response) => response,
+                    Err(e) => {
+                        warn!("Error answering {} {}: {}", request.method,
+                              request.path, e);
+                        Response::empty(500)
+                    }
+                }
+            }
+            Err(status) => Response::empty(status),
+        };
+        write_response(&mut stream, &response)
+    }
+
+    fn respond(&mut self, request: &Request) -> errors::Result<Response> {
+        let path = request.path.trim_start_matches('/');
+        let (kind, name) = match path.split_once('/') {
+            Some((kind, name)) => (kind, name),
+            None => (path, ""),
+        };
+        let method = &request.method[..];
+        match (kind, name) {
+            ("", "") => {
+                let create = request.query.split('&')
+                    .any(|p| p == "create=true");
+                if method == "POST" && create {
+                    // The repository always exists
+                    Ok(Response::empty(200))
+                } else {
+                    Ok(Response::empty(400))
+                }
+            }
+            ("config", "") => {
+                match method {
+                    "HEAD" | "GET" => {
+                        let entry = self.config.clone();
+                        self.send_file(request, entry)
+                    }
+                    "POST" => {
+                        self.config = Some(self.receive_file(request,
+                                                             "config")?);
+                        self.save(None)?;
+                        Ok(Response::empty(200))
+                    }
+                    "DELETE" => {
+                        self.config = None;
+                        self.save(None)?;
+                        Ok(Response::empty(200))
+                    }
+                    _ => Ok(Response::empty(405)),
+                }
+            }
+            (kind, name) => {
+                let kind = match TYPES.iter().find(|&&t| t == kind) {
+                    Some(&kind) => kind,
+                    None => return Ok(Response::empty(404)),
+                };
+                if name.is_empty() {
+                    return match method {
+                        "GET" => Ok(self.list(request, kind)),
+                        _ => Ok(Response::empty(405)),
+                    };
+                }
+                if !name.bytes().all(|b| b.is_ascii_alphanumeric()) {
+                    return Ok(Response::empty(400));
+                }
+                match method {
+                    "HEAD" | "GET" => {
+                        let entry = self.dirs[kind].get(name).cloned();
+                        self.send_file(request, entry)
+                    }
+                    "POST" => {
+                        let entry = self.receive_file(request, name)?;
+                        self.dirs.get_mut(kind).unwrap()
+                            .insert(name.to_owned(), entry);
+                        self.save(Some((kind, name)))?;
+                        Ok(Response::empty(200))
+                    }
+                    "DELETE" => {
+                        let dir = self.dirs.get_mut(kind).unwrap();
+                        if dir.remove(name).is_none() {
+                            return Ok(Response::empty(404));
+                        }
+                        self.save(Some((kind, name)))?;
+                        Ok(Response::empty(200))
+                    }
+                    _ => Ok(Response::empty(405)),
+                }
+            }
+        }
+    }
+
+    fn list(&self, request: &Request, kind: &str) -> Response {
+        let v2 = request.header("accept")
+            .is_some_and(|a| a.contains(LIST_V2));
+        let files = &self.dirs[kind];
+        // Names are checked to be alphanumeric, they don't need escaping
+        let items: Vec<String> = files.iter()
+            .map(|(name, entry)| if v2 {
+                format!("{{\"name\":\"{}\",\"size\":{}}}", name, entry.size)
+            } else {
+                format!("\"{}\"", name)
+            })
+            .collect();
+        let content_type = if v2 { LIST_V2 } else { "application/json" };
+        Response {
+            status: 200,
+            headers: vec![("Content-Type", content_type.to_owned())],
+            body: format!("[{}]", items.join(",")).into_bytes(),
+            head_length: None,
+        }
+    }
+
+    /// Stores the body of a request as a file object.
+    fn receive_file(&mut self, request: &Request, name: &str)
+        -> errors::Result<Entry>
+    {
+        let chunking = self.store.chunking.for_path(Path::new(name)).clone();
+        let (id, size, _) = self.store.add_file_object(
+            Cursor::new(&request.body), &chunking)?;
+        info!("Received {}, {} bytes, id = {}", request.path, size, id);
+        Ok(Entry { id, size: size as u64 })
+    }
+
+    /// Answers with a file or part of it.
+    fn send_file(&self, request: &Request, entry: Option<Entry>)
+        -> errors::Result<Response>
+    {
+        let entry = match entry {
+            Some(entry) => entry,
+            None => return Ok(Response::empty(404)),
+        };
+        let (start, end) = match request.header("range") {
+            Some(range) => match parse_range(range, entry.size) {
+                Some(range) => range,
+                None => {
+                    let mut response = Response::empty(416);
+                    response.headers.push(
+                        ("Content-Range", format!("bytes */{}", entry.size)));
+                    return Ok(response);
+                }
+            },
+            None => (0, entry.size),
+        };
+        let mut response = Response::empty(200);
+        if request.header("range").is_some() {
+            response.status = 206;
+            response.headers.push(
+                ("Content-Range",
+                 format!("bytes {}-{}/{}", start, end - 1, entry.size)));
+        }
+        response.headers.push(("Content-Type",
+                               "application/octet-stream".to_owned()));
+        if request.method == "HEAD" {
+            response.head_length = Some(end - start);
+            return Ok(response);
+        }
+        let mut reader = self.store.get_file(&entry.id)?;
+        io::copy(&mut (&mut reader).take(start), &mut io::sink())
+            .map_err(|e| ("Error reading file", e))?;
+        reader.take(end - start).read_to_end(&mut response.body)
+            .map_err(|e| ("Error reading file", e))?;
+        Ok(response)
+    }
+}
+
+/// Makes a directory object from files and subdirectories.
+fn directory<'a, D>(files: &BTreeMap<String, Entry>, subdirs: D) -> ObjectData
+    where D: Iterator<Item = (&'a str, &'a ObjectId)>
+{
+    let mut dict = Dict::new();
+    for (name, entry) in files {
+        dict.insert(format!("{}/type", name),
+                    Property::String(EntryKind::File.as_str().into()));
+        dict.insert(format!("{}/size", name),
+                    Property::Integer(entry.size as i64));
+        dict.insert(name.clone(), Property::Reference(entry.id.clone()));
+    }
+    for (name, id) in subdirs {
+        dict.insert(format!("{}/type", name),
+                    Property::String(EntryKind::Dir.as_str().into()));
+        dict.insert(name.to_owned(), Property::Reference(id.clone()));
+    }
+    ObjectData::Dict(dict)
+}
+
+/// The subdirectory of a file of the sharded type.
+fn shard(name: &str) -> &str {
+    // Names are alphanumeric ASCII
+    &name[..name.len().min(2)]
+}
+
+/// Parses a `Range` header, for a single range, into a start and end.
+fn parse_range(range: &str, size: u64) -> Option<(u64, u64)> {
+    let (start, end) = range.trim().strip_prefix("bytes=")?.split_once('-')?;
+    let (start, end) = match (start.trim(), end.trim()) {
+        // Suffix, the last bytes
+        ("", last) => {
+            let last: u64 = last.parse().ok()?;
+            (size.saturating_sub(last), size)
+        }
+        (start, "") => (start.parse().ok()?, size),
+        (start, end) => {
+            let end: u64 = end.parse().ok()?;
+            (start.parse().ok()?, end.saturating_add(1).min(size))
+        }
+    };
+    if start >= end {
+        return None;
+    }
+    Some((start, end))
+}
+
+/// Reads a request, or returns the status to answer with if it is
+/// malformed or too large.
+fn read_request<R: Read>(stream: &mut R) -> io::Result<Result<Request, u16>> {
+    let mut head = Vec::new();
+    let mut byte = [0u8; 1];
+    while !head.ends_with(b"\r\n\r\n") {
+        if head.len() >= MAX_HEAD || stream.read(&mut byte)? == 0 {
+            return Ok(Err(400));
+        }
+        head.push(byte[0]);
+    }
+    let mut request = match parse_head(&head) {
+        Some(request) => request,
+        None => return Ok(Err(400)),
+    };
+    if request.header("transfer-encoding").is_some() {
+        // restic always sends the length
+        return Ok(Err(400));
+    }
+    let length = match request.header("content-length") {
+        Some(length) => match length.parse() {
+            Ok(length) => length,
+            Err(_) => return Ok(Err(400)),
+        },
+        None => 0,
+    };
+    if length > MAX_BODY {
+        return Ok(Err(413));
+    }
+    stream.take(length).read_to_end(&mut request.body)?;
+    if request.body.len() as u64 != length {
+        return Ok(Err(400));
+    }
+    Ok(Ok(request))
+}
+
+fn parse_head(head: &[u8]) -> Option<Request> {
+    let head = std::str::from_utf8(head).ok()?;
+    let mut lines = head.trim_end().split("\r\n");
+    let mut parts = lines.next()?.split(' ');
+    let method = parts.next()?.to_owned();
+    let target = parts.next()?;
+    let (path, query) = match target.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (target, ""),
+    };
+    let mut headers = Vec::new();
+    for line in lines {
+        let (name, value) = line.split_once(':')?;
+        headers.push((name.to_ascii_lowercase(), value.trim().to_owned()));
+    }
+    Some(Request {
+        method,
+        path: path.to_owned(),
+        query: query.to_owned(),
+        headers,
+        body: Vec::new(),
+    })
+}
+
+fn write_response<W: Write>(stream: &mut W, response: &Response)
+    -> io::Result<()>
+{
+    let reason = match response.status {
+        200 => "OK",
+        206 => "Partial Content",
+        400 => "Bad Request",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        413 => "Content Too Large",
+        416 => "Range Not Satisfiable",
+        _ => "Internal Server Error",
+    };
+    let length = response.head_length
+        .unwrap_or(response.body.len() as u64);
+    let mut head = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\n\
+                            Connection: close\r\n",
+                           response.status, reason, length);
+    for (name, value) in &response.headers {
+        head.push_str(&format!("{}: {}\r\n", name, value));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes())?;
+    stream.write_all(&response.body)?;
+    stream.flush()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+    use std::fs;
+    use std::io::Cursor;
+    use std::iter;
+
+    use crate::common::{Dict, Object, ObjectData, ObjectId, ObjectIndex,
+                        Property, Sort};
+    use crate::{DynStore, EntryKind, claim, dir_entries, permanode};
+    use super::{MAX_BODY, Request, ResticServer, directory, parse_range,
+                read_request};
+
+    fn request(method: &str, path: &str, body: &[u8]) -> Request {
+        Request {
+            method: method.into(),
+            path: path.into(),
+            query: String::new(),
+            headers: Vec::new(),
+            body: body.to_vec(),
+        }
+    }
+
+    /// Lists a directory of the store, with the kinds of the entries.
+    fn listing(store: &DynStore, id: &ObjectId)
+        -> Vec<(String, Option<EntryKind>, ObjectId)>
+    {
+        match store.get_object(id).unwrap().as_deref() {
+            Some(Object { data: ObjectData::Dict(dict), .. }) => {
+                dir_entries(dict).unwrap()
+                    .into_iter()
+                    .map(|e| (e.name, e.kind, e.id))
+                    .collect()
+            }
+            _ => panic!("Expected a directory"),
+        }
+    }
+
+    /// The current version of the repository.
+    fn current(store: &DynStore, node: &ObjectId) -> ObjectId {
+        let page = store.index.list_permanode(node, None, 1).unwrap()
+            .unwrap();
+        page.members[0].value.clone()
+    }
+
+    #[test]
+    fn test_sharding() {
+        let path = std::env::temp_dir().join(format!(
+            "dhstore-test-restic-{}", std::process::id()));
+        if path.exists() {
+            fs::remove_dir_all(&path).unwrap();
+        }
+        crate::create(&path).unwrap();
+        let mut store = crate::open(&path).unwrap();
+
+        let mut server = ResticServer::create(&mut store).unwrap();
+        for (name, body) in [("aa01", "one"), ("aa02", "two"),
+                             ("bb01", "three")]
+        {
+            let response = server.respond(&request(
+                "POST", &format!("/data/{}", name), body.as_bytes()))
+                .unwrap();
+            assert_eq!(response.status, 200);
+        }
+        server.respond(&request("POST", "/keys/k1", b"key")).unwrap();
+        assert_eq!(server.respond(&request("DELETE", "/data/bb01", b""))
+                       .unwrap().status,
+                   200);
+        let node = server.node().clone();
+        drop(server);
+
+        // The data files are in subdirectories, the others are not
+        let top = listing(&store, &current(&store, &node));
+        let names: Vec<_> = top.iter()
+            .map(|(name, kind, _)| (&name[..], *kind))
+            .collect();
+        assert_eq!(names, vec![("data", Some(EntryKind::Dir)),
+                               ("keys", Some(EntryKind::Dir))]);
+        let data = listing(&store, &top[0].2);
+        assert_eq!(data.len(), 1);
+        assert_eq!((&data[0].0[..], data[0].1), ("aa", Some(EntryKind::Dir)));
+        let shard: Vec<_> = listing(&store, &data[0].2).into_iter()
+            .map(|(name, kind, _)| (name, kind))
+            .collect();
+        assert_eq!(shard, vec![("aa01".to_owned(), Some(EntryKind::File)),
+                               ("aa02".to_owned(), Some(EntryKind::File))]);
+        let keys = listing(&store, &top[1].2);
+        assert_eq!(keys[0].0, "k1");
+
+        let mut server = ResticServer::open(&mut store, &node).unwrap();
+        let response = server.respond(&request("GET", "/data/aa02", b""))
+            .unwrap();
+        assert_eq!(response.body, b"two");
+        assert_eq!(server.respond(&request("GET", "/data/bb01", b""))
+                       .unwrap().status,
+                   404);
+        let files = server.dirs["data"].clone();
+        drop(server);
+
+        // Repositories written before sharding have the files directly
+        let unsharded = store.index.add(directory(&files, iter::empty()))
+            .unwrap();
+        let top = store.index.add(directory(
+            &BTreeMap::new(), iter::once(("data", &unsharded)))).unwrap();
+        let old = store.index.add(
+            permanode(Dict::new(), Sort::Ascending("date".into())).data)
+            .unwrap();
+        let mut date = Dict::new();
+        date.insert("date".into(), Property::Integer(1));
+        store.index.add(claim(&old, &top, date)).unwrap();
+        let mut server = ResticServer::open(&mut store, &old).unwrap();
+        assert_eq!(server.dirs["data"].keys().collect::<Vec<_>>(),
+                   vec!["aa01", "aa02"]);
+        assert_eq!(server.shard_ids.keys().collect::<Vec<_>>(), vec!["aa"]);
+        let response = server.respond(&request("GET", "/data/aa01", b""))
+            .unwrap();
+        assert_eq!(response.body, b"one");
+        server.respond(&request("POST", "/data/cc01", b"four")).unwrap();
+        drop(server);
+        let top = listing(&store, &current(&store, &old));
+        let data: Vec<_> = listing(&store, &top[0].2).into_iter()
+            .map(|(name, _, _)| name)
+            .collect();
+        assert_eq!(data, vec!["aa", "cc"]);
+
+        drop(store);
+        fs::remove_dir_all(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_range() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some((0, 100)));
+        assert_eq!(parse_range("bytes=900-", 1000), Some((900, 1000)));
+        assert_eq!(parse_range("bytes=-10", 1000), Some((990, 1000)));
+        assert_eq!(parse_range("bytes=990-2000", 1000), Some((990, 1000)));
+        assert_eq!(parse_range("bytes=1000-", 1000), None);
+        assert_eq!(parse_range(&format!("bytes=0-{}", u64::MAX), 1000),
+                   Some((0, 1000)));
+        assert_eq!(parse_range("lines=0-1", 1000), None);
+    }
+
+    #[test]
+    fn test_read_request() {
+        let mut input = Cursor::new(
+            &b"POST /data/abc01?x=1 HTTP/1.1\r\nHost: localhost\r\n\
+               Content-Length: 5\r\n\r\nhelloextra"[..]);
+        let request = read_request(&mut input).unwrap().unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/data/abc01");
+        assert_eq!(request.query, "x=1");
+        assert_eq!(request.header("content-length"), Some("5"));
+        assert_eq!(request.body, b"hello");
+
+        let mut truncated = Cursor::new(
+            &b"POST /config HTTP/1.1\r\nContent-Length: 5\r\n\r\nhel"[..]);
+        assert!(matches!(read_request(&mut truncated).unwrap(), Err(400)));
+
+        // The body is not read
+        let mut large = Cursor::new(format!(
+            "POST /data/abc01 HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            MAX_BODY + 1).into_bytes());
+        assert!(matches!(read_request(&mut large).unwrap(), Err(413)));
+    }
+}
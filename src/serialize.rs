@@ -10,8 +10,9 @@ use std::io::{self, Read, Write};
 
 use log::error;
 
-use crate::common::{ID, Dict, List, Object, ObjectData, Property};
-use crate::hash::{Hasher, HasherReader, HasherWriter};
+use crate::common::{BlobId, Dict, ID, List, Object, ObjectData, ObjectId,
+                    Property};
+use crate::hash::{self, Hasher, HasherReader, HasherWriter};
 
 // Dictionary: d<id><key><value><key><value>...e
 // List: l<value><value>...e
@@ -19,8 +20,20 @@ use crate::hash::{Hasher, HasherReader, HasherWriter};
 // Integer: i42e
 // Reference: {"ref": d} = d3:ref64:abcdef...e
 // Blob: {"blob": id} = d4:blob64:abcdef...e
+// Inline: {"inline": base64} = d6:inline4:YWJje
 // Object: {"d": "dhstore_0001", "r": ...}
 //   r: either a list or a dict
+//
+// IDs hash these bytes, so each value has exactly one encoding, which
+// doesn't depend on the locale:
+// * strings are UTF-8, and their length is in bytes
+// * lengths and integers are in decimal, without leading zeros or plus sign,
+//   and zero is never negative
+// * dict keys are sorted bytewise, without duplicates
+// * IDs and inline data use unpadded URL-safe base64
+// A property type added later has to follow the same rules, e.g. a time
+// would be an RFC 3339 string in UTC with a fixed number of digits, so its
+// encoding also sorts in order. `deserialize()` rejects anything else.
 
 macro_rules! invalid {
     () => {
@@ -57,8 +70,13 @@ fn write_property<W: Write>(out: &mut W, prop: &Property) -> io::Result<()> {
     match *prop {
         Property::String(ref s) => write_str(out, s),
         Property::Integer(i) => write!(out, "i{}e", i),
-        Property::Reference(ref id) => write_ref(out, id, false),
-        Property::Blob(ref id) => write_ref(out, id, true),
+        Property::Reference(ref id) => write_ref(out, id.id(), false),
+        Property::Blob(ref id) => write_ref(out, id.id(), true),
+        Property::Inline(ref data) => {
+            out.write_all(b"d6:inline")?;
+            write_str(out, &hash::base64_encode(data))?;
+            out.write_all(b"e")
+        }
     }
 }
 
@@ -86,7 +104,9 @@ fn write_data<W: Write>(out: &mut W, data: &ObjectData)
 }
 
 /// Write out the object on the given `Write` handle.
-pub fn serialize<W: Write>(mut out: &mut W, object: &Object) -> io::Result<()> {
+pub fn serialize<W: Write>(mut out: &mut W, object: &Object)
+    -> io::Result<()>
+{
     out.write_all(b"d\
                     1:d12:dhstore_0001\
                     1:r")?;
@@ -95,7 +115,7 @@ pub fn serialize<W: Write>(mut out: &mut W, object: &Object) -> io::Result<()> {
         hasher.write_all(b"object\n").unwrap();
         let mut hasherwriter = HasherWriter::with_hasher(&mut out, hasher);
         write_data(&mut hasherwriter, &object.data)?;
-        if hasherwriter.result() != object.id {
+        if hasherwriter.result() != *object.id.id() {
             panic!("serializing an object yielded a different ID");
         }
     } else {
@@ -131,12 +151,20 @@ impl Item {
     }
 }
 
-fn read_item<R: Read>(read: &mut R) -> io::Result<Item> {
+/// How deep dicts and lists can be nested in an object: the object itself,
+/// then the dicts of references, blobs and inline data.
+///
+/// Checked before recursing, so that untrusted input can't overflow the
+/// stack.
+const MAX_DEPTH: usize = 2;
+
+fn read_item<R: Read>(read: &mut R, depth: usize) -> io::Result<Item> {
     match read_byte(read)? {
+        b'd' | b'l' if depth >= MAX_DEPTH => invalid!("nested too deeply"),
         b'd' => {
             let mut dict = BTreeMap::new();
             loop {
-                let key = match read_item(read)? {
+                let key = match read_item(read, depth + 1)? {
                     Item::End => return Ok(Item::Dict(dict)),
                     Item::String(s) => s,
                     _ => invalid!("invalid dict key"),
@@ -149,7 +177,7 @@ fn read_item<R: Read>(read: &mut R) -> io::Result<Item> {
                 if dict.get(&key).is_some() {
                     invalid!("duplicate key {:?} in dict", key);
                 }
-                let value = match read_item(read)? {
+                let value = match read_item(read, depth + 1)? {
                     Item::End => invalid!("missing value for key {:?} in dict",
                                           key),
                     v => v,
@@ -160,48 +188,75 @@ fn read_item<R: Read>(read: &mut R) -> io::Result<Item> {
         b'l' => {
             let mut list = Vec::new();
             loop {
-                match read_item(read)? {
+                match read_item(read, depth + 1)? {
                     Item::End => return Ok(Item::List(list)),
                     v => list.push(v),
                 }
             }
         }
         c @ b'0'..=b'9' => {
-            let mut len = (c - b'0') as usize;
+            let mut len = (c - b'0') as u64;
             loop {
                 let c = read_byte(read)?;
-                if b'0' <= c && c <= b'9' {
-                    len = len * 10 + (c - b'0') as usize;
+                if c.is_ascii_digit() && len == 0 {
+                    invalid!("leading zero in string length");
+                } else if c.is_ascii_digit() {
+                    len = match len.checked_mul(10)
+                        .and_then(|l| l.checked_add((c - b'0') as u64))
+                    {
+                        Some(l) => l,
+                        None => invalid!("invalid string length"),
+                    };
                 } else if c == b':' {
-                    let mut s = String::new();
-                    for _ in 0..len {
-                        s.push(read_byte(read)? as char);
+                    let mut bytes = Vec::new();
+                    read.take(len).read_to_end(&mut bytes)?;
+                    if (bytes.len() as u64) < len {
+                        return Err(io::ErrorKind::UnexpectedEof.into());
                     }
-                    return Ok(Item::String(s));
+                    return match String::from_utf8(bytes) {
+                        Ok(s) => Ok(Item::String(s)),
+                        Err(_) => invalid!("string is not valid UTF-8"),
+                    };
                 } else {
                     invalid!("invalid string length");
                 }
             }
         }
         b'i' => {
+            let mut d = read_byte(read)?;
+            let negative = d == b'-';
+            if negative {
+                d = read_byte(read)?;
+            }
+            // Accumulated with the sign, so that i64::MIN can be read
             let mut nb: i64 = 0;
+            let mut digits = 0;
             loop {
-                let d = read_byte(read)?;
-                if b'0' <= d && d <= b'9' {
-                    let (n, o) = nb.overflowing_mul(10);
-                    if o {
-                        invalid!("integer overflow");
-                    }
-                    let (n, o) = n.overflowing_add((d - b'0') as i64);
-                    if o {
-                        invalid!("integer overflow");
-                    }
-                    nb = n;
+                if d.is_ascii_digit() && digits > 0 && nb == 0 {
+                    invalid!("leading zero in integer");
+                } else if d.is_ascii_digit() {
+                    let digit = (d - b'0') as i64;
+                    nb = match nb.checked_mul(10).and_then(|n| {
+                        if negative {
+                            n.checked_sub(digit)
+                        } else {
+                            n.checked_add(digit)
+                        }
+                    }) {
+                        Some(n) => n,
+                        None => invalid!("integer overflow"),
+                    };
+                    digits += 1;
+                } else if d == b'e' && digits == 0 {
+                    invalid!("integer without digits");
+                } else if d == b'e' && negative && nb == 0 {
+                    invalid!("negative zero");
                 } else if d == b'e' {
                     return Ok(Item::Integer(nb));
                 } else {
                     invalid!("invalid character in integer");
                 }
+                d = read_byte(read)?;
             }
         }
         b'e' => Ok(Item::End),
@@ -216,12 +271,19 @@ fn convert_property(item: Item) -> Option<Property> {
         Item::Dict(d) => {
             if d.len() == 1 {
                 let (k, v) = d.into_iter().next().unwrap();
+                if k == "inline" {
+                    return v.str().map(str::as_bytes)
+                        .and_then(hash::base64_decode)
+                        .map(Property::Inline);
+                }
                 if let Some(v) = v.str().map(str::as_bytes)
                     .and_then(ID::from_str)
                 {
                     return match &k[..] {
-                        "ref" => Some(Property::Reference(v)),
-                        "blob" => Some(Property::Blob(v)),
+                        "ref" => {
+                            Some(Property::Reference(ObjectId::from_id(v)))
+                        }
+                        "blob" => Some(Property::Blob(BlobId::from_id(v))),
                         _ => None,
                     };
                 }
@@ -245,7 +307,7 @@ fn expect<R: Read>(mut read: R, what: &[u8]) -> io::Result<()> {
 /// Read an Object from the given `Read` handle.
 pub fn deserialize<R: Read>(mut read: R) -> io::Result<Object> {
     expect(&mut read, b"d1:d")?;
-    let obj = read_item(&mut read)?;
+    let obj = read_item(&mut read, 0)?;
     match obj {
         Item::String(s) => {
             if s != "dhstore_0001" {
@@ -259,8 +321,8 @@ pub fn deserialize<R: Read>(mut read: R) -> io::Result<Object> {
         let mut hasher = Hasher::new();
         hasher.write_all(b"object\n").unwrap();
         let mut reader = HasherReader::with_hasher(&mut read, hasher);
-        let obj = read_item(&mut reader)?;
-        (obj, reader.result())
+        let obj = read_item(&mut reader, 0)?;
+        (obj, ObjectId::from_id(reader.result()))
     };
     expect(&mut read, b"e")?;
     if read.read(&mut [0u8])? != 0 {
@@ -290,6 +352,11 @@ pub fn deserialize<R: Read>(mut read: R) -> io::Result<Object> {
         }
         _ => invalid!("invalid object type"),
     };
+    // Any other encoding of the same data, such as a leading zero, would
+    // give the object an ID that serializing it doesn't
+    if object_id(&data) != id {
+        invalid!("object is not in canonical form");
+    }
     let object = Object {
         id: id,
         data,
@@ -298,21 +365,53 @@ pub fn deserialize<R: Read>(mut read: R) -> io::Result<Object> {
 }
 
 /// Hash the given object data, and tack on the digest to form an `Object`.
+///
+/// The ID is the hash of the canonical encoding of the data, described at
+/// the top of this module, so it doesn't depend on how the data was built.
 pub fn hash_object(data: ObjectData) -> Object {
-    let mut hasher = Hasher::new();
-    hasher.write_all(b"object\n").unwrap();
-    write_data(&mut hasher, &data).unwrap();
     Object {
-        id: hasher.result(),
+        id: object_id(&data),
         data: data,
     }
 }
 
+/// Computes the ID that the given object data should have.
+pub fn object_id(data: &ObjectData) -> ObjectId {
+    let mut hasher = Hasher::new();
+    hasher.write_all(b"object\n").unwrap();
+    write_data(&mut hasher, data).unwrap();
+    ObjectId::from_id(hasher.result())
+}
+
+/// Encodes object data in its canonical form, the bytes its ID hashes.
+pub fn canonical_bytes(data: &ObjectData) -> Vec<u8> {
+    let mut out = b"object\n".to_vec();
+    write_data(&mut out, data).unwrap();
+    out
+}
+
+/// Computes the ID of a blob.
+pub fn hash_blob(blob: &[u8]) -> BlobId {
+    let mut hasher = Hasher::new();
+    hasher.write_all(b"blob\n").unwrap();
+    hasher.write_all(blob).unwrap();
+    BlobId::from_id(hasher.result())
+}
+
+/// Computes the ID of a blob, reading it from a stream.
+pub fn hash_blob_reader(reader: &mut dyn Read) -> io::Result<BlobId> {
+    let mut hasher = Hasher::new();
+    hasher.write_all(b"blob\n").unwrap();
+    io::copy(reader, &mut hasher)?;
+    Ok(BlobId::from_id(hasher.result()))
+}
+
 #[cfg(test)]
 mod tests {
     use std::io::Cursor;
 
-    use crate::common::{ID, Dict, List, ObjectData, Property};
+    use crate::common::{BlobId, Dict, ID, List, ObjectData, ObjectId,
+                        Property};
     use crate::serialize::{hash_object, serialize, deserialize};
 
     fn fake_id(digit: u8) -> ID {
@@ -345,10 +444,12 @@ mod tests {
         properties.insert("filename".into(),
                           Property::String("DSC_20170303223104.jpg".into()));
         properties.insert("people".into(), Property::Integer(5));
-        properties.insert("camera".into(), Property::Reference(fake_id(1)));
-        properties.insert("data".into(), Property::Blob(fake_id(2)));
-        let hash = ID::from_str(b"DNbf17WpaH2XJC5tRWYhMO\
-                                  TQdMt2TSutfKAp3wnKoIV7").unwrap();
+        properties.insert("camera".into(),
+                          Property::Reference(ObjectId::from_id(fake_id(1))));
+        properties.insert("data".into(),
+                          Property::Blob(BlobId::from_id(fake_id(2))));
+        let hash = ObjectId::parse(b"DNbf17WpaH2XJC5tRWYhMO\
+                                     TQdMt2TSutfKAp3wnKoIV7").unwrap();
         let obj = hash_object(ObjectData::Dict(properties));
         assert_eq!(obj.id, hash);
         let mut serialized = Vec::new();
@@ -360,8 +461,8 @@ mod tests {
     fn test_deserialize_dict() {
         let obj = deserialize(Cursor::new(TEST_DICT)).unwrap();
         assert_eq!(obj.id,
-                   ID::from_str(b"DNbf17WpaH2XJC5tRWYhMO\
-                                  TQdMt2TSutfKAp3wnKoIV7").unwrap());
+                   ObjectId::parse(b"DNbf17WpaH2XJC5tRWYhMO\
+                                     TQdMt2TSutfKAp3wnKoIV7").unwrap());
     }
 
     const TEST_LIST: &'static [u8] =
@@ -383,8 +484,8 @@ mod tests {
             .map(|&s: &&str| -> String { s.into() })
             .map(Property::String)
             .collect();
-        let hash = ID::from_str(b"DOdY4OwCEf6AouK4eK6fRs\
-                                  mG6JiGoKjfe-fOJ-I29H1D").unwrap();
+        let hash = ObjectId::parse(b"DOdY4OwCEf6AouK4eK6fRs\
+                                     mG6JiGoKjfe-fOJ-I29H1D").unwrap();
         let obj = hash_object(ObjectData::List(properties));
         assert_eq!(obj.id, hash);
         let mut serialized = Vec::new();
@@ -396,7 +497,68 @@ mod tests {
     fn test_deserialize_list() {
         let obj = deserialize(Cursor::new(TEST_LIST)).unwrap();
         assert_eq!(obj.id,
-                   ID::from_str(b"DOdY4OwCEf6AouK4eK6fRs\
-                                  mG6JiGoKjfe-fOJ-I29H1D").unwrap());
+                   ObjectId::parse(b"DOdY4OwCEf6AouK4eK6fRs\
+                                     mG6JiGoKjfe-fOJ-I29H1D").unwrap());
+    }
+
+    #[test]
+    fn test_canonical() {
+        let list = vec![Property::Integer(-42), Property::Integer(i64::MIN),
+                        Property::String("Café ☕".into())];
+        let obj = hash_object(ObjectData::List(list.clone()));
+        let mut serialized = Vec::new();
+        serialize(&mut serialized, &obj).unwrap();
+        assert_eq!(serialized,
+                   "d1:d12:dhstore_00011:rl\
+                    i-42ei-9223372036854775808e9:Café ☕ee".as_bytes());
+        let obj2 = deserialize(Cursor::new(&serialized)).unwrap();
+        assert_eq!(obj2.id, obj.id);
+        match obj2.data {
+            ObjectData::List(ref l) => assert_eq!(l, &list),
+            _ => panic!("not a list"),
+        }
+
+        let invalid: &[&[u8]] = &[
+            b"d1:d12:dhstore_00011:rli007eee",
+            b"d1:d12:dhstore_00011:rli-0eee",
+            b"d1:d12:dhstore_00011:rliee",
+            b"d1:d12:dhstore_00011:rl03:abcee",
+            b"d1:d12:dhstore_00011:rl2:\xc3(ee",
+            b"d1:d12:dhstore_00011:rl99999999999999999999:ee",
+            b"d1:d12:dhstore_00011:rld6:inline3:AA=eee",
+            b"d1:d012:dhstore_00011:rlee",
+            b"d1:d12:dhstore_00011:rlllleeee",
+        ];
+        for bytes in invalid {
+            assert!(deserialize(Cursor::new(bytes)).is_err(),
+                    "accepted {:?}", String::from_utf8_lossy(bytes));
+        }
+    }
+
+    #[test]
+    fn test_nesting() {
+        let mut bytes = b"d1:d12:dhstore_00011:r".to_vec();
+        bytes.extend(std::iter::repeat_n(b'l', 1_000_000));
+        assert!(deserialize(Cursor::new(bytes)).is_err());
+    }
+
+    #[test]
+    fn test_inline() {
+        let data = vec![0u8, 1, 2, 253, 254, 255, b'e'];
+        let list = vec![Property::Integer(0), Property::Inline(data.clone())];
+        let obj = hash_object(ObjectData::List(list));
+        let mut serialized = Vec::new();
+        serialize(&mut serialized, &obj).unwrap();
+        assert_eq!(serialized,
+                   &b"d1:d12:dhstore_00011:rl\
+                      i0ed6:inline10:AAEC_f7_ZQeee"[..]);
+        let obj2 = deserialize(Cursor::new(&serialized)).unwrap();
+        assert_eq!(obj2.id, obj.id);
+        match obj2.data {
+            ObjectData::List(ref l) => {
+                assert_eq!(l[1], Property::Inline(data));
+            }
+            _ => panic!("not a list"),
+        }
     }
 }
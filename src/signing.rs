@@ -0,0 +1,248 @@
+//! Detached signatures over objects.
+//!
+//! A signature is an object of its own, referencing the object it signs, so
+//! anything can be signed after the fact without changing its ID. It covers
+//! the canonical encoding of the signed object, and so everything that object
+//! references by ID. The signature objects are kept alive along with the
+//! object they sign, and travel with it when it is copied to another store.
+//!
+//! Signatures use Ed25519. They don't say whether the key should be trusted;
+//! that is up to whoever checks them.
+
+use std::fmt;
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use ed25519_dalek::{PUBLIC_KEY_LENGTH, SIGNATURE_LENGTH, Signature,
+                    Signer, Verifier, VerifyingKey};
+use sha2::{Digest, Sha256};
+
+use crate::common::{Dict, Object, ObjectData, ObjectId, Property};
+use crate::errors::{self, Error};
+use crate::hash::{base64_decode, base64_encode};
+use crate::serialize::canonical_bytes;
+
+/// Prefix of the signed message, so signatures made with the same key for
+/// other purposes can't be passed off as signatures of objects.
+const CONTEXT: &[u8] = b"dhstore signature\n";
+
+/// Secret key used to sign objects.
+#[derive(Clone)]
+pub struct SigningKey([u8; 32]);
+
+impl SigningKey {
+    /// Uses these bytes as the secret key directly.
+    pub fn from_bytes(bytes: [u8; 32]) -> SigningKey {
+        SigningKey(bytes)
+    }
+
+    /// Derives a key from the contents of a file, which should be random.
+    pub fn from_keyfile<P: AsRef<Path>>(path: P)
+        -> errors::Result<SigningKey>
+    {
+        let mut contents = Vec::new();
+        File::open(path)
+            .and_then(|mut fp| fp.read_to_end(&mut contents))
+            .map_err(|e| ("Can't read key file", e))?;
+        if contents.len() < 16 {
+            return Err(Error::InvalidInput("Key file is too short"));
+        }
+        Ok(SigningKey(Sha256::digest(&contents).into()))
+    }
+
+    /// The public key, with which signatures are checked.
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.dalek().verifying_key().to_bytes())
+    }
+
+    fn dalek(&self) -> ed25519_dalek::SigningKey {
+        ed25519_dalek::SigningKey::from_bytes(&self.0)
+    }
+}
+
+/// Public key of a signer.
+///
+/// It is displayed and parsed in base64, with the same alphabet as IDs.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct PublicKey([u8; PUBLIC_KEY_LENGTH]);
+
+impl PublicKey {
+    /// Makes a key from its 32 bytes.
+    pub fn from_bytes(bytes: &[u8]) -> Option<PublicKey> {
+        if bytes.len() != PUBLIC_KEY_LENGTH {
+            return None;
+        }
+        let mut key = [0u8; PUBLIC_KEY_LENGTH];
+        key.copy_from_slice(bytes);
+        Some(PublicKey(key))
+    }
+
+    /// Parses a key from its base64 form.
+    pub fn parse(encoded: &str) -> Option<PublicKey> {
+        PublicKey::from_bytes(&base64_decode(encoded.as_bytes())?)
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&base64_encode(&self.0))
+    }
+}
+
+/// A signature found by `Store::verify_signature()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SignatureCheck {
+    /// The signature object
+    pub signature: ObjectId,
+    /// The key it claims to be from
+    pub key: PublicKey,
+    /// Whether the signature matches the object and key
+    pub valid: bool,
+}
+
+/// Makes a signature object for an object.
+pub fn signature(key: &SigningKey, object: &Object) -> ObjectData {
+    let mut message = CONTEXT.to_vec();
+    message.extend_from_slice(&canonical_bytes(&object.data));
+    let sig = key.dalek().sign(&message).to_bytes();
+    let mut data = Dict::new();
+    data.insert("dhstore_kind".into(), Property::String("signature".into()));
+    data.insert("algorithm".into(), Property::String("ed25519".into()));
+    data.insert("signed".into(), Property::Reference(object.id.clone()));
+    data.insert("key".into(),
+                Property::Inline(key.public_key().0.to_vec()));
+    data.insert("signature".into(), Property::Inline(sig.to_vec()));
+    ObjectData::Dict(data)
+}
+
+/// Gets the ID of the object a signature is for.
+///
+/// Returns `None` if the dict is not a well-formed signature object.
+pub fn signed_object(dict: &Dict) -> Option<&ObjectId> {
+    match (dict.get("dhstore_kind"), dict.get("algorithm"),
+           dict.get("signed"))
+    {
+        (Some(Property::String(kind)), Some(Property::String(algo)),
+         Some(Property::Reference(id)))
+            if kind == "signature" && algo == "ed25519" => Some(id),
+        _ => None,
+    }
+}
+
+/// Checks a signature object against the object it signs.
+///
+/// Returns `None` if the dict is not a well-formed signature of an object
+/// with that ID.
+pub fn check_signature(signature: &Object, object: &Object)
+    -> Option<SignatureCheck>
+{
+    let dict = match signature.data {
+        ObjectData::Dict(ref dict) => dict,
+        ObjectData::List(_) => return None,
+    };
+    if signed_object(dict) != Some(&object.id) {
+        return None;
+    }
+    let (key, sig) = match (dict.get("key"), dict.get("signature")) {
+        (Some(Property::Inline(key)), Some(Property::Inline(sig)))
+            if sig.len() == SIGNATURE_LENGTH => (key, sig),
+        _ => return None,
+    };
+    let key = PublicKey::from_bytes(key)?;
+    let sig = Signature::from_slice(sig).ok()?;
+    let mut message = CONTEXT.to_vec();
+    message.extend_from_slice(&canonical_bytes(&object.data));
+    let valid = match VerifyingKey::from_bytes(&key.0) {
+        Ok(verifying) => verifying.verify(&message, &sig).is_ok(),
+        Err(_) => false,
+    };
+    Some(SignatureCheck { signature: signature.id.clone(), key, valid })
+}
+
+#[cfg(test)]
+mod tests {
+    use ed25519_dalek::Signer;
+
+    use crate::common::{ObjectData, Property};
+    use crate::serialize::hash_object;
+    use super::{PublicKey, SigningKey, check_signature, signature};
+
+    #[test]
+    fn test_signature() {
+        let key = SigningKey::from_bytes([1u8; 32]);
+        let public = key.public_key();
+        assert_eq!(PublicKey::parse(&public.to_string()),
+                   Some(public.clone()));
+
+        let object = hash_object(ObjectData::List(vec![Property::Integer(1)]));
+        let sig = hash_object(signature(&key, &object));
+        let check = check_signature(&sig, &object).unwrap();
+        assert_eq!(check.key, public);
+        assert!(check.valid);
+
+        // Not a signature for this object
+        let other = hash_object(ObjectData::List(vec![Property::Integer(2)]));
+        assert!(check_signature(&sig, &other).is_none());
+
+        // Signature from another key
+        let mut forged = match sig.data {
+            ObjectData::Dict(ref dict) => dict.clone(),
+            _ => unreachable!(),
+        };
+        let other_key = SigningKey::from_bytes([2u8; 32]).public_key();
+        forged.insert("key".into(), Property::Inline(other_key.0.to_vec()));
+        let forged = hash_object(ObjectData::Dict(forged));
+        let check = check_signature(&forged, &object).unwrap();
+        assert_eq!(check.key, other_key);
+        assert!(!check.valid);
+
+        // S + L verifies mathematically, but isn't canonical
+        const L: [u8; 32] = [
+            0xed, 0xd3, 0xf5, 0x5c, 0x1a, 0x63, 0x12, 0x58,
+            0xd6, 0x9c, 0xf7, 0xa2, 0xde, 0xf9, 0xde, 0x14,
+            0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x10,
+        ];
+        let mut malleable = match sig.data {
+            ObjectData::Dict(ref dict) => dict.clone(),
+            _ => unreachable!(),
+        };
+        let mut bytes = match malleable.get("signature") {
+            Some(Property::Inline(bytes)) => bytes.clone(),
+            _ => unreachable!(),
+        };
+        let mut carry = 0;
+        for i in 0..32 {
+            let s = u16::from(bytes[32 + i]) + u16::from(L[i]) + carry;
+            bytes[32 + i] = s as u8;
+            carry = s >> 8;
+        }
+        malleable.insert("signature".into(), Property::Inline(bytes));
+        let malleable = hash_object(ObjectData::Dict(malleable));
+        assert!(!check_signature(&malleable, &object).unwrap().valid);
+    }
+
+    #[test]
+    fn test_ed25519() {
+        // RFC 8032 section 7.1, test 2
+        let key = SigningKey::from_bytes([
+            0x4c, 0xcd, 0x08, 0x9b, 0x28, 0xff, 0x96, 0xda,
+            0x9d, 0xb6, 0xc3, 0x46, 0xec, 0x11, 0x4e, 0x0f,
+            0x5b, 0x8a, 0x31, 0x9f, 0x35, 0xab, 0xa6, 0x24,
+            0xda, 0x8c, 0xf6, 0xed, 0x4f, 0xb8, 0xa6, 0xfb,
+        ]);
+        assert_eq!(key.public_key().to_string(),
+                   "PUAXw-hDiVqStwqnTRt-vJyYLM8uxJaMwM1V8Sr0Zgw");
+        let sig = key.dalek().sign(b"\x72").to_bytes();
+        assert_eq!(sig.to_vec(), vec![
+            0x92, 0xa0, 0x09, 0xa9, 0xf0, 0xd4, 0xca, 0xb8,
+            0x72, 0x0e, 0x82, 0x0b, 0x5f, 0x64, 0x25, 0x40,
+            0xa2, 0xb2, 0x7b, 0x54, 0x16, 0x50, 0x3f, 0x8f,
+            0xb3, 0x76, 0x22, 0x23, 0xeb, 0xdb, 0x69, 0xda,
+            0x08, 0x5a, 0xc1, 0xe4, 0x3e, 0x15, 0x99, 0x6e,
+            0x45, 0x8f, 0x36, 0x13, 0xd0, 0xf1, 0x1d, 0x8c,
+            0x38, 0x7b, 0x2e, 0xae, 0xb4, 0x30, 0x2a, 0xee,
+            0xb0, 0x0d, 0x29, 0x16, 0x12, 0xbb, 0x0c, 0x00,
+        ]);
+    }
+}
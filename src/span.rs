@@ -0,0 +1,88 @@
+//! Timing of operations, for profiling.
+//!
+//! A `Span` covers an operation, such as adding a file or writing a batch of
+//! blobs. When it ends, it logs how long it took along with the operations it
+//! was nested in, what it was about and the number of bytes it handled, for
+//! example:
+//!
+//! ```text
+//! add_file > chunk > add_blobs: 12 blobs, 4194304 bytes in 3.1ms
+//! ```
+//!
+//! Spans are logged at the trace level with the target `dhstore::span`, and
+//! cost nothing more than checking the log level when that is disabled. They
+//! are left out of the build entirely with the `max_level_*` features of
+//! `log`.
+
+use std::cell::RefCell;
+use std::fmt::Display;
+use std::time::Instant;
+
+use log::{Level, log_enabled, trace};
+
+thread_local! {
+    /// Names of the spans open on this thread, outermost first.
+    static OPEN: RefCell<Vec<&'static str>> =
+        const { RefCell::new(Vec::new()) };
+}
+
+/// A timed operation, logged when dropped.
+pub struct Span {
+    /// When the span started, `None` if spans are not logged
+    start: Option<Instant>,
+    detail: String,
+    bytes: Option<u64>,
+}
+
+impl Span {
+    /// Starts an operation, nested in the spans currently open.
+    pub fn enter(name: &'static str) -> Span {
+        let start = if log_enabled!(target: "dhstore::span", Level::Trace) {
+            OPEN.with(|open| open.borrow_mut().push(name));
+            Some(Instant::now())
+        } else {
+            None
+        };
+        Span { start, detail: String::new(), bytes: None }
+    }
+
+    /// Describes what the operation is about, such as an ID or a path.
+    pub fn detail<D: Display>(&mut self, detail: D) {
+        if self.start.is_some() {
+            self.detail = detail.to_string();
+        }
+    }
+
+    /// Counts bytes handled by the operation.
+    pub fn add_bytes(&mut self, bytes: u64) {
+        if self.start.is_some() {
+            *self.bytes.get_or_insert(0) += bytes;
+        }
+    }
+}
+
+impl Drop for Span {
+    fn drop(&mut self) {
+        let start = match self.start {
+            Some(start) => start,
+            None => return,
+        };
+        let elapsed = start.elapsed();
+        let names = OPEN.with(|open| {
+            let mut open = open.borrow_mut();
+            let names = open.join(" > ");
+            open.pop();
+            names
+        });
+        let mut message = names;
+        if !self.detail.is_empty() {
+            message.push_str(": ");
+            message.push_str(&self.detail);
+        }
+        if let Some(bytes) = self.bytes {
+            message.push_str(if self.detail.is_empty() { ": " } else { ", " });
+            message.push_str(&format!("{} bytes", bytes));
+        }
+        trace!(target: "dhstore::span", "{} in {:?}", message, elapsed);
+    }
+}
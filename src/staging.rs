@@ -0,0 +1,117 @@
+//! Objects and blobs written tentatively, outside of a store.
+//!
+//! A large import, or data received over the network, takes a while to come
+//! in and can stop halfway. Writing it to a `Staging` area instead of the
+//! store keeps the partial data out of the index: queries don't see it, and
+//! garbage collection neither deletes it nor keeps its blobs alive. Once
+//! complete, the staged data is moved into the store with
+//! `Store::promote()`, or thrown away with `Staging::discard()`.
+
+use std::collections::HashMap;
+use std::io::Read;
+
+use crate::common::{BlobId, EnumerableBlobStorage, Object, ObjectData,
+                    ObjectId};
+use crate::errors;
+use crate::serialize::hash_object;
+
+/// Staging area for objects and blobs.
+///
+/// Blobs are written to a blob storage dedicated to the staging area, such
+/// as the one from `dhstore::staging_area()`. Objects are kept in memory,
+/// in the order they were added.
+pub struct Staging<B: EnumerableBlobStorage> {
+    blobs: B,
+    objects: Vec<Object>,
+    /// Position of each object in `objects`
+    ids: HashMap<ObjectId, usize>,
+}
+
+impl<B: EnumerableBlobStorage> Staging<B> {
+    /// Stages blobs in this storage.
+    ///
+    /// Blobs it already contains are considered staged, e.g. from an
+    /// interrupted earlier attempt.
+    pub fn new(blobs: B) -> Staging<B> {
+        Staging {
+            blobs,
+            objects: Vec::new(),
+            ids: HashMap::new(),
+        }
+    }
+
+    /// Stages a blob.
+    pub fn add_blob(&mut self, blob: &[u8]) -> errors::Result<BlobId> {
+        self.blobs.add_blob(blob)
+    }
+
+    /// Stages a blob from a reader.
+    pub fn add_blob_from_reader(&mut self, reader: &mut dyn Read)
+        -> errors::Result<BlobId>
+    {
+        self.blobs.add_blob_from_reader(reader)
+    }
+
+    /// Hashes an object and stages it.
+    pub fn add(&mut self, data: ObjectData) -> ObjectId {
+        let object = hash_object(data);
+        let id = object.id.clone();
+        if !self.ids.contains_key(&id) {
+            self.ids.insert(id.clone(), self.objects.len());
+            self.objects.push(object);
+        }
+        id
+    }
+
+    /// Gets a staged object.
+    pub fn get_object(&self, id: &ObjectId) -> Option<&Object> {
+        self.ids.get(id).map(|&i| &self.objects[i])
+    }
+
+    /// The staged objects, in the order they were added.
+    pub fn objects(&self) -> &[Object] {
+        &self.objects
+    }
+
+    /// The storage holding the staged blobs.
+    pub fn blobs(&self) -> &B {
+        &self.blobs
+    }
+
+    /// Forgets the staged objects and deletes the staged blobs.
+    pub fn discard(&mut self) -> errors::Result<()> {
+        self.objects.clear();
+        self.ids.clear();
+        let blobs = self.blobs.list_blobs()?
+            .collect::<errors::Result<Vec<BlobId>>>()?;
+        for id in blobs {
+            self.blobs.delete_blob(&id)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::common::{BlobStorage, MemoryBlobStorage, ObjectData,
+                        Property};
+    use super::Staging;
+
+    #[test]
+    fn test_staging() {
+        let mut staging = Staging::new(MemoryBlobStorage::default());
+        let blob = staging.add_blob(b"chunk").unwrap();
+        let list = ObjectData::List(vec![Property::Integer(0),
+                                         Property::Blob(blob.clone())]);
+        let first = staging.add(list.clone());
+        assert_eq!(staging.add(list), first);
+        assert_eq!(staging.objects().len(), 1);
+        assert!(staging.get_object(&first).is_some());
+        assert!(staging.blobs().contains(&blob).unwrap());
+
+        staging.discard().unwrap();
+        assert!(staging.objects().is_empty());
+        assert!(staging.get_object(&first).is_none());
+        assert!(!staging.blobs().contains(&blob).unwrap());
+    }
+}
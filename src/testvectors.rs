@@ -0,0 +1,256 @@
+//! Test vectors, for checking other implementations of the format.
+//!
+//! `generate()` always returns the same objects and blobs, covering the
+//! corner cases of the encoding: key order, Unicode strings, extreme
+//! integers, inline data of every padding length. `write()` publishes them
+//! one per line:
+//!
+//! ```text
+//! object <name> <ID> <serialized object, in hexadecimal>
+//! blob <name> <ID> <contents, in hexadecimal>
+//! ```
+//!
+//! An implementation is compatible if it computes the same IDs, and reads
+//! then serializes each object back to the same bytes. `verify()` checks a
+//! published file against this crate.
+
+use std::io::{BufRead, Write};
+
+use crate::common::{BlobId, Dict, ID, ObjectData, ObjectId, Property};
+use crate::errors::{self, Error};
+use crate::serialize;
+
+/// A test vector: an object or blob, with its ID and encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TestVector {
+    pub name: &'static str,
+    pub kind: VectorKind,
+    pub id: ID,
+    /// The serialized object, or the contents of the blob
+    pub bytes: Vec<u8>,
+}
+
+/// Whether a test vector is an object or a blob.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VectorKind {
+    Object,
+    Blob,
+}
+
+impl VectorKind {
+    /// The name used in the published file.
+    pub fn as_str(&self) -> &'static str {
+        match *self {
+            VectorKind::Object => "object",
+            VectorKind::Blob => "blob",
+        }
+    }
+
+    pub fn parse(name: &str) -> Option<VectorKind> {
+        match name {
+            "object" => Some(VectorKind::Object),
+            "blob" => Some(VectorKind::Blob),
+            _ => None,
+        }
+    }
+}
+
+fn blob(name: &'static str, contents: &[u8]) -> TestVector {
+    TestVector {
+        name,
+        kind: VectorKind::Blob,
+        id: serialize::hash_blob(contents).into(),
+        bytes: contents.to_vec(),
+    }
+}
+
+fn object(name: &'static str, data: ObjectData) -> TestVector {
+    let object = serialize::hash_object(data);
+    let mut bytes = Vec::new();
+    serialize::serialize(&mut bytes, &object).unwrap();
+    let id = object.id.into();
+    TestVector { name, kind: VectorKind::Object, id, bytes }
+}
+
+/// A reference to an earlier object vector.
+fn reference(vector: &TestVector) -> Property {
+    Property::Reference(ObjectId::from_id(vector.id.clone()))
+}
+
+/// A reference to an earlier blob vector.
+fn blob_ref(vector: &TestVector) -> Property {
+    Property::Blob(BlobId::from_id(vector.id.clone()))
+}
+
+fn dict(entries: Vec<(&str, Property)>) -> ObjectData {
+    ObjectData::Dict(entries.into_iter()
+        .map(|(k, v)| (k.to_owned(), v))
+        .collect::<Dict>())
+}
+
+/// Builds the test vectors.
+///
+/// Later vectors reference the earlier ones, the way files and
+/// directories reference their blobs.
+pub fn generate() -> Vec<TestVector> {
+    let empty_blob = blob("empty-blob", b"");
+    let hello_blob = blob("hello-blob", b"hello\n");
+    let bytes_blob = blob("all-bytes-blob", &(0..=255).collect::<Vec<u8>>());
+    let empty_dict = object("empty-dict", ObjectData::Dict(Dict::new()));
+    let empty_list = object("empty-list", ObjectData::List(Vec::new()));
+    let strings = object("strings", ObjectData::List(
+        ["", "a", "with space", "new\nline", "Café ☕", "\u{10348}"].iter()
+            .map(|&s| Property::String(s.into()))
+            .collect()));
+    let integers = object("integers", ObjectData::List(
+        [0, 1, -1, 9, 10, -10, 1 << 32, i64::MAX, i64::MIN].iter()
+            .map(|&i| Property::Integer(i))
+            .collect()));
+    // Sorted by bytes, so "B" < "a" < "aa" < "b" < "é"
+    let key_order = object("key-order", dict(vec![
+        ("b", Property::Integer(1)),
+        ("é", Property::Integer(2)),
+        ("aa", Property::Integer(3)),
+        ("a", Property::Integer(4)),
+        ("B", Property::Integer(5)),
+        ("", Property::Integer(6)),
+    ]));
+    let inline = object("inline", ObjectData::List(
+        [&b""[..], b"\x00", b"\x00\x01", b"\x00\x01\x02", b"\xff\xfe\xfd\xfc"]
+            .iter()
+            .map(|d| Property::Inline(d.to_vec()))
+            .collect()));
+    let references = object("references", dict(vec![
+        ("dict", reference(&empty_dict)),
+        ("list", reference(&empty_list)),
+        ("blob", blob_ref(&empty_blob)),
+    ]));
+    let file = object("file", dict(vec![
+        ("size", Property::Integer(6)),
+        ("contents", blob_ref(&hello_blob)),
+    ]));
+    let chunks = object("chunks", ObjectData::List(vec![
+        Property::Integer(0),
+        blob_ref(&hello_blob),
+        Property::Integer(6),
+        blob_ref(&bytes_blob),
+    ]));
+    let chunked_file = object("chunked-file", dict(vec![
+        ("size", Property::Integer(262)),
+        ("contents", reference(&chunks)),
+    ]));
+    let directory = object("directory", dict(vec![
+        ("hello.txt", reference(&file)),
+        ("hello.txt/size", Property::Integer(6)),
+        ("hello.txt/type", Property::String("file".into())),
+        ("bytes", reference(&chunked_file)),
+        ("bytes/size", Property::Integer(262)),
+        ("bytes/type", Property::String("file".into())),
+    ]));
+    vec![empty_blob, hello_blob, bytes_blob,
+         empty_dict, empty_list, strings, integers, key_order, inline,
+         references, file, chunks, chunked_file, directory]
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2)
+        .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// Writes the test vectors, in the format described in the module
+/// documentation.
+pub fn write(out: &mut dyn Write) -> errors::Result<()> {
+    for vector in generate() {
+        writeln!(out, "{} {} {} {}", vector.kind.as_str(), vector.name,
+                 vector.id, hex(&vector.bytes))
+            .map_err(|e| ("Error writing test vectors", e))?;
+    }
+    Ok(())
+}
+
+/// Checks a file of test vectors against this implementation.
+///
+/// Returns the names of the vectors that don't match, either because the
+/// bytes hash to another ID or because an object doesn't read back to the
+/// same bytes. Empty lines and lines starting with `#` are ignored.
+pub fn verify<R: BufRead>(input: R) -> errors::Result<Vec<String>> {
+    let mut failed = Vec::new();
+    for line in input.lines() {
+        let line = line.map_err(|e| ("Error reading test vectors", e))?;
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let fields: Vec<&str> = line.split(' ').collect();
+        let (kind, name, id, bytes) = match fields[..] {
+            [kind, name, id, bytes] => (
+                VectorKind::parse(kind),
+                name,
+                ID::from_str(id.as_bytes()),
+                unhex(bytes),
+            ),
+            _ => return Err(Error::InvalidInput("Invalid test vector line")),
+        };
+        let (kind, id, bytes) = match (kind, id, bytes) {
+            (Some(kind), Some(id), Some(bytes)) => (kind, id, bytes),
+            _ => return Err(Error::InvalidInput("Invalid test vector line")),
+        };
+        let ok = match kind {
+            VectorKind::Blob => serialize::hash_blob(&bytes).into_id() == id,
+            VectorKind::Object => match serialize::deserialize(&bytes[..]) {
+                Ok(object) => {
+                    let mut out = Vec::new();
+                    serialize::serialize(&mut out, &object).unwrap();
+                    *object.id.id() == id && out == bytes
+                }
+                Err(_) => false,
+            },
+        };
+        if !ok {
+            failed.push(name.to_owned());
+        }
+    }
+    Ok(failed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{generate, verify, write};
+
+    #[test]
+    fn test_vectors() {
+        let vectors = generate();
+        assert_eq!(vectors, generate());
+        let empty = vectors.iter().find(|v| v.name == "empty-dict").unwrap();
+        assert_eq!(empty.bytes, b"d1:d12:dhstore_00011:rdee");
+        // The IDs are published, they can't change
+        let id = |name| {
+            vectors.iter().find(|v| v.name == name).unwrap().id.str()
+        };
+        assert_eq!(id("hello-blob"),
+                   "DBvoYV9_vaheHvAp8CVzvf7-CfsBrznmzmhBBKRaNgro");
+        assert_eq!(id("empty-dict"),
+                   "DJI0xGHUNShlOVy4VUzw6gNoMWheQcgqTGQG-0OyMbJb");
+        assert_eq!(id("directory"),
+                   "DGeD-CyonJDFeCZTmQZFbCq39hRli91HeUkzg_CZc4A6");
+
+        let mut published = Vec::new();
+        write(&mut published).unwrap();
+        assert!(verify(&published[..]).unwrap().is_empty());
+
+        // Changing a byte of an object breaks it
+        let mut published = String::from_utf8(published).unwrap();
+        let pos = published.find("\nobject empty-list ").unwrap();
+        let end = published[pos + 1..].find('\n').unwrap() + pos;
+        published.replace_range(end - 4..end - 2, "6c");
+        assert_eq!(verify(published.as_bytes()).unwrap(), ["empty-list"]);
+        assert!(verify(&b"object x y z\n"[..]).is_err());
+    }
+}
@@ -0,0 +1,147 @@
+//! Traversal of everything reachable from an object.
+//!
+//! `Store::walk_from()` goes over the objects and blobs an object leads to,
+//! following references and the values of permanodes, like
+//! `Store::copy_object()` does. Tools that need the whole tree, such as
+//! exporters or statistics, can build on it instead of walking the graph
+//! themselves.
+
+use std::borrow::Cow;
+use std::collections::{HashSet, VecDeque};
+
+use crate::common::{BlobId, ObjectId, BlobStorage, Object, ObjectIndex,
+                    Property};
+use crate::errors;
+use crate::{Store, object_properties};
+
+/// Order in which `Store::walk_from()` visits objects.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WalkOrder {
+    /// All the objects at some depth before any deeper one
+    BreadthFirst,
+    /// Everything reachable from a reference before the next reference
+    DepthFirst,
+}
+
+/// An object or blob reached by `Store::walk_from()`.
+#[derive(Clone)]
+pub enum WalkItem<'a> {
+    /// An object, with its distance from the starting object
+    Object { object: Cow<'a, Object>, depth: usize },
+    /// An object that is referenced but not in the index
+    Missing { id: ObjectId, depth: usize },
+    /// A blob, with the first object found referencing it
+    Blob { id: BlobId, from: ObjectId },
+}
+
+/// Iterator over the objects and blobs reachable from an object.
+///
+/// Each object and blob is reached once, even if it is referenced several
+/// times, so cycles don't make it loop. The blobs of an object come right
+/// after it. Iteration ends after an error.
+pub struct Walk<'a, S: BlobStorage, I: ObjectIndex> {
+    store: &'a Store<S, I>,
+    order: WalkOrder,
+    /// Objects to visit, with their depth
+    open: VecDeque<(ObjectId, usize)>,
+    seen: HashSet<ObjectId>,
+    seen_blobs: HashSet<BlobId>,
+    /// Blobs of the last object, to yield before moving on
+    pending: VecDeque<WalkItem<'a>>,
+}
+
+impl<'a, S: BlobStorage, I: ObjectIndex> Walk<'a, S, I> {
+    pub(crate) fn new(store: &'a Store<S, I>, root: &ObjectId,
+                      order: WalkOrder)
+        -> Walk<'a, S, I>
+    {
+        let mut open = VecDeque::new();
+        open.push_back((root.clone(), 0));
+        Walk {
+            store,
+            order,
+            open,
+            seen: HashSet::new(),
+            seen_blobs: HashSet::new(),
+            pending: VecDeque::new(),
+        }
+    }
+
+    /// Lists the objects an object leads to: references, then the values
+    /// if it is a permanode.
+    fn children(&self, object: &Object) -> errors::Result<Vec<ObjectId>> {
+        let mut refs: Vec<ObjectId> = object_properties(object)
+            .filter_map(|p| match p {
+                Property::Reference(r) => Some(r.clone()),
+                _ => None,
+            })
+            .collect();
+        let mut after = None;
+        while let Some(page) = self.store.index.list_permanode(
+            &object.id, after.as_ref(), 100)?
+        {
+            refs.extend(page.members.into_iter().map(|m| m.value));
+            match page.next {
+                Some(next) => after = Some(next),
+                None => break,
+            }
+        }
+        Ok(refs)
+    }
+
+    fn visit(&mut self, id: ObjectId, depth: usize)
+        -> errors::Result<WalkItem<'a>>
+    {
+        let object = match self.store.index.get_object(&id)? {
+            Some(o) => o,
+            None => return Ok(WalkItem::Missing { id, depth }),
+        };
+        for property in object_properties(&object) {
+            if let Property::Blob(blob) = property {
+                if self.seen_blobs.insert(blob.clone()) {
+                    self.pending.push_back(WalkItem::Blob {
+                        id: blob.clone(),
+                        from: id.clone(),
+                    });
+                }
+            }
+        }
+        let mut children: Vec<(ObjectId, usize)> = self.children(&object)?
+            .into_iter()
+            .filter(|r| !self.seen.contains(r))
+            .map(|r| (r, depth + 1))
+            .collect();
+        // The stack is popped from the back, so the first reference has to
+        // be pushed last
+        if self.order == WalkOrder::DepthFirst {
+            children.reverse();
+        }
+        self.open.extend(children);
+        Ok(WalkItem::Object { object, depth })
+    }
+}
+
+impl<'a, S: BlobStorage, I: ObjectIndex> Iterator for Walk<'a, S, I> {
+    type Item = errors::Result<WalkItem<'a>>;
+
+    fn next(&mut self) -> Option<errors::Result<WalkItem<'a>>> {
+        if let Some(item) = self.pending.pop_front() {
+            return Some(Ok(item));
+        }
+        loop {
+            let (id, depth) = match self.order {
+                WalkOrder::BreadthFirst => self.open.pop_front()?,
+                WalkOrder::DepthFirst => self.open.pop_back()?,
+            };
+            if !self.seen.insert(id.clone()) {
+                continue;
+            }
+            let item = self.visit(id, depth);
+            if item.is_err() {
+                self.open.clear();
+                self.pending.clear();
+            }
+            return Some(item);
+        }
+    }
+}